@@ -75,6 +75,7 @@ fn create_demo_flows() -> Vec<FlowInput> {
                     ingress_port: None,
                 },
             ],
+            retention_policy: None,
         },
         
         // 流 2: s1 → s2 → s4 (低延迟)
@@ -112,6 +113,7 @@ fn create_demo_flows() -> Vec<FlowInput> {
                     ingress_port: None,
                 },
             ],
+            retention_policy: None,
         },
         
         // 流 3: s2 → s3 → s4 (中等延迟)
@@ -149,6 +151,7 @@ fn create_demo_flows() -> Vec<FlowInput> {
                     ingress_port: None,
                 },
             ],
+            retention_policy: None,
         },
     ]
 }
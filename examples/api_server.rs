@@ -3,14 +3,15 @@ use tokio::net::TcpListener;
 use log::info;
 
 use intdb::{StorageEngine, AppState, create_router};
+use intdb::storage::EngineConfig;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     // Initialize logging
     env_logger::init();
     
-    // Create storage engine
-    let engine = StorageEngine::new();
+    // Create storage engine (replays the WAL when INTDB_DATA_DIR is set)
+    let engine = StorageEngine::open(EngineConfig::from_env()?)?;
     
     // Create application state
     let state = AppState::new(engine);
@@ -1,6 +1,6 @@
 use chrono::Utc;
-use intdb::models::{Flow, Hop, TelemetryMetrics, NetworkPath};
-use intdb::storage::{StorageEngine, QueryBuilder, PathCondition, TimeCondition, MetricCondition};
+use intdb::models::{Flow, Hop, TelemetryMetrics};
+use intdb::storage::{StorageEngine, QueryBuilder, PathCondition, MetricCondition};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("🚀 IntDB Storage Engine Demo");
@@ -118,9 +118,3 @@ fn create_flow(
     
     Ok(Flow::new(flow_id.to_string(), hops)?)
 }
-
-fn get_flows(engine: &StorageEngine, flow_ids: &[String]) -> Vec<Flow> {
-    flow_ids.iter()
-        .filter_map(|id| engine.get_flow(id))
-        .collect()
-} 
\ No newline at end of file
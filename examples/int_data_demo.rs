@@ -328,6 +328,7 @@ fn demonstrate_json_formats() -> Result<(), Box<dyn std::error::Error>> {
                 ingress_port: Some(1),
             },
         ],
+        retention_policy: None,
     };
     
     println!("Legacy Flow JSON format:");
@@ -363,6 +364,7 @@ fn demonstrate_json_formats() -> Result<(), Box<dyn std::error::Error>> {
                 ],
             },
         ],
+        retention_policy: None,
     };
     
     println!("\nSpatiotemporal Flow JSON format:");
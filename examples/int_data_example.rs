@@ -286,6 +286,7 @@ fn print_json_examples() -> Result<(), Box<dyn std::error::Error>> {
                 ingress_port: Some(0),
             },
         ],
+        retention_policy: None,
     };
     
     println!("Legacy Flow Input JSON:");
@@ -314,6 +315,7 @@ fn print_json_examples() -> Result<(), Box<dyn std::error::Error>> {
                 ],
             },
         ],
+        retention_policy: None,
     };
     
     println!("\nSpatiotemporal Flow Input JSON (Logical Only):");
@@ -355,6 +357,7 @@ fn print_json_examples() -> Result<(), Box<dyn std::error::Error>> {
                 ],
             },
         ],
+        retention_policy: None,
     };
     
     println!("\nSpatiotemporal Flow Input JSON (With Spatial Info):");
@@ -365,7 +368,7 @@ fn print_json_examples() -> Result<(), Box<dyn std::error::Error>> {
 
 /// Demonstrate various queries
 async fn demonstrate_queries(app_state: &AppState) -> Result<(), Box<dyn std::error::Error>> {
-    use intdb::storage::{QueryBuilder, PathCondition, TimeCondition};
+    use intdb::storage::QueryBuilder;
     
     println!("Query 1: Find flows through switch 's2'");
     let query = QueryBuilder::through_switch("s2").limit(10);
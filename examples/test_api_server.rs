@@ -92,6 +92,7 @@ fn populate_test_data(engine: &mut StorageEngine) -> Result<(), Box<dyn Error>>
                 ingress_port: None,
             },
         ],
+        retention_policy: None,
     };
     
     // 测试流 2: s1 → s2 → s4 (低延迟)
@@ -129,6 +130,7 @@ fn populate_test_data(engine: &mut StorageEngine) -> Result<(), Box<dyn Error>>
                 ingress_port: None,
             },
         ],
+        retention_policy: None,
     };
     
     // 测试流 3: 大数据中心路径 s1 → s2 → s5 → s6 → s7
@@ -186,6 +188,7 @@ fn populate_test_data(engine: &mut StorageEngine) -> Result<(), Box<dyn Error>>
                 ingress_port: None,
             },
         ],
+        retention_policy: None,
     };
     
     // 插入测试流
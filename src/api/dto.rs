@@ -1,16 +1,19 @@
+use std::collections::BTreeMap;
 use serde::{Deserialize, Serialize};
 use chrono::{DateTime, Utc};
 use crate::models::{Flow, FlowInput, SpatiotemporalFlow, SpatiotemporalFlowInput, SpatialExtent};
-use crate::storage::{QueryResult, PathCondition, TimeCondition, MetricCondition};
+use crate::replication::ReplicationStatus;
+use crate::storage::{QueryBuilder, QueryResult, RankedFlow, PathCondition, TimeCondition, MetricCondition, SnapshotInfo, SweepStats};
+use crate::storage::{FlowTelemetrySummary, SwitchHop, SwitchHopStats};
 
 /// Flow insertion request (legacy)
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct InsertFlowRequest {
     pub flow: FlowInput,
 }
 
 /// Spatiotemporal flow insertion request (new format)
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct InsertSpatiotemporalFlowRequest {
     pub flow: SpatiotemporalFlowInput,
 }
@@ -26,24 +29,27 @@ pub struct InsertFlowResponse {
 /// Single flow response (legacy)
 #[derive(Debug, Serialize)]
 pub struct FlowResponse {
+    #[serde(with = "crate::models::schema::record")]
     pub flow: Flow,
 }
 
 /// Single spatiotemporal flow response (new format)
 #[derive(Debug, Serialize)]
 pub struct SpatiotemporalFlowResponse {
+    #[serde(with = "crate::models::schema::record")]
     pub flow: SpatiotemporalFlow,
 }
 
 /// Multiple flows response
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct FlowsResponse {
+    #[serde(with = "crate::models::schema::records")]
     pub flows: Vec<Flow>,
     pub count: usize,
 }
 
 /// Query request for flows (legacy)
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct QueryRequest {
     /// Path-based conditions
     #[serde(default)]
@@ -66,6 +72,10 @@ pub struct QueryRequest {
     /// Whether to include full flow data or just IDs
     #[serde(default)]
     pub include_flows: bool,
+    
+    /// Evaluate the query, and return flows, as they were stored at this ingest time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub as_of: Option<DateTime<Utc>>,
 }
 
 /// Query request for spatiotemporal flows (new format)
@@ -138,6 +148,7 @@ pub enum QualityConditionDto {
 #[derive(Debug, Serialize)]
 pub struct QueryResponse {
     pub flow_ids: Vec<String>,
+    #[serde(with = "crate::models::schema::optional_records")]
     pub flows: Option<Vec<Flow>>,
     pub total_count: usize,
     pub has_more: bool,
@@ -148,13 +159,14 @@ pub struct QueryResponse {
 #[derive(Debug, Serialize)]
 pub struct SpatiotemporalQueryResponse {
     pub flow_ids: Vec<String>,
+    #[serde(with = "crate::models::schema::optional_records")]
     pub flows: Option<Vec<SpatiotemporalFlow>>,
     pub total_count: usize,
     pub limit: Option<usize>,
 }
 
 /// Path condition DTO
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type", content = "value")]
 pub enum PathConditionDto {
     #[serde(rename = "exact_path")]
@@ -172,6 +184,9 @@ pub enum PathConditionDto {
     #[serde(rename = "through_switch")]
     ThroughSwitch { switch_id: String },
     
+    #[serde(rename = "through_link")]
+    ThroughLink { from: String, to: String },
+    
     #[serde(rename = "length_equals")]
     LengthEquals { length: usize },
     
@@ -180,7 +195,7 @@ pub enum PathConditionDto {
 }
 
 /// Time condition DTO
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type", content = "value")]
 pub enum TimeConditionDto {
     #[serde(rename = "after")]
@@ -203,7 +218,7 @@ pub enum TimeConditionDto {
 }
 
 /// Metric condition DTO
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type", content = "value")]
 pub enum MetricConditionDto {
     #[serde(rename = "delay_gt")]
@@ -229,6 +244,9 @@ pub enum MetricConditionDto {
     
     #[serde(rename = "duration_lt")]
     DurationLessThan { threshold: i64 },
+    
+    #[serde(rename = "link_delay_gt")]
+    LinkDelayGreaterThan { from: String, to: String, threshold: u64 },
 }
 
 /// API error response
@@ -256,6 +274,157 @@ pub struct StatsResponse {
     pub unique_switches: usize,
     pub time_buckets: usize,
     pub memory_usage_estimate: usize,
+    pub change_buffer_bytes: usize,
+    pub evictions: u64,
+    pub retention_sweep: SweepStats,
+    pub cold_flows: usize,
+    pub segment_count: usize,
+    pub compression_ratio: f64,
+    pub partition_count: usize,
+    pub shard_count: usize,
+    pub interned_switches: usize,
+    pub interned_paths: usize,
+    pub backend: String,
+    pub read_only: bool,
+    pub replication: Option<ReplicationStatus>,
+}
+
+/// Snapshot creation request
+#[derive(Debug, Default, Deserialize)]
+pub struct CreateSnapshotRequest {
+    /// Snapshot name (defaults to a UTC timestamp)
+    pub name: Option<String>,
+}
+
+/// Snapshot description
+#[derive(Debug, Serialize)]
+pub struct SnapshotResponse {
+    pub name: String,
+    pub format_version: u32,
+    pub engine_version: String,
+    pub created_at: DateTime<Utc>,
+    pub flow_count: usize,
+    pub size_bytes: u64,
+    pub wal_lsn: u64,
+}
+
+/// Snapshot list response
+#[derive(Debug, Serialize)]
+pub struct SnapshotListResponse {
+    pub snapshots: Vec<SnapshotResponse>,
+    pub count: usize,
+}
+
+/// Flow lookup parameters
+#[derive(Debug, Default, Deserialize)]
+pub struct FlowQuery {
+    /// Return the flow as it was stored at this ingest time
+    pub as_of: Option<DateTime<Utc>>,
+}
+
+/// Switch hop lookup parameters
+#[derive(Debug, Deserialize)]
+pub struct SwitchHopsQuery {
+    /// Start of the window, inclusive
+    pub from: DateTime<Utc>,
+    
+    /// End of the window, inclusive
+    pub to: DateTime<Utc>,
+    
+    /// Maximum number of hops to return, oldest first
+    pub limit: Option<usize>,
+}
+
+/// Hops a switch saw within a time window
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SwitchHopsResponse {
+    pub switch_id: String,
+    pub hops: Vec<SwitchHop>,
+    pub total_count: usize,
+}
+
+/// Change feed long-poll parameters
+#[derive(Debug, Default, Deserialize)]
+pub struct ChangesQuery {
+    /// Return events after this sequence number (0 for everything still buffered)
+    #[serde(default)]
+    pub after: u64,
+    
+    /// Maximum number of events to return
+    pub limit: Option<usize>,
+    
+    /// How long to wait for an event when none is pending, in milliseconds
+    pub timeout_ms: Option<u64>,
+}
+
+/// Delete-by-query response
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteFlowsResponse {
+    pub flow_ids: Vec<String>,
+    pub deleted_count: usize,
+}
+
+/// One node's share of a cluster query (internal)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RankedQueryResponse {
+    pub flows: Vec<RankedFlow>,
+    pub total_count: usize,
+}
+
+/// What a node's metrics sample holds beyond its counters (internal)
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct MetricsSampleQuery {
+    /// Summarize every in-memory flow and the per-switch series
+    #[serde(default)]
+    pub aggregates: bool,
+    
+    /// Number of most recently started flows to include
+    #[serde(default)]
+    pub recent_flows: usize,
+}
+
+/// One node's counters and telemetry aggregates behind the Prometheus endpoints (internal)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MetricsSampleResponse {
+    pub flow_count: usize,
+    pub memory_usage_bytes: usize,
+    pub evictions: u64,
+    
+    /// Telemetry of every in-memory flow, if aggregates were asked for
+    #[serde(default)]
+    pub summary: Option<FlowTelemetrySummary>,
+    
+    /// Most recently started flows, newest first
+    #[serde(default, with = "crate::models::schema::records")]
+    pub recent_flows: Vec<Flow>,
+    
+    /// Hops each switch saw over the recent window of the per-switch series
+    #[serde(default)]
+    pub switch_stats: BTreeMap<String, SwitchHopStats>,
+}
+
+impl QueryRequest {
+    /// Check whether any path, time or metric condition is set
+    pub fn has_conditions(&self) -> bool {
+        !self.path_conditions.is_empty()
+            || !self.time_conditions.is_empty()
+            || !self.metric_conditions.is_empty()
+    }
+}
+
+impl From<SpatiotemporalQueryRequest> for QueryRequest {
+    /// Keep the logical path and temporal conditions; spatial and quality conditions have no equivalent
+    fn from(request: SpatiotemporalQueryRequest) -> Self {
+        Self {
+            path_conditions: request.logical_path_conditions.unwrap_or_default(),
+            time_conditions: request.temporal_conditions.unwrap_or_default(),
+            metric_conditions: Vec::new(),
+            limit: request.limit,
+            skip: request.skip,
+            include_flows: request.include_flows,
+            as_of: None,
+        }
+    }
 }
 
 /// Conversion implementations
@@ -277,6 +446,9 @@ impl From<PathConditionDto> for PathCondition {
             PathConditionDto::ThroughSwitch { switch_id } => {
                 PathCondition::ThroughSwitch(switch_id)
             }
+            PathConditionDto::ThroughLink { from, to } => {
+                PathCondition::ThroughLink(from, to)
+            }
             PathConditionDto::LengthEquals { length } => {
                 PathCondition::LengthEquals(length)
             }
@@ -327,29 +499,73 @@ impl From<MetricConditionDto> for MetricCondition {
             MetricConditionDto::DurationLessThan { threshold } => {
                 MetricCondition::DurationLessThan(threshold)
             }
+            MetricConditionDto::LinkDelayGreaterThan { from, to, threshold } => {
+                MetricCondition::LinkDelayGreaterThan(from, to, threshold)
+            }
         }
     }
 }
 
+impl From<QueryRequest> for QueryBuilder {
+    fn from(request: QueryRequest) -> Self {
+        let mut query_builder = QueryBuilder::new();
+        
+        for condition in request.path_conditions {
+            query_builder = query_builder.with_path_condition(condition.into());
+        }
+        
+        for condition in request.time_conditions {
+            query_builder = query_builder.with_time_condition(condition.into());
+        }
+        
+        for condition in request.metric_conditions {
+            query_builder = query_builder.with_metric_condition(condition.into());
+        }
+        
+        if let Some(limit) = request.limit {
+            query_builder = query_builder.limit(limit);
+        }
+        
+        if let Some(skip) = request.skip {
+            query_builder = query_builder.skip(skip);
+        }
+        
+        if let Some(at) = request.as_of {
+            query_builder = query_builder.as_of(at);
+        }
+        
+        query_builder
+    }
+}
+
 impl From<QueryResult> for QueryResponse {
     fn from(result: QueryResult) -> Self {
         let count = result.flow_ids.len();
-        let has_more = if let Some(limit) = result.limit {
-            result.total_count > limit
-        } else {
-            false
-        };
         
         Self {
             flow_ids: result.flow_ids,
             flows: None, // To be filled by handler if needed
             total_count: result.total_count,
-            has_more,
+            has_more: result.has_more,
             count,
         }
     }
 }
 
+impl From<SnapshotInfo> for SnapshotResponse {
+    fn from(info: SnapshotInfo) -> Self {
+        Self {
+            name: info.name(),
+            format_version: info.header.format_version,
+            engine_version: info.header.engine_version,
+            created_at: info.header.created_at,
+            flow_count: info.header.flow_count,
+            size_bytes: info.size_bytes,
+            wal_lsn: info.header.wal_lsn,
+        }
+    }
+}
+
 /// Grafana query request structure
 #[derive(Debug, Deserialize)]
 pub struct GrafanaQueryRequest {
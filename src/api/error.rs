@@ -5,6 +5,7 @@ use axum::{
 };
 
 
+use crate::cluster::ClusterError;
 use crate::storage::StorageError;
 use crate::models::FlowError;
 use crate::api::ErrorResponse;
@@ -18,6 +19,9 @@ pub enum ApiError {
     #[error("Flow error: {0}")]
     Flow(#[from] FlowError),
     
+    #[error("Cluster error: {0}")]
+    Cluster(#[from] ClusterError),
+    
     #[error("Validation error: {0}")]
     Validation(String),
     
@@ -27,6 +31,9 @@ pub enum ApiError {
     #[error("Bad request: {0}")]
     BadRequest(String),
     
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+    
     #[error("Internal server error: {0}")]
     Internal(String),
     
@@ -46,12 +53,42 @@ impl IntoResponse for ApiError {
             ApiError::Storage(StorageError::StorageFull) => {
                 (StatusCode::INSUFFICIENT_STORAGE, "Storage capacity exceeded", None)
             }
+            ApiError::Storage(StorageError::MemoryLimit { .. }) => {
+                (StatusCode::SERVICE_UNAVAILABLE, "Memory budget exceeded, retry later", None)
+            }
             ApiError::Storage(StorageError::ReadOnly) => {
-                (StatusCode::FORBIDDEN, "Database is read-only", None)
+                (StatusCode::FORBIDDEN, "Database is read-only", Some("This instance serves queries only; writes are disabled".to_string()))
             }
             ApiError::Storage(StorageError::InvalidQuery(msg)) => {
                 (StatusCode::BAD_REQUEST, "Invalid query", Some(msg.clone()))
             }
+            ApiError::Storage(StorageError::Io(e)) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "Storage I/O error", Some(e.to_string()))
+            }
+            ApiError::Storage(StorageError::IncompatibleSnapshot(version)) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, "Incompatible snapshot", Some(format!("format version {}", version)))
+            }
+            ApiError::Storage(StorageError::CorruptSnapshot(msg)) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, "Corrupt snapshot", Some(msg.clone()))
+            }
+            ApiError::Storage(StorageError::CorruptSegment(msg)) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "Corrupt segment", Some(msg.clone()))
+            }
+            ApiError::Storage(StorageError::Backend(msg)) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "Storage backend error", Some(msg.clone()))
+            }
+            ApiError::Storage(StorageError::Replication(msg)) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "Replication error", Some(msg.clone()))
+            }
+            ApiError::Storage(StorageError::Schema(e)) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, "Unreadable record", Some(e.to_string()))
+            }
+            ApiError::Cluster(ClusterError::Config(msg)) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "Invalid cluster configuration", Some(msg.clone()))
+            }
+            ApiError::Cluster(e) => {
+                (StatusCode::BAD_GATEWAY, "Cluster node request failed", Some(e.to_string()))
+            }
             ApiError::Flow(_) => {
                 (StatusCode::BAD_REQUEST, "Invalid flow data", None)
             }
@@ -64,6 +101,9 @@ impl IntoResponse for ApiError {
             ApiError::BadRequest(msg) => {
                 (StatusCode::BAD_REQUEST, "Bad request", Some(msg.clone()))
             }
+            ApiError::Forbidden(msg) => {
+                (StatusCode::FORBIDDEN, "Forbidden", Some(msg.clone()))
+            }
             ApiError::Internal(msg) => {
                 (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error", Some(msg.clone()))
             }
@@ -98,6 +138,10 @@ impl ApiError {
         Self::BadRequest(msg.into())
     }
     
+    pub fn forbidden(msg: impl Into<String>) -> Self {
+        Self::Forbidden(msg.into())
+    }
+    
     pub fn internal(msg: impl Into<String>) -> Self {
         Self::Internal(msg.into())
     }
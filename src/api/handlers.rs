@@ -1,14 +1,18 @@
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use axum::{
     extract::{Path, State, Query},
-    http::StatusCode,
+    http::{header, HeaderMap, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
     Json,
 };
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
+use crate::cluster::{merge_ranked, Cluster};
 use crate::models::{Flow, SpatiotemporalFlow};
-use crate::storage::{StorageEngine, QueryBuilder, TimeCondition};
+use crate::replication::ReplicationFollower;
+use crate::storage::{path_interner, switch_interner, ChangePage, StorageEngine, QueryBuilder, QueryResult, SwitchHop, TimeCondition, snapshot};
 use crate::api::{
     ApiError, ApiResult,
     InsertFlowRequest, InsertFlowResponse,
@@ -20,6 +24,9 @@ use crate::api::{
     SpatiotemporalQueryRequest, SpatiotemporalQueryResponse,
     GrafanaQueryRequest, GrafanaQueryResponse,
     GrafanaTimeSeries,
+    CreateSnapshotRequest, SnapshotResponse, SnapshotListResponse,
+    DeleteFlowsResponse, RankedQueryResponse, MetricsSampleQuery, MetricsSampleResponse,
+    ChangesQuery, FlowQuery, SwitchHopsQuery, SwitchHopsResponse,
 };
 
 /// Application state containing the storage engine
@@ -27,6 +34,12 @@ use crate::api::{
 pub struct AppState {
     pub engine: Arc<StorageEngine>,
     pub start_time: SystemTime,
+    
+    /// Set when this instance follows a replication primary
+    pub follower: Option<Arc<ReplicationFollower>>,
+    
+    /// Set when this instance is one node of a cluster
+    pub cluster: Option<Arc<Cluster>>,
 }
 
 impl AppState {
@@ -34,8 +47,47 @@ impl AppState {
         Self {
             engine: Arc::new(engine),
             start_time: SystemTime::now(),
+            follower: None,
+            cluster: None,
+        }
+    }
+    
+    /// Report replication status from a follower
+    pub fn with_follower(mut self, follower: Arc<ReplicationFollower>) -> Self {
+        self.follower = Some(follower);
+        self
+    }
+    
+    /// Join a cluster: route writes to flow owners and scatter queries to every node
+    pub fn with_cluster(mut self, cluster: Cluster) -> Self {
+        self.cluster = Some(Arc::new(cluster));
+        self
+    }
+    
+    /// Get the cluster unless a peer forwarded this request, which is then answered locally
+    fn coordinating_cluster(&self, headers: &HeaderMap) -> Option<&Arc<Cluster>> {
+        self.cluster.as_ref().filter(|cluster| !cluster.is_forwarded(headers))
+    }
+    
+    /// Check that a peer sent the request, for endpoints only other nodes may call
+    fn require_peer(&self, headers: &HeaderMap) -> ApiResult<()> {
+        match &self.cluster {
+            Some(cluster) if cluster.is_forwarded(headers) => Ok(()),
+            _ => Err(ApiError::forbidden("Only cluster peers may call this endpoint")),
         }
     }
+    
+    /// Get the cluster and owning node when a request about a flow belongs to another node
+    fn remote_owner(&self, headers: &HeaderMap, flow_id: &str) -> Option<(&Cluster, &str)> {
+        let cluster = self.coordinating_cluster(headers)?;
+        cluster.remote_owner(flow_id).map(|owner| (cluster.as_ref(), owner))
+    }
+}
+
+/// Relay a request to the node owning its flow and pass the answer back unchanged
+async fn forward_to_owner(cluster: &Cluster, owner: &str, method: Method, path: &str, body: Vec<u8>) -> ApiResult<Response> {
+    let (status, body) = cluster.send(owner, method, path, body).await?;
+    Ok((status, [(header::CONTENT_TYPE, "application/json")], body).into_response())
 }
 
 /// Health check endpoint
@@ -64,7 +116,20 @@ pub async fn get_stats(State(state): State<AppState>) -> ApiResult<Json<StatsRes
         unique_paths: 0, // Would need to implement this
         unique_switches: 0, // Would need to implement this  
         time_buckets: 0, // Would need to implement this
-        memory_usage_estimate: state.engine.estimate_memory_usage(),
+        memory_usage_estimate: state.engine.memory_usage_bytes(),
+        change_buffer_bytes: state.engine.change_buffer_bytes(),
+        evictions: state.engine.eviction_count(),
+        retention_sweep: state.engine.sweep_stats(),
+        cold_flows: state.engine.cold_flow_count(),
+        segment_count: state.engine.segment_count(),
+        compression_ratio: state.engine.compression_ratio(),
+        partition_count: state.engine.partition_count(),
+        shard_count: state.engine.shard_count(),
+        interned_switches: switch_interner().len(),
+        interned_paths: path_interner().len(),
+        backend: state.engine.backend_name().to_string(),
+        read_only: state.engine.is_read_only(),
+        replication: state.follower.as_ref().map(|follower| follower.status()),
     };
     
     Ok(Json(response))
@@ -73,8 +138,13 @@ pub async fn get_stats(State(state): State<AppState>) -> ApiResult<Json<StatsRes
 /// Insert a new flow (legacy format)
 pub async fn insert_flow(
     State(state): State<AppState>,
+    headers: HeaderMap,
     Json(request): Json<InsertFlowRequest>,
-) -> ApiResult<Json<InsertFlowResponse>> {
+) -> ApiResult<Response> {
+    if let Some((cluster, owner)) = state.remote_owner(&headers, &request.flow.flow_id) {
+        return forward_to_owner(cluster, owner, Method::POST, "/flows", serde_json::to_vec(&request)?).await;
+    }
+    
     // Convert FlowInput to Flow
     let flow = Flow::try_from(request.flow)?;
     let flow_id = flow.flow_id.clone();
@@ -88,14 +158,19 @@ pub async fn insert_flow(
         message: format!("Flow {} inserted successfully", flow_id),
     };
     
-    Ok(Json(response))
+    Ok(Json(response).into_response())
 }
 
 /// Insert a new spatiotemporal flow (new format)
 pub async fn insert_spatiotemporal_flow(
     State(state): State<AppState>,
+    headers: HeaderMap,
     Json(request): Json<InsertSpatiotemporalFlowRequest>,
-) -> ApiResult<Json<InsertFlowResponse>> {
+) -> ApiResult<Response> {
+    if let Some((cluster, owner)) = state.remote_owner(&headers, &request.flow.flow_id) {
+        return forward_to_owner(cluster, owner, Method::POST, "/st-flows", serde_json::to_vec(&request)?).await;
+    }
+    
     // Convert SpatiotemporalFlowInput to SpatiotemporalFlow
     let spatiotemporal_flow = SpatiotemporalFlow::try_from(request.flow)?;
     let flow_id = spatiotemporal_flow.flow_id.clone();
@@ -112,7 +187,7 @@ pub async fn insert_spatiotemporal_flow(
         message: format!("Spatiotemporal flow {} inserted successfully", flow_id),
     };
     
-    Ok(Json(response))
+    Ok(Json(response).into_response())
 }
 
 /// Helper function to convert spatiotemporal flow to legacy format
@@ -142,28 +217,45 @@ fn convert_spatiotemporal_to_legacy(st_flow: SpatiotemporalFlow) -> Result<Flow,
         )
     }).collect();
     
-    Flow::new(st_flow.flow_id, hops)
-        .map_err(|e| ApiError::bad_request(&format!("Invalid flow data: {}", e)))
+    let mut flow = Flow::new(st_flow.flow_id, hops)
+        .map_err(|e| ApiError::bad_request(format!("Invalid flow data: {}", e)))?;
+    flow.retention_policy = st_flow.temporal_metadata.retention_policy;
+    Ok(flow)
 }
 
-/// Get a flow by ID
+/// Get a flow by ID, optionally as it was stored at a past ingest time
 pub async fn get_flow(
     State(state): State<AppState>,
+    headers: HeaderMap,
+    uri: Uri,
     Path(flow_id): Path<String>,
-) -> ApiResult<Json<FlowResponse>> {
+    Query(params): Query<FlowQuery>,
+) -> ApiResult<Response> {
+    if let Some((cluster, owner)) = state.remote_owner(&headers, &flow_id) {
+        let path = uri.path_and_query().map_or(uri.path(), |path| path.as_str());
+        return forward_to_owner(cluster, owner, Method::GET, path, Vec::new()).await;
+    }
+    
     let flow = state.engine.get_flow(&flow_id)
+        .and_then(|flow| as_of(flow, params.as_of))
         .ok_or_else(|| ApiError::not_found(format!("Flow {}", flow_id)))?;
     
     let response = FlowResponse { flow };
     
-    Ok(Json(response))
+    Ok(Json(response).into_response())
 }
 
 /// Get a spatiotemporal flow by ID
 pub async fn get_spatiotemporal_flow(
     State(state): State<AppState>,
+    headers: HeaderMap,
+    uri: Uri,
     Path(flow_id): Path<String>,
-) -> ApiResult<Json<SpatiotemporalFlowResponse>> {
+) -> ApiResult<Response> {
+    if let Some((cluster, owner)) = state.remote_owner(&headers, &flow_id) {
+        return forward_to_owner(cluster, owner, Method::GET, uri.path(), Vec::new()).await;
+    }
+    
     let flow = state.engine.get_flow(&flow_id)
         .ok_or_else(|| ApiError::not_found(format!("Flow {}", flow_id)))?;
     
@@ -174,55 +266,21 @@ pub async fn get_spatiotemporal_flow(
         flow: spatiotemporal_flow 
     };
     
-    Ok(Json(response))
+    Ok(Json(response).into_response())
 }
 
 /// Query flows (legacy format)
 pub async fn query_flows(
     State(state): State<AppState>,
+    headers: HeaderMap,
     Json(request): Json<QueryRequest>,
 ) -> ApiResult<Json<QueryResponse>> {
-    // Build query from request
-    let mut query_builder = QueryBuilder::new();
-    
-    // Add path conditions
-    for condition_dto in request.path_conditions {
-        let condition = condition_dto.into();
-        query_builder = query_builder.with_path_condition(condition);
-    }
-    
-    // Add time conditions
-    for condition_dto in request.time_conditions {
-        let condition = condition_dto.into();
-        query_builder = query_builder.with_time_condition(condition);
-    }
-    
-    // Add metric conditions
-    for condition_dto in request.metric_conditions {
-        let condition = condition_dto.into();
-        query_builder = query_builder.with_metric_condition(condition);
-    }
-    
-    // Add pagination
-    if let Some(limit) = request.limit {
-        query_builder = query_builder.limit(limit);
-    }
-    
-    if let Some(skip) = request.skip {
-        query_builder = query_builder.skip(skip);
-    }
-    
     // Execute query
-    let query_result = state.engine.query(query_builder)?;
+    let (query_result, flows) = run_query(&state, &headers, request).await?;
     
     // Convert to response
     let mut response: QueryResponse = query_result.into();
-    
-    // Include full flow data if requested
-    if request.include_flows {
-        let flows = state.engine.get_flows(&response.flow_ids);
-        response.flows = Some(flows);
-    }
+    response.flows = flows;
     
     Ok(Json(response))
 }
@@ -230,51 +288,19 @@ pub async fn query_flows(
 /// Query spatiotemporal flows (new format)
 pub async fn query_spatiotemporal_flows(
     State(state): State<AppState>,
+    headers: HeaderMap,
     Json(request): Json<SpatiotemporalQueryRequest>,
 ) -> ApiResult<Json<SpatiotemporalQueryResponse>> {
-    // Build query from request (reuse legacy query builder for now)
-    let mut query_builder = QueryBuilder::new();
-    
-    // Add logical path conditions
-    if let Some(logical_path_conditions) = request.logical_path_conditions {
-        for condition_dto in logical_path_conditions {
-            let condition = condition_dto.into();
-            query_builder = query_builder.with_path_condition(condition);
-        }
-    }
-    
-    // Add temporal conditions
-    if let Some(temporal_conditions) = request.temporal_conditions {
-        for condition_dto in temporal_conditions {
-            let condition = condition_dto.into();
-            query_builder = query_builder.with_time_condition(condition);
-        }
-    }
-    
     // Add spatial conditions (TODO: implement spatial query logic)
     if request.spatial_conditions.is_some() {
         return Err(ApiError::bad_request("Spatial queries not yet implemented"));
     }
     
-    // Add pagination
-    if let Some(limit) = request.limit {
-        query_builder = query_builder.limit(limit);
-    }
-    
-    if let Some(skip) = request.skip {
-        query_builder = query_builder.skip(skip);
-    }
-    
-    // Execute query
-    let query_result = state.engine.query(query_builder)?;
+    // Reuse the legacy query for the logical path and temporal conditions
+    let (query_result, flows) = run_query(&state, &headers, QueryRequest::from(request)).await?;
     
     // Convert flows to spatiotemporal format
-    let spatiotemporal_flows = if request.include_flows {
-        let flows = state.engine.get_flows(&query_result.flow_ids);
-        Some(flows.into_iter().map(|f| f.to_spatiotemporal()).collect())
-    } else {
-        None
-    };
+    let spatiotemporal_flows = flows.map(|flows| flows.into_iter().map(|f| f.to_spatiotemporal()).collect());
     
     let response = SpatiotemporalQueryResponse {
         flow_ids: query_result.flow_ids,
@@ -286,12 +312,100 @@ pub async fn query_spatiotemporal_flows(
     Ok(Json(response))
 }
 
+/// Run a query on this node, or on every node when coordinating a cluster request
+///
+/// Also fetches the page's flows if the request asks for them.
+async fn run_query(state: &AppState, headers: &HeaderMap, request: QueryRequest) -> ApiResult<(QueryResult, Option<Vec<Flow>>)> {
+    let (include_flows, at) = (request.include_flows, request.as_of);
+    let query_result = match state.coordinating_cluster(headers) {
+        Some(cluster) => scatter_query(state, cluster, request).await?,
+        None => state.engine.query(QueryBuilder::from(request))?,
+    };
+    
+    // Include full flow data if requested
+    let flows = if include_flows {
+        let flows = fetch_flows(state, headers, &query_result.flow_ids).await?;
+        Some(flows.into_iter().filter_map(|flow| as_of(flow, at)).collect())
+    } else {
+        None
+    };
+    
+    Ok((query_result, flows))
+}
+
+/// Get a flow as of an ingest time, if one is given
+fn as_of(flow: Flow, at: Option<chrono::DateTime<chrono::Utc>>) -> Option<Flow> {
+    match at {
+        Some(at) => flow.as_of(at),
+        None => Some(flow),
+    }
+}
+
+/// Run a query on every node and cut the requested page from the merged matches
+async fn scatter_query(state: &AppState, cluster: &Cluster, mut request: QueryRequest) -> ApiResult<QueryResult> {
+    let (limit, skip) = (request.limit, request.skip.unwrap_or(0));
+    
+    // Each node returns its first skip + limit matches, which is enough to cut any page from the merge
+    request.limit = limit.map(|limit| limit.saturating_add(skip));
+    request.skip = None;
+    request.include_flows = false;
+    
+    let body = serde_json::to_vec(&request)?;
+    let local = state.engine.query_ranked(&QueryBuilder::from(request))?;
+    let remote: Vec<RankedQueryResponse> = cluster.scatter(Method::POST, "/cluster/query", body).await?;
+    
+    let pages = std::iter::once(local).chain(remote.into_iter().map(|response| (response.flows, response.total_count)));
+    let (page, total_count) = merge_ranked(pages, skip, limit);
+    let flow_ids = page.into_iter().map(|flow| flow.flow_id).collect();
+    
+    Ok(QueryResult::with_skip(flow_ids, total_count, skip, limit))
+}
+
+/// Answer a peer's share of a cluster query from this node's engine
+pub async fn cluster_query(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<QueryRequest>,
+) -> ApiResult<Json<RankedQueryResponse>> {
+    state.require_peer(&headers)?;
+    let (flows, total_count) = state.engine.query_ranked(&QueryBuilder::from(request))?;
+    Ok(Json(RankedQueryResponse { flows, total_count }))
+}
+
+/// Get flows by ID in the requested order, asking their owners when coordinating a cluster request
+async fn fetch_flows(state: &AppState, headers: &HeaderMap, flow_ids: &[String]) -> ApiResult<Vec<Flow>> {
+    let Some(cluster) = state.coordinating_cluster(headers) else {
+        return Ok(state.engine.get_flows(flow_ids));
+    };
+    
+    let mut local_ids = Vec::new();
+    let mut remote_ids: HashMap<&str, Vec<&String>> = HashMap::new();
+    for flow_id in flow_ids {
+        match cluster.remote_owner(flow_id) {
+            Some(owner) => remote_ids.entry(owner).or_default().push(flow_id),
+            None => local_ids.push(flow_id.clone()),
+        }
+    }
+    
+    let mut found: HashMap<String, Flow> = state.engine.get_flows(&local_ids)
+        .into_iter()
+        .map(|flow| (flow.flow_id.clone(), flow))
+        .collect();
+    for (owner, ids) in remote_ids {
+        let response: FlowsResponse = cluster.call(owner, Method::POST, "/flows/batch", serde_json::to_vec(&ids)?).await?;
+        found.extend(response.flows.into_iter().map(|flow| (flow.flow_id.clone(), flow)));
+    }
+    
+    Ok(flow_ids.iter().filter_map(|flow_id| found.remove(flow_id)).collect())
+}
+
 /// Get multiple flows by IDs (via query parameters or POST body)
 pub async fn get_flows(
     State(state): State<AppState>,
+    headers: HeaderMap,
     Json(flow_ids): Json<Vec<String>>,
 ) -> ApiResult<Json<FlowsResponse>> {
-    let flows = state.engine.get_flows(&flow_ids);
+    let flows = fetch_flows(&state, &headers, &flow_ids).await?;
     
     let response = FlowsResponse {
         count: flows.len(),
@@ -301,18 +415,45 @@ pub async fn get_flows(
     Ok(Json(response))
 }
 
-/// Delete a flow by ID (optional - may not be needed for INT use case)
+/// Delete a flow by ID
 pub async fn delete_flow(
     State(state): State<AppState>,
+    headers: HeaderMap,
+    uri: Uri,
     Path(flow_id): Path<String>,
-) -> ApiResult<StatusCode> {
-    // Check if flow exists
-    if state.engine.get_flow(&flow_id).is_none() {
-        return Err(ApiError::not_found(format!("Flow {}", flow_id)));
+) -> ApiResult<Response> {
+    if let Some((cluster, owner)) = state.remote_owner(&headers, &flow_id) {
+        return forward_to_owner(cluster, owner, Method::DELETE, uri.path(), Vec::new()).await;
     }
     
-    // For now, return method not allowed since deletion might not be needed for INT
-    Err(ApiError::bad_request("Flow deletion not implemented"))
+    state.engine.remove_flow(&flow_id)?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Delete every flow matching a query
+pub async fn delete_flows_by_query(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<QueryRequest>,
+) -> ApiResult<Json<DeleteFlowsResponse>> {
+    // Refuse an unconditional purge; an empty body would match every flow
+    if !request.has_conditions() {
+        return Err(ApiError::validation("Delete by query requires at least one condition"));
+    }
+    
+    let body = serde_json::to_vec(&request)?;
+    let mut flow_ids = state.engine.remove_flows_matching(QueryBuilder::from(request))?;
+    
+    // Every node deletes its own matches
+    if let Some(cluster) = state.coordinating_cluster(&headers) {
+        let remote: Vec<DeleteFlowsResponse> = cluster.scatter(Method::POST, "/flows/delete-by-query", body).await?;
+        flow_ids.extend(remote.into_iter().flat_map(|response| response.flow_ids));
+    }
+    
+    Ok(Json(DeleteFlowsResponse {
+        deleted_count: flow_ids.len(),
+        flow_ids,
+    }))
 }
 
 /// Quick query endpoint for common cases
@@ -327,6 +468,56 @@ pub async fn quick_query_through_switch(
     Ok(Json(response))
 }
 
+/// Quick query endpoint for flows that crossed a directed link
+pub async fn quick_query_through_link(
+    State(state): State<AppState>,
+    Path((from, to)): Path<(String, String)>,
+) -> ApiResult<Json<QueryResponse>> {
+    let query = QueryBuilder::through_link(&from, &to).limit(100);
+    let query_result = state.engine.query(query)?;
+    
+    let response: QueryResponse = query_result.into();
+    Ok(Json(response))
+}
+
+/// Most hops returned by one switch lookup
+const MAX_SWITCH_HOPS: usize = 10_000;
+
+/// Get the hops a switch saw within a time window, oldest first
+///
+/// A cluster node gathers them from every node unless a peer forwarded the request.
+pub async fn get_switch_hops(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    uri: Uri,
+    Path(switch_id): Path<String>,
+    Query(params): Query<SwitchHopsQuery>,
+) -> ApiResult<Json<SwitchHopsResponse>> {
+    if params.from > params.to {
+        return Err(ApiError::validation("from must not be after to"));
+    }
+    let limit = params.limit.unwrap_or(MAX_SWITCH_HOPS).min(MAX_SWITCH_HOPS);
+    
+    let (mut hops, mut total_count) = state.engine.hops_at_switch(&switch_id, params.from, params.to, limit)?;
+    
+    if let Some(cluster) = state.coordinating_cluster(&headers) {
+        let path = uri.path_and_query().map_or(uri.path(), |path| path.as_str());
+        let remote: Vec<SwitchHopsResponse> = cluster.scatter(Method::GET, path, Vec::new()).await?;
+        for node in remote {
+            total_count += node.total_count;
+            hops.extend(node.hops);
+        }
+        hops.sort_by(SwitchHop::cmp_by_time);
+        hops.truncate(limit);
+    }
+    
+    Ok(Json(SwitchHopsResponse {
+        switch_id,
+        hops,
+        total_count,
+    }))
+}
+
 /// Quick query endpoint for exact path
 pub async fn quick_query_exact_path(
     State(state): State<AppState>,
@@ -352,7 +543,7 @@ pub async fn quick_query_recent(
     Ok(Json(response))
 }
 
-/// Spatiotemporal-specific quick queries
+// Spatiotemporal-specific quick queries
 
 /// Quick query for flows in spatial region
 pub async fn quick_query_spatial_region(
@@ -394,69 +585,195 @@ pub async fn quick_query_spatial_flows(
     Ok(Json(response))
 }
 
-/// Prometheus metrics endpoint for Grafana integration
-pub async fn prometheus_metrics(State(state): State<AppState>) -> ApiResult<String> {
-    let flow_count = state.engine.flow_count();
-    let uptime = state.start_time
-        .elapsed()
-        .map_err(|e| ApiError::internal(format!("Time error: {}", e)))?
-        .as_secs();
-    
-    // Get detailed network statistics
-    let query_builder = QueryBuilder::new();
-    let query_result = state.engine.query(query_builder)?;
-    let flows = state.engine.get_flows(&query_result.flow_ids);
+/// Most events returned by one change poll
+const MAX_CHANGES_PER_POLL: usize = 1000;
+
+/// Longest a change poll waits for an event
+const MAX_CHANGES_WAIT: Duration = Duration::from_secs(60);
+
+/// Long-poll the change feed for flow creations, appends and removals
+///
+/// Returns as soon as there are events after `after`, or empty once `timeout_ms` passes.
+/// Resume with the returned `last_seq`; `missed` means events were dropped in between.
+pub async fn poll_changes(
+    State(state): State<AppState>,
+    Query(params): Query<ChangesQuery>,
+) -> ApiResult<Json<ChangePage>> {
+    let limit = params.limit.unwrap_or(MAX_CHANGES_PER_POLL).clamp(1, MAX_CHANGES_PER_POLL);
+    let timeout = params.timeout_ms.map_or(Duration::from_secs(30), Duration::from_millis).min(MAX_CHANGES_WAIT);
+    
+    // Waiting blocks a thread, so keep it off the async workers
+    let engine = state.engine.clone();
+    let page = tokio::task::spawn_blocking(move || engine.poll_changes(params.after, limit, timeout))
+        .await
+        .map_err(|e| ApiError::internal(format!("Change poll failed: {}", e)))?;
+    
+    Ok(Json(page))
+}
+
+/// Get the configured snapshot directory
+fn snapshot_dir(state: &AppState) -> ApiResult<PathBuf> {
+    state.engine.config().snapshot_dir.clone().ok_or_else(|| {
+        ApiError::bad_request("Snapshots are not configured (set INTDB_DATA_DIR or INTDB_SNAPSHOT_DIR)")
+    })
+}
+
+/// Trigger a snapshot of the whole database
+pub async fn create_snapshot(
+    State(state): State<AppState>,
+    request: Option<Json<CreateSnapshotRequest>>,
+) -> ApiResult<Json<SnapshotResponse>> {
+    let dir = snapshot_dir(&state)?;
+    let request = request.map(|Json(r)| r).unwrap_or_default();
+    
+    let name = request
+        .name
+        .unwrap_or_else(|| format!("intdb-{}", chrono::Utc::now().format("%Y%m%dT%H%M%SZ")));
+    let file_name = snapshot::snapshot_file_name(&name)
+        .ok_or_else(|| ApiError::validation(format!("Invalid snapshot name: {}", name)))?;
+    
+    let path = dir.join(&file_name);
+    if path.exists() {
+        return Err(ApiError::bad_request(format!("Snapshot {} already exists", file_name)));
+    }
     
-    // Calculate network metrics
-    let delay_values: Vec<u64> = flows.iter()
-        .flat_map(|f| &f.hops)
-        .filter_map(|h| h.metrics.delay_ns)
-        .collect();
+    // Snapshots can be large; keep the blocking I/O off the async workers
+    let engine = state.engine.clone();
+    let info = tokio::task::spawn_blocking(move || engine.snapshot(path))
+        .await
+        .map_err(|e| ApiError::internal(format!("Snapshot task failed: {}", e)))??;
     
-    let queue_values: Vec<f64> = flows.iter()
-        .flat_map(|f| &f.hops)
-        .filter_map(|h| h.metrics.queue_util)
-        .collect();
+    Ok(Json(info.into()))
+}
+
+/// List snapshots in the snapshot directory
+pub async fn list_snapshots(State(state): State<AppState>) -> ApiResult<Json<SnapshotListResponse>> {
+    let dir = snapshot_dir(&state)?;
     
-    let unique_switches: std::collections::HashSet<_> = flows.iter()
-        .flat_map(|f| &f.hops)
-        .map(|h| &h.switch_id)
+    let snapshots: Vec<SnapshotResponse> = snapshot::list_snapshots(dir)?
+        .into_iter()
+        .map(SnapshotResponse::from)
         .collect();
     
-    let unique_paths: std::collections::HashSet<_> = flows.iter()
-        .map(|f| &f.path.switches)
-        .collect();
+    let response = SnapshotListResponse {
+        count: snapshots.len(),
+        snapshots,
+    };
     
-    // Calculate statistics
-    let avg_delay = if !delay_values.is_empty() {
-        delay_values.iter().sum::<u64>() as f64 / delay_values.len() as f64
-    } else { 0.0 };
+    Ok(Json(response))
+}
+
+/// Restore the database from a named snapshot
+pub async fn restore_snapshot(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> ApiResult<Json<SnapshotResponse>> {
+    let dir = snapshot_dir(&state)?;
+    let file_name = snapshot::snapshot_file_name(&name)
+        .ok_or_else(|| ApiError::validation(format!("Invalid snapshot name: {}", name)))?;
+    
+    let path = dir.join(&file_name);
+    if !path.exists() {
+        return Err(ApiError::not_found(format!("Snapshot {}", file_name)));
+    }
     
-    let max_delay = delay_values.iter().max().unwrap_or(&0).clone() as f64;
+    let engine = state.engine.clone();
+    let info = tokio::task::spawn_blocking(move || engine.restore(path))
+        .await
+        .map_err(|e| ApiError::internal(format!("Restore task failed: {}", e)))??;
     
-    let avg_queue_util = if !queue_values.is_empty() {
-        queue_values.iter().sum::<f64>() / queue_values.len() as f64
-    } else { 0.0 };
+    Ok(Json(info.into()))
+}
+
+/// Window of hop timestamps the per-switch Prometheus series cover
+const SWITCH_SERIES_WINDOW: chrono::Duration = chrono::Duration::minutes(5);
+
+/// Most recent flows the per-flow Prometheus query series are computed from
+const SAMPLE_FLOWS: usize = 1000;
+
+/// Collect the counters and telemetry behind the Prometheus endpoints
+///
+/// A cluster node gathers them from every node unless a peer forwarded the request.
+/// Nodes send aggregates and at most `SAMPLE_FLOWS` recent flows, never all their flows;
+/// the merged sample keeps the most recent flows cluster-wide, newest first.
+async fn metrics_sample(state: &AppState, headers: &HeaderMap, request: MetricsSampleQuery) -> ApiResult<MetricsSampleResponse> {
+    let mut sample = local_metrics_sample(state, request)?;
+    if let Some(cluster) = state.coordinating_cluster(headers) {
+        let path = format!(
+            "/cluster/metrics-sample?aggregates={}&recent_flows={}",
+            request.aggregates,
+            request.recent_flows
+        );
+        let remote: Vec<MetricsSampleResponse> = cluster.scatter(Method::GET, &path, Vec::new()).await?;
+        for node in remote {
+            sample.flow_count += node.flow_count;
+            sample.memory_usage_bytes += node.memory_usage_bytes;
+            sample.evictions += node.evictions;
+            if let (Some(summary), Some(node_summary)) = (&mut sample.summary, &node.summary) {
+                summary.merge(node_summary);
+            }
+            sample.recent_flows.extend(node.recent_flows);
+            for (switch_id, stats) in node.switch_stats {
+                sample.switch_stats.entry(switch_id).or_default().merge(&stats);
+            }
+        }
+        
+        sample.recent_flows.sort_by(|a, b| b.start_time.cmp(&a.start_time).then_with(|| a.flow_id.cmp(&b.flow_id)));
+        sample.recent_flows.truncate(request.recent_flows.min(SAMPLE_FLOWS));
+    }
     
-    let max_queue_util = queue_values.iter().fold(0.0f64, |a, &b| a.max(b));
+    Ok(sample)
+}
+
+/// Collect this node's counters and telemetry (scrapes must not count as queries for eviction)
+fn local_metrics_sample(state: &AppState, request: MetricsSampleQuery) -> ApiResult<MetricsSampleResponse> {
+    let now = chrono::Utc::now();
+    let switch_stats = if request.aggregates {
+        state.engine.switch_hop_stats(now - SWITCH_SERIES_WINDOW, now)?
+    } else {
+        BTreeMap::new()
+    };
     
-    let congested_hops = queue_values.iter().filter(|&&x| x > 0.8).count();
-    let congestion_ratio = if !queue_values.is_empty() {
-        congested_hops as f64 / queue_values.len() as f64
-    } else { 0.0 };
+    Ok(MetricsSampleResponse {
+        flow_count: state.engine.flow_count(),
+        memory_usage_bytes: state.engine.memory_usage_bytes(),
+        evictions: state.engine.eviction_count(),
+        summary: request.aggregates.then(|| state.engine.telemetry_summary()),
+        recent_flows: state.engine.recent_flows(request.recent_flows.min(SAMPLE_FLOWS))?,
+        switch_stats,
+    })
+}
+
+/// Answer a peer's request for this node's metrics sample
+pub async fn cluster_metrics_sample(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(request): Query<MetricsSampleQuery>,
+) -> ApiResult<Json<MetricsSampleResponse>> {
+    state.require_peer(&headers)?;
+    Ok(Json(local_metrics_sample(&state, request)?))
+}
+
+/// Prometheus metrics endpoint for Grafana integration
+///
+/// Flow and memory figures cover the whole cluster; uptime, retention, compression
+/// and replication figures describe the scraped node.
+pub async fn prometheus_metrics(State(state): State<AppState>, headers: HeaderMap) -> ApiResult<String> {
+    let aggregates = MetricsSampleQuery { aggregates: true, recent_flows: 0 };
+    let sample = metrics_sample(&state, &headers, aggregates).await?;
+    let flow_count = sample.flow_count;
+    let uptime = state.start_time
+        .elapsed()
+        .map_err(|e| ApiError::internal(format!("Time error: {}", e)))?
+        .as_secs();
     
-    let avg_path_length = if !flows.is_empty() {
-        flows.iter().map(|f| f.hops.len()).sum::<usize>() as f64 / flows.len() as f64
-    } else { 0.0 };
+    // Network statistics over every in-memory flow, summarized by each node
+    let summary = sample.summary.unwrap_or_default();
     
-    // Count flows by status
-    use crate::models::flow::FlowStatus;
-    let active_flows = flows.iter().filter(|f| matches!(f.status, FlowStatus::Partial)).count();
-    let complete_flows = flows.iter().filter(|f| matches!(f.status, FlowStatus::Complete)).count();
-    let timeout_flows = flows.iter().filter(|f| matches!(f.status, FlowStatus::Timeout)).count();
+    let sweep_stats = state.engine.sweep_stats();
     
     // Generate Prometheus format metrics
-    let metrics = format!(
+    let mut metrics = format!(
         r#"# HELP intdb_flows_total Total number of flows stored
 # TYPE intdb_flows_total gauge
 intdb_flows_total {}
@@ -516,23 +833,109 @@ intdb_flows_complete {}
 # HELP intdb_flows_timeout Number of timed-out flows
 # TYPE intdb_flows_timeout gauge
 intdb_flows_timeout {}
+
+# HELP intdb_retention_sweeps_total Number of completed retention sweeps
+# TYPE intdb_retention_sweeps_total counter
+intdb_retention_sweeps_total {}
+
+# HELP intdb_retention_expired_flows_total Flows removed by the retention sweeper
+# TYPE intdb_retention_expired_flows_total counter
+intdb_retention_expired_flows_total {}
+
+# HELP intdb_retention_last_sweep_duration_ms Duration of the last retention sweep in milliseconds
+# TYPE intdb_retention_last_sweep_duration_ms gauge
+intdb_retention_last_sweep_duration_ms {}
+
+# HELP intdb_retention_last_sweep_timestamp_seconds Unix time of the last retention sweep
+# TYPE intdb_retention_last_sweep_timestamp_seconds gauge
+intdb_retention_last_sweep_timestamp_seconds {}
+
+# HELP intdb_evictions_total Flows evicted to make room for new flows
+# TYPE intdb_evictions_total counter
+intdb_evictions_total {}
+
+# HELP intdb_compression_ratio Uncompressed to stored size of in-memory hop telemetry
+# TYPE intdb_compression_ratio gauge
+intdb_compression_ratio {:.2}
 "#,
         flow_count,
         uptime,
-        state.engine.estimate_memory_usage(),
-        avg_delay,
-        max_delay,
-        avg_queue_util,
-        max_queue_util,
-        congestion_ratio,
-        unique_switches.len(),
-        unique_paths.len(),
-        avg_path_length,
-        active_flows,
-        complete_flows,
-        timeout_flows
+        sample.memory_usage_bytes,
+        summary.avg_delay_ns(),
+        summary.max_delay_ns,
+        summary.avg_queue_util(),
+        summary.max_queue_util,
+        summary.congestion_ratio(),
+        summary.switches.len(),
+        summary.distinct_paths,
+        summary.avg_path_length(),
+        summary.partial_flows,
+        summary.complete_flows,
+        summary.timeout_flows,
+        sweep_stats.sweeps,
+        sweep_stats.flows_expired,
+        sweep_stats.last_sweep_duration_ms,
+        sweep_stats.last_sweep_at.map_or(0, |t| t.timestamp()),
+        sample.evictions,
+        state.engine.compression_ratio()
     );
     
+    // Average delay per directed link, from hop timestamps
+    if !summary.link_delays.is_empty() {
+        metrics.push_str("\n# HELP intdb_link_delay_ns Average delay between consecutive switches in nanoseconds\n# TYPE intdb_link_delay_ns gauge\n");
+        for (from, links) in &summary.link_delays {
+            for (to, (sum, count)) in links {
+                metrics.push_str(&format!(
+                    "intdb_link_delay_ns{{from=\"{}\",to=\"{}\"}} {}\n",
+                    from,
+                    to,
+                    *sum as f64 / *count as f64
+                ));
+            }
+        }
+    }
+    
+    // Per-switch series over the recent window, from the hop indexes
+    if !sample.switch_stats.is_empty() {
+        let mut hops = String::from("\n# HELP intdb_switch_hops Hops recorded at each switch in the last 5 minutes\n# TYPE intdb_switch_hops gauge\n");
+        let mut delays = String::from("\n# HELP intdb_switch_avg_delay_ns Average hop delay at each switch in the last 5 minutes\n# TYPE intdb_switch_avg_delay_ns gauge\n");
+        let mut queues = String::from("\n# HELP intdb_switch_max_queue_utilization Maximum queue utilization at each switch in the last 5 minutes\n# TYPE intdb_switch_max_queue_utilization gauge\n");
+        for (switch_id, stats) in &sample.switch_stats {
+            hops.push_str(&format!("intdb_switch_hops{{switch=\"{}\"}} {}\n", switch_id, stats.hops));
+            if let Some(avg_delay) = stats.avg_delay_ns() {
+                delays.push_str(&format!("intdb_switch_avg_delay_ns{{switch=\"{}\"}} {}\n", switch_id, avg_delay));
+            }
+            if let Some(max_queue) = stats.max_queue_util {
+                queues.push_str(&format!("intdb_switch_max_queue_utilization{{switch=\"{}\"}} {}\n", switch_id, max_queue));
+            }
+        }
+        metrics.push_str(&hops);
+        metrics.push_str(&delays);
+        metrics.push_str(&queues);
+    }
+    
+    if let Some(follower) = &state.follower {
+        let status = follower.status();
+        metrics.push_str(&format!(
+            r#"
+# HELP intdb_replication_connected Whether the follower is streaming from its primary (1=yes, 0=no)
+# TYPE intdb_replication_connected gauge
+intdb_replication_connected {}
+
+# HELP intdb_replication_lag_records Records committed on the primary but not yet applied
+# TYPE intdb_replication_lag_records gauge
+intdb_replication_lag_records {}
+
+# HELP intdb_replication_applied_lsn LSN of the last primary record applied
+# TYPE intdb_replication_applied_lsn gauge
+intdb_replication_applied_lsn {}
+"#,
+            status.connected as u8,
+            status.lag_records,
+            status.applied_lsn
+        ));
+    }
+    
     Ok(metrics)
 }
 
@@ -544,9 +947,9 @@ pub async fn grafana_query(
 ) -> ApiResult<Json<GrafanaQueryResponse>> {
     // Extract time range from request
     let from_time = request.range.from.parse::<chrono::DateTime<chrono::Utc>>()
-        .map_err(|e| ApiError::bad_request(&format!("Invalid from time: {}", e)))?;
+        .map_err(|e| ApiError::bad_request(format!("Invalid from time: {}", e)))?;
     let to_time = request.range.to.parse::<chrono::DateTime<chrono::Utc>>()
-        .map_err(|e| ApiError::bad_request(&format!("Invalid to time: {}", e)))?;
+        .map_err(|e| ApiError::bad_request(format!("Invalid to time: {}", e)))?;
 
     // Build query based on Grafana target
     let mut query_builder = QueryBuilder::new();
@@ -601,7 +1004,7 @@ pub async fn grafana_query(
             }
         },
         _ => {
-            return Err(ApiError::bad_request(&format!("Unknown metric: {}", metric_type)));
+            return Err(ApiError::bad_request(format!("Unknown metric: {}", metric_type)));
         }
     }
     
@@ -619,19 +1022,21 @@ pub async fn grafana_query(
 /// This is what Grafana actually calls when configured as a Prometheus data source
 pub async fn prometheus_query(
     State(state): State<AppState>,
+    headers: HeaderMap,
     Query(params): Query<HashMap<String, String>>,
 ) -> ApiResult<Json<serde_json::Value>> {
     let query = params.get("query").unwrap_or(&"".to_string()).clone();
     
     // Get real-time data from the storage engine
-    let flow_count = state.engine.flow_count();
+    let sample = metrics_sample(&state, &headers, MetricsSampleQuery { aggregates: false, recent_flows: SAMPLE_FLOWS }).await?;
+    let flow_count = sample.flow_count;
     let uptime = state.start_time
         .elapsed()
         .map_err(|e| ApiError::internal(format!("Time error: {}", e)))?
         .as_secs();
     
     // Calculate network metrics from actual flow data
-    let network_metrics = calculate_network_metrics(&state);
+    let network_metrics = calculate_network_metrics(&sample);
     
     // Parse basic Prometheus queries and return real data
     match query.as_str() {
@@ -671,7 +1076,7 @@ pub async fn prometheus_query(
                     "result": [
                         {
                             "metric": {"__name__": "intdb_memory_usage_estimate_bytes"},
-                            "value": [chrono::Utc::now().timestamp(), state.engine.estimate_memory_usage().to_string()]
+                            "value": [chrono::Utc::now().timestamp(), sample.memory_usage_bytes.to_string()]
                         }
                     ]
                 }
@@ -864,6 +1269,7 @@ pub async fn prometheus_query(
 /// Prometheus range query endpoint
 pub async fn prometheus_query_range(
     State(state): State<AppState>,
+    headers: HeaderMap,
     Query(params): Query<HashMap<String, String>>,
 ) -> ApiResult<Json<serde_json::Value>> {
     let query = params.get("query").unwrap_or(&"".to_string()).clone();
@@ -882,14 +1288,15 @@ pub async fn prometheus_query_range(
         .unwrap_or(30); // Default: 30 seconds
     
     // Get real-time data from the storage engine
-    let flow_count = state.engine.flow_count();
+    let sample = metrics_sample(&state, &headers, MetricsSampleQuery { aggregates: false, recent_flows: SAMPLE_FLOWS }).await?;
+    let flow_count = sample.flow_count;
     let uptime = state.start_time
         .elapsed()
         .map_err(|e| ApiError::internal(format!("Time error: {}", e)))?
         .as_secs();
     
     // Calculate network metrics from actual flow data
-    let network_metrics = calculate_network_metrics(&state);
+    let network_metrics = calculate_network_metrics(&sample);
     
     // Generate time series data for the range query
     let current_timestamp = chrono::Utc::now().timestamp();
@@ -939,8 +1346,8 @@ pub async fn prometheus_query_range(
                         {
                             "metric": {"__name__": "intdb_memory_usage_estimate_bytes"},
                             "values": [
-                                [current_timestamp - 60, state.engine.estimate_memory_usage().to_string()],
-                                [current_timestamp, state.engine.estimate_memory_usage().to_string()]
+                                [current_timestamp - 60, sample.memory_usage_bytes.to_string()],
+                                [current_timestamp, sample.memory_usage_bytes.to_string()]
                             ]
                         }
                     ]
@@ -966,7 +1373,7 @@ pub async fn prometheus_query_range(
         },
         "intdb_avg_delay_ns" => {
             // Extract real historical data from IntDB flows based on time range
-            let real_values = extract_historical_delay_data(&state, "avg", start_time, end_time, step);
+            let real_values = extract_historical_delay_data(&sample, "avg", start_time, end_time, step);
             
             Ok(Json(serde_json::json!({
                 "status": "success",
@@ -983,7 +1390,7 @@ pub async fn prometheus_query_range(
         },
         "intdb_max_delay_ns" => {
             // Extract real historical data from IntDB flows based on time range
-            let real_values = extract_historical_delay_data(&state, "max", start_time, end_time, step);
+            let real_values = extract_historical_delay_data(&sample, "max", start_time, end_time, step);
             
             Ok(Json(serde_json::json!({
                 "status": "success",
@@ -1000,7 +1407,7 @@ pub async fn prometheus_query_range(
         },
         "intdb_avg_queue_utilization" => {
             // Extract real historical data from IntDB flows based on time range
-            let real_values = extract_historical_queue_data(&state, "avg", start_time, end_time, step);
+            let real_values = extract_historical_queue_data(&sample, "avg", start_time, end_time, step);
             
             Ok(Json(serde_json::json!({
                 "status": "success",
@@ -1017,7 +1424,7 @@ pub async fn prometheus_query_range(
         },
         "intdb_max_queue_utilization" => {
             // Extract real historical data from IntDB flows based on time range
-            let real_values = extract_historical_queue_data(&state, "max", start_time, end_time, step);
+            let real_values = extract_historical_queue_data(&sample, "max", start_time, end_time, step);
             
             Ok(Json(serde_json::json!({
                 "status": "success",
@@ -1034,7 +1441,7 @@ pub async fn prometheus_query_range(
         },
         "intdb_queue_congestion_ratio" => {
             // Extract real historical data from IntDB flows based on time range
-            let real_values = extract_historical_queue_data(&state, "congestion", start_time, end_time, step);
+            let real_values = extract_historical_queue_data(&sample, "congestion", start_time, end_time, step);
             
             Ok(Json(serde_json::json!({
                 "status": "success",
@@ -1216,30 +1623,26 @@ struct NetworkMetrics {
 }
 
 /// Extract real historical delay data from IntDB flows based on time range
-fn extract_historical_delay_data(state: &AppState, metric_type: &str, start_time: i64, end_time: i64, step: i64) -> Vec<[serde_json::Value; 2]> {
+fn extract_historical_delay_data(sample: &MetricsSampleResponse, metric_type: &str, start_time: i64, end_time: i64, step: i64) -> Vec<[serde_json::Value; 2]> {
     let mut time_values = Vec::new();
     
-    // Get flows and extract real historical data from hops
-    if let Ok(query_result) = state.engine.query(
-        crate::storage::QueryBuilder::new().limit(100) // Get more flows for better time coverage
-    ) {
-        let flows = state.engine.get_flows(&query_result.flow_ids);
-        
-        // Collect all hop data within the time range
-        for flow in flows {
-            for hop in &flow.hops {
-                if let Some(delay) = hop.metrics.delay_ns {
-                    let timestamp = hop.timestamp.timestamp();
-                    
-                    // Only include data within the requested time range
-                    if timestamp >= start_time && timestamp <= end_time {
-                        let delay_value = match metric_type {
-                            "avg" => delay as f64,
-                            "max" => delay as f64, // For max, we'll use the same value (simplification)
-                            _ => delay as f64,
-                        };
-                        time_values.push([serde_json::Value::Number(serde_json::Number::from(timestamp)), serde_json::Value::String(delay_value.to_string())]);
-                    }
+    // Extract real historical data from the hops of recent flows
+    let flows = sample.recent_flows.iter().take(100); // Get more flows for better time coverage
+    
+    // Collect all hop data within the time range
+    for flow in flows {
+        for hop in &flow.hops {
+            if let Some(delay) = hop.metrics.delay_ns {
+                let timestamp = hop.timestamp.timestamp();
+                
+                // Only include data within the requested time range
+                if timestamp >= start_time && timestamp <= end_time {
+                    let delay_value = match metric_type {
+                        "avg" => delay as f64,
+                        "max" => delay as f64, // For max, we'll use the same value (simplification)
+                        _ => delay as f64,
+                    };
+                    time_values.push([serde_json::Value::Number(serde_json::Number::from(timestamp)), serde_json::Value::String(delay_value.to_string())]);
                 }
             }
         }
@@ -1293,7 +1696,7 @@ fn extract_historical_delay_data(state: &AppState, metric_type: &str, start_time
     }
     
     // If no real data, return current value at the end time
-    let network_metrics = calculate_network_metrics(state);
+    let network_metrics = calculate_network_metrics(sample);
     let current_value = match metric_type {
         "avg" => network_metrics.avg_delay,
         "max" => network_metrics.max_delay as f64,
@@ -1303,31 +1706,27 @@ fn extract_historical_delay_data(state: &AppState, metric_type: &str, start_time
 }
 
 /// Extract real historical queue utilization data from IntDB flows based on time range
-fn extract_historical_queue_data(state: &AppState, metric_type: &str, start_time: i64, end_time: i64, step: i64) -> Vec<[serde_json::Value; 2]> {
+fn extract_historical_queue_data(sample: &MetricsSampleResponse, metric_type: &str, start_time: i64, end_time: i64, step: i64) -> Vec<[serde_json::Value; 2]> {
     let mut time_values = Vec::new();
     
-    // Get flows and extract real historical data from hops
-    if let Ok(query_result) = state.engine.query(
-        crate::storage::QueryBuilder::new().limit(100) // Get more flows for better time coverage
-    ) {
-        let flows = state.engine.get_flows(&query_result.flow_ids);
-        
-        // Collect all hop data within the time range
-        for flow in flows {
-            for hop in &flow.hops {
-                if let Some(queue_util) = hop.metrics.queue_util {
-                    let timestamp = hop.timestamp.timestamp();
-                    
-                    // Only include data within the requested time range
-                    if timestamp >= start_time && timestamp <= end_time {
-                        let queue_value = match metric_type {
-                            "avg" => queue_util,
-                            "max" => queue_util, // For max, we'll use the same value (simplification)
-                            "congestion" => if queue_util > 0.7 { 1.0 } else { 0.0 },
-                            _ => queue_util,
-                        };
-                        time_values.push([serde_json::Value::Number(serde_json::Number::from(timestamp)), serde_json::Value::String(queue_value.to_string())]);
-                    }
+    // Extract real historical data from the hops of recent flows
+    let flows = sample.recent_flows.iter().take(100); // Get more flows for better time coverage
+    
+    // Collect all hop data within the time range
+    for flow in flows {
+        for hop in &flow.hops {
+            if let Some(queue_util) = hop.metrics.queue_util {
+                let timestamp = hop.timestamp.timestamp();
+                
+                // Only include data within the requested time range
+                if timestamp >= start_time && timestamp <= end_time {
+                    let queue_value = match metric_type {
+                        "avg" => queue_util,
+                        "max" => queue_util, // For max, we'll use the same value (simplification)
+                        "congestion" => if queue_util > 0.7 { 1.0 } else { 0.0 },
+                        _ => queue_util,
+                    };
+                    time_values.push([serde_json::Value::Number(serde_json::Number::from(timestamp)), serde_json::Value::String(queue_value.to_string())]);
                 }
             }
         }
@@ -1382,7 +1781,7 @@ fn extract_historical_queue_data(state: &AppState, metric_type: &str, start_time
     }
     
     // If no real data, return current value at the end time
-    let network_metrics = calculate_network_metrics(state);
+    let network_metrics = calculate_network_metrics(sample);
     let current_value = match metric_type {
         "avg" => network_metrics.avg_queue_util,
         "max" => network_metrics.max_queue_util,
@@ -1393,9 +1792,9 @@ fn extract_historical_queue_data(state: &AppState, metric_type: &str, start_time
 }
 
 /// Calculate network metrics from all flows in the system
-fn calculate_network_metrics(state: &AppState) -> NetworkMetrics {
+fn calculate_network_metrics(sample: &MetricsSampleResponse) -> NetworkMetrics {
     // Get all flows
-    let flow_count = state.engine.flow_count();
+    let flow_count = sample.flow_count;
     
     if flow_count == 0 {
         return NetworkMetrics {
@@ -1417,30 +1816,25 @@ fn calculate_network_metrics(state: &AppState) -> NetworkMetrics {
     let mut unique_switches = std::collections::HashSet::new();
     let mut path_lengths = Vec::new();
     
-    // Since we don't have a direct way to iterate all flows, we'll use a query
-    // to get recent flows and calculate metrics from them
-    if let Ok(query_result) = state.engine.query(
-        crate::storage::QueryBuilder::new().limit(1000) // Get up to 1000 flows
-    ) {
-        let flows = state.engine.get_flows(&query_result.flow_ids);
+    // Calculate metrics from the most recent flows
+    let flows = sample.recent_flows.iter().take(1000); // Get up to 1000 flows
+    
+    for flow in flows {
+        // Collect path length
+        path_lengths.push(flow.path.switches.len() as f64);
         
-        for flow in flows {
-            // Collect path length
-            path_lengths.push(flow.path.switches.len() as f64);
-            
-            // Collect unique switches
-            for switch in &flow.path.switches {
-                unique_switches.insert(switch.clone());
+        // Collect unique switches
+        for switch in &flow.path.switches {
+            unique_switches.insert(switch.clone());
+        }
+        
+        // Collect metrics from hops
+        for hop in &flow.hops {
+            if let Some(delay) = hop.metrics.delay_ns {
+                all_delays.push(delay);
             }
-            
-            // Collect metrics from hops
-            for hop in &flow.hops {
-                if let Some(delay) = hop.metrics.delay_ns {
-                    all_delays.push(delay);
-                }
-                if let Some(queue_util) = hop.metrics.queue_util {
-                    all_queue_utils.push(queue_util);
-                }
+            if let Some(queue_util) = hop.metrics.queue_util {
+                all_queue_utils.push(queue_util);
             }
         }
     }
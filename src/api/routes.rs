@@ -27,6 +27,7 @@ pub fn create_router(state: AppState) -> Router {
         .route("/flows/:id", get(get_flow))
         .route("/flows/:id", delete(delete_flow))
         .route("/flows/batch", post(get_flows))
+        .route("/flows/delete-by-query", post(delete_flows_by_query))
         
         // New spatiotemporal flow endpoints
         .route("/st-flows", post(insert_spatiotemporal_flow))
@@ -40,6 +41,7 @@ pub fn create_router(state: AppState) -> Router {
         
         // Quick query endpoints for common use cases (legacy)
         .route("/quick/through/:switch_id", get(quick_query_through_switch))
+        .route("/quick/link/:from/:to", get(quick_query_through_link))
         .route("/quick/path", post(quick_query_exact_path))
         .route("/quick/recent/:minutes", get(quick_query_recent))
         
@@ -47,6 +49,21 @@ pub fn create_router(state: AppState) -> Router {
         .route("/st-quick/spatial-region", post(quick_query_spatial_region))
         .route("/st-quick/spatial-flows", get(quick_query_spatial_flows))
         
+        // Per-switch hop window queries
+        .route("/switches/:switch_id/hops", get(get_switch_hops))
+        
+        // Change-data-capture feed (long poll)
+        .route("/changes", get(poll_changes))
+        
+        // Admin endpoints
+        .route("/admin/snapshots", get(list_snapshots))
+        .route("/admin/snapshots", post(create_snapshot))
+        .route("/admin/snapshots/:name/restore", post(restore_snapshot))
+        
+        // Internal cluster endpoints (node-to-node)
+        .route("/cluster/query", post(cluster_query))
+        .route("/cluster/metrics-sample", get(cluster_metrics_sample))
+        
         // Set the application state
         .with_state(state)
 }
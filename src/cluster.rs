@@ -0,0 +1,451 @@
+//! Multi-node cluster: flow-ID hash ranges, write forwarding and scatter-gather queries
+//!
+//! Every node is configured with the same ordered list of node addresses. The 64-bit
+//! hash space is split into one contiguous range per node, and a flow belongs to the
+//! node whose range holds the SHA-256 prefix of its ID, so ownership needs no
+//! coordination. Any node accepts writes and forwards them to the owner; queries fan
+//! out to every node and the pages are merged here. Requests between nodes carry
+//! `FORWARDED_HEADER` set to the shared cluster secret, which makes the receiver
+//! answer from its own engine only; the header from anyone else is ignored.
+
+use std::time::Duration;
+use axum::http::{header, HeaderMap, Method, Request, StatusCode};
+use axum::body::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper_util::client::legacy::{connect::HttpConnector, Client};
+use hyper_util::rt::TokioExecutor;
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+
+use crate::storage::RankedFlow;
+
+/// Header marking a request sent by another node; its value is the cluster secret
+pub const FORWARDED_HEADER: &str = "x-intdb-forwarded";
+
+/// How long a node waits for a peer before giving up on the request
+const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Cluster settings read from the environment
+#[derive(Debug, Clone)]
+pub struct ClusterConfig {
+    /// API address (`host:port`) of every node, in the same order on all nodes
+    pub nodes: Vec<String>,
+    
+    /// This node's entry in `nodes`
+    pub node: String,
+    
+    /// Secret shared by every node, proving a request with `FORWARDED_HEADER` came from a peer
+    pub secret: String,
+}
+
+impl ClusterConfig {
+    /// Read `INTDB_CLUSTER_NODES` (comma-separated `host:port`), `INTDB_CLUSTER_NODE`
+    /// and `INTDB_CLUSTER_SECRET`
+    ///
+    /// Returns `None` when `INTDB_CLUSTER_NODES` is unset, i.e. the node runs standalone.
+    pub fn from_env() -> Result<Option<Self>, ClusterError> {
+        let Ok(nodes) = std::env::var("INTDB_CLUSTER_NODES") else {
+            return Ok(None);
+        };
+        let node = std::env::var("INTDB_CLUSTER_NODE")
+            .map_err(|_| ClusterError::Config("INTDB_CLUSTER_NODE must name this node".to_string()))?;
+        let secret = std::env::var("INTDB_CLUSTER_SECRET")
+            .map_err(|_| ClusterError::Config("INTDB_CLUSTER_SECRET must be set on every node".to_string()))?;
+        
+        Ok(Some(Self {
+            nodes: nodes.split(',').map(str::trim).filter(|node| !node.is_empty()).map(String::from).collect(),
+            node,
+            secret,
+        }))
+    }
+}
+
+/// Cluster error types
+#[derive(Debug, thiserror::Error)]
+pub enum ClusterError {
+    #[error("Invalid cluster configuration: {0}")]
+    Config(String),
+    
+    #[error("Node {node} is unreachable: {reason}")]
+    Unreachable { node: String, reason: String },
+    
+    #[error("Node {node} answered {status}: {body}")]
+    Node { node: String, status: StatusCode, body: String },
+}
+
+/// Pick the index of the node owning a flow
+///
+/// Node `i` of `n` owns hashes in `[i * 2^64 / n, (i + 1) * 2^64 / n)`.
+pub fn owner_index(flow_id: &str, node_count: usize) -> usize {
+    let digest = Sha256::digest(flow_id.as_bytes());
+    let hash = u64::from_be_bytes(digest[..8].try_into().unwrap());
+    ((hash as u128 * node_count.max(1) as u128) >> 64) as usize
+}
+
+/// Merge per-node query pages into one page of the cluster-wide result
+///
+/// Each page must hold the node's first `skip + limit` matches in `RankedFlow` order;
+/// the second element of each pair is the node's total match count.
+pub fn merge_ranked(
+    pages: impl IntoIterator<Item = (Vec<RankedFlow>, usize)>,
+    skip: usize,
+    limit: Option<usize>,
+) -> (Vec<RankedFlow>, usize) {
+    let mut total_count = 0;
+    let mut merged = Vec::new();
+    for (page, count) in pages {
+        total_count += count;
+        merged.extend(page);
+    }
+    merged.sort();
+    
+    let page = merged.into_iter().skip(skip).take(limit.unwrap_or(usize::MAX)).collect();
+    (page, total_count)
+}
+
+/// A node's view of the cluster and its HTTP client for talking to peers
+#[derive(Debug, Clone)]
+pub struct Cluster {
+    nodes: Vec<String>,
+    local: usize,
+    secret: String,
+    client: Client<HttpConnector, Full<Bytes>>,
+}
+
+impl Cluster {
+    /// Create the cluster view, checking that this node is listed exactly once
+    pub fn new(config: ClusterConfig) -> Result<Self, ClusterError> {
+        if config.secret.is_empty() {
+            return Err(ClusterError::Config("The cluster secret can't be empty".to_string()));
+        }
+        
+        let mut positions = config.nodes.iter().enumerate().filter(|(_, node)| **node == config.node);
+        let local = match (positions.next(), positions.next()) {
+            (Some((index, _)), None) => index,
+            (None, _) => return Err(ClusterError::Config(format!("{} is not in the node list", config.node))),
+            (Some(_), Some(_)) => return Err(ClusterError::Config(format!("{} is listed more than once", config.node))),
+        };
+        
+        Ok(Self {
+            nodes: config.nodes,
+            local,
+            secret: config.secret,
+            client: Client::builder(TokioExecutor::new()).build_http(),
+        })
+    }
+    
+    /// Get every node's address, in hash-range order
+    pub fn nodes(&self) -> &[String] {
+        &self.nodes
+    }
+    
+    /// Get this node's address
+    pub fn local_node(&self) -> &str {
+        &self.nodes[self.local]
+    }
+    
+    /// Get the address of the node owning a flow, or `None` if this node owns it
+    pub fn remote_owner(&self, flow_id: &str) -> Option<&str> {
+        let owner = owner_index(flow_id, self.nodes.len());
+        (owner != self.local).then(|| self.nodes[owner].as_str())
+    }
+    
+    /// Get every node except this one
+    pub fn peers(&self) -> impl Iterator<Item = &str> {
+        let local = self.local;
+        self.nodes.iter().enumerate().filter(move |(index, _)| *index != local).map(|(_, node)| node.as_str())
+    }
+    
+    /// Send a request to a node and return its status and body, whatever the status
+    pub async fn send(&self, node: &str, method: Method, path: &str, body: Vec<u8>) -> Result<(StatusCode, Bytes), ClusterError> {
+        let unreachable = |reason: String| ClusterError::Unreachable { node: node.to_string(), reason };
+        
+        let request = Request::builder()
+            .method(method)
+            .uri(format!("http://{}{}", node, path))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(FORWARDED_HEADER, &self.secret)
+            .body(Full::new(Bytes::from(body)))
+            .map_err(|e| unreachable(e.to_string()))?;
+        
+        let exchange = async {
+            let response = self.client.request(request).await.map_err(|e| unreachable(e.to_string()))?;
+            let status = response.status();
+            let body = response.into_body().collect().await.map_err(|e| unreachable(e.to_string()))?;
+            Ok((status, body.to_bytes()))
+        };
+        
+        tokio::time::timeout(PEER_TIMEOUT, exchange)
+            .await
+            .map_err(|_| unreachable(format!("no answer within {:?}", PEER_TIMEOUT)))?
+    }
+    
+    /// Send a request to a node and decode its JSON answer, failing on an error status
+    pub async fn call<T: DeserializeOwned>(&self, node: &str, method: Method, path: &str, body: Vec<u8>) -> Result<T, ClusterError> {
+        let (status, body) = self.send(node, method, path, body).await?;
+        if !status.is_success() {
+            return Err(ClusterError::Node {
+                node: node.to_string(),
+                status,
+                body: String::from_utf8_lossy(&body).into_owned(),
+            });
+        }
+        
+        serde_json::from_slice(&body).map_err(|e| ClusterError::Node {
+            node: node.to_string(),
+            status,
+            body: format!("invalid response: {}", e),
+        })
+    }
+    
+    /// Send the same request to every peer concurrently, returning answers in peer order
+    pub async fn scatter<T>(&self, method: Method, path: &str, body: Vec<u8>) -> Result<Vec<T>, ClusterError>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let calls: Vec<_> = self
+            .peers()
+            .map(|node| {
+                let cluster = self.clone();
+                let (node, method, path, body) = (node.to_string(), method.clone(), path.to_string(), body.clone());
+                tokio::spawn(async move { cluster.call(&node, method, &path, body).await })
+            })
+            .collect();
+        
+        let mut answers = Vec::with_capacity(calls.len());
+        for call in calls {
+            let answer = call.await.map_err(|e| ClusterError::Unreachable {
+                node: "peer".to_string(),
+                reason: format!("request task failed: {}", e),
+            })??;
+            answers.push(answer);
+        }
+        Ok(answers)
+    }
+    
+    /// Check whether a peer forwarded a request, which must then be answered locally
+    ///
+    /// Digests are compared so the check doesn't leak how much of the secret matched.
+    pub fn is_forwarded(&self, headers: &HeaderMap) -> bool {
+        headers
+            .get(FORWARDED_HEADER)
+            .is_some_and(|value| Sha256::digest(value.as_bytes()) == Sha256::digest(self.secret.as_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use tokio::net::TcpListener;
+    use crate::api::{create_router, AppState, MetricsSampleResponse};
+    use crate::storage::{EngineConfig, StorageEngine};
+    
+    fn ranked(flow_id: &str, secs: i64) -> RankedFlow {
+        RankedFlow { flow_id: flow_id.to_string(), start_time: Utc.timestamp_opt(secs, 0).unwrap() }
+    }
+    
+    #[test]
+    fn test_owner_index_splits_hash_space() {
+        let mut counts = [0usize; 3];
+        for i in 0..3000 {
+            let flow_id = format!("flow{}", i);
+            let owner = owner_index(&flow_id, 3);
+            assert_eq!(owner, owner_index(&flow_id, 3));
+            counts[owner] += 1;
+        }
+        assert!(counts.iter().all(|&count| count > 800), "unbalanced ranges: {:?}", counts);
+        assert_eq!(owner_index("flow1", 1), 0);
+    }
+    
+    #[test]
+    fn test_merge_ranked_paginates_across_nodes() {
+        let node_a = (vec![ranked("a1", 50), ranked("a2", 30)], 5);
+        let node_b = (vec![ranked("b1", 40), ranked("b2", 30)], 2);
+        
+        let (page, total_count) = merge_ranked([node_a, node_b], 1, Some(2));
+        let ids: Vec<&str> = page.iter().map(|flow| flow.flow_id.as_str()).collect();
+        assert_eq!(ids, vec!["b1", "a2"]);
+        assert_eq!(total_count, 7);
+    }
+    
+    #[test]
+    fn test_cluster_requires_local_node_once() {
+        let config = |node: &str| ClusterConfig {
+            nodes: vec!["127.0.0.1:1".to_string(), "127.0.0.1:2".to_string()],
+            node: node.to_string(),
+            secret: "secret".to_string(),
+        };
+        assert!(Cluster::new(config("127.0.0.1:3")).is_err());
+        
+        let cluster = Cluster::new(config("127.0.0.1:2")).unwrap();
+        assert_eq!(cluster.local_node(), "127.0.0.1:2");
+        assert_eq!(cluster.peers().collect::<Vec<_>>(), vec!["127.0.0.1:1"]);
+    }
+    
+    async fn start_cluster(size: usize) -> (Vec<String>, Vec<AppState>) {
+        let mut listeners = Vec::new();
+        for _ in 0..size {
+            listeners.push(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        }
+        let nodes: Vec<String> = listeners.iter().map(|listener| listener.local_addr().unwrap().to_string()).collect();
+        
+        let mut states = Vec::new();
+        for (listener, node) in listeners.into_iter().zip(&nodes) {
+            let config = ClusterConfig { nodes: nodes.clone(), node: node.clone(), secret: "secret".to_string() };
+            let cluster = Cluster::new(config).unwrap();
+            let state = AppState::new(StorageEngine::with_config(EngineConfig::default())).with_cluster(cluster);
+            states.push(state.clone());
+            tokio::spawn(async move { axum::serve(listener, create_router(state)).await.unwrap() });
+        }
+        (nodes, states)
+    }
+    
+    /// Send a plain client request, i.e. one without the forwarded header
+    async fn request(cluster: &Cluster, method: Method, node: &str, path: &str, body: String) -> (StatusCode, Bytes) {
+        let request = Request::builder()
+            .method(method)
+            .uri(format!("http://{}{}", node, path))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Full::new(Bytes::from(body)))
+            .unwrap();
+        let response = cluster.client.request(request).await.unwrap();
+        let status = response.status();
+        (status, response.into_body().collect().await.unwrap().to_bytes())
+    }
+    
+    async fn post(cluster: &Cluster, node: &str, path: &str, body: serde_json::Value) -> (StatusCode, serde_json::Value) {
+        let (status, body) = request(cluster, Method::POST, node, path, body.to_string()).await;
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+    
+    fn flow_input(flow_id: &str, start_secs: i64) -> serde_json::Value {
+        let timestamp = Utc.timestamp_opt(start_secs, 0).unwrap();
+        serde_json::json!({
+            "flow": {
+                "flow_id": flow_id,
+                "telemetry": [
+                    { "switch_id": "s1", "timestamp": timestamp, "queue_util": 0.5, "delay_ns": 100 },
+                    { "switch_id": "s2", "timestamp": timestamp, "queue_util": 0.9, "delay_ns": 300 }
+                ]
+            }
+        })
+    }
+    
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_writes_route_to_owner_and_queries_merge() {
+        let (nodes, states) = start_cluster(3).await;
+        let client = states[0].cluster.clone().unwrap();
+        
+        // Every write goes to the first node, which forwards it to the owner
+        let base = Utc::now().timestamp() - 100;
+        for i in 0..12 {
+            let (status, _) = post(&client, &nodes[0], "/flows", flow_input(&format!("flow{}", i), base + i)).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        for (index, state) in states.iter().enumerate() {
+            for flow in state.engine.all_flows() {
+                assert_eq!(owner_index(&flow.flow_id, nodes.len()), index);
+            }
+        }
+        assert_eq!(states.iter().map(|state| state.engine.flow_count()).sum::<usize>(), 12);
+        
+        // Appends reach the same owner whichever node takes them, and reads follow
+        post(&client, &nodes[1], "/flows", flow_input("flow0", base)).await;
+        assert_eq!(states.iter().map(|state| state.engine.flow_count()).sum::<usize>(), 12);
+        for node in &nodes {
+            let (status, body) = request(&client, Method::GET, node, "/flows/flow0", String::new()).await;
+            assert_eq!(status, StatusCode::OK);
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["flow"]["hops"].as_array().unwrap().len(), 4);
+        }
+        
+        // The owner's errors are passed through
+        let missing = (0..).map(|i| format!("missing{}", i)).find(|id| owner_index(id, nodes.len()) != 0).unwrap();
+        let (status, _) = request(&client, Method::GET, &nodes[0], &format!("/flows/{}", missing), String::new()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        
+        // Pages are cut from the merged, cluster-wide order
+        let query = serde_json::json!({ "limit": 4, "skip": 2, "include_flows": true });
+        let (status, body) = post(&client, &nodes[2], "/query", query).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["total_count"], 12);
+        assert_eq!(body["has_more"], true);
+        assert_eq!(body["flow_ids"], serde_json::json!(["flow9", "flow8", "flow7", "flow6"]));
+        assert_eq!(body["flows"].as_array().unwrap().len(), 4);
+        
+        let st_query = serde_json::json!({
+            "logical_path_conditions": [{ "type": "through_switch", "value": { "switch_id": "s2" } }],
+            "limit": 3
+        });
+        let (_, body) = post(&client, &nodes[1], "/st-query", st_query).await;
+        assert_eq!(body["total_count"], 12);
+        assert_eq!(body["flow_ids"], serde_json::json!(["flow11", "flow10", "flow9"]));
+        
+        // Metrics cover the whole cluster, whichever node is scraped
+        let (status, metrics) = request(&client, Method::GET, &nodes[0], "/metrics", String::new()).await;
+        assert_eq!(status, StatusCode::OK);
+        let metrics = String::from_utf8_lossy(&metrics).into_owned();
+        assert!(metrics.contains("intdb_flows_total 12\n"), "{}", metrics);
+        assert!(metrics.contains("intdb_max_delay_ns 300\n"));
+        
+        let (_, body) = request(&client, Method::GET, &nodes[1], "/api/v1/query?query=intdb_max_delay_ns", String::new()).await;
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["data"]["result"][0]["value"][1], "300");
+        
+        // Peers send their aggregates and a bounded sample of recent flows, never every flow
+        let path = "/cluster/metrics-sample?aggregates=true&recent_flows=2";
+        let (_, body) = client.send(&nodes[1], Method::GET, path, Vec::new()).await.unwrap();
+        let sample: MetricsSampleResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(sample.summary.unwrap().flows as usize, states[1].engine.flow_count());
+        assert!(sample.recent_flows.len() <= 2);
+        
+        // A scrape forwarded by a peer only reports the node itself
+        let (_, metrics) = client.send(&nodes[0], Method::GET, "/metrics", Vec::new()).await.unwrap();
+        let expected = format!("intdb_flows_total {}\n", states[0].engine.flow_count());
+        assert!(String::from_utf8_lossy(&metrics).contains(&expected));
+    }
+    
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_client_forwarded_header_is_still_routed() {
+        let (nodes, states) = start_cluster(2).await;
+        let client = states[0].cluster.clone().unwrap();
+        assert!(!client.is_forwarded(&HeaderMap::new()));
+        
+        // A client claiming to be a peer, with a guessed secret or a node address
+        let flow_id = (0..).map(|i| format!("flow{}", i)).find(|id| owner_index(id, nodes.len()) == 1).unwrap();
+        for claim in ["guess", nodes[1].as_str()] {
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri(format!("http://{}/flows", nodes[0]))
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(FORWARDED_HEADER, claim)
+                .body(Full::new(Bytes::from(flow_input(&flow_id, Utc::now().timestamp()).to_string())))
+                .unwrap();
+            let response = client.client.request(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        
+        assert_eq!(states[0].engine.flow_count(), 0);
+        assert_eq!(states[1].engine.get_flow(&flow_id).unwrap().hops.len(), 4);
+    }
+    
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_internal_endpoints_require_the_secret() {
+        let (nodes, states) = start_cluster(2).await;
+        let client = states[0].cluster.clone().unwrap();
+        let query = serde_json::json!({ "limit": 1 }).to_string();
+        
+        // Clients calling the node-to-node endpoints directly are turned away
+        let (status, _) = request(&client, Method::POST, &nodes[1], "/cluster/query", query.clone()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = request(&client, Method::GET, &nodes[1], "/cluster/metrics-sample", String::new()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        
+        // Peers get through
+        let (status, _) = client.send(&nodes[1], Method::POST, "/cluster/query", query.into_bytes()).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = client.send(&nodes[1], Method::GET, "/cluster/metrics-sample", Vec::new()).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+    }
+}
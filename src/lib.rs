@@ -6,6 +6,8 @@
 pub mod models;
 pub mod storage;
 pub mod api;
+pub mod replication;
+pub mod cluster;
 
 // Re-export commonly used types
 pub use models::*;
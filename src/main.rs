@@ -1,8 +1,12 @@
-use log::info;
+use std::time::Duration;
+use log::{info, error};
 
 use intdb::api::routes::create_router;
 use intdb::api::handlers::AppState;
-use intdb::storage::engine::StorageEngine;
+use intdb::storage::engine::{EngineConfig, StorageEngine};
+use intdb::storage::WalSyncPolicy;
+use intdb::replication::{ReplicationConfig, ReplicationFollower, ReplicationPrimary};
+use intdb::cluster::{Cluster, ClusterConfig};
 
 #[tokio::main]
 async fn main() {
@@ -11,21 +15,67 @@ async fn main() {
     
     info!("🚀 Starting IntDB API Server...");
     
-    // 创建数据库引擎
-    let engine = StorageEngine::new();
+    // 读取配置并打开数据库引擎（按配置选择存储后端；如配置了WAL则回放日志）
+    let mut config = EngineConfig::from_env().expect("Invalid engine configuration");
+    let replication = ReplicationConfig::from_env().expect("Invalid replication configuration");
+    
+    // 从节点只接受主节点同步的数据
+    if replication.primary.is_some() {
+        config.read_only = true;
+    }
+    
+    let engine = StorageEngine::open(config).expect("Failed to open storage engine");
+    info!("💾 Storage backend: {}", engine.backend_name());
     
     // 创建应用状态
-    let app_state = AppState::new(engine);
+    let mut app_state = AppState::new(engine);
+    
+    // 主从复制：主节点推送WAL，从节点跟随并应用
+    if let Some(listen) = &replication.listen {
+        ReplicationPrimary::start(app_state.engine.clone(), listen.as_str(), replication.secret.as_str())
+            .expect("Failed to start replication listener");
+    }
+    if let Some(primary) = replication.primary {
+        info!("🔁 Following replication primary {}", primary);
+        let follower = ReplicationFollower::start(app_state.engine.clone(), primary, replication.secret);
+        app_state = app_state.with_follower(follower);
+    }
+    
+    // 集群模式：按流ID哈希范围分片，写入转发到归属节点，查询分发到所有节点后合并
+    if let Some(cluster_config) = ClusterConfig::from_env().expect("Invalid cluster configuration") {
+        let cluster = Cluster::new(cluster_config).expect("Invalid cluster configuration");
+        info!("🧩 Cluster node {} of {:?}", cluster.local_node(), cluster.nodes());
+        app_state = app_state.with_cluster(cluster);
+    }
+    
+    // 只读模式：只提供查询，不启动任何会修改数据的后台任务
+    if app_state.engine.is_read_only() {
+        info!("🔒 Read-only mode: write endpoints return 403");
+    } else {
+        // 周期性刷写WAL
+        if let WalSyncPolicy::Periodic(interval) = app_state.engine.config().wal_sync_policy {
+            spawn_wal_syncer(app_state.clone(), interval);
+        }
+        
+        // 后台过期清理（按保留策略删除旧流）
+        spawn_retention_sweeper(app_state.clone(), app_state.engine.config().retention_sweep_interval);
+        
+        // 冷数据落盘（把长时间无更新的流写入列式段文件）
+        if app_state.engine.config().cold_flow_age.is_some() {
+            spawn_segment_freezer(app_state.clone(), app_state.engine.config().segment_interval);
+        }
+    }
     
     // 创建路由
     let app = create_router(app_state);
     
-    // 启动服务器
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:2999")
+    // 启动服务器（INTDB_LISTEN可覆盖监听地址，便于单机运行多个节点）
+    let addr = std::env::var("INTDB_LISTEN").unwrap_or_else(|_| "127.0.0.1:2999".to_string());
+    let listener = tokio::net::TcpListener::bind(&addr)
         .await
         .expect("Failed to bind to address");
     
-    info!("🌐 IntDB API Server running on http://127.0.0.1:2999");
+    info!("🌐 IntDB API Server running on http://{}", addr);
     info!("📊 Available endpoints:");
     info!("   POST /flows - Insert legacy flow data");
     info!("   GET  /flows/:id - Get legacy flow data");
@@ -37,4 +87,49 @@ async fn main() {
     axum::serve(listener, app)
         .await
         .expect("Failed to start server");
-} 
\ No newline at end of file
+}
+
+/// 后台任务：按固定间隔清理超过保留期的流
+fn spawn_retention_sweeper(state: AppState, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let engine = state.engine.clone();
+            match tokio::task::spawn_blocking(move || engine.sweep_expired(chrono::Utc::now())).await {
+                Ok(Err(e)) => error!("Retention sweep failed: {}", e),
+                Err(e) => error!("Retention sweep task panicked: {}", e),
+                Ok(Ok(_)) => {}
+            }
+        }
+    });
+}
+
+/// 后台任务：按固定间隔把冷流冻结到磁盘段
+fn spawn_segment_freezer(state: AppState, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let engine = state.engine.clone();
+            match tokio::task::spawn_blocking(move || engine.freeze_cold_flows(chrono::Utc::now())).await {
+                Ok(Err(e)) => error!("Segment freeze failed: {}", e),
+                Err(e) => error!("Segment freeze task panicked: {}", e),
+                Ok(Ok(_)) => {}
+            }
+        }
+    });
+}
+
+/// 后台任务：按固定间隔fsync WAL
+fn spawn_wal_syncer(state: AppState, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = state.engine.sync_wal() {
+                error!("WAL sync failed: {}", e);
+            }
+        }
+    });
+}
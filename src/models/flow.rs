@@ -216,6 +216,24 @@ pub struct Flow {
     
     /// Flow completion status
     pub status: FlowStatus,
+    
+    /// Per-flow retention policy (e.g. "7d"); the engine default applies when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention_policy: Option<String>,
+    
+    /// Writes that delivered this flow's telemetry, oldest first (empty until stored)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ingest_versions: Vec<IngestVersion>,
+}
+
+/// One write of a flow's telemetry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IngestVersion {
+    /// When the storage engine accepted the write
+    pub ingested_at: DateTime<Utc>,
+    
+    /// Number of hops the flow had after the write
+    pub hop_count: usize,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -330,6 +348,9 @@ impl SpatiotemporalFlow {
             flow.flow_id,
             flow.path.switches,
         );
+        if flow.retention_policy.is_some() {
+            spatiotemporal_flow.temporal_metadata.retention_policy = flow.retention_policy;
+        }
         
         // Convert hops to a single spatiotemporal window
         let window = SpatiotemporalWindow {
@@ -411,6 +432,8 @@ impl Flow {
             start_time,
             end_time,
             status: FlowStatus::Complete,
+            retention_policy: None,
+            ingest_versions: Vec::new(),
         })
     }
     
@@ -432,6 +455,8 @@ impl Flow {
             start_time,
             end_time,
             status: FlowStatus::Partial,
+            retention_policy: None,
+            ingest_versions: Vec::new(),
         }
     }
     
@@ -456,6 +481,117 @@ impl Flow {
         Ok(())
     }
     
+    /// Record that the whole flow was ingested at `at`, replacing any earlier versions
+    pub fn stamp_ingest(&mut self, at: DateTime<Utc>) {
+        self.ingest_versions = vec![IngestVersion {
+            ingested_at: at,
+            hop_count: self.hops.len(),
+        }];
+    }
+    
+    /// Merge telemetry reported later for the same flow
+    ///
+    /// The new hops are numbered after the existing ones, the time range widens to
+    /// cover both, unseen switches extend the path and the other flow's ingest
+    /// versions are appended.
+    pub fn merge(&mut self, other: &Flow) {
+        let previous_hop_count = self.hops.len();
+        
+        // Calculate the new hop_index offset to avoid conflicts
+        let max_hop_index = self.hops.iter()
+            .map(|h| h.hop_index)
+            .max()
+            .unwrap_or(0);
+        
+        // Append all new hops with adjusted hop_index
+        for (i, new_hop) in other.hops.iter().enumerate() {
+            let mut appended_hop = new_hop.clone();
+            appended_hop.hop_index = max_hop_index + 1 + i as u32;
+            self.hops.push(appended_hop);
+        }
+        
+        // Update flow timestamps
+        if other.start_time < self.start_time {
+            self.start_time = other.start_time;
+        }
+        if other.end_time > self.end_time {
+            self.end_time = other.end_time;
+        }
+        
+        // Update path if necessary (add any new switches), refreshing its cached hash
+        let mut switches = self.path.switches.clone();
+        for switch in &other.path.switches {
+            if !switches.contains(switch) {
+                switches.push(switch.clone());
+            }
+        }
+        if switches.len() != self.path.switches.len() {
+            self.path = NetworkPath::new(switches);
+        }
+        
+        // Keep hops sorted by hop_index to maintain chronological order
+        self.hops.sort_by_key(|h| h.hop_index);
+        
+        // The most recently supplied retention policy wins
+        if other.retention_policy.is_some() {
+            self.retention_policy = other.retention_policy.clone();
+        }
+        
+        // Versions stay in ingest order even if racing writers stamped them out of order
+        for version in &other.ingest_versions {
+            let ingested_at = self.ingest_versions
+                .last()
+                .map_or(version.ingested_at, |last| last.ingested_at.max(version.ingested_at));
+            self.ingest_versions.push(IngestVersion {
+                ingested_at,
+                hop_count: previous_hop_count + version.hop_count,
+            });
+        }
+    }
+    
+    /// Get the flow as it was stored at ingest time `at`, or None if it hadn't been ingested yet
+    ///
+    /// The hops of each version up to `at` are merged again in order, so path and time
+    /// range are those the flow had then; status and retention policy are the current
+    /// ones. A flow without ingest versions is returned as is.
+    pub fn as_of(&self, at: DateTime<Utc>) -> Option<Flow> {
+        let known = self.ingest_versions.partition_point(|version| version.ingested_at <= at);
+        if known == self.ingest_versions.len() {
+            return Some(self.clone());
+        }
+        
+        let mut flow: Option<Flow> = None;
+        let mut first_hop = 0;
+        for version in &self.ingest_versions[..known] {
+            let end = version.hop_count.clamp(first_hop, self.hops.len());
+            let hops = self.hops[first_hop..end].to_vec();
+            first_hop = end;
+            let (Some(first), Some(last)) = (hops.first(), hops.last()) else {
+                continue;
+            };
+            
+            let write = Flow {
+                flow_id: self.flow_id.clone(),
+                path: NetworkPath::new(hops.iter().map(|h| h.switch_id.clone()).collect()),
+                start_time: first.timestamp,
+                end_time: last.timestamp,
+                status: self.status.clone(),
+                retention_policy: self.retention_policy.clone(),
+                ingest_versions: vec![IngestVersion {
+                    ingested_at: version.ingested_at,
+                    hop_count: hops.len(),
+                }],
+                hops,
+            };
+            match flow.as_mut() {
+                Some(flow) => flow.merge(&write),
+                None => flow = Some(write),
+            }
+        }
+        
+        flow
+    }
+    
     /// Mark flow as complete
     pub fn mark_complete(&mut self) {
         self.status = FlowStatus::Complete;
@@ -498,6 +634,29 @@ impl Flow {
         }
     }
     
+    /// Get each traversed link with its delay in nanoseconds, from consecutive hop timestamps
+    ///
+    /// Links whose hop timestamps run backwards are skipped.
+    pub fn link_delays(&self) -> impl Iterator<Item = (&str, &str, u64)> + '_ {
+        self.hops.windows(2).filter_map(|pair| {
+            let delay = (pair[1].timestamp - pair[0].timestamp).num_nanoseconds()?;
+            Some((pair[0].switch_id.as_str(), pair[1].switch_id.as_str(), u64::try_from(delay).ok()?))
+        })
+    }
+    
+    /// Get the delay of the link `from -> to`, the longest if the flow crossed it more than once
+    pub fn link_delay(&self, from: &str, to: &str) -> Option<u64> {
+        self.link_delays()
+            .filter(|(a, b, _)| *a == from && *b == to)
+            .map(|(_, _, delay)| delay)
+            .max()
+    }
+    
+    /// Check if the flow went from `from` straight to `to`
+    pub fn traverses_link(&self, from: &str, to: &str) -> bool {
+        self.path.switches.windows(2).any(|pair| pair[0] == from && pair[1] == to)
+    }
+    
     /// Check if flow contains the given switch
     pub fn contains_switch(&self, switch_id: &str) -> bool {
         self.hops.iter().any(|h| h.switch_id == switch_id)
@@ -518,6 +677,13 @@ impl Flow {
         self.path.hash()
     }
     
+    /// Get the flow's own retention period, if it has a valid policy
+    pub fn retention(&self) -> Option<chrono::Duration> {
+        self.retention_policy
+            .as_deref()
+            .and_then(|policy| parse_retention_policy(policy).ok())
+    }
+    
     /// Convert to new spatiotemporal format
     pub fn to_spatiotemporal(self) -> SpatiotemporalFlow {
         SpatiotemporalFlow::from_legacy_flow(self)
@@ -529,6 +695,8 @@ impl Flow {
 pub struct FlowInput {
     pub flow_id: String,
     pub telemetry: Vec<HopInput>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention_policy: Option<String>,
 }
 
 /// Input format for creating spatiotemporal flows
@@ -538,6 +706,8 @@ pub struct SpatiotemporalFlowInput {
     pub logical_path: Vec<String>,
     pub topology_coordinates: Option<Vec<TopologyCoordinate>>,
     pub telemetry_data: Vec<HopTelemetryInput>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention_policy: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -563,6 +733,10 @@ impl TryFrom<SpatiotemporalFlowInput> for SpatiotemporalFlow {
     type Error = FlowError;
     
     fn try_from(input: SpatiotemporalFlowInput) -> Result<Self, Self::Error> {
+        if let Some(policy) = &input.retention_policy {
+            parse_retention_policy(policy)?;
+        }
+        
         let spatial_metadata = if let Some(coordinates) = input.topology_coordinates {
             SpatialMetadata::with_spatial_info(input.logical_path, coordinates, None)
         } else {
@@ -577,7 +751,7 @@ impl TryFrom<SpatiotemporalFlowInput> for SpatiotemporalFlow {
                 creation_time: Utc::now(),
                 last_update: Utc::now(),
                 window_duration: Some(60000),
-                retention_policy: Some("7d".to_string()),
+                retention_policy: input.retention_policy.or_else(|| Some("7d".to_string())),
             },
             spatiotemporal_windows: Vec::new(),
             spatiotemporal_indices: SpatiotemporalIndices {
@@ -678,16 +852,51 @@ impl TryFrom<FlowInput> for Flow {
             return Err(FlowError::EmptyFlow);
         }
         
+        if let Some(policy) = &input.retention_policy {
+            parse_retention_policy(policy)?;
+        }
+        
         let hops: Vec<Hop> = input.telemetry
             .into_iter()
             .enumerate()
             .map(|(i, hop_input)| Hop::from((i as u32, hop_input)))
             .collect();
             
-        Flow::new(input.flow_id, hops)
+        let mut flow = Flow::new(input.flow_id, hops)?;
+        flow.retention_policy = input.retention_policy;
+        Ok(flow)
     }
 }
 
+/// Parse a retention policy such as `"30m"`, `"12h"` or `"7d"`
+///
+/// Supported units are `s`, `m`, `h`, `d` and `w`; the amount must be positive.
+pub fn parse_retention_policy(policy: &str) -> Result<chrono::Duration, FlowError> {
+    let invalid = || FlowError::InvalidRetentionPolicy(policy.to_string());
+    
+    let policy = policy.trim();
+    let unit_start = policy.len().checked_sub(1).ok_or_else(invalid)?;
+    if !policy.is_char_boundary(unit_start) {
+        return Err(invalid());
+    }
+    let (amount, unit) = policy.split_at(unit_start);
+    let amount: i64 = amount.parse().ok().filter(|n| *n > 0).ok_or_else(invalid)?;
+    
+    let seconds_per_unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86_400,
+        "w" => 604_800,
+        _ => return Err(invalid()),
+    };
+    
+    amount
+        .checked_mul(seconds_per_unit)
+        .and_then(chrono::Duration::try_seconds)
+        .ok_or_else(invalid)
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum FlowError {
     #[error("Flow cannot be empty")]
@@ -701,6 +910,9 @@ pub enum FlowError {
     
     #[error("Duplicate hop index")]
     DuplicateHop,
+    
+    #[error("Invalid retention policy: {0}")]
+    InvalidRetentionPolicy(String),
 }
 
 #[cfg(test)]
@@ -740,6 +952,18 @@ mod tests {
         assert!(!flow.contains_switch("s4"));
     }
 
+    #[test]
+    fn test_link_delays_from_hop_timestamps() {
+        let flow = Flow::new("flow1".to_string(), create_test_hops()).unwrap();
+        
+        let links: Vec<_> = flow.link_delays().collect();
+        assert_eq!(links, vec![("s1", "s2", 10_000_000), ("s2", "s3", 10_000_000)]);
+        assert_eq!(flow.link_delay("s2", "s3"), Some(10_000_000));
+        assert_eq!(flow.link_delay("s1", "s3"), None);
+        assert!(flow.traverses_link("s1", "s2"));
+        assert!(!flow.traverses_link("s2", "s1"));
+    }
+
     #[test]
     fn test_partial_flow() {
         let hops = vec![
@@ -783,6 +1007,7 @@ mod tests {
                     ingress_port: None,
                 },
             ],
+            retention_policy: None,
         };
         
         let flow = Flow::try_from(input).unwrap();
@@ -802,4 +1027,44 @@ mod tests {
         ];
         assert!(matches!(Flow::new("flow1".to_string(), bad_hops), Err(FlowError::InvalidHopOrdering)));
     }
+
+    #[test]
+    fn test_retention_policy_parsing() {
+        assert_eq!(parse_retention_policy("7d").unwrap(), chrono::Duration::days(7));
+        assert_eq!(parse_retention_policy("90s").unwrap(), chrono::Duration::seconds(90));
+        assert_eq!(parse_retention_policy("2w").unwrap(), chrono::Duration::weeks(2));
+        
+        for invalid in ["", "d", "0h", "-1d", "7", "7y", "1.5h", "7д"] {
+            assert!(matches!(parse_retention_policy(invalid), Err(FlowError::InvalidRetentionPolicy(_))), "{}", invalid);
+        }
+        
+        let mut flow = Flow::new("flow1".to_string(), create_test_hops()).unwrap();
+        assert_eq!(flow.retention(), None);
+        flow.retention_policy = Some("12h".to_string());
+        assert_eq!(flow.retention(), Some(chrono::Duration::hours(12)));
+        assert_eq!(flow.to_spatiotemporal().temporal_metadata.retention_policy.as_deref(), Some("12h"));
+    }
+    
+    #[test]
+    fn test_as_of_replays_ingest_versions() {
+        let t0 = Utc::now();
+        let mut flow = Flow::new("flow1".to_string(), create_test_hops()).unwrap();
+        assert_eq!(flow.as_of(t0), Some(flow.clone()));
+        flow.stamp_ingest(t0);
+        
+        let late_hop = Hop::with_basic_metrics(0, "s4".to_string(), t0 - chrono::Duration::seconds(1), 0.4, 400);
+        let mut late = Flow::new("flow1".to_string(), vec![late_hop]).unwrap();
+        late.stamp_ingest(t0 + chrono::Duration::seconds(5));
+        let original = flow.clone();
+        flow.merge(&late);
+        
+        assert_eq!(flow.hops.len(), 4);
+        assert_eq!(flow.hops[3].hop_index, 3);
+        assert_eq!(flow.start_time, t0 - chrono::Duration::seconds(1));
+        assert_eq!(flow.path, NetworkPath::from_switches(&["s1", "s2", "s3", "s4"]));
+        
+        assert_eq!(flow.as_of(t0 - chrono::Duration::seconds(1)), None);
+        assert_eq!(flow.as_of(t0 + chrono::Duration::seconds(1)), Some(original));
+        assert_eq!(flow.as_of(t0 + chrono::Duration::seconds(5)), Some(flow.clone()));
+    }
 } 
\ No newline at end of file
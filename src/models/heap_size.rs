@@ -0,0 +1,123 @@
+use std::mem::size_of;
+
+use crate::models::{Flow, FlowStatus, Hop, IngestVersion, NetworkPath, TelemetryMetrics};
+
+/// Bytes a value owns on the heap, excluding its own inline size
+///
+/// Sizes are computed from lengths rather than capacities so that a value and
+/// its clones always report the same number, which keeps incremental totals exact.
+pub trait HeapSize {
+    fn heap_size(&self) -> usize;
+}
+
+impl HeapSize for String {
+    fn heap_size(&self) -> usize {
+        self.len()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Vec<T> {
+    fn heap_size(&self) -> usize {
+        self.len() * size_of::<T>() + self.iter().map(HeapSize::heap_size).sum::<usize>()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Option<T> {
+    fn heap_size(&self) -> usize {
+        self.as_ref().map_or(0, HeapSize::heap_size)
+    }
+}
+
+impl<K: HeapSize, V: HeapSize> HeapSize for indexmap::IndexMap<K, V> {
+    fn heap_size(&self) -> usize {
+        // Each entry is a (hash, key, value) bucket plus a slot in the index table
+        let per_entry = size_of::<(u64, K, V)>() + size_of::<usize>();
+        self.iter()
+            .map(|(key, value)| per_entry + key.heap_size() + value.heap_size())
+            .sum()
+    }
+}
+
+impl HeapSize for serde_json::Value {
+    fn heap_size(&self) -> usize {
+        match self {
+            serde_json::Value::String(s) => s.heap_size(),
+            serde_json::Value::Array(values) => values.heap_size(),
+            serde_json::Value::Object(map) => map
+                .iter()
+                .map(|(key, value)| size_of::<(String, serde_json::Value)>() + key.heap_size() + value.heap_size())
+                .sum(),
+            _ => 0,
+        }
+    }
+}
+
+impl HeapSize for TelemetryMetrics {
+    fn heap_size(&self) -> usize {
+        self.custom_metrics.heap_size()
+    }
+}
+
+impl HeapSize for Hop {
+    fn heap_size(&self) -> usize {
+        self.switch_id.heap_size() + self.metrics.heap_size()
+    }
+}
+
+impl HeapSize for NetworkPath {
+    fn heap_size(&self) -> usize {
+        self.switches.heap_size() + self.path_hash.heap_size()
+    }
+}
+
+impl HeapSize for FlowStatus {
+    fn heap_size(&self) -> usize {
+        match self {
+            FlowStatus::Error(msg) => msg.heap_size(),
+            _ => 0,
+        }
+    }
+}
+
+impl HeapSize for IngestVersion {
+    fn heap_size(&self) -> usize {
+        0
+    }
+}
+
+impl HeapSize for Flow {
+    fn heap_size(&self) -> usize {
+        self.flow_id.heap_size()
+            + self.path.heap_size()
+            + self.hops.heap_size()
+            + self.status.heap_size()
+            + self.retention_policy.heap_size()
+            + self.ingest_versions.heap_size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    
+    #[test]
+    fn test_flow_heap_size_tracks_contents() {
+        let hops = vec![
+            Hop::with_basic_metrics(0, "s1".to_string(), Utc::now(), 0.1, 100),
+            Hop::with_basic_metrics(1, "s2".to_string(), Utc::now(), 0.2, 200),
+        ];
+        let flow = Flow::new("flow1".to_string(), hops).unwrap();
+        
+        let hops_bytes = 2 * size_of::<Hop>() + 2 * "s1".len();
+        let path_bytes = 2 * size_of::<String>() + 2 * "s1".len() + 64;
+        assert_eq!(flow.heap_size(), "flow1".len() + path_bytes + hops_bytes);
+        assert_eq!(flow.clone().heap_size(), flow.heap_size());
+        
+        // Long switch IDs and custom metrics are counted byte for byte
+        let mut metrics = TelemetryMetrics::new();
+        metrics.add_custom_metric("note".to_string(), serde_json::Value::String("x".repeat(1000)));
+        let hop = Hop::new(0, "a".repeat(500), Utc::now(), metrics);
+        assert!(hop.heap_size() >= 1500);
+    }
+}
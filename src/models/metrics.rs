@@ -64,7 +64,7 @@ impl TelemetryMetrics {
             && self.drop_count.is_none()
             && self.egress_port.is_none()
             && self.ingress_port.is_none()
-            && self.custom_metrics.as_ref().map_or(true, |m| m.is_empty())
+            && self.custom_metrics.as_ref().is_none_or(|m| m.is_empty())
     }
 }
 
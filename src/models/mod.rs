@@ -1,9 +1,13 @@
 pub mod flow;
+pub mod heap_size;
 pub mod hop;
 pub mod path;
 pub mod metrics;
+pub mod schema;
 
 pub use flow::*;
+pub use heap_size::*;
 pub use hop::*;
 pub use path::*;
-pub use metrics::*; 
\ No newline at end of file
+pub use metrics::*;
+pub use schema::*; 
\ No newline at end of file
@@ -4,6 +4,7 @@ use std::fmt;
 
 /// Represents a network path as a sequence of switch identifiers
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "NetworkPathData")]
 pub struct NetworkPath {
     /// Ordered sequence of switch identifiers
     pub switches: Vec<String>,
@@ -13,6 +14,18 @@ pub struct NetworkPath {
     pub path_hash: Option<String>,
 }
 
+/// Serialized form of a path; the hash is recomputed on deserialization
+#[derive(Deserialize)]
+struct NetworkPathData {
+    switches: Vec<String>,
+}
+
+impl From<NetworkPathData> for NetworkPath {
+    fn from(data: NetworkPathData) -> Self {
+        Self::new(data.switches)
+    }
+}
+
 impl NetworkPath {
     /// Create a new network path
     pub fn new(switches: Vec<String>) -> Self {
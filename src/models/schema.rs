@@ -0,0 +1,265 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+
+use crate::models::{Flow, SpatiotemporalFlow};
+
+/// Field of a persisted or exported record holding its schema version
+pub const SCHEMA_VERSION_FIELD: &str = "schema_version";
+
+/// Upgrade a record's JSON object from one schema version to the next
+pub type Migration = fn(&mut Map<String, Value>) -> Result<(), String>;
+
+/// A record type whose JSON layout is versioned
+///
+/// Records are written with the current `SCHEMA_VERSION`; records written by an
+/// earlier build are upgraded on load by applying each migration from their
+/// version onwards. Records without a version field predate versioning and are
+/// version 1.
+pub trait Versioned: Serialize + DeserializeOwned {
+    /// Record name for error messages
+    const KIND: &'static str;
+    
+    /// Schema version written by this build
+    const SCHEMA_VERSION: u32;
+    
+    /// Upgrade steps, oldest first: `MIGRATIONS[i]` upgrades version `i + 1` to `i + 2`
+    const MIGRATIONS: &'static [Migration];
+    
+    /// Encode the record with its schema version
+    fn to_record(&self) -> Result<Value, SchemaError> {
+        let mut value = serde_json::to_value(self).map_err(|e| invalid::<Self>(e.to_string()))?;
+        let object = value.as_object_mut().ok_or_else(|| invalid::<Self>("not a JSON object"))?;
+        object.insert(SCHEMA_VERSION_FIELD.to_string(), Value::from(Self::SCHEMA_VERSION));
+        Ok(value)
+    }
+    
+    /// Decode a record of any supported schema version
+    fn from_record(mut value: Value) -> Result<Self, SchemaError> {
+        let object = value.as_object_mut().ok_or_else(|| invalid::<Self>("not a JSON object"))?;
+        let version = match object.remove(SCHEMA_VERSION_FIELD) {
+            None => 1,
+            Some(version) => version.as_u64().ok_or_else(|| invalid::<Self>("schema_version is not a number"))?,
+        };
+        Self::upgrade(value, version)
+    }
+    
+    /// Decode a record whose schema version is stored elsewhere (e.g. in a file header)
+    fn upgrade(mut value: Value, version: u64) -> Result<Self, SchemaError> {
+        if version == 0 || version > Self::SCHEMA_VERSION as u64 {
+            return Err(SchemaError::Unsupported {
+                kind: Self::KIND,
+                version,
+                supported: Self::SCHEMA_VERSION,
+            });
+        }
+        
+        let object = value.as_object_mut().ok_or_else(|| invalid::<Self>("not a JSON object"))?;
+        for migration in &Self::MIGRATIONS[version as usize - 1..] {
+            migration(object).map_err(invalid::<Self>)?;
+        }
+        
+        serde_json::from_value(value).map_err(|e| invalid::<Self>(e.to_string()))
+    }
+}
+
+/// Error loading a versioned record
+#[derive(Debug, thiserror::Error)]
+pub enum SchemaError {
+    #[error("{kind} schema version {version} is not supported (this build reads versions 1 to {supported})")]
+    Unsupported { kind: &'static str, version: u64, supported: u32 },
+    
+    #[error("Invalid {kind} record: {reason}")]
+    Invalid { kind: &'static str, reason: String },
+}
+
+fn invalid<T: Versioned>(reason: impl Into<String>) -> SchemaError {
+    SchemaError::Invalid {
+        kind: T::KIND,
+        reason: reason.into(),
+    }
+}
+
+/// Serde adapter writing a field as a versioned record and upgrading it on read
+///
+/// Use with `#[serde(with = "crate::models::schema::record")]`.
+pub mod record {
+    use serde::de::Error as _;
+    use serde::ser::Error as _;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use serde_json::Value;
+    
+    use super::Versioned;
+    
+    pub fn serialize<T: Versioned, S: Serializer>(record: &T, serializer: S) -> Result<S::Ok, S::Error> {
+        record.to_record().map_err(S::Error::custom)?.serialize(serializer)
+    }
+    
+    pub fn deserialize<'de, T: Versioned, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+        T::from_record(Value::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+/// A borrowed record written through `record`
+struct RecordRef<'a, T>(&'a T);
+
+impl<T: Versioned> Serialize for RecordRef<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        record::serialize(self.0, serializer)
+    }
+}
+
+/// A record read through `record`
+struct Record<T>(T);
+
+impl<'de, T: Versioned> Deserialize<'de> for Record<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        record::deserialize(deserializer).map(Record)
+    }
+}
+
+/// Serde adapter like `record`, for an optional record
+pub mod optional_record {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    
+    use super::{Record, RecordRef, Versioned};
+    
+    pub fn serialize<T: Versioned, S: Serializer>(record: &Option<T>, serializer: S) -> Result<S::Ok, S::Error> {
+        record.as_ref().map(RecordRef).serialize(serializer)
+    }
+    
+    pub fn deserialize<'de, T: Versioned, D: Deserializer<'de>>(deserializer: D) -> Result<Option<T>, D::Error> {
+        Ok(Option::<Record<T>>::deserialize(deserializer)?.map(|record| record.0))
+    }
+}
+
+/// Serde adapter like `record`, for a list of records
+pub mod records {
+    use serde::{Deserialize, Deserializer, Serializer};
+    
+    use super::{Record, RecordRef, Versioned};
+    
+    pub fn serialize<T: Versioned, S: Serializer>(records: &[T], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(records.iter().map(RecordRef))
+    }
+    
+    pub fn deserialize<'de, T: Versioned, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<T>, D::Error> {
+        Ok(Vec::<Record<T>>::deserialize(deserializer)?.into_iter().map(|record| record.0).collect())
+    }
+}
+
+/// Serde adapter like `record`, for an optional list of records
+pub mod optional_records {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    
+    use super::{Record, RecordRef, Versioned};
+    
+    pub fn serialize<T: Versioned, S: Serializer>(records: &Option<Vec<T>>, serializer: S) -> Result<S::Ok, S::Error> {
+        records
+            .as_ref()
+            .map(|records| records.iter().map(RecordRef).collect::<Vec<_>>())
+            .serialize(serializer)
+    }
+    
+    pub fn deserialize<'de, T: Versioned, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<T>>, D::Error> {
+        let records = Option::<Vec<Record<T>>>::deserialize(deserializer)?;
+        Ok(records.map(|records| records.into_iter().map(|record| record.0).collect()))
+    }
+}
+
+/// Flows, including their hops and telemetry metrics
+///
+/// 1. Unversioned records, written before flows carried ingest versions
+/// 2. `ingest_versions` and `retention_policy` are always present
+impl Versioned for Flow {
+    const KIND: &'static str = "flow";
+    const SCHEMA_VERSION: u32 = 2;
+    const MIGRATIONS: &'static [Migration] = &[flow_v1_to_v2];
+}
+
+fn flow_v1_to_v2(flow: &mut Map<String, Value>) -> Result<(), String> {
+    flow.entry("retention_policy").or_insert(Value::Null);
+    flow.entry("ingest_versions").or_insert_with(|| Value::Array(Vec::new()));
+    Ok(())
+}
+
+/// Spatiotemporal flows as exported by the API
+///
+/// 1. The initial layout
+impl Versioned for SpatiotemporalFlow {
+    const KIND: &'static str = "spatiotemporal flow";
+    const SCHEMA_VERSION: u32 = 1;
+    const MIGRATIONS: &'static [Migration] = &[];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Hop, TelemetryMetrics};
+    use chrono::Utc;
+    
+    const FLOW_V1: &str = include_str!("../../tests/fixtures/flow_v1.json");
+    
+    #[test]
+    fn test_flow_v1_fixture_is_upgraded() {
+        let flow = Flow::from_record(serde_json::from_str(FLOW_V1).unwrap()).unwrap();
+        assert_eq!(flow.flow_id, "flow1");
+        assert_eq!(flow.path.switches, vec!["s1", "s2", "s3"]);
+        assert_eq!(flow.hops.len(), 3);
+        assert_eq!(flow.hops[1].metrics.delay_ns, Some(100));
+        assert_eq!(flow.path.hash(), crate::models::NetworkPath::from_switches(&["s1", "s2", "s3"]).hash());
+        assert!(flow.ingest_versions.is_empty());
+        assert_eq!(flow.retention_policy, None);
+    }
+    
+    #[test]
+    fn test_records_carry_their_version() {
+        let hop = Hop::new(0, "s1".to_string(), Utc::now(), TelemetryMetrics::with_basic(0.5, 10));
+        let mut flow = Flow::new("flow1".to_string(), vec![hop]).unwrap();
+        flow.stamp_ingest(Utc::now());
+        
+        let record = flow.to_record().unwrap();
+        assert_eq!(record[SCHEMA_VERSION_FIELD], Value::from(Flow::SCHEMA_VERSION));
+        assert_eq!(Flow::from_record(record.clone()).unwrap(), flow);
+        
+        let mut future = record;
+        future[SCHEMA_VERSION_FIELD] = Value::from(Flow::SCHEMA_VERSION + 1);
+        assert!(matches!(Flow::from_record(future), Err(SchemaError::Unsupported { version: 3, .. })));
+        assert!(matches!(Flow::from_record(Value::from("flow1")), Err(SchemaError::Invalid { .. })));
+    }
+    
+    #[test]
+    fn test_exported_records_carry_their_version() {
+        use crate::api::{FlowsResponse, SpatiotemporalFlowResponse};
+        use crate::storage::{ChangeEvent, ChangeKind};
+        
+        let hop = Hop::new(0, "s1".to_string(), Utc::now(), TelemetryMetrics::with_basic(0.5, 10));
+        let flow = Flow::new("flow1".to_string(), vec![hop]).unwrap();
+        
+        let response = FlowsResponse { flows: vec![flow.clone()], count: 1 };
+        let exported = serde_json::to_value(&response).unwrap();
+        assert_eq!(exported["flows"][0][SCHEMA_VERSION_FIELD], Value::from(Flow::SCHEMA_VERSION));
+        assert_eq!(serde_json::from_value::<FlowsResponse>(exported).unwrap().flows, vec![flow.clone()]);
+        
+        // Flows exported by an older build are upgraded when read back
+        let legacy = serde_json::json!({ "flows": [serde_json::from_str::<Value>(FLOW_V1).unwrap()], "count": 1 });
+        let response: FlowsResponse = serde_json::from_value(legacy).unwrap();
+        assert!(response.flows[0].ingest_versions.is_empty());
+        
+        let st_flow = flow.clone().to_spatiotemporal();
+        let exported = serde_json::to_value(SpatiotemporalFlowResponse { flow: st_flow.clone() }).unwrap();
+        assert_eq!(exported["flow"][SCHEMA_VERSION_FIELD], Value::from(SpatiotemporalFlow::SCHEMA_VERSION));
+        assert_eq!(SpatiotemporalFlow::from_record(exported["flow"].clone()).unwrap(), st_flow);
+        
+        let event = ChangeEvent {
+            seq: 1,
+            at: Utc::now(),
+            kind: ChangeKind::Created,
+            flow_id: "flow1".to_string(),
+            telemetry: Some(flow),
+        };
+        let exported = serde_json::to_value(&event).unwrap();
+        assert_eq!(exported["telemetry"][SCHEMA_VERSION_FIELD], Value::from(Flow::SCHEMA_VERSION));
+        assert_eq!(serde_json::from_value::<ChangeEvent>(exported).unwrap(), event);
+    }
+}
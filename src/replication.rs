@@ -0,0 +1,443 @@
+//! Tail-follow replication from a primary to read-only followers
+//!
+//! The primary streams its committed WAL records over TCP as newline-delimited
+//! JSON. A follower sends the LSN it wants to resume from, applies what it
+//! receives to its own engine, and reconnects from its last applied record when
+//! the connection drops. Followers keep their copy in memory and rebuild it from
+//! the primary's log on restart. Both sides share a secret, which the follower
+//! presents in its handshake.
+
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::storage::{StorageEngine, StorageError, WalEntry};
+
+/// How often an idle primary tells followers its latest LSN
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a follower waits before reconnecting
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+
+/// How long the primary waits for a new connection's handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest handshake line the primary reads
+const MAX_HANDSHAKE_BYTES: u64 = 4096;
+
+/// Replication settings read from the environment
+#[derive(Debug, Clone, Default)]
+pub struct ReplicationConfig {
+    /// Address the primary accepts followers on
+    pub listen: Option<String>,
+    
+    /// Primary this instance follows (makes the engine read-only)
+    pub primary: Option<String>,
+    
+    /// Secret shared by the primary and its followers
+    pub secret: String,
+}
+
+impl ReplicationConfig {
+    /// Read `INTDB_REPLICATION_LISTEN` and `INTDB_REPLICATE_FROM` (both `host:port`)
+    /// and `INTDB_REPLICATION_SECRET`, which either of the others requires
+    pub fn from_env() -> Result<Self, StorageError> {
+        let config = Self {
+            listen: std::env::var("INTDB_REPLICATION_LISTEN").ok(),
+            primary: std::env::var("INTDB_REPLICATE_FROM").ok(),
+            secret: std::env::var("INTDB_REPLICATION_SECRET").unwrap_or_default(),
+        };
+        if (config.listen.is_some() || config.primary.is_some()) && config.secret.is_empty() {
+            return Err(StorageError::Replication(
+                "INTDB_REPLICATION_SECRET must be set on the primary and its followers".to_string(),
+            ));
+        }
+        Ok(config)
+    }
+}
+
+/// Handshake a follower sends on connecting
+#[derive(Debug, Serialize, Deserialize)]
+struct FollowRequest {
+    from_lsn: u64,
+    secret: String,
+}
+
+/// Message streamed from the primary, one JSON object per line
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ReplicationMessage {
+    /// Discard all state; the records that follow rebuild it from the start of the log
+    Reset,
+    
+    /// A committed record
+    Entry { entry: WalEntry },
+    
+    /// The primary's latest LSN, sent while idle so followers can measure lag
+    Heartbeat { last_lsn: u64 },
+    
+    /// The handshake was refused, and the connection closes
+    Refused { reason: String },
+}
+
+/// Accepts followers and streams the engine's WAL to each from a background thread
+#[derive(Debug)]
+pub struct ReplicationPrimary {
+    local_addr: SocketAddr,
+}
+
+impl ReplicationPrimary {
+    /// Listen on `addr` and serve followers presenting `secret` until the process exits
+    pub fn start(engine: Arc<StorageEngine>, addr: impl ToSocketAddrs, secret: impl Into<String>) -> Result<Self, StorageError> {
+        if engine.wal_last_lsn().is_none() {
+            return Err(StorageError::Replication("Replication needs a write-ahead log".to_string()));
+        }
+        let secret: Arc<str> = secret.into().into();
+        if secret.is_empty() {
+            return Err(StorageError::Replication("The replication secret can't be empty".to_string()));
+        }
+        
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        thread::Builder::new().name("replication-primary".to_string()).spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let (engine, secret) = (engine.clone(), secret.clone());
+                        thread::spawn(move || {
+                            let peer = stream.peer_addr().map_or_else(|_| "unknown".to_string(), |addr| addr.to_string());
+                            info!("Follower {} connected", peer);
+                            if let Err(e) = serve_follower(&engine, stream, &secret) {
+                                info!("Follower {} disconnected: {}", peer, e);
+                            }
+                        });
+                    }
+                    Err(e) => warn!("Failed to accept follower: {}", e),
+                }
+            }
+        })?;
+        
+        info!("Replication primary listening on {}", local_addr);
+        Ok(Self { local_addr })
+    }
+    
+    /// Get the address followers connect to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+/// Stream the log to one follower until it disconnects, falls behind or the log is rewritten
+fn serve_follower(engine: &StorageEngine, stream: TcpStream, secret: &str) -> Result<(), StorageError> {
+    // A peer that never completes the handshake doesn't get to hold a thread
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?.take(MAX_HANDSHAKE_BYTES));
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let request: FollowRequest = serde_json::from_str(line.trim_end())
+        .map_err(|e| StorageError::Replication(format!("Invalid handshake: {}", e)))?;
+    
+    // Digests are compared so the check doesn't leak how much of the secret matched
+    let mut writer = BufWriter::new(stream);
+    if Sha256::digest(request.secret.as_bytes()) != Sha256::digest(secret.as_bytes()) {
+        send(&mut writer, &ReplicationMessage::Refused { reason: "Wrong replication secret".to_string() })?;
+        writer.flush()?;
+        return Err(StorageError::Replication("Wrong replication secret".to_string()));
+    }
+    
+    let follow = engine.follow_wal(request.from_lsn)?;
+    if follow.reset {
+        send(&mut writer, &ReplicationMessage::Reset)?;
+    }
+    follow.backlog.scan(|entry| send(&mut writer, &ReplicationMessage::Entry { entry }))?;
+    writer.flush()?;
+    
+    loop {
+        let message = match follow.live.recv_timeout(HEARTBEAT_INTERVAL) {
+            Ok(entry) => ReplicationMessage::Entry { entry },
+            Err(RecvTimeoutError::Timeout) => ReplicationMessage::Heartbeat {
+                last_lsn: engine.wal_last_lsn().unwrap_or(0),
+            },
+            // The log was rewritten or the follower fell behind; it reconnects and
+            // resets or catches up from the file
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        send(&mut writer, &message)?;
+        writer.flush()?;
+    }
+}
+
+/// Write one message line
+fn send(writer: &mut impl Write, message: &ReplicationMessage) -> Result<(), StorageError> {
+    serde_json::to_writer(&mut *writer, message).map_err(std::io::Error::from)?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Replication state as seen by a follower
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplicationStatus {
+    /// Primary address
+    pub primary: String,
+    
+    /// Whether the follower is currently streaming from the primary
+    pub connected: bool,
+    
+    /// LSN of the last record applied
+    pub applied_lsn: u64,
+    
+    /// Latest LSN the primary has reported
+    pub primary_lsn: u64,
+    
+    /// Records committed on the primary but not yet applied here
+    pub lag_records: u64,
+    
+    /// When the primary was last heard from
+    pub last_contact: Option<DateTime<Utc>>,
+    
+    /// Connections made after the first
+    pub reconnects: u64,
+}
+
+/// Mutable part of a follower's status
+#[derive(Debug, Default)]
+struct FollowerState {
+    connected: bool,
+    primary_lsn: u64,
+    last_contact: Option<DateTime<Utc>>,
+    connections: u64,
+}
+
+/// Follows a primary from a background thread, applying its records to a local engine
+#[derive(Debug)]
+pub struct ReplicationFollower {
+    engine: Arc<StorageEngine>,
+    primary: String,
+    secret: String,
+    state: Mutex<FollowerState>,
+    stopped: AtomicBool,
+}
+
+impl ReplicationFollower {
+    /// Start following `primary` (`host:port`), which must share `secret`
+    pub fn start(engine: Arc<StorageEngine>, primary: impl Into<String>, secret: impl Into<String>) -> Arc<Self> {
+        let follower = Arc::new(Self {
+            engine,
+            primary: primary.into(),
+            secret: secret.into(),
+            state: Mutex::new(FollowerState::default()),
+            stopped: AtomicBool::new(false),
+        });
+        
+        let runner = follower.clone();
+        thread::Builder::new()
+            .name("replication-follower".to_string())
+            .spawn(move || runner.run())
+            .expect("Failed to spawn replication follower");
+        
+        follower
+    }
+    
+    /// Get the current replication status
+    pub fn status(&self) -> ReplicationStatus {
+        let state = self.state.lock().unwrap();
+        let applied_lsn = self.engine.replicated_lsn();
+        ReplicationStatus {
+            primary: self.primary.clone(),
+            connected: state.connected,
+            applied_lsn,
+            primary_lsn: state.primary_lsn,
+            lag_records: state.primary_lsn.saturating_sub(applied_lsn),
+            last_contact: state.last_contact,
+            reconnects: state.connections.saturating_sub(1),
+        }
+    }
+    
+    /// Stop following after the current message
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+    
+    /// Connect, stream and reconnect until stopped
+    fn run(&self) {
+        while !self.stopped.load(Ordering::Relaxed) {
+            if let Err(e) = self.follow_once() {
+                warn!("Replication from {} interrupted: {}", self.primary, e);
+            }
+            self.state.lock().unwrap().connected = false;
+            
+            if !self.stopped.load(Ordering::Relaxed) {
+                thread::sleep(RECONNECT_DELAY);
+            }
+        }
+    }
+    
+    /// Stream from the primary over one connection, resuming after the last applied record
+    fn follow_once(&self) -> Result<(), StorageError> {
+        let stream = TcpStream::connect(&self.primary)?;
+        
+        // Heartbeats keep an idle connection busy, so silence means the primary is gone
+        stream.set_read_timeout(Some(HEARTBEAT_INTERVAL * 3))?;
+        
+        let mut writer = stream.try_clone()?;
+        let request = FollowRequest {
+            from_lsn: self.engine.replicated_lsn() + 1,
+            secret: self.secret.clone(),
+        };
+        serde_json::to_writer(&mut writer, &request).map_err(std::io::Error::from)?;
+        writer.write_all(b"\n")?;
+        
+        {
+            let mut state = self.state.lock().unwrap();
+            state.connected = true;
+            state.connections += 1;
+        }
+        info!("Following {} from LSN {}", self.primary, request.from_lsn);
+        
+        for line in BufReader::new(stream).lines() {
+            if self.stopped.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            
+            let message: ReplicationMessage = serde_json::from_str(&line?)
+                .map_err(|e| StorageError::Replication(format!("Invalid message: {}", e)))?;
+            self.state.lock().unwrap().last_contact = Some(Utc::now());
+            
+            match message {
+                ReplicationMessage::Reset => {
+                    info!("Primary {} asked for a reset; rebuilding from its log", self.primary);
+                    self.engine.reset_replica()?;
+                }
+                ReplicationMessage::Entry { entry } => {
+                    let lsn = entry.lsn;
+                    self.engine.apply_replicated(entry)?;
+                    let mut state = self.state.lock().unwrap();
+                    state.primary_lsn = state.primary_lsn.max(lsn);
+                }
+                ReplicationMessage::Heartbeat { last_lsn } => {
+                    self.state.lock().unwrap().primary_lsn = last_lsn;
+                }
+                ReplicationMessage::Refused { reason } => {
+                    return Err(StorageError::Replication(format!("Primary refused to stream: {}", reason)));
+                }
+            }
+        }
+        
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use crate::models::{Flow, Hop, TelemetryMetrics};
+    use crate::storage::{EngineConfig, QueryBuilder};
+    
+    fn create_test_flow(flow_id: &str, switch: &str) -> Flow {
+        let hop = Hop::new(0, switch.to_string(), Utc::now(), TelemetryMetrics::with_basic(0.1, 100));
+        Flow::new(flow_id.to_string(), vec![hop]).unwrap()
+    }
+    
+    fn wait_for(what: &str, condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+    
+    fn start_primary(dir: &tempfile::TempDir) -> (Arc<StorageEngine>, ReplicationPrimary) {
+        let engine = Arc::new(
+            StorageEngine::open(EngineConfig {
+                wal_path: Some(dir.path().join("intdb.wal")),
+                ..EngineConfig::default()
+            })
+            .unwrap(),
+        );
+        let primary = ReplicationPrimary::start(engine.clone(), "127.0.0.1:0", "secret").unwrap();
+        (engine, primary)
+    }
+    
+    fn start_follower(primary: &ReplicationPrimary, secret: &str) -> (Arc<StorageEngine>, Arc<ReplicationFollower>) {
+        let engine = Arc::new(StorageEngine::with_config(EngineConfig { read_only: true, ..EngineConfig::default() }));
+        let follower = ReplicationFollower::start(engine.clone(), primary.local_addr().to_string(), secret);
+        (engine, follower)
+    }
+    
+    #[test]
+    fn test_follower_streams_inserts_and_deletes() {
+        let dir = tempfile::tempdir().unwrap();
+        let (primary_engine, primary) = start_primary(&dir);
+        primary_engine.insert_flow(create_test_flow("flow1", "s1")).unwrap();
+        
+        let (engine, follower) = start_follower(&primary, "secret");
+        wait_for("catch-up", || engine.flow_count() == 1);
+        
+        primary_engine.insert_flow(create_test_flow("flow2", "s2")).unwrap();
+        primary_engine.insert_flow(create_test_flow("flow1", "s3")).unwrap();
+        primary_engine.remove_flow("flow2").unwrap();
+        wait_for("live records", || engine.replicated_lsn() == 4);
+        
+        assert_eq!(engine.flow_count(), 1);
+        assert_eq!(engine.get_flow("flow1").unwrap().hops.len(), 2);
+        assert_eq!(engine.query(QueryBuilder::through_switch("s3")).unwrap().flow_ids, vec!["flow1"]);
+        assert!(engine.insert_flow(create_test_flow("flow3", "s1")).is_err());
+        
+        let status = follower.status();
+        assert!(status.connected);
+        assert_eq!(status.lag_records, 0);
+        follower.stop();
+    }
+    
+    #[test]
+    fn test_follower_reconnects_and_resets_after_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let (primary_engine, primary) = start_primary(&dir);
+        primary_engine.insert_flow(create_test_flow("flow1", "s1")).unwrap();
+        let snapshot = dir.path().join("before.snap");
+        primary_engine.snapshot(&snapshot).unwrap();
+        primary_engine.insert_flow(create_test_flow("flow2", "s2")).unwrap();
+        
+        let (engine, follower) = start_follower(&primary, "secret");
+        wait_for("catch-up", || engine.flow_count() == 2);
+        
+        // Restoring rewrites the primary's log, which drops the connection
+        primary_engine.restore(&snapshot).unwrap();
+        primary_engine.insert_flow(create_test_flow("flow3", "s3")).unwrap();
+        wait_for("reconnect", || engine.get_flow("flow3").is_some());
+        
+        assert!(engine.get_flow("flow2").is_none());
+        assert_eq!(engine.get_flow("flow1").unwrap().hops.len(), 1);
+        assert_eq!(engine.replicated_lsn(), primary_engine.wal_last_lsn().unwrap());
+        assert!(follower.status().reconnects >= 1);
+        follower.stop();
+    }
+    
+    #[test]
+    fn test_primary_refuses_followers_without_the_secret() {
+        let dir = tempfile::tempdir().unwrap();
+        let (primary_engine, primary) = start_primary(&dir);
+        primary_engine.insert_flow(create_test_flow("flow1", "s1")).unwrap();
+        
+        let (engine, follower) = start_follower(&primary, "guess");
+        wait_for("refusal", || follower.status().reconnects >= 1);
+        assert_eq!(engine.flow_count(), 0);
+        follower.stop();
+        
+        // A connection that never sends a handshake is closed
+        let mut stream = TcpStream::connect(primary.local_addr()).unwrap();
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT * 2)).unwrap();
+        let started = Instant::now();
+        assert_eq!(stream.read(&mut [0; 64]).unwrap(), 0);
+        assert!(started.elapsed() < HANDSHAKE_TIMEOUT * 2);
+    }
+}
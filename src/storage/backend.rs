@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::RwLock;
+use sha2::{Digest, Sha256};
+
+use crate::models::{Flow, HeapSize, Versioned};
+use crate::storage::{shard_index, StorageError, StoredFlow};
+
+/// Extension of a flow record file
+const FLOW_EXTENSION: &str = "flow";
+
+/// Where flow records live; the engine keeps only index entries in its partitions
+///
+/// Writes to one flow are serialized by the engine (its shard writer lock), so
+/// implementations only need to tolerate reads racing a write.
+pub trait StorageBackend: fmt::Debug + Send + Sync {
+    /// Short name for logs and stats
+    fn name(&self) -> &'static str;
+    
+    /// Whether records survive a restart, so the engine rebuilds its indexes from a scan
+    fn is_persistent(&self) -> bool;
+    
+    /// Add or replace a flow record
+    fn put(&self, flow: &StoredFlow) -> Result<(), StorageError>;
+    
+    /// Get a flow record by ID
+    fn get(&self, flow_id: &str) -> Result<Option<StoredFlow>, StorageError>;
+    
+    /// Remove a flow record, returning it
+    fn delete(&self, flow_id: &str) -> Result<Option<StoredFlow>, StorageError>;
+    
+    /// Visit every flow record, in no particular order
+    fn scan(&self, visit: &mut dyn FnMut(StoredFlow)) -> Result<(), StorageError>;
+    
+    /// Remove every flow record
+    fn clear(&self) -> Result<(), StorageError> {
+        let mut flow_ids = Vec::new();
+        self.scan(&mut |flow| flow_ids.push(flow.flow_id))?;
+        for flow_id in flow_ids {
+            self.delete(&flow_id)?;
+        }
+        Ok(())
+    }
+    
+    /// Bytes of memory a record takes in the backend, for the engine's memory budget
+    fn resident_bytes(&self, flow: &StoredFlow) -> usize;
+}
+
+/// Which storage backend the engine keeps flow records in
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum BackendKind {
+    /// Compressed records in memory (made durable by the WAL, if configured)
+    #[default]
+    Memory,
+    
+    /// One file per flow under the given directory
+    File(PathBuf),
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+    
+    /// Parse `memory` or `file:<dir>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "memory" => Ok(Self::Memory),
+            Some(("file", dir)) if !dir.is_empty() => Ok(Self::File(PathBuf::from(dir))),
+            _ => Err(format!("Unknown storage backend: {}", s)),
+        }
+    }
+}
+
+/// Flow records held in memory, sharded by flow ID like the engine
+#[derive(Debug)]
+pub struct MemoryBackend {
+    shards: Vec<RwLock<HashMap<String, StoredFlow>>>,
+}
+
+impl MemoryBackend {
+    /// Create an empty backend with `shard_count` independently locked maps
+    pub fn new(shard_count: usize) -> Self {
+        Self {
+            shards: (0..shard_count.max(1)).map(|_| RwLock::new(HashMap::new())).collect(),
+        }
+    }
+    
+    /// Get the map a flow belongs to
+    fn shard(&self, flow_id: &str) -> &RwLock<HashMap<String, StoredFlow>> {
+        &self.shards[shard_index(flow_id, self.shards.len())]
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+    
+    fn is_persistent(&self) -> bool {
+        false
+    }
+    
+    fn put(&self, flow: &StoredFlow) -> Result<(), StorageError> {
+        self.shard(&flow.flow_id).write().unwrap().insert(flow.flow_id.clone(), flow.clone());
+        Ok(())
+    }
+    
+    fn get(&self, flow_id: &str) -> Result<Option<StoredFlow>, StorageError> {
+        Ok(self.shard(flow_id).read().unwrap().get(flow_id).cloned())
+    }
+    
+    fn delete(&self, flow_id: &str) -> Result<Option<StoredFlow>, StorageError> {
+        Ok(self.shard(flow_id).write().unwrap().remove(flow_id))
+    }
+    
+    fn scan(&self, visit: &mut dyn FnMut(StoredFlow)) -> Result<(), StorageError> {
+        for shard in &self.shards {
+            for flow in shard.read().unwrap().values() {
+                visit(flow.clone());
+            }
+        }
+        Ok(())
+    }
+    
+    fn clear(&self) -> Result<(), StorageError> {
+        for shard in &self.shards {
+            shard.write().unwrap().clear();
+        }
+        Ok(())
+    }
+    
+    fn resident_bytes(&self, flow: &StoredFlow) -> usize {
+        // The map key is a second copy of the flow ID
+        size_of::<(String, StoredFlow)>() + flow.flow_id.len() + flow.heap_size()
+    }
+}
+
+/// Flow records kept on disk, one versioned JSON record per file
+///
+/// Each write goes to a temporary file that is fsynced and renamed over the
+/// record, so a crash leaves either the old or the new version.
+#[derive(Debug)]
+pub struct FileBackend {
+    dir: PathBuf,
+}
+
+impl FileBackend {
+    /// Open (creating if needed) a record directory
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, StorageError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+    
+    /// Get the record directory
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+    
+    /// Path of a flow's record file (flow IDs are hashed, so any ID is a safe file name)
+    fn record_path(&self, flow_id: &str) -> PathBuf {
+        let digest = Sha256::digest(flow_id.as_bytes());
+        self.dir.join(format!("{:x}.{}", digest, FLOW_EXTENSION))
+    }
+    
+    /// Read a record file, or `None` if it doesn't exist
+    fn read_record(path: &Path) -> Result<Option<StoredFlow>, StorageError> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        
+        let record = serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| StorageError::Backend(format!("Invalid flow record {}: {}", path.display(), e)))?;
+        Ok(Some(StoredFlow::new(&Flow::from_record(record)?)))
+    }
+}
+
+impl StorageBackend for FileBackend {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+    
+    fn is_persistent(&self) -> bool {
+        true
+    }
+    
+    fn put(&self, flow: &StoredFlow) -> Result<(), StorageError> {
+        let path = self.record_path(&flow.flow_id);
+        let tmp_path = path.with_extension("tmp");
+        
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, &flow.decode().to_record()?).map_err(io::Error::from)?;
+        writer.flush()?;
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        drop(file);
+        
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+    
+    fn get(&self, flow_id: &str) -> Result<Option<StoredFlow>, StorageError> {
+        Self::read_record(&self.record_path(flow_id))
+    }
+    
+    fn delete(&self, flow_id: &str) -> Result<Option<StoredFlow>, StorageError> {
+        let path = self.record_path(flow_id);
+        let Some(flow) = Self::read_record(&path)? else {
+            return Ok(None);
+        };
+        
+        fs::remove_file(&path)?;
+        Ok(Some(flow))
+    }
+    
+    fn scan(&self, visit: &mut dyn FnMut(StoredFlow)) -> Result<(), StorageError> {
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some(FLOW_EXTENSION) => {
+                    if let Some(flow) = Self::read_record(&path)? {
+                        visit(flow);
+                    }
+                }
+                // Left behind by a write interrupted before its rename
+                Some("tmp") => {
+                    fs::remove_file(&path)?;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+    
+    fn resident_bytes(&self, _flow: &StoredFlow) -> usize {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use crate::models::{Hop, TelemetryMetrics};
+    
+    fn create_test_flow(flow_id: &str) -> StoredFlow {
+        let hop = Hop::new(0, "s1".to_string(), Utc::now(), TelemetryMetrics::with_basic(0.1, 100));
+        StoredFlow::new(&Flow::new(flow_id.to_string(), vec![hop]).unwrap())
+    }
+    
+    fn exercise(backend: &dyn StorageBackend) {
+        let flow1 = create_test_flow("flow1");
+        let flow2 = create_test_flow("flow/2");
+        backend.put(&flow1).unwrap();
+        backend.put(&flow2).unwrap();
+        
+        assert_eq!(backend.get("flow1").unwrap().unwrap().decode(), flow1.decode());
+        assert!(backend.get("missing").unwrap().is_none());
+        
+        let mut ids = Vec::new();
+        backend.scan(&mut |flow| ids.push(flow.flow_id)).unwrap();
+        ids.sort();
+        assert_eq!(ids, vec!["flow/2", "flow1"]);
+        
+        assert_eq!(backend.delete("flow/2").unwrap().unwrap().decode(), flow2.decode());
+        assert!(backend.delete("flow/2").unwrap().is_none());
+        
+        backend.clear().unwrap();
+        assert!(backend.get("flow1").unwrap().is_none());
+    }
+    
+    #[test]
+    fn test_backends_put_get_delete_scan() {
+        exercise(&MemoryBackend::new(4));
+        
+        let dir = tempfile::tempdir().unwrap();
+        exercise(&FileBackend::open(dir.path()).unwrap());
+    }
+    
+    #[test]
+    fn test_file_backend_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let flow = create_test_flow("flow1");
+        FileBackend::open(dir.path()).unwrap().put(&flow).unwrap();
+        fs::write(dir.path().join("leftover.tmp"), b"partial").unwrap();
+        
+        let reopened = FileBackend::open(dir.path()).unwrap();
+        let mut flows = Vec::new();
+        reopened.scan(&mut |flow| flows.push(flow)).unwrap();
+        assert_eq!(flows.len(), 1);
+        assert_eq!(flows[0].decode(), flow.decode());
+        assert!(!dir.path().join("leftover.tmp").exists());
+    }
+    
+    #[test]
+    fn test_parse_backend_kind() {
+        assert_eq!("memory".parse::<BackendKind>(), Ok(BackendKind::Memory));
+        assert_eq!("file:/var/lib/intdb".parse::<BackendKind>(), Ok(BackendKind::File(PathBuf::from("/var/lib/intdb"))));
+        assert!("file:".parse::<BackendKind>().is_err());
+        assert!("rocksdb".parse::<BackendKind>().is_err());
+    }
+}
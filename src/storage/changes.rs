@@ -0,0 +1,293 @@
+use std::collections::VecDeque;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::models::{Flow, HeapSize};
+
+/// Events a subscriber may fall behind before it is dropped and has to resubscribe
+pub const SUBSCRIBER_BUFFER: usize = 1024;
+
+/// What happened to a flow
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    /// `insert_flow` stored a new flow
+    Created,
+    
+    /// `insert_flow` appended telemetry to an existing flow
+    Appended,
+    
+    /// The flow was deleted
+    Deleted,
+    
+    /// The flow was evicted to make room for another
+    Evicted,
+    
+    /// The flow outlived its retention period
+    Expired,
+}
+
+/// One entry of the change feed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChangeEvent {
+    /// Position in the feed, increasing by one per event from 1
+    pub seq: u64,
+    
+    /// When the change was applied
+    pub at: DateTime<Utc>,
+    
+    pub kind: ChangeKind,
+    
+    pub flow_id: String,
+    
+    /// The telemetry written: the whole flow when created, only the new hops when appended
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "crate::models::schema::optional_record")]
+    pub telemetry: Option<Flow>,
+}
+
+/// What a subscriber resuming after some sequence number needs to catch up and keep up
+#[derive(Debug)]
+pub struct ChangeSubscription {
+    /// Events between the requested position and the oldest one still buffered were dropped
+    /// (or the feed restarted), so the subscriber can't rely on having seen every change
+    pub missed: bool,
+    
+    /// Buffered events after the requested position
+    pub backlog: Vec<ChangeEvent>,
+    
+    /// Events published from now on; disconnected once the subscriber falls more than
+    /// `SUBSCRIBER_BUFFER` events behind, so it resubscribes after the last one it got
+    pub live: Receiver<ChangeEvent>,
+}
+
+/// A page of events returned by a long poll
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangePage {
+    pub events: Vec<ChangeEvent>,
+    
+    /// See `ChangeSubscription::missed`
+    pub missed: bool,
+    
+    /// Sequence number to resume after
+    pub last_seq: u64,
+}
+
+/// Mutable state of the feed
+#[derive(Debug, Default)]
+struct FeedState {
+    last_seq: u64,
+    recent: VecDeque<ChangeEvent>,
+    subscribers: Vec<SyncSender<ChangeEvent>>,
+}
+
+/// Ordered change-data-capture feed of flow creations, appends and removals
+///
+/// The most recent `capacity` events are buffered so pollers can resume after a
+/// sequence number; subscribers also get every event as it is published. Sequence
+/// numbers restart at 1 with the process.
+#[derive(Debug)]
+pub struct ChangeFeed {
+    state: Mutex<FeedState>,
+    
+    /// Signalled on every publish, for pollers waiting on the buffer
+    published: Condvar,
+    
+    capacity: usize,
+    
+    /// Bytes held by buffered events
+    buffered_bytes: AtomicUsize,
+}
+
+impl ChangeFeed {
+    /// Create an empty feed buffering up to `capacity` events
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            state: Mutex::new(FeedState::default()),
+            published: Condvar::new(),
+            capacity,
+            buffered_bytes: AtomicUsize::new(0),
+        }
+    }
+    
+    /// Assign the next sequence number to a change and deliver it
+    pub fn publish(&self, kind: ChangeKind, flow_id: &str, telemetry: Option<Flow>) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.last_seq += 1;
+        let event = ChangeEvent {
+            seq: state.last_seq,
+            at: Utc::now(),
+            kind,
+            flow_id: flow_id.to_string(),
+            telemetry,
+        };
+        
+        // Subscribers that have gone away or fallen too far behind are dropped here
+        if !state.subscribers.is_empty() {
+            state.subscribers.retain(|subscriber| subscriber.try_send(event.clone()).is_ok());
+        }
+        
+        if self.capacity > 0 {
+            if state.recent.len() == self.capacity {
+                if let Some(oldest) = state.recent.pop_front() {
+                    self.buffered_bytes.fetch_sub(buffered_size(&oldest), Ordering::Relaxed);
+                }
+            }
+            self.buffered_bytes.fetch_add(buffered_size(&event), Ordering::Relaxed);
+            state.recent.push_back(event);
+        }
+        
+        self.published.notify_all();
+        state.last_seq
+    }
+    
+    /// Get the bytes held by buffered events
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffered_bytes.load(Ordering::Relaxed)
+    }
+    
+    /// Get the sequence number of the latest event (0 before any)
+    pub fn last_seq(&self) -> u64 {
+        self.state.lock().unwrap().last_seq
+    }
+    
+    /// Start receiving events published after `after`
+    pub fn subscribe(&self, after: u64) -> ChangeSubscription {
+        let mut state = self.state.lock().unwrap();
+        let (missed, backlog) = Self::buffered_after(&state, after, usize::MAX);
+        
+        let (sender, live) = mpsc::sync_channel(SUBSCRIBER_BUFFER);
+        state.subscribers.push(sender);
+        ChangeSubscription { missed, backlog, live }
+    }
+    
+    /// Get up to `limit` buffered events after `after`, waiting up to `timeout` for the first one
+    ///
+    /// Pollers wait on the buffer itself, so a poll leaves nothing behind, and a feed
+    /// buffering no events can't be polled.
+    pub fn poll(&self, after: u64, limit: usize, timeout: Duration) -> ChangePage {
+        let deadline = Instant::now().checked_add(timeout);
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let (missed, events) = Self::buffered_after(&state, after, limit);
+            let remaining = deadline.map_or(timeout, |deadline| deadline.saturating_duration_since(Instant::now()));
+            if !events.is_empty() || remaining.is_zero() {
+                let last_seq = events.last().map_or_else(|| after.min(state.last_seq), |event| event.seq);
+                return ChangePage { events, missed, last_seq };
+            }
+            state = self.published.wait_timeout(state, remaining).unwrap().0;
+        }
+    }
+    
+    /// Get up to `limit` buffered events after `after`, and whether any before them are gone
+    fn buffered_after(state: &FeedState, after: u64, limit: usize) -> (bool, Vec<ChangeEvent>) {
+        // A position ahead of the feed comes from before a restart
+        if after > state.last_seq {
+            return (true, state.recent.iter().take(limit).cloned().collect());
+        }
+        
+        let oldest = state.recent.front().map_or(state.last_seq + 1, |event| event.seq);
+        let missed = after + 1 < oldest;
+        let backlog = state.recent.iter().filter(|event| event.seq > after).take(limit).cloned().collect();
+        (missed, backlog)
+    }
+}
+
+/// Bytes an event holds while buffered
+fn buffered_size(event: &ChangeEvent) -> usize {
+    size_of::<ChangeEvent>() + event.flow_id.heap_size() + event.telemetry.heap_size()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_subscribe_resumes_after_sequence_number() {
+        let feed = ChangeFeed::new(3);
+        for flow_id in ["a", "b", "c", "d"] {
+            feed.publish(ChangeKind::Created, flow_id, None);
+        }
+        
+        // Event 1 fell out of the buffer
+        let subscription = feed.subscribe(2);
+        assert!(!subscription.missed);
+        assert_eq!(subscription.backlog.iter().map(|event| event.seq).collect::<Vec<_>>(), vec![3, 4]);
+        assert!(feed.subscribe(0).missed);
+        
+        feed.publish(ChangeKind::Deleted, "a", None);
+        let event = subscription.live.try_recv().unwrap();
+        assert_eq!((event.seq, event.kind, event.flow_id.as_str()), (5, ChangeKind::Deleted, "a"));
+        
+        // A position from before a restart replays the whole buffer
+        let subscription = feed.subscribe(9);
+        assert!(subscription.missed);
+        assert_eq!(subscription.backlog.len(), 3);
+    }
+    
+    #[test]
+    fn test_poll_waits_for_next_event() {
+        let feed = std::sync::Arc::new(ChangeFeed::new(10));
+        feed.publish(ChangeKind::Created, "a", None);
+        
+        let page = feed.poll(1, 10, Duration::from_millis(10));
+        assert!(page.events.is_empty());
+        assert_eq!(page.last_seq, 1);
+        
+        let publisher = feed.clone();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            publisher.publish(ChangeKind::Appended, "a", None);
+        });
+        let page = feed.poll(1, 10, Duration::from_secs(10));
+        handle.join().unwrap();
+        assert_eq!(page.events.len(), 1);
+        assert_eq!(page.last_seq, 2);
+        assert_eq!(page.events[0].kind, ChangeKind::Appended);
+    }
+    
+    #[test]
+    fn test_polls_leave_no_subscribers_behind() {
+        let feed = ChangeFeed::new(10);
+        for _ in 0..100 {
+            feed.poll(0, 10, Duration::ZERO);
+        }
+        feed.publish(ChangeKind::Created, "a", None);
+        assert_eq!(feed.poll(0, 10, Duration::ZERO).events.len(), 1);
+        assert!(feed.state.lock().unwrap().subscribers.is_empty());
+    }
+    
+    #[test]
+    fn test_lagging_subscriber_is_dropped() {
+        let feed = ChangeFeed::new(0);
+        let subscription = feed.subscribe(0);
+        for _ in 0..=SUBSCRIBER_BUFFER {
+            feed.publish(ChangeKind::Created, "a", None);
+        }
+        
+        // The buffered events still arrive, then the subscriber has to resubscribe
+        assert_eq!(subscription.live.try_iter().count(), SUBSCRIBER_BUFFER);
+        assert!(subscription.live.recv().is_err());
+        assert!(feed.state.lock().unwrap().subscribers.is_empty());
+    }
+    
+    #[test]
+    fn test_buffered_bytes_follow_the_buffer() {
+        let feed = ChangeFeed::new(2);
+        assert_eq!(feed.buffered_bytes(), 0);
+        
+        let hop = crate::models::Hop::new(0, "s".repeat(4096), Utc::now(), crate::models::TelemetryMetrics::with_basic(0.1, 100));
+        let flow = Flow::new("a".to_string(), vec![hop]).unwrap();
+        feed.publish(ChangeKind::Created, "a", Some(flow));
+        assert!(feed.buffered_bytes() > 4096);
+        
+        // Once the big event rolls out of the buffer, only the small ones count
+        feed.publish(ChangeKind::Deleted, "a", None);
+        feed.publish(ChangeKind::Deleted, "b", None);
+        assert_eq!(feed.buffered_bytes(), 2 * size_of::<ChangeEvent>() + 2);
+    }
+}
@@ -0,0 +1,436 @@
+use std::mem::size_of;
+use chrono::{DateTime, Utc};
+use indexmap::IndexMap;
+
+use crate::models::{parse_retention_policy, Flow, FlowStatus, HeapSize, Hop, IngestVersion, NetworkPath, TelemetryMetrics};
+use crate::storage::{PathId, SwitchId};
+
+/// Presence bits for the optional metric columns of a hop
+const HAS_QUEUE_UTIL: u8 = 1 << 0;
+const HAS_DELAY_NS: u8 = 1 << 1;
+const HAS_BANDWIDTH_BPS: u8 = 1 << 2;
+const HAS_DROP_COUNT: u8 = 1 << 3;
+const HAS_EGRESS_PORT: u8 = 1 << 4;
+const HAS_INGRESS_PORT: u8 = 1 << 5;
+const HAS_CUSTOM_METRICS: u8 = 1 << 6;
+
+/// XOR control byte for a float equal to the previous one
+const XOR_UNCHANGED: u8 = 0xFF;
+
+/// A flow as kept in memory: metadata as is, hops compressed
+///
+/// Path, times, status and retention are what indexes, eviction and expiry look
+/// at, so they stay decoded; hops are decoded only when a caller needs them.
+/// Switch IDs and the path hash are interned.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredFlow {
+    pub flow_id: String,
+    pub path: Box<[SwitchId]>,
+    pub path_id: PathId,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    pub status: FlowStatus,
+    pub retention_policy: Option<String>,
+    pub ingest_versions: Box<[IngestVersion]>,
+    hops: CompressedHops,
+}
+
+/// Encoded hop sequence
+#[derive(Debug, Clone, PartialEq)]
+enum CompressedHops {
+    /// Delta-of-delta timestamps, XOR floats and zigzag varint integers
+    Encoded {
+        hop_count: usize,
+        raw_bytes: usize,
+        bytes: Box<[u8]>,
+    },
+    
+    /// Hops with a timestamp outside the i64 nanosecond range, kept uncompressed
+    Raw(Vec<Hop>),
+}
+
+impl StoredFlow {
+    /// Compress a flow for storage
+    pub fn new(flow: &Flow) -> Self {
+        Self {
+            flow_id: flow.flow_id.clone(),
+            path: SwitchId::intern_all(&flow.path.switches).into_boxed_slice(),
+            path_id: PathId::intern(&flow.path),
+            start_time: flow.start_time,
+            end_time: flow.end_time,
+            status: flow.status.clone(),
+            retention_policy: flow.retention_policy.clone(),
+            ingest_versions: flow.ingest_versions.clone().into_boxed_slice(),
+            hops: encode_hops(&flow.hops),
+        }
+    }
+    
+    /// Decode the full flow
+    pub fn decode(&self) -> Flow {
+        Flow {
+            flow_id: self.flow_id.clone(),
+            path: self.path(),
+            hops: self.hops(),
+            start_time: self.start_time,
+            end_time: self.end_time,
+            status: self.status.clone(),
+            retention_policy: self.retention_policy.clone(),
+            ingest_versions: self.ingest_versions.to_vec(),
+        }
+    }
+    
+    /// Resolve the interned path
+    pub fn path(&self) -> NetworkPath {
+        NetworkPath {
+            switches: self.path.iter().map(|switch| switch.resolve().to_string()).collect(),
+            path_hash: Some(self.path_id.resolve().to_string()),
+        }
+    }
+    
+    /// Decode the hops
+    pub fn hops(&self) -> Vec<Hop> {
+        match &self.hops {
+            CompressedHops::Encoded { hop_count, bytes, .. } => decode_hops(bytes, *hop_count),
+            CompressedHops::Raw(hops) => hops.clone(),
+        }
+    }
+    
+    /// Get the number of hops
+    pub fn hop_count(&self) -> usize {
+        match &self.hops {
+            CompressedHops::Encoded { hop_count, .. } => *hop_count,
+            CompressedHops::Raw(hops) => hops.len(),
+        }
+    }
+    
+    /// Get the flow's own retention period (see [`Flow::retention`])
+    pub fn retention(&self) -> Option<chrono::Duration> {
+        self.retention_policy
+            .as_deref()
+            .and_then(|policy| parse_retention_policy(policy).ok())
+    }
+    
+    /// Bytes the hops would take on the heap uncompressed
+    pub fn raw_hop_bytes(&self) -> usize {
+        match &self.hops {
+            CompressedHops::Encoded { raw_bytes, .. } => *raw_bytes,
+            CompressedHops::Raw(hops) => hops.heap_size(),
+        }
+    }
+    
+    /// Bytes the hops take on the heap as stored
+    pub fn stored_hop_bytes(&self) -> usize {
+        match &self.hops {
+            CompressedHops::Encoded { bytes, .. } => bytes.len(),
+            CompressedHops::Raw(hops) => hops.heap_size(),
+        }
+    }
+}
+
+impl From<Flow> for StoredFlow {
+    fn from(flow: Flow) -> Self {
+        Self::new(&flow)
+    }
+}
+
+impl HeapSize for StoredFlow {
+    fn heap_size(&self) -> usize {
+        self.flow_id.heap_size()
+            + std::mem::size_of_val(&*self.path)
+            + self.status.heap_size()
+            + self.retention_policy.heap_size()
+            + std::mem::size_of_val(&*self.ingest_versions)
+            + self.stored_hop_bytes()
+    }
+}
+
+/// Encode hops, falling back to the raw hops if a timestamp can't be encoded
+fn encode_hops(hops: &[Hop]) -> CompressedHops {
+    let Some(timestamps) = hops.iter().map(|hop| hop.timestamp.timestamp_nanos_opt()).collect::<Option<Vec<_>>>() else {
+        return CompressedHops::Raw(hops.to_vec());
+    };
+    
+    let mut out = Vec::new();
+    
+    // Switch dictionary of interned IDs, in order of first appearance
+    let mut switches: IndexMap<&str, SwitchId> = IndexMap::new();
+    for hop in hops {
+        switches.entry(&hop.switch_id).or_insert_with(|| SwitchId::intern(&hop.switch_id));
+    }
+    write_varint(&mut out, switches.len() as u64);
+    for switch in switches.values() {
+        write_varint(&mut out, switch.raw() as u64);
+    }
+    
+    let mut previous = Previous::default();
+    for (hop, timestamp) in hops.iter().zip(timestamps) {
+        let metrics = &hop.metrics;
+        
+        write_signed(&mut out, hop.hop_index as i64 - previous.hop_index as i64);
+        write_varint(&mut out, switches.get_index_of(hop.switch_id.as_str()).unwrap_or_default() as u64);
+        
+        // Delta-of-delta: evenly spaced hops encode as zero
+        let delta = timestamp.wrapping_sub(previous.timestamp);
+        write_signed(&mut out, delta.wrapping_sub(previous.delta));
+        
+        let presence = [
+            (metrics.queue_util.is_some(), HAS_QUEUE_UTIL),
+            (metrics.delay_ns.is_some(), HAS_DELAY_NS),
+            (metrics.bandwidth_bps.is_some(), HAS_BANDWIDTH_BPS),
+            (metrics.drop_count.is_some(), HAS_DROP_COUNT),
+            (metrics.egress_port.is_some(), HAS_EGRESS_PORT),
+            (metrics.ingress_port.is_some(), HAS_INGRESS_PORT),
+            (metrics.custom_metrics.is_some(), HAS_CUSTOM_METRICS),
+        ]
+        .iter()
+        .filter(|(present, _)| *present)
+        .fold(0, |bits, (_, bit)| bits | bit);
+        out.push(presence);
+        
+        if let Some(util) = metrics.queue_util {
+            write_xor(&mut out, util.to_bits() ^ previous.queue_util);
+            previous.queue_util = util.to_bits();
+        }
+        if let Some(delay) = metrics.delay_ns {
+            write_signed(&mut out, delay.wrapping_sub(previous.delay_ns) as i64);
+            previous.delay_ns = delay;
+        }
+        if let Some(bandwidth) = metrics.bandwidth_bps {
+            write_signed(&mut out, bandwidth.wrapping_sub(previous.bandwidth_bps) as i64);
+            previous.bandwidth_bps = bandwidth;
+        }
+        if let Some(drops) = metrics.drop_count {
+            write_signed(&mut out, drops.wrapping_sub(previous.drop_count) as i64);
+            previous.drop_count = drops;
+        }
+        if let Some(port) = metrics.egress_port {
+            write_signed(&mut out, port as i64 - previous.egress_port as i64);
+            previous.egress_port = port;
+        }
+        if let Some(port) = metrics.ingress_port {
+            write_signed(&mut out, port as i64 - previous.ingress_port as i64);
+            previous.ingress_port = port;
+        }
+        if let Some(custom) = &metrics.custom_metrics {
+            let json = serde_json::to_vec(custom).unwrap_or_default();
+            write_varint(&mut out, json.len() as u64);
+            out.extend_from_slice(&json);
+        }
+        
+        previous.hop_index = hop.hop_index;
+        previous.timestamp = timestamp;
+        previous.delta = delta;
+    }
+    
+    CompressedHops::Encoded {
+        hop_count: hops.len(),
+        raw_bytes: std::mem::size_of_val(hops) + hops.iter().map(HeapSize::heap_size).sum::<usize>(),
+        bytes: out.into_boxed_slice(),
+    }
+}
+
+/// Decode hops written by `encode_hops`
+fn decode_hops(bytes: &[u8], hop_count: usize) -> Vec<Hop> {
+    let mut reader = Reader { bytes, pos: 0 };
+    
+    let switch_count = reader.varint() as usize;
+    let switches: Vec<String> = (0..switch_count)
+        .map(|_| SwitchId::from_raw(reader.varint() as u32).resolve().to_string())
+        .collect();
+    
+    let mut previous = Previous::default();
+    let mut hops = Vec::with_capacity(hop_count);
+    for _ in 0..hop_count {
+        let hop_index = (previous.hop_index as i64 + reader.signed()) as u32;
+        let switch_id = switches[reader.varint() as usize].clone();
+        
+        let delta = previous.delta.wrapping_add(reader.signed());
+        let timestamp = previous.timestamp.wrapping_add(delta);
+        
+        let presence = reader.take(1)[0];
+        let mut metrics = TelemetryMetrics::new();
+        if presence & HAS_QUEUE_UTIL != 0 {
+            previous.queue_util ^= reader.xor();
+            metrics.queue_util = Some(f64::from_bits(previous.queue_util));
+        }
+        if presence & HAS_DELAY_NS != 0 {
+            previous.delay_ns = previous.delay_ns.wrapping_add(reader.signed() as u64);
+            metrics.delay_ns = Some(previous.delay_ns);
+        }
+        if presence & HAS_BANDWIDTH_BPS != 0 {
+            previous.bandwidth_bps = previous.bandwidth_bps.wrapping_add(reader.signed() as u64);
+            metrics.bandwidth_bps = Some(previous.bandwidth_bps);
+        }
+        if presence & HAS_DROP_COUNT != 0 {
+            previous.drop_count = previous.drop_count.wrapping_add(reader.signed() as u64);
+            metrics.drop_count = Some(previous.drop_count);
+        }
+        if presence & HAS_EGRESS_PORT != 0 {
+            previous.egress_port = (previous.egress_port as i64 + reader.signed()) as u32;
+            metrics.egress_port = Some(previous.egress_port);
+        }
+        if presence & HAS_INGRESS_PORT != 0 {
+            previous.ingress_port = (previous.ingress_port as i64 + reader.signed()) as u32;
+            metrics.ingress_port = Some(previous.ingress_port);
+        }
+        if presence & HAS_CUSTOM_METRICS != 0 {
+            let len = reader.varint() as usize;
+            metrics.custom_metrics = serde_json::from_slice(reader.take(len)).ok();
+        }
+        
+        hops.push(Hop::new(hop_index, switch_id, DateTime::from_timestamp_nanos(timestamp), metrics));
+        
+        previous.hop_index = hop_index;
+        previous.timestamp = timestamp;
+        previous.delta = delta;
+    }
+    
+    hops
+}
+
+/// Previous value of each column, shared by the encoder and decoder
+#[derive(Debug, Default)]
+struct Previous {
+    hop_index: u32,
+    timestamp: i64,
+    delta: i64,
+    queue_util: u64,
+    delay_ns: u64,
+    bandwidth_bps: u64,
+    drop_count: u64,
+    egress_port: u32,
+    ingress_port: u32,
+}
+
+/// Write an unsigned LEB128 varint
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Write a zigzag-encoded signed varint, so small negative deltas stay small
+fn write_signed(out: &mut Vec<u8>, value: i64) {
+    write_varint(out, ((value << 1) ^ (value >> 63)) as u64);
+}
+
+/// Write the XOR of two float bit patterns, keeping only the bytes between
+/// the leading and trailing zero bytes
+fn write_xor(out: &mut Vec<u8>, xor: u64) {
+    if xor == 0 {
+        out.push(XOR_UNCHANGED);
+        return;
+    }
+    
+    let leading = (xor.leading_zeros() / 8) as usize;
+    let trailing = (xor.trailing_zeros() / 8) as usize;
+    out.push(((leading << 4) | trailing) as u8);
+    out.extend_from_slice(&xor.to_be_bytes()[leading..size_of::<u64>() - trailing]);
+}
+
+/// Cursor over encoded hops
+///
+/// The bytes are produced in-process by `encode_hops`, so malformed input is a bug.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> &'a [u8] {
+        let slice = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        slice
+    }
+    
+    fn varint(&mut self) -> u64 {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.take(1)[0];
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return value;
+            }
+            shift += 7;
+        }
+    }
+    
+    fn signed(&mut self) -> i64 {
+        let value = self.varint();
+        ((value >> 1) as i64) ^ -((value & 1) as i64)
+    }
+    
+    fn xor(&mut self) -> u64 {
+        let control = self.take(1)[0];
+        if control == XOR_UNCHANGED {
+            return 0;
+        }
+        
+        let (leading, trailing) = ((control >> 4) as usize, (control & 0x0F) as usize);
+        let mut be_bytes = [0u8; size_of::<u64>()];
+        be_bytes[leading..size_of::<u64>() - trailing].copy_from_slice(self.take(size_of::<u64>() - leading - trailing));
+        u64::from_be_bytes(be_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn create_test_flow(hop_count: usize) -> Flow {
+        let start = Utc::now();
+        let hops = (0..hop_count)
+            .map(|i| {
+                let mut metrics = TelemetryMetrics::with_basic(0.5 + (i % 3) as f64 * 0.01, 1_000 + (i % 4) as u64);
+                metrics.bandwidth_bps = Some(10_000_000_000);
+                metrics.drop_count = Some(0);
+                metrics.egress_port = Some(i as u32 % 2);
+                Hop::new(
+                    i as u32,
+                    format!("s{}", i % 5),
+                    start + chrono::Duration::nanoseconds(i as i64 * 850),
+                    metrics,
+                )
+            })
+            .collect();
+        Flow::new("flow1".to_string(), hops).unwrap()
+    }
+    
+    #[test]
+    fn test_roundtrip() {
+        let mut flow = create_test_flow(20);
+        flow.hops[3].metrics.queue_util = None;
+        flow.hops[4].metrics.ingress_port = Some(7);
+        flow.hops[5].metrics.delay_ns = Some(0);
+        flow.hops[6].metrics.add_custom_metric("ecn".to_string(), serde_json::json!({"ce": true}));
+        flow.hops[7].timestamp -= chrono::Duration::seconds(1);
+        flow.retention_policy = Some("2h".to_string());
+        
+        let stored = StoredFlow::new(&flow);
+        assert_eq!(stored.hop_count(), 20);
+        assert_eq!(stored.retention(), Some(chrono::Duration::hours(2)));
+        assert_eq!(stored.decode(), flow);
+    }
+    
+    #[test]
+    fn test_repetitive_telemetry_compresses() {
+        let stored = StoredFlow::from(create_test_flow(100));
+        assert!(stored.raw_hop_bytes() > 10 * stored.stored_hop_bytes());
+        
+        let empty = StoredFlow::from(Flow::new_partial("empty".to_string(), Vec::new()));
+        assert!(empty.hops().is_empty());
+    }
+    
+    #[test]
+    fn test_out_of_range_timestamps_are_kept_raw() {
+        let mut flow = create_test_flow(2);
+        flow.hops[1].timestamp = DateTime::<Utc>::MAX_UTC;
+        
+        let stored = StoredFlow::new(&flow);
+        assert_eq!(stored.raw_hop_bytes(), stored.stored_hop_bytes());
+        assert_eq!(stored.decode(), flow);
+    }
+}
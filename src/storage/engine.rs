@@ -1,9 +1,23 @@
-use std::collections::{BTreeSet, HashMap};
-use std::sync::{Arc, RwLock};
-use chrono::Utc;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet};
+use std::fs;
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::Serialize;
 
-use crate::models::Flow;
-use crate::storage::{PathIndex, TimeIndex, QueryBuilder, QueryResult, PathCondition, TimeCondition};
+use crate::models::{parse_retention_policy, Flow, Hop, SchemaError};
+use crate::storage::{shard_index, FlowEntry, FlowHandle, Partition, Shard, TimeIndex, DEFAULT_MAX_SPAN_BUCKETS, QueryBuilder, QueryResult, RankedFlow};
+use crate::storage::{FlowTelemetrySummary, HopPosition, SwitchHop, SwitchHopStats};
+use crate::storage::{BackendKind, FileBackend, MemoryBackend, StorageBackend};
+use crate::storage::{WriteAheadLog, WalEntry, WalFollow, WalRecord, WalSyncPolicy};
+use crate::storage::{AccessTracker, EvictionPolicy, StoredFlow};
+use crate::storage::{ChangeFeed, ChangeKind, ChangePage, ChangeSubscription};
+use crate::storage::snapshot::{self, SnapshotHeader, SnapshotInfo, SNAPSHOT_FORMAT_VERSION};
+use crate::storage::segment::{self, SegmentCatalog, SegmentInfo};
 
 /// IntDB storage engine configuration
 #[derive(Debug, Clone)]
@@ -11,23 +25,282 @@ pub struct EngineConfig {
     /// Time bucket size for time index in seconds
     pub time_bucket_size: i64,
     
+    /// Most time buckets a flow is indexed under; flows spanning more are listed as
+    /// long-lived and returned by every time lookup, so one bad end time stays cheap
+    pub time_index_max_span: usize,
+    
+    /// Width of the time window each storage partition covers, in seconds
+    pub partition_secs: i64,
+    
+    /// Number of independently locked shards flows are spread over by ID
+    ///
+    /// With a WAL, writers still take turns appending to the log itself.
+    pub shard_count: usize,
+    
     /// Maximum number of flows to keep in memory
     pub max_flows: Option<usize>,
     
+    /// Memory budget for stored flows, their index entries and the change feed's buffered events
+    pub max_memory_bytes: Option<usize>,
+    
+    /// What to do with a new flow once `max_flows` or `max_memory_bytes` is reached
+    pub eviction_policy: EvictionPolicy,
+    
     /// Automatically clean up old flows after this duration (in hours)
+    ///
+    /// Flows with their own `retention_policy` use that instead.
     pub auto_cleanup_hours: Option<i64>,
+    
+    /// How often the background retention sweeper runs
+    pub retention_sweep_interval: Duration,
+    
+    /// Write-ahead log file (None keeps the engine purely in memory)
+    pub wal_path: Option<PathBuf>,
+    
+    /// When WAL records are fsynced
+    pub wal_sync_policy: WalSyncPolicy,
+    
+    /// Directory used by the snapshot admin endpoints
+    pub snapshot_dir: Option<PathBuf>,
+    
+    /// Directory holding cold flow segments
+    pub segment_dir: Option<PathBuf>,
+    
+    /// Flows whose last telemetry is older than this move to on-disk segments (None keeps everything in memory)
+    pub cold_flow_age: Option<chrono::Duration>,
+    
+    /// How often the background freezer moves cold flows into segments
+    pub segment_interval: Duration,
+    
+    /// Where flow records are kept (the file backend is durable on its own, so it can't be combined with a WAL)
+    pub backend: BackendKind,
+    
+    /// Serve queries only, rejecting every write; the WAL and data files are read but never modified
+    pub read_only: bool,
+    
+    /// Snapshot to serve instead of the data directory (implies `read_only`)
+    pub open_snapshot: Option<PathBuf>,
+    
+    /// Number of recent change events kept for pollers resuming after a sequence number
+    ///
+    /// Buffered events count towards `max_memory_bytes`; 0 buffers nothing, so polls
+    /// only ever report missed events.
+    pub change_buffer: usize,
+    
+    /// Number of decoded segments kept for cold flow lookups (0 decodes the segment on every lookup)
+    ///
+    /// Cached flows don't count towards `max_memory_bytes`.
+    pub segment_cache_size: usize,
 }
 
 impl Default for EngineConfig {
     fn default() -> Self {
         Self {
             time_bucket_size: 60, // 1 minute buckets
+            time_index_max_span: DEFAULT_MAX_SPAN_BUCKETS,
+            partition_secs: 3600, // 1 hour partitions
+            shard_count: 16,
             max_flows: Some(1_000_000), // 1M flows
+            max_memory_bytes: None,
+            eviction_policy: EvictionPolicy::Reject,
             auto_cleanup_hours: Some(24), // Keep 24 hours
+            retention_sweep_interval: Duration::from_secs(60),
+            wal_path: None,
+            wal_sync_policy: WalSyncPolicy::EveryWrite,
+            snapshot_dir: None,
+            segment_dir: None,
+            cold_flow_age: None,
+            segment_interval: Duration::from_secs(300),
+            backend: BackendKind::Memory,
+            read_only: false,
+            open_snapshot: None,
+            change_buffer: 10_000,
+            segment_cache_size: 4,
+        }
+    }
+}
+
+impl EngineConfig {
+    /// Build a configuration from `INTDB_*` environment variables on top of the defaults
+    ///
+    /// - `INTDB_DATA_DIR`: directory holding `intdb.wal`, `snapshots/` and `segments/`; enables the write-ahead log
+    /// - `INTDB_WAL_SYNC`: `always`, `batch:<records>` or `periodic:<millis>`
+    /// - `INTDB_SNAPSHOT_DIR`: overrides the snapshot directory
+    /// - `INTDB_MAX_MEMORY_BYTES`: memory budget in bytes
+    /// - `INTDB_EVICTION_POLICY`: `reject`, `oldest` or `lru`
+    /// - `INTDB_RETENTION_HOURS`: default retention in hours, or `off` to keep flows forever
+    /// - `INTDB_RETENTION_SWEEP_SECS`: retention sweep interval in seconds
+    /// - `INTDB_COLD_FLOW_AGE`: age (e.g. `6h`) after which flows move to on-disk segments
+    /// - `INTDB_SEGMENT_INTERVAL_SECS`: segment freezer interval in seconds
+    /// - `INTDB_PARTITION_SECS`: storage partition width in seconds
+    /// - `INTDB_SHARDS`: number of storage shards
+    /// - `INTDB_BACKEND`: `memory`, `file:<dir>`, or `file` for `flows/` under `INTDB_DATA_DIR`; `file` disables the WAL
+    /// - `INTDB_READ_ONLY`: `true` to serve the data directory without accepting writes
+    /// - `INTDB_OPEN_SNAPSHOT`: snapshot file to serve read-only instead of the data directory
+    /// - `INTDB_CHANGE_BUFFER`: number of recent change events kept for `/changes` pollers
+    /// - `INTDB_SEGMENT_CACHE`: number of decoded segments kept for cold flow lookups
+    pub fn from_env() -> Result<Self, String> {
+        let mut config = Self::default();
+        
+        let data_dir = std::env::var("INTDB_DATA_DIR").ok().map(PathBuf::from);
+        if let Some(data_dir) = &data_dir {
+            config.wal_path = Some(data_dir.join("intdb.wal"));
+            config.snapshot_dir = Some(data_dir.join("snapshots"));
+            config.segment_dir = Some(data_dir.join("segments"));
+        }
+        
+        if let Ok(backend) = std::env::var("INTDB_BACKEND") {
+            config.backend = match (backend.as_str(), &data_dir) {
+                ("file", Some(data_dir)) => BackendKind::File(data_dir.join("flows")),
+                _ => backend.parse()?,
+            };
+            if config.backend != BackendKind::Memory {
+                config.wal_path = None;
+            }
+        }
+        
+        if let Ok(snapshot_dir) = std::env::var("INTDB_SNAPSHOT_DIR") {
+            config.snapshot_dir = Some(PathBuf::from(snapshot_dir));
+        }
+        
+        if let Ok(policy) = std::env::var("INTDB_WAL_SYNC") {
+            config.wal_sync_policy = policy.parse()?;
+        }
+        
+        if let Ok(bytes) = std::env::var("INTDB_MAX_MEMORY_BYTES") {
+            config.max_memory_bytes = Some(
+                bytes
+                    .parse()
+                    .map_err(|_| format!("Invalid memory budget: {}", bytes))?,
+            );
+        }
+        
+        if let Ok(policy) = std::env::var("INTDB_EVICTION_POLICY") {
+            config.eviction_policy = policy.parse()?;
+        }
+        
+        if let Ok(hours) = std::env::var("INTDB_RETENTION_HOURS") {
+            config.auto_cleanup_hours = match hours.as_str() {
+                "off" => None,
+                hours => Some(
+                    hours
+                        .parse()
+                        .ok()
+                        .filter(|h| *h > 0)
+                        .ok_or_else(|| format!("Invalid retention hours: {}", hours))?,
+                ),
+            };
+        }
+        
+        if let Ok(secs) = std::env::var("INTDB_RETENTION_SWEEP_SECS") {
+            config.retention_sweep_interval = secs
+                .parse()
+                .ok()
+                .filter(|s| *s > 0)
+                .map(Duration::from_secs)
+                .ok_or_else(|| format!("Invalid retention sweep interval: {}", secs))?;
+        }
+        
+        if let Ok(age) = std::env::var("INTDB_COLD_FLOW_AGE") {
+            config.cold_flow_age = Some(parse_retention_policy(&age).map_err(|e| e.to_string())?);
+        }
+        
+        if let Ok(secs) = std::env::var("INTDB_SEGMENT_INTERVAL_SECS") {
+            config.segment_interval = secs
+                .parse()
+                .ok()
+                .filter(|s| *s > 0)
+                .map(Duration::from_secs)
+                .ok_or_else(|| format!("Invalid segment interval: {}", secs))?;
+        }
+        
+        if let Ok(secs) = std::env::var("INTDB_PARTITION_SECS") {
+            config.partition_secs = secs
+                .parse()
+                .ok()
+                .filter(|s| *s > 0)
+                .ok_or_else(|| format!("Invalid partition width: {}", secs))?;
+        }
+        
+        if let Ok(read_only) = std::env::var("INTDB_READ_ONLY") {
+            config.read_only = match read_only.as_str() {
+                "true" | "1" => true,
+                "false" | "0" => false,
+                _ => return Err(format!("Invalid read-only flag: {}", read_only)),
+            };
+        }
+        
+        if let Ok(snapshot) = std::env::var("INTDB_OPEN_SNAPSHOT") {
+            config.open_snapshot = Some(PathBuf::from(snapshot));
+            config.read_only = true;
+        }
+        
+        if let Ok(shards) = std::env::var("INTDB_SHARDS") {
+            config.shard_count = shards
+                .parse()
+                .ok()
+                .filter(|n| *n > 0)
+                .ok_or_else(|| format!("Invalid shard count: {}", shards))?;
+        }
+        
+        if let Ok(events) = std::env::var("INTDB_CHANGE_BUFFER") {
+            config.change_buffer = events
+                .parse()
+                .map_err(|_| format!("Invalid change buffer size: {}", events))?;
+        }
+        
+        if let Ok(segments) = std::env::var("INTDB_SEGMENT_CACHE") {
+            config.segment_cache_size = segments
+                .parse()
+                .map_err(|_| format!("Invalid segment cache size: {}", segments))?;
         }
+        
+        Ok(config)
+    }
+}
+
+/// A switch hop ordered by `SwitchHop::cmp_by_time`, for keeping the oldest in a heap
+struct OldestHop(SwitchHop);
+
+impl PartialEq for OldestHop {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for OldestHop {}
+
+impl PartialOrd for OldestHop {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OldestHop {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.cmp_by_time(&other.0)
     }
 }
 
+/// Retention sweeper statistics
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SweepStats {
+    /// Number of completed sweeps
+    pub sweeps: u64,
+    
+    /// Flows expired since startup
+    pub flows_expired: u64,
+    
+    /// When the last sweep finished
+    pub last_sweep_at: Option<DateTime<Utc>>,
+    
+    /// Flows expired by the last sweep
+    pub last_sweep_expired: usize,
+    
+    /// Duration of the last sweep in milliseconds
+    pub last_sweep_duration_ms: u64,
+}
+
 /// Storage engine error types
 #[derive(Debug, thiserror::Error)]
 pub enum StorageError {
@@ -40,24 +313,86 @@ pub enum StorageError {
     #[error("Storage full: reached maximum capacity")]
     StorageFull,
     
+    #[error("Memory budget exhausted: {needed} more bytes needed with {used} of {budget} in use")]
+    MemoryLimit { needed: usize, used: usize, budget: usize },
+    
     #[error("Invalid query: {0}")]
     InvalidQuery(String),
     
     #[error("Engine is read-only")]
     ReadOnly,
+    
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    
+    #[error("Incompatible snapshot format version {0} (expected {SNAPSHOT_FORMAT_VERSION})")]
+    IncompatibleSnapshot(u32),
+    
+    #[error("Corrupt snapshot: {0}")]
+    CorruptSnapshot(String),
+    
+    #[error("Corrupt segment: {0}")]
+    CorruptSegment(String),
+    
+    #[error("Storage backend error: {0}")]
+    Backend(String),
+    
+    #[error("Replication error: {0}")]
+    Replication(String),
+    
+    #[error("Unreadable record: {0}")]
+    Schema(#[from] SchemaError),
 }
 
 /// Thread-safe IntDB storage engine
 #[derive(Debug)]
 pub struct StorageEngine {
-    /// Main flow storage
-    flows: Arc<RwLock<HashMap<String, Flow>>>,
+    /// Flow indexes sharded by flow ID, each shard partitioned by start time
+    shards: Vec<Shard>,
+    
+    /// Flow records, fetched through the indexes
+    backend: Box<dyn StorageBackend>,
+    
+    /// Number of flows held in memory
+    hot_flows: AtomicUsize,
+    
+    /// Write-ahead log, locked only to append
+    ///
+    /// Writers hold their flow's shard writer lock from the append until the record
+    /// applies, so each flow's records are logged in apply order. Locks are taken
+    /// shard writer locks first (in shard order), then the WAL, then the segment catalog.
+    wal: Option<Mutex<WriteAheadLog>>,
+    
+    /// Shortest retention in use, in seconds (only ever shrinks; `i64::MAX` when nothing expires)
+    shortest_retention_secs: AtomicI64,
+    
+    /// Retention sweeper statistics
+    sweep_stats: Mutex<SweepStats>,
     
-    /// Path-based index
-    path_index: Arc<RwLock<PathIndex>>,
+    /// Flow access order, kept only for least-recently-queried eviction
+    access_tracker: Option<Mutex<AccessTracker>>,
     
-    /// Time-based index
-    time_index: Arc<RwLock<TimeIndex>>,
+    /// Number of flows evicted to make room for new ones
+    evictions: AtomicU64,
+    
+    /// Bytes accounted to stored flows (see `flow_footprint`)
+    memory_bytes: AtomicUsize,
+    
+    /// Heap bytes of stored hops before and after compression
+    raw_hop_bytes: AtomicUsize,
+    stored_hop_bytes: AtomicUsize,
+    
+    /// Cold flows frozen into on-disk segments
+    segments: RwLock<SegmentCatalog>,
+    
+    /// LSN of the last primary record applied, when the engine is a replication follower
+    replicated_lsn: AtomicU64,
+    
+    /// Change-data-capture feed of flow creations, appends and removals
+    changes: ChangeFeed,
+    
+    /// Set while `open` replays recovered state, which is not reported as changes
+    replaying: bool,
     
     /// Engine configuration
     config: EngineConfig,
@@ -72,337 +407,2943 @@ impl StorageEngine {
         Self::with_config(EngineConfig::default())
     }
     
-    /// Create a new storage engine with custom configuration
+    /// Create a new in-memory storage engine with custom configuration
+    ///
+    /// `wal_path` and `backend` are ignored here; use [`StorageEngine::open`] for a durable engine.
     pub fn with_config(config: EngineConfig) -> Self {
-        Self {
-            flows: Arc::new(RwLock::new(HashMap::new())),
-            path_index: Arc::new(RwLock::new(PathIndex::new())),
-            time_index: Arc::new(RwLock::new(TimeIndex::new(config.time_bucket_size))),
+        let engine = Self {
+            shards: (0..config.shard_count.max(1))
+                .map(|_| Shard::new(config.partition_secs, config.time_bucket_size, config.time_index_max_span))
+                .collect(),
+            backend: Box::new(MemoryBackend::new(config.shard_count)),
+            hot_flows: AtomicUsize::new(0),
+            wal: None,
+            shortest_retention_secs: AtomicI64::new(i64::MAX),
+            sweep_stats: Mutex::new(SweepStats::default()),
+            access_tracker: (config.eviction_policy == EvictionPolicy::EvictLeastRecentlyQueried)
+                .then(|| Mutex::new(AccessTracker::new())),
+            evictions: AtomicU64::new(0),
+            memory_bytes: AtomicUsize::new(0),
+            raw_hop_bytes: AtomicUsize::new(0),
+            stored_hop_bytes: AtomicUsize::new(0),
+            segments: RwLock::new(SegmentCatalog::with_cache_capacity(config.segment_cache_size)),
+            replicated_lsn: AtomicU64::new(0),
+            changes: ChangeFeed::new(config.change_buffer),
+            replaying: false,
+            read_only: config.read_only,
             config,
-            read_only: false,
-        }
-    }
-
-    /// Insert a new flow into the storage
-    pub fn insert_flow(&self, flow: Flow) -> Result<(), StorageError> {
-        if self.read_only {
-            return Err(StorageError::ReadOnly);
-        }
-        
-        let flow_id = flow.flow_id.clone();
-        
-        // Check if flow already exists
-        let existing_flow = {
-            let flows = self.flows.read().unwrap();
-            flows.get(&flow_id).cloned()
         };
         
-        match existing_flow {
-            Some(mut existing) => {
-                // Flow exists, append new telemetry data
-                self.append_telemetry(&mut existing, &flow)?;
-                
-                // Update the existing flow in storage
-                {
-                    let mut flows = self.flows.write().unwrap();
-                    flows.insert(flow_id.clone(), existing.clone());
-                }
-                
-                // Update indexes with the updated flow
-                {
-                    let mut path_index = self.path_index.write().unwrap();
-                    path_index.update_flow(&existing);
-                }
-                
-                {
-                    let mut time_index = self.time_index.write().unwrap();
-                    time_index.update_flow(&existing);
-                }
-            }
-            None => {
-                // New flow, check capacity
-                if let Some(max_flows) = self.config.max_flows {
-                    let flows = self.flows.read().unwrap();
-                    if flows.len() >= max_flows {
-                        return Err(StorageError::StorageFull);
-                    }
-                }
-                
-                // Insert into main storage
-                {
-                    let mut flows = self.flows.write().unwrap();
-                    flows.insert(flow_id.clone(), flow.clone());
-                }
-                
-                // Update indexes
-                {
-                    let mut path_index = self.path_index.write().unwrap();
-                    path_index.add_flow(&flow);
-                }
-                
-                {
-                    let mut time_index = self.time_index.write().unwrap();
-                    time_index.add_flow(&flow);
-                }
-            }
+        if let Some(retention) = engine.default_retention() {
+            engine.shortest_retention_secs.store(retention.num_seconds(), Ordering::Relaxed);
         }
         
-        Ok(())
+        engine
     }
     
-    /// Append telemetry data from new flow to existing flow
-    fn append_telemetry(&self, existing: &mut Flow, new_flow: &Flow) -> Result<(), StorageError> {
-        // Calculate the new hop_index offset to avoid conflicts
-        let max_hop_index = existing.hops.iter()
-            .map(|h| h.hop_index)
-            .max()
-            .unwrap_or(0);
+    /// Open a storage engine, loading cold segments and replaying the write-ahead log if configured
+    ///
+    /// A persistent backend's records are scanned to rebuild the indexes instead.
+    /// With `open_snapshot` set, only the snapshot is loaded and the engine is read-only.
+    pub fn open(config: EngineConfig) -> Result<Self, StorageError> {
+        let mut engine = Self::with_config(config);
+        engine.replaying = true;
+        
+        if let Some(path) = engine.config.open_snapshot.clone() {
+            engine.read_only = true;
+            let info = engine.load_snapshot(&path)?;
+            info!("Serving snapshot {} read-only ({} flows)", info.path.display(), info.header.flow_count);
+            engine.replaying = false;
+            return Ok(engine);
+        }
+        
+        if let BackendKind::File(dir) = &engine.config.backend {
+            // Replaying logged appends over records that already hold them would duplicate hops
+            if engine.config.wal_path.is_some() {
+                return Err(StorageError::Backend("The file backend can't be combined with a WAL".to_string()));
+            }
+            engine.backend = Box::new(FileBackend::open(dir)?);
+        }
         
-        // Append all new hops with adjusted hop_index
-        for (i, new_hop) in new_flow.hops.iter().enumerate() {
-            let mut appended_hop = new_hop.clone();
-            appended_hop.hop_index = max_hop_index + 1 + i as u32;
-            existing.hops.push(appended_hop);
+        // The catalog starts at its final state so replay can tell which records a segment already covers
+        let mut loaded = Vec::new();
+        if let Some(segment_dir) = engine.config.segment_dir.clone() {
+            let catalog = engine.segments.get_mut().unwrap();
+            for (info, flow_ids) in segment::list_segments(&segment_dir)? {
+                loaded.push(info.clone());
+                catalog.add(info, flow_ids);
+            }
         }
         
-        // Update flow timestamps
-        if new_flow.start_time < existing.start_time {
-            existing.start_time = new_flow.start_time;
+        if engine.backend.is_persistent() {
+            engine.load_backend()?;
         }
-        if new_flow.end_time > existing.end_time {
-            existing.end_time = new_flow.end_time;
+        
+        if let Some(wal_path) = engine.config.wal_path.clone() {
+            // A read-only engine replays the log without opening it for writing
+            let (wal, entries) = if engine.read_only {
+                (None, WriteAheadLog::read(&wal_path)?)
+            } else {
+                let (wal, entries) = WriteAheadLog::open(&wal_path, engine.config.wal_sync_policy)?;
+                (Some(wal), entries)
+            };
+            
+            let replayed = entries.len();
+            for entry in entries {
+                engine.apply_record(entry.lsn, entry.record)?;
+            }
+            
+            info!("Replayed {} WAL records from {} ({} flows)", replayed, wal_path.display(), engine.flow_count());
+            engine.wal = wal.map(Mutex::new);
         }
         
-        // Update path if necessary (add any new switches)
-        for switch in &new_flow.path.switches {
-            if !existing.path.switches.contains(switch) {
-                existing.path.switches.push(switch.clone());
+        // Finish deleting segments whose drop was logged before a crash
+        if !engine.read_only {
+            let catalog = engine.segments.get_mut().unwrap();
+            for info in loaded.iter().filter(|info| catalog.segment(info.header.segment_id).is_none()) {
+                remove_segment_file(info);
             }
         }
         
-        // Keep hops sorted by hop_index to maintain chronological order
-        existing.hops.sort_by_key(|h| h.hop_index);
+        engine.replaying = false;
+        Ok(engine)
+    }
+    
+    /// Rebuild partitions and indexes from the records of a persistent backend
+    ///
+    /// A flow found both there and in a segment was thawed after freezing, so the record wins.
+    fn load_backend(&self) -> Result<(), StorageError> {
+        self.backend.scan(&mut |stored| {
+            let flow = stored.decode();
+            self.segments.write().unwrap().forget(&flow.flow_id);
+            self.note_retention(&flow);
+            self.record_access([flow.flow_id.as_str()]);
+            self.account(Some(&stored), None);
+            self.insert_into_partition(&flow, None);
+            self.hot_flows.fetch_add(1, Ordering::Relaxed);
+        })?;
         
+        info!("Loaded {} flows from the {} backend", self.hot_flows.load(Ordering::Relaxed), self.backend.name());
         Ok(())
     }
     
-    /// Get a flow by ID
-    pub fn get_flow(&self, flow_id: &str) -> Option<Flow> {
-        let flows = self.flows.read().unwrap();
-        flows.get(flow_id).cloned()
+    /// Get the engine configuration
+    pub fn config(&self) -> &EngineConfig {
+        &self.config
     }
-
-    /// Execute a query
-    pub fn query(&self, query: QueryBuilder) -> Result<QueryResult, StorageError> {
-        // Get candidate flow IDs from indexes
-        let candidate_ids = self.get_candidate_flows(&query)?;
-        
-        // Apply all conditions
-        let flows_guard = self.flows.read().unwrap();
-        let mut matching_flows: Vec<_> = candidate_ids
-            .into_iter()
-            .filter_map(|flow_id| {
-                flows_guard.get(&flow_id).map(|flow| (flow_id, flow))
-            })
-            .filter(|(_, flow)| self.matches_all_conditions(flow, &query))
-            .collect();
-        
-        // Sort by start time (most recent first)
-        matching_flows.sort_by(|a, b| b.1.start_time.cmp(&a.1.start_time));
-        
-        let total_count = matching_flows.len();
-        
-        // Apply pagination
-        let (limit, skip) = query.pagination();
-        let skip = skip.unwrap_or(0);
-        
-        let flow_ids: Vec<String> = matching_flows
-            .into_iter()
-            .skip(skip)
-            .take(limit.unwrap_or(usize::MAX))
-            .map(|(flow_id, _)| flow_id)
-            .collect();
-        
-        Ok(QueryResult::new(flow_ids, total_count, limit))
+    
+    /// Get the name of the storage backend holding flow records
+    pub fn backend_name(&self) -> &'static str {
+        self.backend.name()
     }
-
-    /// Get candidate flow IDs from indexes
-    fn get_candidate_flows(&self, query: &QueryBuilder) -> Result<BTreeSet<String>, StorageError> {
-        let (path_conditions, time_conditions, _) = query.conditions();
-        
-        let mut candidates: Option<BTreeSet<String>> = None;
-        
-        // Apply path-based index optimizations
-        for condition in path_conditions {
-            let path_candidates = self.get_path_candidates(condition)?;
-            
-            candidates = match candidates {
-                None => Some(path_candidates),
-                Some(existing) => Some(existing.intersection(&path_candidates).cloned().collect()),
-            };
-            
-            // Early exit if no candidates
-            if candidates.as_ref().map_or(false, |c| c.is_empty()) {
-                return Ok(BTreeSet::new());
-            }
+    
+    /// Check whether the engine rejects writes
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+    
+    /// Force buffered WAL records to stable storage
+    pub fn sync_wal(&self) -> Result<(), StorageError> {
+        if let Some(wal) = &self.wal {
+            wal.lock().unwrap().sync()?;
+        }
+        Ok(())
+    }
+    
+    /// Rewrite the WAL as just the records that rebuild the current state from the segment files
+    ///
+    /// Everything frozen, deleted or superseded drops out of the log, so replay no
+    /// longer grows with history. Writers wait meanwhile, and followers reconnect
+    /// and reset afterwards.
+    pub fn compact_wal(&self) -> Result<(), StorageError> {
+        if self.read_only {
+            return Err(StorageError::ReadOnly);
         }
+        let Some(wal) = &self.wal else {
+            return Ok(());
+        };
         
-        // Apply time-based index optimizations
-        for condition in time_conditions {
-            let time_candidates = self.get_time_candidates(condition)?;
-            
-            candidates = match candidates {
-                None => Some(time_candidates),
-                Some(existing) => Some(existing.intersection(&time_candidates).cloned().collect()),
-            };
-            
-            // Early exit if no candidates
-            if candidates.as_ref().map_or(false, |c| c.is_empty()) {
-                return Ok(BTreeSet::new());
+        let _writers = self.lock_all_writes();
+        let mut wal = wal.lock().unwrap();
+        let segments = self.segments.read().unwrap();
+        
+        // Open catalogs every segment file's flows, so undo the drops, deletes and
+        // thaws since then before the hot flows are inserted again
+        let mut records = Vec::new();
+        let mut on_disk = BTreeSet::new();
+        if let Some(segment_dir) = &self.config.segment_dir {
+            for (info, flow_ids) in segment::list_segments(segment_dir)? {
+                let segment_id = info.header.segment_id;
+                if segments.segment(segment_id).is_some() {
+                    on_disk.extend(flow_ids);
+                } else {
+                    records.push(WalRecord::DropSegment { segment_id });
+                }
             }
         }
+        records.extend(
+            on_disk
+                .into_iter()
+                .filter(|flow_id| segments.lookup(flow_id).is_none())
+                .map(|flow_id| WalRecord::Delete { flow_id }),
+        );
+        self.backend.scan(&mut |stored| records.push(WalRecord::Insert { flow: stored.decode() }))?;
         
-        // If no index-based conditions, return all flows
-        Ok(candidates.unwrap_or_else(|| {
-            let flows = self.flows.read().unwrap();
-            flows.keys().cloned().collect()
-        }))
+        let before = wal.last_lsn();
+        wal.rewrite(records)?;
+        info!("Compacted the WAL up to LSN {} into {} records", before, wal.last_lsn() - before);
+        Ok(())
     }
-
-    /// Get candidate flows from path index
-    fn get_path_candidates(&self, condition: &PathCondition) -> Result<BTreeSet<String>, StorageError> {
-        let path_index = self.path_index.read().unwrap();
-        
-        Ok(match condition {
-            PathCondition::ExactPath(path) => path_index.find_exact_path(path),
-            PathCondition::ThroughSwitch(switch_id) => path_index.find_flows_through_switch(switch_id),
-            PathCondition::ContainsPath(subpath) => path_index.find_flows_containing_path(subpath),
-            PathCondition::StartsWith(prefix) => path_index.find_flows_with_prefix(prefix),
-            // For conditions that can't be optimized by index, return all flows
-            _ => {
-                let flows = self.flows.read().unwrap();
-                flows.keys().cloned().collect()
+    
+    /// Compact the WAL once it has grown enough since it was last rewritten
+    ///
+    /// The caller's work already succeeded, so a failure is only logged.
+    fn compact_wal_if_due(&self) {
+        let due = self.wal.as_ref().is_some_and(|wal| wal.lock().unwrap().compaction_due());
+        if due {
+            if let Err(e) = self.compact_wal() {
+                warn!("Failed to compact the WAL: {}", e);
             }
-        })
+        }
     }
     
-    /// Get candidate flows from time index
-    fn get_time_candidates(&self, condition: &TimeCondition) -> Result<BTreeSet<String>, StorageError> {
-        let time_index = self.time_index.read().unwrap();
-        let now = Utc::now();
-        
-        Ok(match condition {
-            TimeCondition::After(time) => time_index.find_flows_after(*time),
-            TimeCondition::Before(time) => time_index.find_flows_before(*time),
-            TimeCondition::InRange(start, end) => time_index.find_flows_in_range(*start, *end),
-            TimeCondition::WithinLast(seconds) => {
-                time_index.find_flows_after(now - chrono::Duration::seconds(*seconds))
+    /// Re-apply a logged mutation during replay
+    ///
+    /// Segments record the LSN they were frozen at, so records they already
+    /// contain are skipped and later records apply on top of them.
+    fn apply_record(&self, lsn: u64, record: WalRecord) -> Result<(), StorageError> {
+        match record {
+            WalRecord::Insert { flow } => {
+                let frozen_later = {
+                    let segments = self.segments.read().unwrap();
+                    segments.lookup(&flow.flow_id).is_some_and(|r| r.wal_lsn > lsn)
+                };
+                if frozen_later {
+                    return Ok(());
+                }
+                
+                // The caller saw this insert fail too, so skip it rather than refuse to start
+                if let Err(e) = self.apply_insert(flow) {
+                    warn!("Skipping WAL record {} that failed to apply: {}", lsn, e);
+                }
+                Ok(())
+            }
+            WalRecord::Delete { flow_id } => {
+                // A delete can only follow its insert, so a miss is harmless
+                let mut removed = self.apply_remove(&flow_id)?.is_some();
+                if !removed {
+                    let mut segments = self.segments.write().unwrap();
+                    if segments.lookup(&flow_id).is_some_and(|r| r.wal_lsn <= lsn) {
+                        segments.forget(&flow_id);
+                        removed = true;
+                    }
+                }
+                
+                // The log doesn't say why, so evictions and expiries show up as deletes
+                if removed {
+                    self.publish_change(ChangeKind::Deleted, &flow_id, None);
+                }
+                Ok(())
+            }
+            WalRecord::Freeze { segment_id, flow_ids } => {
+                // A segment dropped since then is gone, and the in-memory copies are
+                // what later appends and deletes apply to
+                let frozen: Vec<String> = {
+                    let segments = self.segments.read().unwrap();
+                    flow_ids
+                        .into_iter()
+                        .filter(|id| segments.lookup(id).is_some_and(|r| r.segment_id == segment_id))
+                        .collect()
+                };
+                for flow_id in frozen {
+                    self.apply_remove(&flow_id)?;
+                }
+                Ok(())
             }
-            TimeCondition::WithinLastMinutes(minutes) => {
-                time_index.find_flows_after(now - chrono::Duration::minutes(*minutes))
+            WalRecord::DropSegment { segment_id } => {
+                self.segments.write().unwrap().remove_segment(segment_id);
+                Ok(())
             }
-            TimeCondition::WithinLastHours(hours) => {
-                time_index.find_flows_after(now - chrono::Duration::hours(*hours))
+            WalRecord::DropPartition { start, end } => {
+                let _writers = self.lock_all_writes();
+                for flow_id in self.drop_range(start, end)? {
+                    self.publish_change(ChangeKind::Expired, &flow_id, None);
+                }
+                Ok(())
             }
-        })
+        }
     }
     
-    /// Check if a flow matches all conditions
-    fn matches_all_conditions(&self, flow: &Flow, query: &QueryBuilder) -> bool {
-        let (path_conditions, time_conditions, metric_conditions) = query.conditions();
-        
-        // Check path conditions
-        for condition in path_conditions {
-            if !condition.matches(flow) {
-                return false;
-            }
+    /// Get the LSN of the last record written to the WAL, if there is one
+    pub fn wal_last_lsn(&self) -> Option<u64> {
+        self.wal.as_ref().map(|wal| wal.lock().unwrap().last_lsn())
+    }
+    
+    /// Start streaming committed WAL records to a follower resuming at `from_lsn`
+    pub fn follow_wal(&self, from_lsn: u64) -> Result<WalFollow, StorageError> {
+        let wal = self
+            .wal
+            .as_ref()
+            .ok_or_else(|| StorageError::Replication("Replication needs a write-ahead log".to_string()))?;
+        Ok(wal.lock().unwrap().follow(from_lsn)?)
+    }
+    
+    /// Apply a record streamed from a replication primary
+    ///
+    /// This is how a read-only follower is fed, so it bypasses `read_only`.
+    /// Freezes and segment drops only change the primary's own storage layout.
+    pub fn apply_replicated(&self, entry: WalEntry) -> Result<(), StorageError> {
+        match entry.record {
+            WalRecord::Freeze { .. } | WalRecord::DropSegment { .. } => {}
+            record => self.apply_record(entry.lsn, record)?,
         }
-        
-        // Check time conditions
-        for condition in time_conditions {
-            if !condition.matches(flow) {
-                return false;
+        self.replicated_lsn.store(entry.lsn, Ordering::Relaxed);
+        Ok(())
+    }
+    
+    /// Discard every flow before a follower rebuilds from the start of the primary's log
+    pub fn reset_replica(&self) -> Result<(), StorageError> {
+        let mut flow_ids = Vec::new();
+        self.backend.scan(&mut |stored| flow_ids.push(stored.flow_id))?;
+        for flow_id in flow_ids {
+            if self.apply_remove(&flow_id)?.is_some() {
+                self.publish_change(ChangeKind::Deleted, &flow_id, None);
             }
         }
-        
-        // Check metric conditions
-        for condition in metric_conditions {
-            if !condition.matches(flow) {
-                return false;
-            }
+        self.segments.write().unwrap().clear();
+        self.replicated_lsn.store(0, Ordering::Relaxed);
+        Ok(())
+    }
+    
+    /// Get the LSN of the last primary record applied (0 before any)
+    pub fn replicated_lsn(&self) -> u64 {
+        self.replicated_lsn.load(Ordering::Relaxed)
+    }
+    
+    /// Report a change to the feed, unless `open` is replaying recovered state
+    fn publish_change(&self, kind: ChangeKind, flow_id: &str, telemetry: Option<Flow>) {
+        if !self.replaying {
+            self.changes.publish(kind, flow_id, telemetry);
         }
-        
-        true
     }
-
-    /// Get flows by IDs
-    pub fn get_flows(&self, flow_ids: &[String]) -> Vec<Flow> {
-        let flows = self.flows.read().unwrap();
-        flow_ids
-            .iter()
-            .filter_map(|id| flows.get(id).cloned())
-            .collect()
+    
+    /// Receive every change after sequence number `after` over a channel
+    ///
+    /// Snapshot restores replace the whole image and are not reported.
+    pub fn subscribe_changes(&self, after: u64) -> ChangeSubscription {
+        self.changes.subscribe(after)
     }
-
-    /// Get the number of flows currently stored
-    pub fn flow_count(&self) -> usize {
-        let flows = self.flows.read().unwrap();
-        flows.len()
+    
+    /// Get up to `limit` changes after `after`, waiting up to `timeout` for the first one
+    pub fn poll_changes(&self, after: u64, limit: usize, timeout: Duration) -> ChangePage {
+        self.changes.poll(after, limit, timeout)
     }
     
-    /// Estimate memory usage in bytes
-    pub fn estimate_memory_usage(&self) -> usize {
-        let flows = self.flows.read().unwrap();
-        let path_index = self.path_index.read().unwrap();
-        let time_index = self.time_index.read().unwrap();
-        
-        let mut total_bytes = 0;
-        
-        // Estimate flows memory usage
-        for flow in flows.values() {
-            total_bytes += self.estimate_flow_memory(flow);
-        }
-        
-        // Estimate indexes memory usage (rough approximation)
-        // Path index: assume each switch ID takes ~20 bytes + overhead
-        total_bytes += path_index.estimated_size_bytes();
-        
-        // Time index: assume each bucket takes ~50 bytes + overhead  
-        total_bytes += time_index.estimated_size_bytes();
-        
-        total_bytes
+    /// Get the sequence number of the latest change (0 before any)
+    pub fn last_change_seq(&self) -> u64 {
+        self.changes.last_seq()
     }
     
-    /// Estimate memory usage for a single flow
-    fn estimate_flow_memory(&self, flow: &Flow) -> usize {
-        let mut bytes = 0;
+    /// Insert a new flow into the storage
+    ///
+    /// The flow is stamped with its ingest time before it is logged, so replay and
+    /// followers keep the same versions.
+    pub fn insert_flow(&self, mut flow: Flow) -> Result<(), StorageError> {
+        if self.read_only {
+            return Err(StorageError::ReadOnly);
+        }
         
-        // Flow ID string
-        bytes += flow.flow_id.len();
+        // Check capacity before logging so rejected flows never reach the WAL; evictions
+        // take their victim's shard writer lock, so this runs before taking the flow's
+        self.make_room(&flow)?;
         
-        // Path switches 
-        for switch in &flow.path.switches {
-            bytes += switch.len(); // switch_id string
-        }
-        bytes += flow.path.switches.len() * 8; // Vec overhead
+        // Thaw a cold flow before logging too, so an unreadable segment rejects the
+        // insert instead of leaving a record that replay can never apply
+        let _writer = self.shard(&flow.flow_id).lock_writes();
+        flow.stamp_ingest(Utc::now());
+        self.thaw(&flow.flow_id)?;
         
-        // Hops
-        for hop in &flow.hops {
-            bytes += hop.switch_id.len(); // switch_id string
-            bytes += 32; // hop_index (4) + timestamp (8) + struct overhead (~20)
-            bytes += 24; // TelemetryMetrics (3 fields × 8 bytes each)
+        // Writers to other shards only wait for the append itself
+        if let Some(wal) = &self.wal {
+            wal.lock().unwrap().append(WalRecord::Insert { flow: flow.clone() })?;
         }
-        bytes += flow.hops.len() * 8; // Vec overhead
         
-        // Timestamps and other fields
-        bytes += 32; // start_time + end_time + struct overhead
+        self.apply_thawed(flow)
+    }
+    
+    /// Move a cold flow back into memory so new telemetry can be appended to it
+    ///
+    /// Callers hold the flow's shard writer lock.
+    fn thaw(&self, flow_id: &str) -> Result<(), StorageError> {
+        let Some(flow) = self.read_cold_flow(flow_id)? else {
+            return Ok(());
+        };
+        
+        self.segments.write().unwrap().forget(flow_id);
+        self.upsert(flow)?;
+        Ok(())
+    }
+    
+    /// Evict flows until `flow` fits within `max_flows` and `max_memory_bytes`
+    ///
+    /// Evictions are logged as deletes so replay reaches the same state. Concurrent
+    /// writers check the limits independently, so they can overshoot them by the
+    /// flows they are writing.
+    fn make_room(&self, flow: &Flow) -> Result<(), StorageError> {
+        if let Some(max_flows) = self.config.max_flows {
+            loop {
+                let is_full = !self.contains_flow(&flow.flow_id)
+                    && self.hot_flows.load(Ordering::Relaxed) >= max_flows;
+                if !is_full {
+                    break;
+                }
+                
+                let victim = self.select_eviction_victim(&flow.flow_id).ok_or(StorageError::StorageFull)?;
+                self.evict(&victim)?;
+            }
+        }
+        
+        if let Some(budget) = self.config.max_memory_bytes {
+            // An append grows the stored flow by roughly the incoming flow's footprint
+            let needed = self.flow_footprint(&StoredFlow::new(flow));
+            loop {
+                let used = self.memory_usage_bytes() + self.change_buffer_bytes();
+                if used + needed <= budget {
+                    break;
+                }
+                
+                // With the reject policy, or nothing left to evict, push back on the writer
+                let victim = self
+                    .select_eviction_victim(&flow.flow_id)
+                    .ok_or(StorageError::MemoryLimit { needed, used, budget })?;
+                self.evict(&victim)?;
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Log and apply the eviction of one flow, unless it was removed since it was picked
+    fn evict(&self, flow_id: &str) -> Result<(), StorageError> {
+        let _writer = self.shard(flow_id).lock_writes();
+        if !self.contains_flow(flow_id) {
+            return Ok(());
+        }
+        
+        if let Some(wal) = &self.wal {
+            wal.lock().unwrap().append(WalRecord::Delete { flow_id: flow_id.to_string() })?;
+        }
+        self.remove(flow_id)?;
+        self.publish_change(ChangeKind::Evicted, flow_id, None);
+        self.evictions.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+    
+    /// Apply an insert (new flow or telemetry append) to storage and indexes
+    ///
+    /// The flow's shard writer lock is held from reading the existing flow until the
+    /// merged one and its index entries are stored, so concurrent appends never lose hops.
+    /// The change is published under the same lock, so a flow's events are in apply order.
+    fn apply_insert(&self, flow: Flow) -> Result<(), StorageError> {
+        let _writer = self.shard(&flow.flow_id).lock_writes();
+        self.thaw(&flow.flow_id)?;
+        self.apply_thawed(flow)
+    }
+    
+    /// Apply an insert whose flow is already in memory, if it exists (callers hold the shard writer lock)
+    fn apply_thawed(&self, flow: Flow) -> Result<(), StorageError> {
+        let telemetry = (!self.replaying).then(|| flow.clone());
+        let flow_id = flow.flow_id.clone();
+        let kind = self.upsert(flow)?;
+        self.publish_change(kind, &flow_id, telemetry);
+        Ok(())
+    }
+    
+    /// Store a new flow or merge it into the existing one (callers hold the shard writer lock)
+    ///
+    /// Returns whether the flow was created or appended to.
+    fn upsert(&self, flow: Flow) -> Result<ChangeKind, StorageError> {
+        let flow_id = flow.flow_id.clone();
+        self.note_retention(&flow);
+        self.record_access([flow_id.as_str()]);
+        
+        // Check if flow already exists
+        let existing_start = self.locate(&flow_id).map(|(start, _)| start);
+        
+        match existing_start {
+            Some(previous_start) => {
+                let previous_stored = self.backend.get(&flow_id)?.ok_or_else(|| missing_record(&flow_id))?;
+                
+                // Flow exists, append new telemetry data
+                let previous = previous_stored.decode();
+                let mut existing = previous.clone();
+                existing.merge(&flow);
+                
+                // Write the record before indexing it, so every indexed flow can be fetched
+                let stored = StoredFlow::new(&existing);
+                self.backend.put(&stored)?;
+                self.account(Some(&stored), Some(&previous_stored));
+                
+                if self.partition_start(existing.start_time) == previous_start {
+                    // Re-index under the old entries, not the updated ones, so an
+                    // earlier start time doesn't leave the flow in a stale bucket
+                    self.insert_into_partition(&existing, Some(&previous));
+                } else {
+                    // An earlier start time moves the flow to an earlier partition
+                    self.insert_into_partition(&existing, None);
+                    self.take_from_partition(previous_start, &previous);
+                }
+                
+                Ok(ChangeKind::Appended)
+            }
+            None => {
+                // Insert into main storage
+                let stored = StoredFlow::new(&flow);
+                self.backend.put(&stored)?;
+                self.account(Some(&stored), None);
+                self.insert_into_partition(&flow, None);
+                self.hot_flows.fetch_add(1, Ordering::Relaxed);
+                
+                Ok(ChangeKind::Created)
+            }
+        }
+    }
+    
+    /// Get the start of the partition window containing `time`
+    fn partition_start(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+        TimeIndex::bucket_start(time, self.config.partition_secs)
+    }
+    
+    /// Get the shard a flow belongs to
+    fn shard(&self, flow_id: &str) -> &Shard {
+        &self.shards[shard_index(flow_id, self.shards.len())]
+    }
+    
+    /// Take every shard's writer lock, in shard order, so no flow changes until they're dropped
+    fn lock_all_writes(&self) -> Vec<MutexGuard<'_, ()>> {
+        self.shards.iter().map(Shard::lock_writes).collect()
+    }
+    
+    /// Find the partition holding a flow
+    fn locate(&self, flow_id: &str) -> Option<(DateTime<Utc>, Arc<RwLock<Partition>>)> {
+        self.shard(flow_id).locate(flow_id)
+    }
+    
+    /// Check whether a flow is held in memory
+    fn contains_flow(&self, flow_id: &str) -> bool {
+        self.shard(flow_id).contains(flow_id)
+    }
+    
+    /// Visit the partitions of every shard that may hold flows matching a query
+    fn for_each_partition_matching(
+        &self,
+        query: &QueryBuilder,
+        mut visit: impl FnMut(&Partition) -> Result<(), StorageError>,
+    ) -> Result<(), StorageError> {
+        let now = Utc::now();
+        for shard in &self.shards {
+            shard.for_each_matching(query, now, &mut visit)?;
+        }
+        Ok(())
+    }
+    
+    /// Add or replace a flow in the partition covering its start time
+    fn insert_into_partition(&self, flow: &Flow, previous: Option<&Flow>) -> Option<FlowEntry> {
+        let start = self.partition_start(flow.start_time);
+        self.shard(&flow.flow_id).insert(start, flow, previous)
+    }
+    
+    /// Remove a flow from a specific partition of its shard
+    fn take_from_partition(&self, start: DateTime<Utc>, flow: &Flow) -> Option<FlowEntry> {
+        self.shard(&flow.flow_id).take(start, flow)
+    }
+    
+    /// Drop a whole partition of one shard and its records, returning the IDs of the flows it held
+    ///
+    /// Callers hold the shard writer lock.
+    fn drop_partition(&self, shard: &Shard, start: DateTime<Utc>) -> Result<Vec<String>, StorageError> {
+        let Some(partition) = shard.remove_partition(start) else {
+            return Ok(Vec::new());
+        };
+        
+        let entries: Vec<FlowEntry> = partition.write().unwrap().drain().collect();
+        self.hot_flows.fetch_sub(entries.len(), Ordering::Relaxed);
+        
+        for entry in &entries {
+            if let Some(stored) = self.backend.delete(&entry.flow_id)? {
+                self.account(None, Some(&stored));
+            }
+            if let Some(tracker) = &self.access_tracker {
+                tracker.lock().unwrap().remove(&entry.flow_id);
+            }
+        }
+        
+        Ok(entries.into_iter().map(|entry| entry.flow_id).collect())
+    }
+    
+    /// Drop every in-memory flow starting in `[start, end)`, returning the IDs of those dropped
+    ///
+    /// Partitions inside the range go whole; a partition straddling an edge (possible
+    /// when replaying a log written with a different `partition_secs`) goes flow by flow.
+    /// Callers hold every shard writer lock.
+    fn drop_range(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<String>, StorageError> {
+        let mut dropped = Vec::new();
+        for shard in &self.shards {
+            let overlapping: Vec<(DateTime<Utc>, Arc<RwLock<Partition>>)> = {
+                let partitions = shard.partitions();
+                partitions
+                    .range(..end)
+                    .filter(|(_, partition)| partition.read().unwrap().end() > start)
+                    .map(|(key, partition)| (*key, partition.clone()))
+                    .collect()
+            };
+            
+            for (key, partition) in overlapping {
+                let (inside, flow_ids) = {
+                    let partition = partition.read().unwrap();
+                    let inside = partition.start() >= start && partition.end() <= end;
+                    let flow_ids: Vec<String> = partition
+                        .flows()
+                        .filter(|flow| flow.start_time >= start && flow.start_time < end)
+                        .map(|flow| flow.flow_id.clone())
+                        .collect();
+                    (inside, flow_ids)
+                };
+                
+                if inside {
+                    dropped.extend(self.drop_partition(shard, key)?);
+                } else {
+                    for flow_id in flow_ids {
+                        if self.remove(&flow_id)?.is_some() {
+                            dropped.push(flow_id);
+                        }
+                    }
+                }
+            }
+        }
+        
+        Ok(dropped)
+    }
+    
+    /// Check whether every shard's partition for the window starting at `start` has expired
+    fn window_expired(&self, start: DateTime<Utc>, now: DateTime<Utc>, default_retention: Option<chrono::Duration>) -> bool {
+        let mut found = false;
+        for shard in &self.shards {
+            if let Some(partition) = shard.partitions().get(&start) {
+                if !partition.read().unwrap().is_expired(now, default_retention) {
+                    return false;
+                }
+                found = true;
+            }
+        }
+        found
+    }
+    
+    /// Get the number of time windows with in-memory flows
+    pub fn partition_count(&self) -> usize {
+        self.shards
+            .iter()
+            .flat_map(|shard| shard.partitions().keys().copied().collect::<Vec<_>>())
+            .collect::<BTreeSet<_>>()
+            .len()
+    }
+    
+    /// Get the number of storage shards
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+    
+    /// Remove a flow from storage and every index
+    pub fn remove_flow(&self, flow_id: &str) -> Result<Flow, StorageError> {
+        if self.read_only {
+            return Err(StorageError::ReadOnly);
+        }
+        
+        let _writer = self.shard(flow_id).lock_writes();
+        
+        // Check existence before logging so unknown IDs never reach the WAL
+        let in_memory = self.contains_flow(flow_id);
+        let cold_flow = if in_memory { None } else { self.read_cold_flow(flow_id)? };
+        if !in_memory && cold_flow.is_none() {
+            return Err(StorageError::FlowNotFound(flow_id.to_string()));
+        }
+        
+        if let Some(wal) = &self.wal {
+            wal.lock().unwrap().append(WalRecord::Delete { flow_id: flow_id.to_string() })?;
+        }
+        
+        // Segments are immutable, so a cold flow is removed by forgetting its copy
+        let flow = match cold_flow {
+            Some(flow) => {
+                self.segments.write().unwrap().forget(flow_id);
+                flow
+            }
+            None => self.remove(flow_id)?.ok_or_else(|| StorageError::FlowNotFound(flow_id.to_string()))?,
+        };
+        
+        self.publish_change(ChangeKind::Deleted, flow_id, None);
+        Ok(flow)
+    }
+    
+    /// Remove every flow matching a query, returning the removed flow IDs
+    ///
+    /// The query's pagination applies, so `limit` caps how many flows are removed.
+    pub fn remove_flows_matching(&self, query: QueryBuilder) -> Result<Vec<String>, StorageError> {
+        if self.read_only {
+            return Err(StorageError::ReadOnly);
+        }
+        
+        let result = self.query(query)?;
+        
+        let mut removed = Vec::with_capacity(result.flow_ids.len());
+        for flow_id in result.flow_ids {
+            match self.remove_flow(&flow_id) {
+                Ok(_) => removed.push(flow_id),
+                // Removed concurrently since the query ran
+                Err(StorageError::FlowNotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        
+        Ok(removed)
+    }
+    
+    /// Apply a removal to storage and the indexes of the flow's partition
+    fn apply_remove(&self, flow_id: &str) -> Result<Option<Flow>, StorageError> {
+        let _writer = self.shard(flow_id).lock_writes();
+        self.remove(flow_id)
+    }
+    
+    /// Remove an in-memory flow (callers hold the shard writer lock)
+    fn remove(&self, flow_id: &str) -> Result<Option<Flow>, StorageError> {
+        let Some((start, _)) = self.locate(flow_id) else {
+            return Ok(None);
+        };
+        
+        // Queries skip index entries whose record is already gone
+        let stored = self.backend.delete(flow_id)?.ok_or_else(|| missing_record(flow_id))?;
+        let flow = stored.decode();
+        self.take_from_partition(start, &flow);
+        self.account(None, Some(&stored));
+        self.hot_flows.fetch_sub(1, Ordering::Relaxed);
+        
+        if let Some(tracker) = &self.access_tracker {
+            tracker.lock().unwrap().remove(flow_id);
+        }
+        
+        Ok(Some(flow))
+    }
+    
+    /// Mark flows as recently used for least-recently-queried eviction
+    fn record_access<'a>(&self, flow_ids: impl IntoIterator<Item = &'a str>) {
+        if let Some(tracker) = &self.access_tracker {
+            let mut tracker = tracker.lock().unwrap();
+            for flow_id in flow_ids {
+                tracker.touch(flow_id);
+            }
+        }
+    }
+    
+    /// Pick the flow to evict under the configured policy
+    ///
+    /// `exclude` is the flow being written, which must not make room for itself.
+    fn select_eviction_victim(&self, exclude: &str) -> Option<String> {
+        match self.config.eviction_policy {
+            EvictionPolicy::Reject => None,
+            EvictionPolicy::EvictOldest => self.oldest_flow_by_end_time(exclude),
+            EvictionPolicy::EvictLeastRecentlyQueried => {
+                let tracker = self.access_tracker.as_ref()?.lock().unwrap();
+                let victim = tracker.iter().find(|id| *id != exclude).map(str::to_string);
+                victim
+            }
+        }
+    }
+    
+    /// Find the flow with the earliest `end_time`
+    ///
+    /// Partitions are keyed by start time, a flow is first listed in the time bucket of
+    /// its start and `start_time <= end_time`, so once a partition or bucket begins after
+    /// the best end time seen, no flow first found there can beat it.
+    fn oldest_flow_by_end_time(&self, exclude: &str) -> Option<String> {
+        let mut oldest: Option<(String, DateTime<Utc>)> = None;
+        for shard in &self.shards {
+            let partitions = shard.partitions();
+            for (partition_start, partition) in partitions.iter() {
+                if oldest.as_ref().is_some_and(|(_, end_time)| *partition_start > *end_time) {
+                    break;
+                }
+                
+                // Long-lived flows are in no bucket, so they're checked first
+                let partition = partition.read().unwrap();
+                let index = partition.time_index();
+                let lists = std::iter::once((None, index.long_lived_flows()))
+                    .chain(index.buckets().map(|(bucket_start, handles)| (Some(bucket_start), handles)));
+                for (bucket_start, handles) in lists {
+                    if bucket_start.zip(oldest.as_ref()).is_some_and(|(bucket_start, (_, end_time))| bucket_start > *end_time) {
+                        break;
+                    }
+                    
+                    for flow in partition.resolve(handles).filter(|flow| flow.flow_id != exclude) {
+                        if oldest.as_ref().is_none_or(|(_, end_time)| flow.end_time < *end_time) {
+                            oldest = Some((flow.flow_id.clone(), flow.end_time));
+                        }
+                    }
+                }
+            }
+        }
+        
+        oldest.map(|(flow_id, _)| flow_id)
+    }
+    
+    /// Get the number of flows evicted to make room for new ones
+    pub fn eviction_count(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+    
+    /// Get the engine-wide retention period from `auto_cleanup_hours`
+    fn default_retention(&self) -> Option<chrono::Duration> {
+        self.config
+            .auto_cleanup_hours
+            .filter(|hours| *hours > 0)
+            .and_then(chrono::Duration::try_hours)
+    }
+    
+    /// Get the retention period that applies to a flow with the given own policy, if it ever expires
+    fn retention_for(&self, own_retention: Option<chrono::Duration>) -> Option<chrono::Duration> {
+        own_retention.or_else(|| self.default_retention())
+    }
+    
+    /// Lower the sweep horizon if the flow carries a shorter retention policy
+    fn note_retention(&self, flow: &Flow) {
+        if let Some(retention) = flow.retention() {
+            self.shortest_retention_secs.fetch_min(retention.num_seconds(), Ordering::Relaxed);
+        }
+    }
+    
+    /// Check whether a flow's retention period has elapsed since its last telemetry
+    fn is_expired(&self, own_retention: Option<chrono::Duration>, end_time: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        self.retention_for(own_retention)
+            .is_some_and(|retention| end_time + retention < now)
+    }
+    
+    /// Remove every flow whose retention period has elapsed, returning how many were removed
+    ///
+    /// Candidates come from time buckets older than the shortest retention in use, so
+    /// recent buckets are never scanned. Removals are logged to the WAL like deletes.
+    pub fn sweep_expired(&self, now: DateTime<Utc>) -> Result<usize, StorageError> {
+        if self.read_only {
+            return Err(StorageError::ReadOnly);
+        }
+        
+        let started = Instant::now();
+        let mut expired = 0;
+        let default_retention = self.default_retention();
+        
+        // Time windows whose flows have all expired, in every shard, are dropped whole
+        let mut windows: BTreeMap<DateTime<Utc>, (DateTime<Utc>, bool)> = BTreeMap::new();
+        for shard in &self.shards {
+            for partition in shard.partitions().values() {
+                let partition = partition.read().unwrap();
+                let is_expired = partition.is_expired(now, default_retention);
+                windows
+                    .entry(partition.start())
+                    .and_modify(|(_, expired)| *expired &= is_expired)
+                    .or_insert((partition.end(), is_expired));
+            }
+        }
+        
+        for (start, (end, _)) in windows.into_iter().filter(|(_, (_, expired))| *expired) {
+            let _writers = self.lock_all_writes();
+            
+            // An append may have extended a flow since the check above
+            if !self.window_expired(start, now, default_retention) {
+                continue;
+            }
+            
+            if let Some(wal) = &self.wal {
+                wal.lock().unwrap().append(WalRecord::DropPartition { start, end })?;
+            }
+            for flow_id in self.drop_range(start, end)? {
+                self.publish_change(ChangeKind::Expired, &flow_id, None);
+                expired += 1;
+            }
+        }
+        
+        let shortest = self.shortest_retention_secs.load(Ordering::Relaxed);
+        if let Some(horizon) = chrono::Duration::try_seconds(shortest).and_then(|s| now.checked_sub_signed(s)) {
+            let mut candidates: Vec<(DateTime<Utc>, String)> = Vec::new();
+            for shard in &self.shards {
+                let partitions = shard.partitions();
+                for (start, partition) in partitions.range(..horizon) {
+                    let partition = partition.read().unwrap();
+                    let handles = partition.time_index().find_flows_before(horizon);
+                    candidates.extend(partition.resolve(&handles).map(|flow| (*start, flow.flow_id.clone())));
+                }
+            }
+            
+            // Re-check each candidate under its shard writer lock, since an append may have extended it
+            for (start, flow_id) in &candidates {
+                let _writer = self.shard(flow_id).lock_writes();
+                let is_expired = {
+                    let partitions = self.shard(flow_id).partitions();
+                    partitions.get(start).is_some_and(|partition| {
+                        let partition = partition.read().unwrap();
+                        partition.get(flow_id).is_some_and(|flow| self.is_expired(flow.retention, flow.end_time, now))
+                    })
+                };
+                if !is_expired {
+                    continue;
+                }
+                
+                if let Some(wal) = &self.wal {
+                    wal.lock().unwrap().append(WalRecord::Delete { flow_id: flow_id.clone() })?;
+                }
+                if self.remove(flow_id)?.is_some() {
+                    self.publish_change(ChangeKind::Expired, flow_id, None);
+                    expired += 1;
+                }
+            }
+        }
+        
+        expired += self.drop_dead_segments(now)?;
+        
+        let mut stats = self.sweep_stats.lock().unwrap();
+        stats.sweeps += 1;
+        stats.flows_expired += expired as u64;
+        stats.last_sweep_at = Some(now);
+        stats.last_sweep_expired = expired;
+        stats.last_sweep_duration_ms = started.elapsed().as_millis() as u64;
+        
+        if expired > 0 {
+            info!("Retention sweep expired {} flows", expired);
+        }
+        
+        Ok(expired)
+    }
+    
+    /// Get retention sweeper statistics
+    pub fn sweep_stats(&self) -> SweepStats {
+        self.sweep_stats.lock().unwrap().clone()
+    }
+    
+    /// Drop segments whose flows have all expired or moved elsewhere, returning how many flows expired
+    ///
+    /// Writers wait meanwhile, so no flow is thawed out of a segment between
+    /// counting its live flows and logging the drop.
+    fn drop_dead_segments(&self, now: DateTime<Utc>) -> Result<usize, StorageError> {
+        let _writers = self.lock_all_writes();
+        let mut wal = self.wal.as_ref().map(|wal| wal.lock().unwrap());
+        let mut segments = self.segments.write().unwrap();
+        
+        let live = segments.live_flow_counts();
+        let dead: Vec<(u64, usize)> = segments
+            .segments()
+            .map(|info| (info, live.get(&info.header.segment_id).copied().unwrap_or(0)))
+            .filter(|(info, count)| *count == 0 || info.header.expires_at.is_some_and(|t| t < now))
+            .map(|(info, count)| (info.header.segment_id, count))
+            .collect();
+        
+        let mut expired = 0;
+        for (segment_id, count) in dead {
+            let flow_ids = if count > 0 { segments.live_flow_ids(segment_id) } else { Vec::new() };
+            if let Some(wal) = wal.as_mut() {
+                // Expired flows are logged as deletes, since replay can't consult a deleted segment
+                for flow_id in &flow_ids {
+                    wal.append(WalRecord::Delete { flow_id: flow_id.clone() })?;
+                }
+                wal.append(WalRecord::DropSegment { segment_id })?;
+            }
+            for flow_id in &flow_ids {
+                self.publish_change(ChangeKind::Expired, flow_id, None);
+            }
+            if let Some(info) = segments.remove_segment(segment_id) {
+                remove_segment_file(&info);
+            }
+            expired += count;
+        }
+        
+        Ok(expired)
+    }
+    
+    /// Move flows whose last telemetry is older than `cold_flow_age` into a new segment
+    ///
+    /// Returns the number of flows frozen. Does nothing unless both `segment_dir`
+    /// and `cold_flow_age` are configured. Writers wait from reading the cold flows
+    /// until they are removed, so no append lands in between and is lost. The WAL is
+    /// compacted afterwards if it has grown enough.
+    pub fn freeze_cold_flows(&self, now: DateTime<Utc>) -> Result<usize, StorageError> {
+        if self.read_only {
+            return Err(StorageError::ReadOnly);
+        }
+        
+        let (Some(segment_dir), Some(cold_flow_age)) = (&self.config.segment_dir, self.config.cold_flow_age) else {
+            return Ok(0);
+        };
+        let Some(cutoff) = now.checked_sub_signed(cold_flow_age) else {
+            return Ok(0);
+        };
+        
+        let writers = self.lock_all_writes();
+        let mut wal = self.wal.as_ref().map(|wal| wal.lock().unwrap());
+        
+        // Buckets are keyed by start time, and a flow can't end before it starts
+        let mut cold_flows: Vec<Flow> = Vec::new();
+        for shard in &self.shards {
+            let partitions = shard.partitions();
+            for partition in partitions.range(..cutoff).map(|(_, partition)| partition.read().unwrap()) {
+                let handles = partition.time_index().find_flows_before(cutoff);
+                for entry in partition.resolve(&handles).filter(|entry| entry.end_time < cutoff) {
+                    if let Some(stored) = self.backend.get(&entry.flow_id)? {
+                        cold_flows.push(stored.decode());
+                    }
+                }
+            }
+        }
+        if cold_flows.is_empty() {
+            return Ok(0);
+        }
+        
+        let segment_id = self.segments.read().unwrap().next_segment_id();
+        let wal_lsn = wal.as_ref().map_or(0, |wal| wal.last_lsn() + 1);
+        let expires_at = cold_flows
+            .iter()
+            .map(|flow| self.retention_for(flow.retention()).map(|retention| flow.end_time + retention))
+            .collect::<Option<Vec<_>>>()
+            .and_then(|times| times.into_iter().max());
+        
+        let path = segment_dir.join(segment::segment_file_name(segment_id));
+        let info = segment::write_segment(&path, segment_id, wal_lsn, expires_at, &cold_flows)?;
+        let flow_ids: Vec<String> = cold_flows.into_iter().map(|flow| flow.flow_id).collect();
+        
+        if let Some(wal) = wal.as_mut() {
+            if let Err(e) = wal.append(WalRecord::Freeze { segment_id, flow_ids: flow_ids.clone() }) {
+                remove_segment_file(&info);
+                return Err(e.into());
+            }
+        }
+        
+        info!("Froze {} cold flows into {} ({} bytes)", flow_ids.len(), info.path.display(), info.size_bytes);
+        
+        self.segments.write().unwrap().add(info, flow_ids.clone());
+        for flow_id in &flow_ids {
+            self.remove(flow_id)?;
+        }
+        
+        drop(wal);
+        drop(writers);
+        self.compact_wal_if_due();
+        Ok(flow_ids.len())
+    }
+    
+    /// Decode the newest cold copy of a flow, if it has one
+    fn read_cold_flow(&self, flow_id: &str) -> Result<Option<Flow>, StorageError> {
+        self.segments.read().unwrap().read_flow(flow_id)
+    }
+    
+    /// Decode the unexpired flows a segment holds the newest copy of
+    fn read_live_segment_flows(
+        &self,
+        segments: &SegmentCatalog,
+        info: &SegmentInfo,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<Flow>, StorageError> {
+        let segment_id = info.header.segment_id;
+        let mut flows = segment::read_segment_flows(&info.path)?;
+        flows.retain(|flow| {
+            segments.lookup(&flow.flow_id).is_some_and(|r| r.segment_id == segment_id)
+                && !self.is_expired(flow.retention(), flow.end_time, now)
+        });
+        Ok(flows)
+    }
+    
+    /// Write a consistent image of all flows plus index metadata to `path`
+    ///
+    /// Read-only engines refuse, so a frozen copy never gains files. The WAL is
+    /// compacted afterwards if it has grown enough.
+    pub fn snapshot(&self, path: impl AsRef<Path>) -> Result<SnapshotInfo, StorageError> {
+        if self.read_only {
+            return Err(StorageError::ReadOnly);
+        }
+        
+        // Writers wait while the image is collected, so it matches a single LSN;
+        // the file is written after they resume
+        let (header, flows, cold_flows) = {
+            let _writers = self.lock_all_writes();
+            let wal = self.wal.as_ref().map(|wal| wal.lock().unwrap());
+            
+            let mut flows: HashMap<String, Flow> = HashMap::new();
+            self.backend.scan(&mut |stored| {
+                flows.insert(stored.flow_id.clone(), stored.decode());
+            })?;
+            let mut time_buckets = 0;
+            for shard in &self.shards {
+                for partition in shard.partitions().values() {
+                    time_buckets += partition.read().unwrap().time_index().stats().bucket_count;
+                }
+            }
+            
+            // Indexes are per partition, so count distinct paths and switches directly
+            let unique_paths = flows.values().map(|flow| flow.path.hash()).collect::<HashSet<_>>().len();
+            let unique_switches = flows
+                .values()
+                .flat_map(|flow| flow.path.switches.iter())
+                .collect::<HashSet<_>>()
+                .len();
+            
+            // Cold flows are part of the image too
+            let mut cold_flows = Vec::new();
+            {
+                let segments = self.segments.read().unwrap();
+                let now = Utc::now();
+                for info in segments.segments() {
+                    cold_flows.extend(
+                        self.read_live_segment_flows(&segments, info, now)?
+                            .into_iter()
+                            .filter(|flow| !flows.contains_key(&flow.flow_id)),
+                    );
+                }
+            }
+            
+            let header = SnapshotHeader {
+                format_version: SNAPSHOT_FORMAT_VERSION,
+                engine_version: env!("CARGO_PKG_VERSION").to_string(),
+                created_at: Utc::now(),
+                wal_lsn: wal.as_ref().map_or(0, |wal| wal.last_lsn()),
+                flow_count: flows.len() + cold_flows.len(),
+                time_bucket_size: self.config.time_bucket_size,
+                unique_paths,
+                unique_switches,
+                time_buckets,
+            };
+            (header, flows, cold_flows)
+        };
+        
+        let info = snapshot::write_snapshot(path, &header, flows.into_values().chain(cold_flows))?;
+        info!("Wrote snapshot {} ({} flows, {} bytes)", info.path.display(), header.flow_count, info.size_bytes);
+        
+        self.compact_wal_if_due();
+        
+        Ok(info)
+    }
+    
+    /// Replace the engine contents with a snapshot, rebuilding all indexes
+    ///
+    /// If a WAL is configured it is rewritten to match the restored image.
+    pub fn restore(&self, path: impl AsRef<Path>) -> Result<SnapshotInfo, StorageError> {
+        if self.read_only {
+            return Err(StorageError::ReadOnly);
+        }
+        
+        let info = self.load_snapshot(path)?;
+        info!("Restored snapshot {} ({} flows)", info.path.display(), info.header.flow_count);
+        
+        Ok(info)
+    }
+    
+    /// Replace the engine contents with a snapshot, rewriting the WAL if there is one
+    fn load_snapshot(&self, path: impl AsRef<Path>) -> Result<SnapshotInfo, StorageError> {
+        let (info, restored_flows) = snapshot::read_snapshot(path)?;
+        
+        let mut flows = HashMap::with_capacity(restored_flows.len());
+        for flow in restored_flows {
+            if let Some(duplicate) = flows.insert(flow.flow_id.clone(), flow) {
+                return Err(StorageError::CorruptSnapshot(format!("Duplicate flow {}", duplicate.flow_id)));
+            }
+        }
+        
+        // Writers wait until the swap is done, so none lands in the old image
+        // after it is cleared or in the new one before its counters are set
+        let _writers = self.lock_all_writes();
+        let mut wal = self.wal.as_ref().map(|wal| wal.lock().unwrap());
+        let mut segments = self.segments.write().unwrap();
+        
+        // The rewritten log drops every segment first, so leftover files never shadow the image
+        if let Some(wal) = wal.as_mut() {
+            let drops = segments.segments().map(|info| WalRecord::DropSegment { segment_id: info.header.segment_id });
+            let inserts = flows.values().map(|flow| WalRecord::Insert { flow: flow.clone() });
+            wal.rewrite(drops.collect::<Vec<_>>().into_iter().chain(inserts))?;
+        }
+        
+        for info in segments.segments() {
+            remove_segment_file(info);
+        }
+        segments.clear();
+        drop(segments);
+        
+        self.backend.clear()?;
+        
+        if let Some(tracker) = &self.access_tracker {
+            tracker.lock().unwrap().clear();
+        }
+        for flow in flows.values() {
+            self.note_retention(flow);
+            self.record_access([flow.flow_id.as_str()]);
+        }
+        
+        let mut partitions: Vec<BTreeMap<DateTime<Utc>, Partition>> = self.shards.iter().map(|_| BTreeMap::new()).collect();
+        let (mut memory_bytes, mut raw_hop_bytes, mut stored_hop_bytes) = (0, 0, 0);
+        let hot_flows = flows.len();
+        for flow in flows.into_values() {
+            let stored = StoredFlow::new(&flow);
+            self.backend.put(&stored)?;
+            memory_bytes += self.flow_footprint(&stored);
+            raw_hop_bytes += stored.raw_hop_bytes();
+            stored_hop_bytes += stored.stored_hop_bytes();
+            
+            let start = self.partition_start(flow.start_time);
+            partitions[shard_index(&flow.flow_id, self.shards.len())]
+                .entry(start)
+                .or_insert_with(|| Partition::new(start, self.config.partition_secs, self.config.time_bucket_size, self.config.time_index_max_span))
+                .insert(&flow, None);
+        }
+        
+        for (shard, partitions) in self.shards.iter().zip(partitions) {
+            shard.replace_partitions(partitions);
+        }
+        self.hot_flows.store(hot_flows, Ordering::Relaxed);
+        self.memory_bytes.store(memory_bytes, Ordering::Relaxed);
+        self.raw_hop_bytes.store(raw_hop_bytes, Ordering::Relaxed);
+        self.stored_hop_bytes.store(stored_hop_bytes, Ordering::Relaxed);
+        
+        Ok(info)
+    }
+    
+    /// Read a flow's record from the backend, logging rather than failing if it can't be read
+    fn read_hot_flow(&self, flow_id: &str) -> Option<Flow> {
+        match self.backend.get(flow_id) {
+            Ok(stored) => stored.map(|stored| stored.decode()),
+            Err(e) => {
+                warn!("Failed to read flow {} from the {} backend: {}", flow_id, self.backend.name(), e);
+                None
+            }
+        }
+    }
+    
+    /// Get a flow by ID, falling back to cold segments
+    pub fn get_flow(&self, flow_id: &str) -> Option<Flow> {
+        let flow = self.read_hot_flow(flow_id);
+        if flow.is_some() {
+            self.record_access([flow_id]);
+            return flow;
+        }
+        
+        match self.read_cold_flow(flow_id) {
+            Ok(flow) => flow.filter(|flow| !self.is_expired(flow.retention(), flow.end_time, Utc::now())),
+            Err(e) => {
+                warn!("Failed to read cold flow {}: {}", flow_id, e);
+                None
+            }
+        }
+    }
+    
+    /// Execute a query
+    pub fn query(&self, query: QueryBuilder) -> Result<QueryResult, StorageError> {
+        let (page, total_count) = self.query_ranked(&query)?;
+        let flow_ids: Vec<String> = page.into_iter().map(|flow| flow.flow_id).collect();
+        
+        // Only in-memory flows can be evicted, so cold ones are not tracked
+        let hot_ids: Vec<&str> = flow_ids.iter().map(String::as_str).filter(|id| self.contains_flow(id)).collect();
+        self.record_access(hot_ids);
+        
+        let (limit, skip) = query.pagination();
+        Ok(QueryResult::with_skip(flow_ids, total_count, skip.unwrap_or(0), limit))
+    }
+    
+    /// Execute a query, returning the requested page with start times and the total match count
+    ///
+    /// Pages from several engines can be merged in `RankedFlow` order and re-paginated.
+    /// Unlike `query`, this does not count as an access for eviction.
+    pub fn query_ranked(&self, query: &QueryBuilder) -> Result<(Vec<RankedFlow>, usize), StorageError> {
+        if let Some(at) = query.as_of_time() {
+            return Ok(Self::rank_page(self.query_as_of(query, at)?, query));
+        }
+        
+        // Fan out to every shard, searching only partitions overlapping the query's time range
+        let mut matching_flows: Vec<(String, DateTime<Utc>)> = Vec::new();
+        let mut seen: HashSet<String> = HashSet::new();
+        self.for_each_partition_matching(query, |partition| {
+            // Intersect candidate handles from the partition's indexes, then apply all
+            // conditions; a flow moving to an earlier partition mid-scan is counted once
+            let candidates = partition.candidates(query);
+            for entry in partition.resolve(&candidates) {
+                if !seen.contains(&entry.flow_id) && self.entry_matches(entry, query)? {
+                    seen.insert(entry.flow_id.clone());
+                    matching_flows.push((entry.flow_id.clone(), entry.start_time));
+                }
+            }
+            Ok(())
+        })?;
+        
+        // Cold matches, skipping any flow that is (again) in memory
+        let cold_matches = self.query_segments(query)?;
+        matching_flows.extend(cold_matches.into_iter().filter(|(flow_id, _)| !seen.contains(flow_id) && !self.contains_flow(flow_id)));
+        
+        Ok(Self::rank_page(matching_flows, query))
+    }
+    
+    /// Order matches and cut the query's page, returning it with the total match count
+    fn rank_page(matching_flows: Vec<(String, DateTime<Utc>)>, query: &QueryBuilder) -> (Vec<RankedFlow>, usize) {
+        // Sort by start time (most recent first), then by ID so the merge order across shards doesn't leak into pages
+        let mut matching_flows: Vec<RankedFlow> = matching_flows
+            .into_iter()
+            .map(|(flow_id, start_time)| RankedFlow { flow_id, start_time })
+            .collect();
+        matching_flows.sort();
+        
+        let total_count = matching_flows.len();
+        
+        // Apply pagination
+        let (limit, skip) = query.pagination();
+        let page = matching_flows
+            .into_iter()
+            .skip(skip.unwrap_or(0))
+            .take(limit.unwrap_or(usize::MAX))
+            .collect();
+        
+        (page, total_count)
+    }
+    
+    /// Find flows whose state as of ingest time `at` matches a query
+    ///
+    /// Indexes and segment statistics describe flows as they are now, so every
+    /// stored flow is checked. Flows removed since `at` are not found.
+    fn query_as_of(&self, query: &QueryBuilder, at: DateTime<Utc>) -> Result<Vec<(String, DateTime<Utc>)>, StorageError> {
+        let mut matches = Vec::new();
+        let mut check = |flow: Flow| {
+            if let Some(flow) = flow.as_of(at).filter(|flow| self.matches_all_conditions(flow, query)) {
+                matches.push((flow.flow_id, flow.start_time));
+            }
+        };
+        
+        self.backend.scan(&mut |stored| check(stored.decode()))?;
+        
+        let segments = self.segments.read().unwrap();
+        let now = Utc::now();
+        for info in segments.segments() {
+            for flow in self.read_live_segment_flows(&segments, info, now)? {
+                if !self.contains_flow(&flow.flow_id) {
+                    check(flow);
+                }
+            }
+        }
+        
+        Ok(matches)
+    }
+    
+    /// Find cold flows matching a query, skipping segments whose statistics rule it out
+    fn query_segments(&self, query: &QueryBuilder) -> Result<Vec<(String, DateTime<Utc>)>, StorageError> {
+        let segments = self.segments.read().unwrap();
+        let now = Utc::now();
+        
+        let mut matches = Vec::new();
+        for info in segments.segments().filter(|info| info.may_match(query)) {
+            for flow in self.read_live_segment_flows(&segments, info, now)? {
+                if self.matches_all_conditions(&flow, query) {
+                    matches.push((flow.flow_id, flow.start_time));
+                }
+            }
+        }
+        
+        Ok(matches)
+    }
+    
+    /// Check if an indexed flow matches all conditions, fetching its record only when a condition needs it
+    fn entry_matches(&self, entry: &FlowEntry, query: &QueryBuilder) -> Result<bool, StorageError> {
+        let (path_conditions, time_conditions, metric_conditions) = query.conditions();
+        
+        // Time conditions only look at the flow's lifetime
+        if !time_conditions.iter().all(|condition| condition.matches_lifetime(entry.start_time, entry.end_time)) {
+            return Ok(false);
+        }
+        if path_conditions.is_empty() && metric_conditions.is_empty() {
+            return Ok(true);
+        }
+        
+        // A record removed since the flow was indexed no longer matches
+        let stored = self.backend.get(&entry.flow_id)?;
+        Ok(stored.is_some_and(|stored| self.matches_all_conditions(&stored.decode(), query)))
+    }
+    
+    /// Check if a flow matches all conditions
+    fn matches_all_conditions(&self, flow: &Flow, query: &QueryBuilder) -> bool {
+        let (path_conditions, time_conditions, metric_conditions) = query.conditions();
+        
+        // Check path conditions
+        for condition in path_conditions {
+            if !condition.matches(flow) {
+                return false;
+            }
+        }
+        
+        // Check time conditions
+        for condition in time_conditions {
+            if !condition.matches(flow) {
+                return false;
+            }
+        }
+        
+        // Check metric conditions
+        for condition in metric_conditions {
+            if !condition.matches(flow) {
+                return false;
+            }
+        }
+        
+        true
+    }
+    
+    /// Get the oldest `limit` hops seen at a switch with timestamps in `[from, to]`,
+    /// along with the number of hops in the window
+    ///
+    /// Stored flows are found through each partition's hop index; cold segments
+    /// are read only if their switches and hop times could match. Only `limit`
+    /// hops are held while scanning, however busy the switch.
+    pub fn hops_at_switch(
+        &self,
+        switch_id: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        limit: usize,
+    ) -> Result<(Vec<SwitchHop>, usize), StorageError> {
+        // Max-heap of the oldest hops so far, so the newest one is replaced first
+        let mut oldest: BinaryHeap<OldestHop> = BinaryHeap::new();
+        let mut total_count = 0;
+        self.visit_hops_in_range(Some(switch_id), from, to, |flow_id, hop| {
+            total_count += 1;
+            let candidate = || OldestHop(SwitchHop { flow_id: flow_id.to_string(), hop: hop.clone() });
+            if oldest.len() < limit {
+                oldest.push(candidate());
+            } else if let Some(mut newest) = oldest.peek_mut() {
+                if (hop.timestamp, flow_id) < (newest.0.hop.timestamp, newest.0.flow_id.as_str()) {
+                    *newest = candidate();
+                }
+            }
+        })?;
+        
+        let hops = oldest.into_sorted_vec().into_iter().map(|hop| hop.0).collect();
+        Ok((hops, total_count))
+    }
+    
+    /// Summarize the hops each switch saw with timestamps in `[from, to]`
+    pub fn switch_hop_stats(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<BTreeMap<String, SwitchHopStats>, StorageError> {
+        let mut stats: BTreeMap<String, SwitchHopStats> = BTreeMap::new();
+        self.visit_hops_in_range(None, from, to, |_, hop| {
+            match stats.get_mut(&hop.switch_id) {
+                Some(switch_stats) => switch_stats.record(hop),
+                None => stats.entry(hop.switch_id.clone()).or_default().record(hop),
+            }
+        })?;
+        Ok(stats)
+    }
+    
+    /// Visit each hop (at one switch, or any) with a timestamp in `[from, to]`, once
+    fn visit_hops_in_range(
+        &self,
+        switch_id: Option<&str>,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        mut visit: impl FnMut(&str, &Hop),
+    ) -> Result<(), StorageError> {
+        let in_range = |hop: &Hop| hop.timestamp >= from && hop.timestamp <= to && switch_id.is_none_or(|s| hop.switch_id == s);
+        let mut seen: HashSet<String> = HashSet::new();
+        
+        // Collect candidate hop positions per flow, then read each record once outside the partition locks
+        for shard in &self.shards {
+            let partitions: Vec<_> = shard.partitions().values().cloned().collect();
+            for partition in partitions {
+                let flow_ids: Vec<(String, Vec<HopPosition>)> = {
+                    let partition = partition.read().unwrap();
+                    let index = partition.hop_index();
+                    let mut positions: BTreeMap<FlowHandle, Vec<HopPosition>> = BTreeMap::new();
+                    match switch_id {
+                        Some(switch_id) => {
+                            for (handle, position) in index.find_hops(switch_id, from, to) {
+                                positions.entry(handle).or_default().push(position);
+                            }
+                        }
+                        None => {
+                            for (_, handle, position) in index.find_all_hops(from, to) {
+                                positions.entry(handle).or_default().push(position);
+                            }
+                        }
+                    }
+                    positions
+                        .into_iter()
+                        .filter_map(|(handle, positions)| Some((partition.get_by_handle(handle)?.flow_id.clone(), positions)))
+                        .collect()
+                };
+                
+                for (flow_id, positions) in flow_ids {
+                    // A flow moved to another partition mid-scan is visited once
+                    if seen.contains(&flow_id) {
+                        continue;
+                    }
+                    let Some(flow) = self.backend.get(&flow_id)?.map(|stored| stored.decode()) else {
+                        continue;
+                    };
+                    for hop in positions.iter().filter_map(|position| flow.hops.get(*position as usize)) {
+                        if in_range(hop) {
+                            visit(&flow.flow_id, hop);
+                        }
+                    }
+                    seen.insert(flow_id);
+                }
+            }
+        }
+        
+        // Cold flows, skipping any that is (again) in memory
+        let segments = self.segments.read().unwrap();
+        let now = Utc::now();
+        for info in segments.segments() {
+            let header = &info.header;
+            let could_match = switch_id.is_none_or(|s| header.switches.binary_search_by(|switch| switch.as_str().cmp(s)).is_ok())
+                && header.hop_timestamp.min.is_some_and(|min| min <= to)
+                && header.hop_timestamp.max.is_some_and(|max| max >= from);
+            if !could_match {
+                continue;
+            }
+            for flow in self.read_live_segment_flows(&segments, info, now)? {
+                if seen.contains(&flow.flow_id) || self.contains_flow(&flow.flow_id) {
+                    continue;
+                }
+                for hop in flow.hops.iter().filter(|hop| in_range(hop)) {
+                    visit(&flow.flow_id, hop);
+                }
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Get flows by IDs, falling back to cold segments
+    pub fn get_flows(&self, flow_ids: &[String]) -> Vec<Flow> {
+        let found: Vec<Option<Flow>> = flow_ids.iter().map(|id| self.read_hot_flow(id)).collect();
+        self.record_access(found.iter().flatten().map(|flow| flow.flow_id.as_str()));
+        self.read_cold_flows(found, flow_ids)
+    }
+    
+    /// Get the most recently started flows, newest first, without counting them as queried (for monitoring)
+    pub fn recent_flows(&self, limit: usize) -> Result<Vec<Flow>, StorageError> {
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+        
+        let (page, _) = self.query_ranked(&QueryBuilder::new().limit(limit))?;
+        let flow_ids: Vec<String> = page.into_iter().map(|flow| flow.flow_id).collect();
+        let found = flow_ids.iter().map(|id| self.read_hot_flow(id)).collect();
+        Ok(self.read_cold_flows(found, &flow_ids))
+    }
+    
+    /// Fill in the flows not found in memory from cold segments, dropping any found nowhere
+    fn read_cold_flows(&self, mut found: Vec<Option<Flow>>, flow_ids: &[String]) -> Vec<Flow> {
+        if found.iter().any(Option::is_none) {
+            // Decode each segment once for all the flows it holds
+            let segments = self.segments.read().unwrap();
+            let mut by_segment: BTreeMap<u64, HashSet<&str>> = BTreeMap::new();
+            for (slot, flow_id) in found.iter().zip(flow_ids) {
+                if let (None, Some(segment_ref)) = (slot, segments.lookup(flow_id)) {
+                    by_segment.entry(segment_ref.segment_id).or_default().insert(flow_id);
+                }
+            }
+            
+            let now = Utc::now();
+            let mut cold: HashMap<String, Flow> = HashMap::new();
+            for (segment_id, wanted) in by_segment {
+                let Some(info) = segments.segment(segment_id) else {
+                    continue;
+                };
+                match self.read_live_segment_flows(&segments, info, now) {
+                    Ok(flows) => cold.extend(
+                        flows
+                            .into_iter()
+                            .filter(|flow| wanted.contains(flow.flow_id.as_str()))
+                            .map(|flow| (flow.flow_id.clone(), flow)),
+                    ),
+                    Err(e) => warn!("Failed to read segment {}: {}", info.path.display(), e),
+                }
+            }
+            
+            for (slot, flow_id) in found.iter_mut().zip(flow_ids) {
+                if slot.is_none() {
+                    *slot = cold.remove(flow_id);
+                }
+            }
+        }
+        
+        found.into_iter().flatten().collect()
+    }
+    
+    /// Summarize the telemetry of every in-memory flow without counting it as a query (for monitoring)
+    ///
+    /// Flows are decoded one at a time, so only the summary is held.
+    pub fn telemetry_summary(&self) -> FlowTelemetrySummary {
+        let mut summary = FlowTelemetrySummary::default();
+        let mut paths = HashSet::new();
+        let scanned = self.backend.scan(&mut |stored| {
+            let flow = stored.decode();
+            paths.insert(flow.path.hash());
+            summary.record(&flow);
+        });
+        if let Err(e) = scanned {
+            warn!("Failed to scan the {} backend: {}", self.backend.name(), e);
+        }
+        summary.distinct_paths = paths.len() as u64;
+        summary
+    }
+    
+    /// Get every in-memory flow without counting it as a query (for monitoring)
+    pub fn all_flows(&self) -> Vec<Flow> {
+        let mut flows = Vec::new();
+        if let Err(e) = self.backend.scan(&mut |stored| flows.push(stored.decode())) {
+            warn!("Failed to scan the {} backend: {}", self.backend.name(), e);
+        }
+        flows
+    }
+    
+    /// Get the number of flows currently stored, in memory and in segments
+    pub fn flow_count(&self) -> usize {
+        self.hot_flows.load(Ordering::Relaxed) + self.cold_flow_count()
+    }
+    
+    /// Get the number of flows held only in cold segments
+    pub fn cold_flow_count(&self) -> usize {
+        self.segments.read().unwrap().flow_count()
+    }
+    
+    /// Get the number of cold segments
+    pub fn segment_count(&self) -> usize {
+        self.segments.read().unwrap().segment_count()
+    }
+    
+    /// Get the bytes held by stored flows and their index entries
+    pub fn memory_usage_bytes(&self) -> usize {
+        self.memory_bytes.load(Ordering::Relaxed)
+    }
+    
+    /// Get the bytes held by buffered change events
+    pub fn change_buffer_bytes(&self) -> usize {
+        self.changes.buffered_bytes()
+    }
+    
+    /// Get the ratio of uncompressed to stored hop bytes (1.0 when nothing is stored)
+    pub fn compression_ratio(&self) -> f64 {
+        let stored = self.stored_hop_bytes.load(Ordering::Relaxed);
+        if stored == 0 {
+            return 1.0;
+        }
+        self.raw_hop_bytes.load(Ordering::Relaxed) as f64 / stored as f64
+    }
+    
+    /// Update memory and compression totals for a stored flow added and/or replaced
+    fn account(&self, added: Option<&StoredFlow>, removed: Option<&StoredFlow>) {
+        if let Some(flow) = added {
+            self.memory_bytes.fetch_add(self.flow_footprint(flow), Ordering::Relaxed);
+            self.raw_hop_bytes.fetch_add(flow.raw_hop_bytes(), Ordering::Relaxed);
+            self.stored_hop_bytes.fetch_add(flow.stored_hop_bytes(), Ordering::Relaxed);
+        }
+        if let Some(flow) = removed {
+            self.memory_bytes.fetch_sub(self.flow_footprint(flow), Ordering::Relaxed);
+            self.raw_hop_bytes.fetch_sub(flow.raw_hop_bytes(), Ordering::Relaxed);
+            self.stored_hop_bytes.fetch_sub(flow.stored_hop_bytes(), Ordering::Relaxed);
+        }
+    }
+    
+    /// Bytes a stored flow accounts for: its entry and handle, index postings and what the backend keeps resident
+    fn flow_footprint(&self, flow: &StoredFlow) -> usize {
+        let id_bytes = flow.flow_id.len();
+        let entry = size_of::<Option<FlowEntry>>() + size_of::<(String, FlowHandle)>() + 2 * id_bytes + self.backend.resident_bytes(flow);
+        
+        // One handle in the exact-path index, per time bucket the flow spans, per
+        // distinct switch and per distinct link, one per node in the prefix and
+        // suffix tries, one per sub-path, and up to three in the metric index
+        let distinct_switches = flow.path.iter().collect::<HashSet<_>>().len();
+        let distinct_links = flow.path.windows(2).collect::<HashSet<_>>().len();
+        let hops = flow.path.len();
+        let time_buckets = TimeIndex::posting_count(
+            flow.start_time,
+            flow.end_time,
+            self.config.time_bucket_size,
+            self.config.time_index_max_span,
+        );
+        let postings = 4 + time_buckets + distinct_switches + distinct_links + 2 * hops + hops * (hops + 1) / 2;
+        let mut index = postings * size_of::<FlowHandle>();
+        
+        // One packed flow handle and hop position per hop in the hop index
+        index += flow.hop_count() * size_of::<u64>();
+        
+        if self.access_tracker.is_some() {
+            index += size_of::<(String, u64)>() + size_of::<(u64, String)>() + 2 * id_bytes;
+        }
+        
+        entry + index
+    }
+}
+
+/// Error for a flow that is indexed but has no record in the backend
+fn missing_record(flow_id: &str) -> StorageError {
+    StorageError::Backend(format!("No record for indexed flow {}", flow_id))
+}
+
+/// Delete a segment file, logging rather than failing if it can't be removed
+fn remove_segment_file(info: &SegmentInfo) {
+    if let Err(e) = fs::remove_file(&info.path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warn!("Failed to remove segment {}: {}", info.path.display(), e);
+        }
+    }
+}
+
+impl Default for StorageEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+} 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Hop, NetworkPath, TelemetryMetrics};
+    use crate::storage::{ChangeEvent, MetricCondition, TimeCondition};
+    use chrono::{DateTime, Utc};
+    
+    fn create_test_flow(flow_id: &str, switches: &[&str], start_time: DateTime<Utc>) -> Flow {
+        let hops: Vec<Hop> = switches
+            .iter()
+            .enumerate()
+            .map(|(i, switch)| {
+                Hop::new(
+                    i as u32,
+                    switch.to_string(),
+                    start_time + chrono::Duration::milliseconds(i as i64 * 10),
+                    TelemetryMetrics::with_basic(0.1 * i as f64, 100 * i as u64),
+                )
+            })
+            .collect();
+        
+        Flow::new(flow_id.to_string(), hops).unwrap()
+    }
+    
+    /// Count the flows the time indexes list (each once, whatever the buckets it spans)
+    fn total_flow_refs(engine: &StorageEngine) -> usize {
+        let mut total = 0;
+        for shard in &engine.shards {
+            for partition in shard.partitions().values() {
+                let partition = partition.read().unwrap();
+                let mut handles = roaring::RoaringBitmap::new();
+                for (_, flows) in partition.time_index().buckets() {
+                    handles |= flows;
+                }
+                handles |= partition.time_index().long_lived_flows();
+                total += handles.len() as usize;
+            }
+        }
+        total
+    }
+    
+    fn wal_config(dir: &tempfile::TempDir) -> EngineConfig {
+        EngineConfig {
+            wal_path: Some(dir.path().join("intdb.wal")),
+            ..EngineConfig::default()
+        }
+    }
+    
+    #[test]
+    fn test_wal_replay_restores_flows_and_indexes() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = Utc::now();
+        
+        {
+            let engine = StorageEngine::open(wal_config(&dir)).unwrap();
+            engine.insert_flow(create_test_flow("flow1", &["s1", "s2", "s3"], now)).unwrap();
+            engine.insert_flow(create_test_flow("flow2", &["s2", "s4"], now)).unwrap();
+            // Append path: same flow ID, new telemetry
+            engine.insert_flow(create_test_flow("flow1", &["s3", "s5"], now + chrono::Duration::seconds(1))).unwrap();
+        }
+        
+        let engine = StorageEngine::open(wal_config(&dir)).unwrap();
+        assert_eq!(engine.flow_count(), 2);
+        
+        let flow1 = engine.get_flow("flow1").unwrap();
+        assert_eq!(flow1.hops.len(), 5);
+        assert_eq!(flow1.path.switches, vec!["s1", "s2", "s3", "s5"]);
+        
+        let through_s5 = engine.query(QueryBuilder::through_switch("s5")).unwrap();
+        assert_eq!(through_s5.flow_ids, vec!["flow1".to_string()]);
+        
+        let recent = engine.query(QueryBuilder::in_time_range(now - chrono::Duration::minutes(1), now + chrono::Duration::minutes(1))).unwrap();
+        assert_eq!(recent.total_count, 2);
+    }
+    
+    #[test]
+    fn test_snapshot_and_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot_path = dir.path().join("backup.snapshot");
+        let now = Utc::now();
+        
+        let source = StorageEngine::new();
+        source.insert_flow(create_test_flow("flow1", &["s1", "s2", "s3"], now)).unwrap();
+        source.insert_flow(create_test_flow("flow2", &["s2", "s4"], now)).unwrap();
+        let info = source.snapshot(&snapshot_path).unwrap();
+        assert_eq!(info.header.flow_count, 2);
+        assert_eq!(info.header.unique_switches, 4);
+        
+        // Restore replaces existing contents
+        let target = StorageEngine::new();
+        target.insert_flow(create_test_flow("stale", &["s9"], now)).unwrap();
+        target.restore(&snapshot_path).unwrap();
+        
+        assert_eq!(target.flow_count(), 2);
+        assert!(target.get_flow("stale").is_none());
+        assert_eq!(target.get_flow("flow1"), source.get_flow("flow1"));
+        assert!(target.query(QueryBuilder::through_switch("s9")).unwrap().is_empty());
+        assert_eq!(target.query(QueryBuilder::through_switch("s2")).unwrap().total_count, 2);
+    }
+    
+    #[test]
+    fn test_restore_rewrites_wal() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot_path = dir.path().join("backup.snapshot");
+        let now = Utc::now();
+        
+        let source = StorageEngine::new();
+        source.insert_flow(create_test_flow("flow1", &["s1", "s2"], now)).unwrap();
+        source.snapshot(&snapshot_path).unwrap();
+        
+        {
+            let engine = StorageEngine::open(wal_config(&dir)).unwrap();
+            engine.insert_flow(create_test_flow("stale", &["s9"], now)).unwrap();
+            engine.restore(&snapshot_path).unwrap();
+            engine.insert_flow(create_test_flow("flow2", &["s3"], now)).unwrap();
+        }
+        
+        let engine = StorageEngine::open(wal_config(&dir)).unwrap();
+        assert_eq!(engine.flow_count(), 2);
+        assert!(engine.get_flow("stale").is_none());
+        assert!(engine.get_flow("flow1").is_some());
+        assert!(engine.get_flow("flow2").is_some());
+    }
+    
+    #[test]
+    fn test_restore_during_inserts_keeps_counters_and_indexes_in_step() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot_path = dir.path().join("backup.snapshot");
+        let now = Utc::now();
+        
+        let source = StorageEngine::new();
+        for i in 0..20 {
+            source.insert_flow(create_test_flow(&format!("restored{}", i), &["s1", "s2"], now)).unwrap();
+        }
+        source.snapshot(&snapshot_path).unwrap();
+        
+        let wal_dir = tempfile::tempdir().unwrap();
+        for engine in [StorageEngine::with_config(EngineConfig { shard_count: 4, ..EngineConfig::default() }), StorageEngine::open(wal_config(&wal_dir)).unwrap()] {
+            let done = std::sync::atomic::AtomicBool::new(false);
+            std::thread::scope(|scope| {
+                for writer in 0..4 {
+                    let (engine, done) = (&engine, &done);
+                    scope.spawn(move || {
+                        let mut i = 0;
+                        while !done.load(Ordering::Relaxed) {
+                            engine.insert_flow(create_test_flow(&format!("flow-{}-{}", writer, i), &["s3"], now)).unwrap();
+                            i += 1;
+                        }
+                    });
+                }
+                
+                for _ in 0..20 {
+                    engine.restore(&snapshot_path).unwrap();
+                }
+                done.store(true, Ordering::Relaxed);
+            });
+            
+            // Every record is indexed and counted, whichever side of a restore its insert landed on
+            let flows = engine.all_flows();
+            assert_eq!(engine.flow_count(), flows.len());
+            assert_eq!(total_flow_refs(&engine), flows.len());
+            let footprint: usize = flows.iter().map(|flow| engine.flow_footprint(&StoredFlow::new(flow))).sum();
+            assert_eq!(engine.memory_usage_bytes(), footprint);
+            assert!((0..20).all(|i| engine.get_flow(&format!("restored{}", i)).is_some()));
+        }
+    }
+    
+    #[test]
+    fn test_remove_flow_cleans_indexes() {
+        let now = Utc::now();
+        let engine = StorageEngine::new();
+        engine.insert_flow(create_test_flow("flow1", &["s1", "s2"], now)).unwrap();
+        engine.insert_flow(create_test_flow("flow2", &["s2", "s3"], now)).unwrap();
+        // Append with an earlier start time moves flow1 to another time bucket
+        engine.insert_flow(create_test_flow("flow1", &["s4"], now - chrono::Duration::hours(1))).unwrap();
+        
+        let removed = engine.remove_flow("flow1").unwrap();
+        assert_eq!(removed.hops.len(), 3);
+        assert!(engine.get_flow("flow1").is_none());
+        assert!(matches!(engine.remove_flow("flow1"), Err(StorageError::FlowNotFound(_))));
+        
+        assert!(engine.query(QueryBuilder::through_switch("s1")).unwrap().is_empty());
+        assert!(engine.query(QueryBuilder::through_switch("s4")).unwrap().is_empty());
+        assert_eq!(engine.partition_count(), 1);
+        assert_eq!(total_flow_refs(&engine), 1);
+        let (_, partition) = engine.locate("flow2").unwrap();
+        assert_eq!(partition.read().unwrap().path_index().stats().unique_switches, 2);
+    }
+    
+    #[test]
+    fn test_remove_flows_matching() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = Utc::now();
+        
+        {
+            let engine = StorageEngine::open(wal_config(&dir)).unwrap();
+            engine.insert_flow(create_test_flow("flow1", &["s1", "s2"], now)).unwrap();
+            engine.insert_flow(create_test_flow("flow2", &["s2", "s3"], now)).unwrap();
+            engine.insert_flow(create_test_flow("flow3", &["s3", "s4"], now)).unwrap();
+            
+            let mut removed = engine.remove_flows_matching(QueryBuilder::through_switch("s2")).unwrap();
+            removed.sort();
+            assert_eq!(removed, vec!["flow1".to_string(), "flow2".to_string()]);
+        }
+        
+        // Deletions are replayed from the WAL
+        let engine = StorageEngine::open(wal_config(&dir)).unwrap();
+        assert_eq!(engine.flow_count(), 1);
+        assert!(engine.get_flow("flow3").is_some());
+        assert!(engine.query(QueryBuilder::through_switch("s2")).unwrap().is_empty());
+    }
+    
+    #[test]
+    fn test_sweep_expires_flows_past_retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = Utc::now();
+        let config = EngineConfig {
+            auto_cleanup_hours: Some(24),
+            ..wal_config(&dir)
+        };
+        
+        {
+            let engine = StorageEngine::open(config.clone()).unwrap();
+            engine.insert_flow(create_test_flow("old", &["s1", "s2"], now - chrono::Duration::hours(30))).unwrap();
+            engine.insert_flow(create_test_flow("recent", &["s1", "s3"], now - chrono::Duration::hours(1))).unwrap();
+            
+            // A per-flow policy can both extend and shorten retention
+            let mut kept = create_test_flow("kept", &["s4"], now - chrono::Duration::hours(30));
+            kept.retention_policy = Some("7d".to_string());
+            engine.insert_flow(kept).unwrap();
+            
+            let mut short = create_test_flow("short", &["s5"], now - chrono::Duration::minutes(10));
+            short.retention_policy = Some("5m".to_string());
+            engine.insert_flow(short).unwrap();
+            
+            // Fresh telemetry keeps an old flow alive
+            engine.insert_flow(create_test_flow("appended", &["s6"], now - chrono::Duration::hours(30))).unwrap();
+            engine.insert_flow(create_test_flow("appended", &["s7"], now - chrono::Duration::minutes(1))).unwrap();
+            
+            assert_eq!(engine.sweep_expired(now).unwrap(), 2);
+            assert_eq!(engine.sweep_expired(now).unwrap(), 0);
+            
+            let stats = engine.sweep_stats();
+            assert_eq!(stats.sweeps, 2);
+            assert_eq!(stats.flows_expired, 2);
+            assert_eq!(stats.last_sweep_expired, 0);
+            
+            assert!(engine.query(QueryBuilder::through_switch("s2")).unwrap().is_empty());
+            assert!(engine.query(QueryBuilder::through_switch("s5")).unwrap().is_empty());
+            assert_eq!(total_flow_refs(&engine), 3);
+        }
         
-        bytes
+        // Expirations are replayed from the WAL
+        let engine = StorageEngine::open(config).unwrap();
+        let mut remaining: Vec<_> = engine.query(QueryBuilder::new()).unwrap().flow_ids;
+        remaining.sort();
+        assert_eq!(remaining, vec!["appended", "kept", "recent"]);
     }
-}
-
-impl Default for StorageEngine {
-    fn default() -> Self {
-        Self::new()
+    
+    #[test]
+    fn test_sweep_without_retention_keeps_everything() {
+        let now = Utc::now();
+        let engine = StorageEngine::with_config(EngineConfig {
+            auto_cleanup_hours: None,
+            ..EngineConfig::default()
+        });
+        engine.insert_flow(create_test_flow("flow1", &["s1"], now - chrono::Duration::days(365))).unwrap();
+        
+        assert_eq!(engine.sweep_expired(now).unwrap(), 0);
+        assert_eq!(engine.flow_count(), 1);
+    }
+    
+    #[test]
+    fn test_evict_oldest_by_end_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = Utc::now();
+        let config = EngineConfig {
+            max_flows: Some(2),
+            eviction_policy: EvictionPolicy::EvictOldest,
+            ..wal_config(&dir)
+        };
+        
+        {
+            let engine = StorageEngine::open(config.clone()).unwrap();
+            // "early" starts first, but "late" was appended to and now ends last
+            engine.insert_flow(create_test_flow("early", &["s1"], now - chrono::Duration::hours(3))).unwrap();
+            engine.insert_flow(create_test_flow("late", &["s2"], now - chrono::Duration::hours(4))).unwrap();
+            engine.insert_flow(create_test_flow("late", &["s3"], now - chrono::Duration::hours(1))).unwrap();
+            
+            engine.insert_flow(create_test_flow("new", &["s4"], now)).unwrap();
+            assert!(engine.get_flow("early").is_none());
+            assert!(engine.get_flow("late").is_some());
+            assert_eq!(engine.eviction_count(), 1);
+            assert!(engine.query(QueryBuilder::through_switch("s1")).unwrap().is_empty());
+        }
+        
+        let engine = StorageEngine::open(config).unwrap();
+        assert_eq!(engine.flow_count(), 2);
+        assert!(engine.get_flow("early").is_none());
+    }
+    
+    #[test]
+    fn test_evict_least_recently_queried() {
+        let now = Utc::now();
+        let engine = StorageEngine::with_config(EngineConfig {
+            max_flows: Some(3),
+            eviction_policy: EvictionPolicy::EvictLeastRecentlyQueried,
+            ..EngineConfig::default()
+        });
+        engine.insert_flow(create_test_flow("flow1", &["s1"], now)).unwrap();
+        engine.insert_flow(create_test_flow("flow2", &["s2"], now)).unwrap();
+        engine.insert_flow(create_test_flow("flow3", &["s3"], now)).unwrap();
+        
+        engine.query(QueryBuilder::through_switch("s1")).unwrap();
+        engine.get_flow("flow2").unwrap();
+        // Monitoring reads do not count as queries
+        assert_eq!(engine.all_flows().len(), 3);
+        
+        engine.insert_flow(create_test_flow("flow4", &["s4"], now)).unwrap();
+        assert!(engine.get_flow("flow3").is_none());
+        
+        engine.insert_flow(create_test_flow("flow5", &["s5"], now)).unwrap();
+        assert!(engine.get_flow("flow1").is_none());
+        assert_eq!(engine.flow_count(), 3);
+        assert_eq!(engine.eviction_count(), 2);
+    }
+    
+    #[test]
+    fn test_memory_accounting_is_incremental() {
+        let now = Utc::now();
+        let engine = StorageEngine::new();
+        assert_eq!(engine.memory_usage_bytes(), 0);
+        
+        engine.insert_flow(create_test_flow("flow1", &["s1", "s2"], now)).unwrap();
+        engine.insert_flow(create_test_flow("flow2", &["s2", "s3"], now)).unwrap();
+        engine.insert_flow(create_test_flow("flow1", &["s4"], now)).unwrap();
+        
+        let recomputed = |engine: &StorageEngine| -> usize {
+            engine.all_flows().iter().map(|flow| engine.flow_footprint(&StoredFlow::new(flow))).sum()
+        };
+        assert_eq!(engine.memory_usage_bytes(), recomputed(&engine));
+        
+        // Switch IDs are interned and shared, but custom metrics grow the total accordingly
+        let before = engine.memory_usage_bytes();
+        let mut flow = create_test_flow("flow3", &[&"x".repeat(4096)], now);
+        flow.hops[0].metrics.add_custom_metric("trace".to_string(), serde_json::Value::String("y".repeat(4096)));
+        engine.insert_flow(flow).unwrap();
+        assert!(engine.memory_usage_bytes() - before > 4096);
+        assert!(engine.memory_usage_bytes() - before < 2 * 4096);
+        
+        engine.remove_flow("flow3").unwrap();
+        engine.remove_flow("flow1").unwrap();
+        assert_eq!(engine.memory_usage_bytes(), recomputed(&engine));
+        
+        engine.remove_flow("flow2").unwrap();
+        assert_eq!(engine.memory_usage_bytes(), 0);
+    }
+    
+    #[test]
+    fn test_compression_ratio_tracks_stored_flows() {
+        let now = Utc::now();
+        let engine = StorageEngine::new();
+        assert_eq!(engine.compression_ratio(), 1.0);
+        
+        let switches: Vec<String> = (0..64).map(|i| format!("s{}", i % 4)).collect();
+        let switches: Vec<&str> = switches.iter().map(String::as_str).collect();
+        engine.insert_flow(create_test_flow("flow1", &switches, now)).unwrap();
+        assert!(engine.compression_ratio() > 4.0);
+        
+        // Lazy decoding still returns the original telemetry
+        let flow = engine.get_flow("flow1").unwrap();
+        let mut expected = create_test_flow("flow1", &switches, now);
+        expected.stamp_ingest(flow.ingest_versions[0].ingested_at);
+        assert_eq!(flow, expected);
+        let query = QueryBuilder::new().with_metric_condition(MetricCondition::MaxQueueUtilGreaterThan(6.0));
+        assert_eq!(engine.query(query).unwrap().flow_ids, vec!["flow1"]);
+        
+        engine.remove_flow("flow1").unwrap();
+        assert_eq!(engine.compression_ratio(), 1.0);
+    }
+    
+    #[test]
+    fn test_memory_budget_evicts_or_pushes_back() {
+        let now = Utc::now();
+        let probe = StorageEngine::new();
+        let mut stored = create_test_flow("flow0", &["s1", "s2"], now);
+        stored.stamp_ingest(now);
+        let footprint = probe.flow_footprint(&StoredFlow::new(&stored));
+        
+        // Buffered change events would eat into the budget too
+        let config = EngineConfig {
+            max_memory_bytes: Some(footprint * 2),
+            change_buffer: 0,
+            ..EngineConfig::default()
+        };
+        
+        let rejecting = StorageEngine::with_config(config.clone());
+        rejecting.insert_flow(create_test_flow("flow1", &["s1", "s2"], now)).unwrap();
+        rejecting.insert_flow(create_test_flow("flow2", &["s1", "s2"], now)).unwrap();
+        assert!(matches!(
+            rejecting.insert_flow(create_test_flow("flow3", &["s1", "s2"], now)),
+            Err(StorageError::MemoryLimit { .. })
+        ));
+        
+        let evicting = StorageEngine::with_config(EngineConfig {
+            eviction_policy: EvictionPolicy::EvictOldest,
+            ..config
+        });
+        evicting.insert_flow(create_test_flow("flow1", &["s1", "s2"], now - chrono::Duration::minutes(2))).unwrap();
+        evicting.insert_flow(create_test_flow("flow2", &["s1", "s2"], now - chrono::Duration::minutes(1))).unwrap();
+        evicting.insert_flow(create_test_flow("flow3", &["s1", "s2"], now)).unwrap();
+        assert!(evicting.get_flow("flow1").is_none());
+        assert_eq!(evicting.flow_count(), 2);
+        assert!(evicting.memory_usage_bytes() <= footprint * 2);
+        
+        // An oversized flow cannot evict its way in
+        let mut huge = create_test_flow("huge", &["s1"], now);
+        huge.hops[0].metrics.add_custom_metric("trace".to_string(), serde_json::Value::String("x".repeat(footprint * 4)));
+        assert!(matches!(evicting.insert_flow(huge), Err(StorageError::MemoryLimit { .. })));
+    }
+    
+    #[test]
+    fn test_change_buffer_counts_towards_memory_budget() {
+        let now = Utc::now();
+        let mut big = create_test_flow("flow0", &["s1"], now);
+        big.hops[0].metrics.add_custom_metric("trace".to_string(), serde_json::Value::String("x".repeat(64 * 1024)));
+        
+        let probe = StorageEngine::new();
+        probe.insert_flow(big.clone()).unwrap();
+        let footprint = probe.memory_usage_bytes();
+        let buffered = probe.change_buffer_bytes();
+        assert!(buffered > 64 * 1024);
+        
+        // Two flows fit the budget, but not alongside both of their change events
+        let config = EngineConfig {
+            max_memory_bytes: Some(footprint * 2 + buffered / 2),
+            ..EngineConfig::default()
+        };
+        let buffering = StorageEngine::with_config(config.clone());
+        buffering.insert_flow(big.clone()).unwrap();
+        big.flow_id = "flow1".to_string();
+        assert!(matches!(buffering.insert_flow(big.clone()), Err(StorageError::MemoryLimit { .. })));
+        
+        let unbuffered = StorageEngine::with_config(EngineConfig { change_buffer: 0, ..config });
+        big.flow_id = "flow0".to_string();
+        unbuffered.insert_flow(big.clone()).unwrap();
+        big.flow_id = "flow1".to_string();
+        unbuffered.insert_flow(big).unwrap();
+        assert_eq!(unbuffered.change_buffer_bytes(), 0);
+    }
+    
+    #[test]
+    fn test_rejected_flows_are_not_logged() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = EngineConfig {
+            max_flows: Some(1),
+            ..wal_config(&dir)
+        };
+        
+        {
+            let engine = StorageEngine::open(config.clone()).unwrap();
+            engine.insert_flow(create_test_flow("flow1", &["s1", "s2"], Utc::now())).unwrap();
+            assert!(matches!(
+                engine.insert_flow(create_test_flow("flow2", &["s1", "s2"], Utc::now())),
+                Err(StorageError::StorageFull)
+            ));
+        }
+        
+        let engine = StorageEngine::open(config).unwrap();
+        assert_eq!(engine.flow_count(), 1);
+        assert!(engine.get_flow("flow2").is_none());
+    }
+    
+    fn segment_config(dir: &tempfile::TempDir) -> EngineConfig {
+        EngineConfig {
+            wal_path: Some(dir.path().join("intdb.wal")),
+            segment_dir: Some(dir.path().join("segments")),
+            cold_flow_age: Some(chrono::Duration::hours(1)),
+            auto_cleanup_hours: None,
+            ..EngineConfig::default()
+        }
+    }
+    
+    #[test]
+    fn test_cold_flows_move_to_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = StorageEngine::open(segment_config(&dir)).unwrap();
+        let now = Utc::now();
+        
+        engine.insert_flow(create_test_flow("old1", &["s1", "s2"], now - chrono::Duration::hours(3))).unwrap();
+        engine.insert_flow(create_test_flow("old2", &["s2", "s3"], now - chrono::Duration::hours(2))).unwrap();
+        engine.insert_flow(create_test_flow("recent", &["s1", "s3"], now)).unwrap();
+        let hot_bytes = engine.memory_usage_bytes();
+        
+        assert_eq!(engine.freeze_cold_flows(now).unwrap(), 2);
+        assert_eq!(engine.freeze_cold_flows(now).unwrap(), 0);
+        assert_eq!(engine.segment_count(), 1);
+        assert_eq!(engine.cold_flow_count(), 2);
+        assert_eq!(engine.flow_count(), 3);
+        assert!(engine.memory_usage_bytes() < hot_bytes);
+        
+        // Reads and queries see cold flows alongside hot ones, newest first
+        assert_eq!(engine.get_flow("old1").unwrap().path.switches, vec!["s1", "s2"]);
+        let result = engine.query(QueryBuilder::through_switch("s2")).unwrap();
+        assert_eq!(result.flow_ids, vec!["old2", "old1"]);
+        let result = engine.query(QueryBuilder::new().limit(2)).unwrap();
+        assert_eq!(result.flow_ids, vec!["recent", "old2"]);
+        assert_eq!(result.total_count, 3);
+        let ids = vec!["old2".to_string(), "recent".to_string(), "old1".to_string()];
+        let flows: Vec<_> = engine.get_flows(&ids).into_iter().map(|f| f.flow_id).collect();
+        assert_eq!(flows, ids);
+        
+        // Appending to a cold flow brings it back into memory
+        engine.insert_flow(create_test_flow("old1", &["s4"], now - chrono::Duration::minutes(5))).unwrap();
+        assert_eq!(engine.cold_flow_count(), 1);
+        assert_eq!(engine.get_flow("old1").unwrap().hops.len(), 3);
+        
+        // Cold flows can be deleted without touching the segment
+        engine.remove_flow("old2").unwrap();
+        assert!(engine.get_flow("old2").is_none());
+        assert_eq!(engine.query(QueryBuilder::through_switch("s3")).unwrap().flow_ids, vec!["recent"]);
+        
+        // The segment no longer holds any current flow, so the sweep drops it
+        engine.sweep_expired(now).unwrap();
+        assert_eq!(engine.segment_count(), 0);
+        assert!(segment::list_segments(dir.path().join("segments")).unwrap().is_empty());
+    }
+    
+    #[test]
+    fn test_freezing_during_appends_loses_no_hops() {
+        const WRITERS: usize = 4;
+        const REPORTS: usize = 100;
+        
+        let dir = tempfile::tempdir().unwrap();
+        let engine = StorageEngine::with_config(EngineConfig { wal_path: None, shard_count: 2, ..segment_config(&dir) });
+        let old = Utc::now() - chrono::Duration::hours(3);
+        
+        // Appends keep the flows cold, so every freeze races them
+        let done = std::sync::atomic::AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                while !done.load(Ordering::Relaxed) {
+                    engine.freeze_cold_flows(Utc::now()).unwrap();
+                }
+            });
+            let writers: Vec<_> = (0..WRITERS)
+                .map(|writer| {
+                    let engine = &engine;
+                    scope.spawn(move || {
+                        for report in 0..REPORTS {
+                            let flow_id = format!("flow{}", (writer + report) % 4);
+                            let start = old + chrono::Duration::milliseconds((writer * REPORTS + report) as i64);
+                            engine.insert_flow(create_test_flow(&flow_id, &["s1", "s2"], start)).unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for writer in writers {
+                writer.join().unwrap();
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+        
+        let ids: Vec<String> = (0..4).map(|i| format!("flow{}", i)).collect();
+        let total_hops: usize = engine.get_flows(&ids).iter().map(|flow| flow.hops.len()).sum();
+        assert_eq!(total_hops, WRITERS * REPORTS * 2);
+    }
+    
+    #[test]
+    fn test_segments_survive_wal_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = Utc::now();
+        
+        {
+            let engine = StorageEngine::open(segment_config(&dir)).unwrap();
+            engine.insert_flow(create_test_flow("old1", &["s1", "s2"], now - chrono::Duration::hours(3))).unwrap();
+            engine.insert_flow(create_test_flow("old2", &["s2", "s3"], now - chrono::Duration::hours(2))).unwrap();
+            engine.insert_flow(create_test_flow("old3", &["s3"], now - chrono::Duration::hours(2))).unwrap();
+            engine.freeze_cold_flows(now).unwrap();
+            
+            engine.remove_flow("old2").unwrap();
+            engine.insert_flow(create_test_flow("old3", &["s5"], now)).unwrap();
+        }
+        
+        let engine = StorageEngine::open(segment_config(&dir)).unwrap();
+        assert_eq!(engine.flow_count(), 2);
+        assert_eq!(engine.cold_flow_count(), 1);
+        assert!(engine.get_flow("old2").is_none());
+        assert_eq!(engine.get_flow("old1").unwrap().hops.len(), 2);
+        assert_eq!(engine.get_flow("old3").unwrap().hops.len(), 2);
+        assert_eq!(engine.query(QueryBuilder::through_switch("s5")).unwrap().flow_ids, vec!["old3"]);
+    }
+    
+    #[test]
+    fn test_compacted_wal_replays_to_the_same_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let wal_path = segment_config(&dir).wal_path.unwrap();
+        let now = Utc::now();
+        let ids: Vec<String> = ["old1", "old2", "old3", "recent"].iter().map(|id| id.to_string()).collect();
+        
+        let flows = {
+            let engine = StorageEngine::open(segment_config(&dir)).unwrap();
+            engine.insert_flow(create_test_flow("old1", &["s1", "s2"], now - chrono::Duration::hours(3))).unwrap();
+            engine.insert_flow(create_test_flow("old2", &["s2", "s3"], now - chrono::Duration::hours(2))).unwrap();
+            engine.insert_flow(create_test_flow("old3", &["s3"], now - chrono::Duration::hours(2))).unwrap();
+            for i in 0..3 {
+                engine.insert_flow(create_test_flow("recent", &["s4"], now + chrono::Duration::seconds(i))).unwrap();
+            }
+            engine.freeze_cold_flows(now).unwrap();
+            engine.remove_flow("old2").unwrap();
+            engine.insert_flow(create_test_flow("old3", &["s5"], now)).unwrap();
+            
+            let logged = WriteAheadLog::read(&wal_path).unwrap().len();
+            engine.compact_wal().unwrap();
+            assert!(WriteAheadLog::read(&wal_path).unwrap().len() < logged);
+            
+            // Later records apply on top of the compacted log
+            engine.insert_flow(create_test_flow("recent", &["s6"], now + chrono::Duration::seconds(5))).unwrap();
+            engine.get_flows(&ids)
+        };
+        
+        let engine = StorageEngine::open(segment_config(&dir)).unwrap();
+        assert_eq!(engine.get_flows(&ids), flows);
+        assert_eq!(engine.flow_count(), 3);
+        assert_eq!(engine.cold_flow_count(), 1);
+        assert_eq!(engine.get_flow("recent").unwrap().hops.len(), 4);
+        assert_eq!(engine.query(QueryBuilder::through_switch("s5")).unwrap().flow_ids, vec!["old3"]);
+    }
+    
+    #[test]
+    fn test_unreadable_segment_rejects_append_before_logging() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = segment_config(&dir);
+        let wal_path = config.wal_path.clone().unwrap();
+        let now = Utc::now();
+        
+        {
+            let engine = StorageEngine::open(segment_config(&dir)).unwrap();
+            engine.insert_flow(create_test_flow("old", &["s1"], now - chrono::Duration::hours(3))).unwrap();
+            engine.freeze_cold_flows(now).unwrap();
+            
+            // Keep the header and flow IDs, so the segment still loads, but break its columns
+            let (info, _) = segment::list_segments(dir.path().join("segments")).unwrap().remove(0);
+            let contents = std::fs::read_to_string(&info.path).unwrap();
+            let index: Vec<&str> = contents.lines().take(2).collect();
+            std::fs::write(&info.path, format!("{}\n{{}}\n", index.join("\n"))).unwrap();
+            
+            let logged = WriteAheadLog::read(&wal_path).unwrap().len();
+            let append = create_test_flow("old", &["s2"], now - chrono::Duration::minutes(5));
+            assert!(engine.insert_flow(append).is_err());
+            assert_eq!(WriteAheadLog::read(&wal_path).unwrap().len(), logged);
+        }
+        
+        // A record that can't be applied, as logged before inserts were checked, is skipped on replay
+        {
+            let (mut wal, _) = WriteAheadLog::open(&wal_path, WalSyncPolicy::EveryWrite).unwrap();
+            let append = create_test_flow("old", &["s2"], now - chrono::Duration::minutes(5));
+            wal.append(WalRecord::Insert { flow: append }).unwrap();
+        }
+        
+        let engine = StorageEngine::open(config).unwrap();
+        assert_eq!(engine.cold_flow_count(), 1);
+        engine.insert_flow(create_test_flow("new", &["s3"], now)).unwrap();
+        assert!(engine.get_flow("new").is_some());
+    }
+    
+    #[test]
+    fn test_snapshot_includes_cold_flows() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot_path = dir.path().join("backup.snapshot");
+        let now = Utc::now();
+        
+        {
+            let engine = StorageEngine::open(segment_config(&dir)).unwrap();
+            engine.insert_flow(create_test_flow("old", &["s1"], now - chrono::Duration::hours(3))).unwrap();
+            engine.insert_flow(create_test_flow("recent", &["s2"], now)).unwrap();
+            engine.freeze_cold_flows(now).unwrap();
+            assert_eq!(engine.snapshot(&snapshot_path).unwrap().header.flow_count, 2);
+            
+            // Restoring brings every flow back into memory and drops the segments
+            engine.restore(&snapshot_path).unwrap();
+            assert_eq!(engine.segment_count(), 0);
+            assert_eq!(engine.flow_count(), 2);
+        }
+        
+        let engine = StorageEngine::open(segment_config(&dir)).unwrap();
+        assert_eq!(engine.cold_flow_count(), 0);
+        assert_eq!(engine.get_flow("old").unwrap().hops.len(), 1);
+        assert_eq!(engine.flow_count(), 2);
+    }
+    
+    #[test]
+    fn test_sweep_drops_expired_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = EngineConfig {
+            auto_cleanup_hours: Some(2),
+            ..segment_config(&dir)
+        };
+        let engine = StorageEngine::open(config).unwrap();
+        let now = Utc::now();
+        
+        engine.insert_flow(create_test_flow("old", &["s1"], now - chrono::Duration::minutes(90))).unwrap();
+        engine.freeze_cold_flows(now).unwrap();
+        
+        assert_eq!(engine.sweep_expired(now).unwrap(), 0);
+        assert_eq!(engine.segment_count(), 1);
+        
+        assert_eq!(engine.sweep_expired(now + chrono::Duration::hours(1)).unwrap(), 1);
+        assert_eq!(engine.segment_count(), 0);
+        assert_eq!(engine.flow_count(), 0);
+        
+        let engine = StorageEngine::open(segment_config(&dir)).unwrap();
+        assert_eq!(engine.flow_count(), 0);
     }
-} 
\ No newline at end of file
+    
+    #[test]
+    fn test_flows_land_in_time_partitions() {
+        let start = TimeIndex::bucket_start(Utc::now(), 3600) - chrono::Duration::hours(5);
+        let engine = StorageEngine::new();
+        engine.insert_flow(create_test_flow("flow1", &["s1"], start + chrono::Duration::minutes(1))).unwrap();
+        engine.insert_flow(create_test_flow("flow2", &["s1"], start + chrono::Duration::minutes(59))).unwrap();
+        engine.insert_flow(create_test_flow("flow3", &["s2"], start + chrono::Duration::hours(2))).unwrap();
+        assert_eq!(engine.partition_count(), 2);
+        assert_eq!(engine.flow_count(), 3);
+        
+        // Only partitions covering the range are searched
+        let query = QueryBuilder::in_time_range(start, start + chrono::Duration::minutes(30));
+        let mut searched = Vec::new();
+        engine.for_each_partition_matching(&query, |partition| {
+            searched.push(partition.start());
+            Ok(())
+        }).unwrap();
+        assert!(!searched.is_empty());
+        assert!(searched.iter().all(|partition_start| *partition_start == start));
+        assert_eq!(engine.query(query).unwrap().flow_ids, vec!["flow1".to_string()]);
+        
+        let query = QueryBuilder::through_switch("s1").with_time_condition(TimeCondition::After(start + chrono::Duration::hours(1)));
+        assert!(engine.query(query).unwrap().is_empty());
+        assert_eq!(engine.query(QueryBuilder::new()).unwrap().total_count, 3);
+        
+        // An append with an earlier start time moves the flow, dropping the emptied partition
+        engine.insert_flow(create_test_flow("flow3", &["s3"], start + chrono::Duration::minutes(10))).unwrap();
+        assert_eq!(engine.partition_count(), 1);
+        assert_eq!(engine.flow_count(), 3);
+        assert_eq!(engine.get_flow("flow3").unwrap().hops.len(), 2);
+        assert_eq!(total_flow_refs(&engine), 3);
+        
+        engine.remove_flow("flow1").unwrap();
+        engine.remove_flow("flow2").unwrap();
+        engine.remove_flow("flow3").unwrap();
+        assert_eq!(engine.partition_count(), 0);
+        assert_eq!(engine.memory_usage_bytes(), 0);
+    }
+    
+    #[test]
+    fn test_sweep_drops_expired_partitions() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = EngineConfig { auto_cleanup_hours: Some(24), ..wal_config(&dir) };
+        let now = Utc::now();
+        let old = TimeIndex::bucket_start(now - chrono::Duration::hours(48), 3600);
+        
+        {
+            let engine = StorageEngine::open(config.clone()).unwrap();
+            engine.insert_flow(create_test_flow("old1", &["s1"], old)).unwrap();
+            engine.insert_flow(create_test_flow("old2", &["s2"], old + chrono::Duration::minutes(30))).unwrap();
+            engine.insert_flow(create_test_flow("fresh", &["s3"], now)).unwrap();
+            assert_eq!(engine.partition_count(), 2);
+            
+            assert_eq!(engine.sweep_expired(now).unwrap(), 2);
+            assert_eq!(engine.partition_count(), 1);
+            assert_eq!(engine.flow_count(), 1);
+        }
+        
+        // The dropped partition stays dropped after replay
+        let engine = StorageEngine::open(config).unwrap();
+        assert_eq!(engine.partition_count(), 1);
+        assert!(engine.get_flow("old1").is_none());
+        assert!(engine.get_flow("fresh").is_some());
+    }
+    
+    #[test]
+    fn test_concurrent_writers_across_shards() {
+        let engine = Arc::new(StorageEngine::with_config(EngineConfig { shard_count: 8, ..EngineConfig::default() }));
+        let now = Utc::now();
+        
+        std::thread::scope(|scope| {
+            for writer in 0..4 {
+                let engine = engine.clone();
+                scope.spawn(move || {
+                    for i in 0..50 {
+                        let flow_id = format!("flow-{}-{}", writer, i);
+                        engine.insert_flow(create_test_flow(&flow_id, &["s1", "s2"], now)).unwrap();
+                        engine.insert_flow(create_test_flow(&flow_id, &["s3"], now)).unwrap();
+                    }
+                });
+            }
+        });
+        
+        assert_eq!(engine.flow_count(), 200);
+        assert_eq!(engine.partition_count(), 1);
+        assert!(engine.shards.iter().filter(|shard| shard.flow_count() > 0).count() > 1);
+        assert_eq!(engine.query(QueryBuilder::through_switch("s3")).unwrap().total_count, 200);
+        assert_eq!(engine.get_flow("flow-3-49").unwrap().hops.len(), 3);
+    }
+    
+    #[test]
+    fn test_query_order_is_independent_of_sharding() {
+        let now = Utc::now();
+        let mut pages = Vec::new();
+        for shard_count in [1, 7] {
+            let engine = StorageEngine::with_config(EngineConfig { shard_count, ..EngineConfig::default() });
+            for i in 0..20 {
+                let start = now - chrono::Duration::seconds(i % 4);
+                engine.insert_flow(create_test_flow(&format!("flow{:02}", i), &["s1"], start)).unwrap();
+            }
+            
+            let result = engine.query(QueryBuilder::through_switch("s1").limit(5).skip(5)).unwrap();
+            assert_eq!(result.total_count, 20);
+            pages.push(result.flow_ids);
+        }
+        
+        assert_eq!(pages[0], pages[1]);
+        assert_eq!(pages[0], vec!["flow01", "flow05", "flow09", "flow13", "flow17"]);
+    }
+    
+    #[test]
+    fn test_concurrent_appends_lose_no_hops() {
+        const WRITERS: usize = 8;
+        const REPORTS: usize = 100;
+        
+        let dir = tempfile::tempdir().unwrap();
+        let now = Utc::now();
+        for engine in [StorageEngine::with_config(EngineConfig { shard_count: 2, ..EngineConfig::default() }), StorageEngine::open(wal_config(&dir)).unwrap()] {
+            std::thread::scope(|scope| {
+                for writer in 0..WRITERS {
+                    let engine = &engine;
+                    scope.spawn(move || {
+                        for report in 0..REPORTS {
+                            // Every report goes to one of two hot flows, two hops each
+                            let flow_id = format!("flow{}", (writer + report) % 2);
+                            let start = now + chrono::Duration::milliseconds((writer * REPORTS + report) as i64);
+                            engine.insert_flow(create_test_flow(&flow_id, &["s1", "s2"], start)).unwrap();
+                        }
+                    });
+                }
+            });
+            
+            let flows = engine.get_flows(&["flow0".to_string(), "flow1".to_string()]);
+            let total_hops: usize = flows.iter().map(|flow| flow.hops.len()).sum();
+            assert_eq!(total_hops, WRITERS * REPORTS * 2);
+            for flow in &flows {
+                let indexes: HashSet<u32> = flow.hops.iter().map(|hop| hop.hop_index).collect();
+                assert_eq!(indexes.len(), flow.hops.len());
+            }
+            assert_eq!(engine.flow_count(), 2);
+            assert_eq!(total_flow_refs(&engine), 2);
+        }
+        
+        // Replay reaches the same flows
+        let engine = StorageEngine::open(wal_config(&dir)).unwrap();
+        let total_hops: usize = engine.all_flows().iter().map(|flow| flow.hops.len()).sum();
+        assert_eq!(total_hops, WRITERS * REPORTS * 2);
+    }
+    
+    #[test]
+    fn test_logged_writes_to_other_shards_proceed_while_one_waits() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = StorageEngine::open(EngineConfig { shard_count: 2, ..wal_config(&dir) }).unwrap();
+        let now = Utc::now();
+        let in_shard = |shard| (0..).map(|i| format!("flow{}", i)).find(|id| shard_index(id, 2) == shard).unwrap();
+        let (stalled, free) = (in_shard(0), in_shard(1));
+        
+        std::thread::scope(|scope| {
+            // A writer waiting on shard 0 holds no lock the other shard needs
+            let writer = engine.shards[0].lock_writes();
+            let engine = &engine;
+            let blocked = scope.spawn(|| engine.insert_flow(create_test_flow(&stalled, &["s1"], now)).unwrap());
+            std::thread::sleep(Duration::from_millis(50));
+            
+            let (sender, receiver) = std::sync::mpsc::channel();
+            scope.spawn(move || {
+                engine.insert_flow(create_test_flow(&free, &["s2"], now)).unwrap();
+                sender.send(()).unwrap();
+            });
+            let finished = receiver.recv_timeout(Duration::from_secs(5)).is_ok();
+            assert!(!blocked.is_finished());
+            drop(writer);
+            assert!(finished);
+        });
+        
+        let engine = StorageEngine::open(wal_config(&dir)).unwrap();
+        assert_eq!(engine.flow_count(), 2);
+    }
+    
+    #[test]
+    fn test_queries_see_flows_moving_between_partitions() {
+        const FLOWS: usize = 20;
+        
+        let engine = StorageEngine::with_config(EngineConfig { shard_count: 2, ..EngineConfig::default() });
+        let now = Utc::now();
+        for i in 0..FLOWS {
+            engine.insert_flow(create_test_flow(&format!("flow{}", i), &["s1"], now)).unwrap();
+        }
+        
+        let done = std::sync::atomic::AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                // Each round starts every flow an hour earlier, moving it to another partition
+                for round in 1..=10 {
+                    for i in 0..FLOWS {
+                        let start = now - chrono::Duration::hours(round);
+                        engine.insert_flow(create_test_flow(&format!("flow{}", i), &["s1"], start)).unwrap();
+                    }
+                }
+                done.store(true, Ordering::Relaxed);
+            });
+            
+            while !done.load(Ordering::Relaxed) {
+                assert_eq!(engine.query(QueryBuilder::through_switch("s1")).unwrap().total_count, FLOWS);
+            }
+        });
+        
+        assert_eq!(engine.partition_count(), 1);
+        assert_eq!(engine.get_flow("flow0").unwrap().hops.len(), 11);
+    }
+    
+    fn file_backend_config(dir: &tempfile::TempDir) -> EngineConfig {
+        EngineConfig {
+            backend: BackendKind::File(dir.path().join("flows")),
+            ..EngineConfig::default()
+        }
+    }
+    
+    #[test]
+    fn test_file_backend_rebuilds_indexes_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = Utc::now();
+        
+        {
+            let engine = StorageEngine::open(file_backend_config(&dir)).unwrap();
+            assert_eq!(engine.backend_name(), "file");
+            engine.insert_flow(create_test_flow("flow1", &["s1", "s2"], now)).unwrap();
+            engine.insert_flow(create_test_flow("flow1", &["s2"], now)).unwrap();
+            engine.insert_flow(create_test_flow("flow2", &["s3"], now - chrono::Duration::hours(2))).unwrap();
+            engine.insert_flow(create_test_flow("flow3", &["s3"], now)).unwrap();
+            engine.remove_flow("flow3").unwrap();
+        }
+        
+        let engine = StorageEngine::open(file_backend_config(&dir)).unwrap();
+        assert_eq!(engine.flow_count(), 2);
+        assert_eq!(engine.get_flow("flow1").unwrap().hops.len(), 3);
+        assert!(engine.get_flow("flow3").is_none());
+        assert_eq!(engine.query(QueryBuilder::through_switch("s3")).unwrap().flow_ids, vec!["flow2"]);
+        assert_eq!(engine.query(QueryBuilder::new()).unwrap().flow_ids, vec!["flow1", "flow2"]);
+    }
+    
+    #[test]
+    fn test_file_backend_rejects_wal() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = EngineConfig {
+            wal_path: Some(dir.path().join("intdb.wal")),
+            ..file_backend_config(&dir)
+        };
+        assert!(matches!(StorageEngine::open(config), Err(StorageError::Backend(_))));
+    }
+    
+    #[test]
+    fn test_read_only_replica_of_data_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = Utc::now();
+        {
+            let engine = StorageEngine::open(wal_config(&dir)).unwrap();
+            engine.insert_flow(create_test_flow("flow1", &["s1", "s2"], now)).unwrap();
+            engine.insert_flow(create_test_flow("flow2", &["s2"], now)).unwrap();
+        }
+        let wal_len = fs::metadata(dir.path().join("intdb.wal")).unwrap().len();
+        
+        let engine = StorageEngine::open(EngineConfig { read_only: true, ..wal_config(&dir) }).unwrap();
+        assert!(engine.is_read_only());
+        assert_eq!(engine.query(QueryBuilder::through_switch("s2")).unwrap().total_count, 2);
+        assert!(engine.get_flow("flow1").is_some());
+        
+        assert!(matches!(engine.insert_flow(create_test_flow("flow3", &["s1"], now)), Err(StorageError::ReadOnly)));
+        assert!(matches!(engine.remove_flow("flow1"), Err(StorageError::ReadOnly)));
+        assert!(matches!(engine.remove_flows_matching(QueryBuilder::new()), Err(StorageError::ReadOnly)));
+        assert!(matches!(engine.sweep_expired(now), Err(StorageError::ReadOnly)));
+        assert!(matches!(engine.snapshot(dir.path().join("copy.snap")), Err(StorageError::ReadOnly)));
+        assert_eq!(fs::metadata(dir.path().join("intdb.wal")).unwrap().len(), wal_len);
+        assert_eq!(engine.flow_count(), 2);
+    }
+    
+    #[test]
+    fn test_open_snapshot_is_read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("incident.snap");
+        let engine = StorageEngine::new();
+        engine.insert_flow(create_test_flow("flow1", &["s1"], Utc::now())).unwrap();
+        engine.snapshot(&path).unwrap();
+        
+        let replica = StorageEngine::open(EngineConfig { open_snapshot: Some(path.clone()), ..wal_config(&dir) }).unwrap();
+        assert!(replica.is_read_only());
+        assert_eq!(replica.query(QueryBuilder::through_switch("s1")).unwrap().flow_ids, vec!["flow1"]);
+        assert!(matches!(replica.restore(&path), Err(StorageError::ReadOnly)));
+        assert!(!dir.path().join("intdb.wal").exists());
+    }
+    
+    #[test]
+    fn test_change_feed_reports_writes_and_removals() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = Utc::now();
+        let config = EngineConfig {
+            max_flows: Some(2),
+            eviction_policy: EvictionPolicy::EvictOldest,
+            ..wal_config(&dir)
+        };
+        
+        {
+            let engine = StorageEngine::open(config.clone()).unwrap();
+            let subscription = engine.subscribe_changes(0);
+            
+            engine.insert_flow(create_test_flow("old", &["s1"], now - chrono::Duration::hours(30))).unwrap();
+            engine.insert_flow(create_test_flow("flow1", &["s1"], now)).unwrap();
+            engine.insert_flow(create_test_flow("flow1", &["s2"], now)).unwrap();
+            engine.insert_flow(create_test_flow("flow2", &["s3"], now)).unwrap();
+            engine.remove_flow("flow1").unwrap();
+            
+            let events: Vec<ChangeEvent> = subscription.live.try_iter().collect();
+            let summary: Vec<(u64, ChangeKind, &str)> = events.iter().map(|e| (e.seq, e.kind, e.flow_id.as_str())).collect();
+            assert_eq!(summary, vec![
+                (1, ChangeKind::Created, "old"),
+                (2, ChangeKind::Created, "flow1"),
+                (3, ChangeKind::Appended, "flow1"),
+                (4, ChangeKind::Evicted, "old"),
+                (5, ChangeKind::Created, "flow2"),
+                (6, ChangeKind::Deleted, "flow1"),
+            ]);
+            
+            // Appends carry only the new telemetry
+            assert_eq!(events[2].telemetry.as_ref().unwrap().path.switches, vec!["s2"]);
+            
+            let mut expiring = create_test_flow("expiring", &["s4"], now - chrono::Duration::minutes(10));
+            expiring.retention_policy = Some("5m".to_string());
+            engine.insert_flow(expiring).unwrap();
+            engine.sweep_expired(now).unwrap();
+            
+            let page = engine.poll_changes(6, 10, Duration::from_millis(10));
+            let kinds: Vec<ChangeKind> = page.events.iter().map(|e| e.kind).collect();
+            assert_eq!(kinds, vec![ChangeKind::Created, ChangeKind::Expired]);
+            assert_eq!(page.last_seq, 8);
+        }
+        
+        // Replayed records are not reported again
+        let engine = StorageEngine::open(config).unwrap();
+        assert_eq!(engine.flow_count(), 1);
+        assert_eq!(engine.last_change_seq(), 0);
+    }
+    
+    #[test]
+    fn test_query_as_of_past_ingest_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = Utc::now();
+        let s1_s2 = || QueryBuilder::exact_path(NetworkPath::new(vec!["s1".to_string(), "s2".to_string()]));
+        
+        let before_appends = {
+            let engine = StorageEngine::open(segment_config(&dir)).unwrap();
+            engine.insert_flow(create_test_flow("flow1", &["s1", "s2"], now)).unwrap();
+            engine.insert_flow(create_test_flow("cold", &["s1", "s2"], now - chrono::Duration::hours(2))).unwrap();
+            assert_eq!(engine.freeze_cold_flows(now).unwrap(), 1);
+            let before_appends = Utc::now();
+            std::thread::sleep(std::time::Duration::from_millis(5));
+            
+            // A late report for flow1 starting earlier, and a new flow
+            engine.insert_flow(create_test_flow("flow1", &["s3"], now - chrono::Duration::minutes(1))).unwrap();
+            engine.insert_flow(create_test_flow("flow2", &["s1", "s2"], now)).unwrap();
+            before_appends
+        };
+        
+        // Versions survive WAL replay and segments
+        let engine = StorageEngine::open(segment_config(&dir)).unwrap();
+        assert_eq!(engine.query(s1_s2()).unwrap().flow_ids, vec!["flow2", "cold"]);
+        assert_eq!(engine.query(s1_s2().as_of(before_appends)).unwrap().flow_ids, vec!["flow1", "cold"]);
+        assert_eq!(engine.query(QueryBuilder::through_switch("s3").as_of(before_appends)).unwrap().total_count, 0);
+        assert_eq!(engine.query(QueryBuilder::new().as_of(now - chrono::Duration::hours(1))).unwrap().total_count, 0);
+        
+        let flow1 = engine.get_flow("flow1").unwrap();
+        assert_eq!(flow1.ingest_versions.iter().map(|v| v.hop_count).collect::<Vec<_>>(), vec![2, 3]);
+        let earlier = flow1.as_of(before_appends).unwrap();
+        assert_eq!(earlier.hops.len(), 2);
+        assert_eq!(earlier.start_time, now);
+        assert_eq!(earlier.path.switches, vec!["s1", "s2"]);
+        assert_eq!(flow1.as_of(Utc::now()).unwrap(), flow1);
+        assert!(engine.get_flow("flow2").unwrap().as_of(before_appends).is_none());
+    }
+    
+    #[test]
+    fn test_loads_data_written_before_schema_versions() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/v1");
+        let dir = tempfile::tempdir().unwrap();
+        fs::copy(fixtures.join("intdb.wal"), dir.path().join("intdb.wal")).unwrap();
+        fs::create_dir(dir.path().join("segments")).unwrap();
+        for entry in fs::read_dir(fixtures.join("segments")).unwrap() {
+            let path = entry.unwrap().path();
+            fs::copy(&path, dir.path().join("segments").join(path.file_name().unwrap())).unwrap();
+        }
+        
+        // WAL records and a segment in the unversioned format
+        let engine = StorageEngine::open(segment_config(&dir)).unwrap();
+        assert_eq!(engine.flow_count(), 2);
+        assert_eq!(engine.cold_flow_count(), 1);
+        let flow1 = engine.get_flow("flow1").unwrap();
+        assert_eq!(flow1.path.switches, vec!["s1", "s2", "s3", "s5"]);
+        assert!(flow1.ingest_versions.is_empty());
+        assert_eq!(engine.query(QueryBuilder::through_switch("s4")).unwrap().flow_ids, vec!["cold"]);
+        assert!(engine.get_flow("gone").is_none());
+        
+        // Appends and new segments are written in the current format alongside the old ones
+        engine.insert_flow(create_test_flow("cold", &["s6"], Utc::now() - chrono::Duration::hours(4))).unwrap();
+        assert_eq!(engine.freeze_cold_flows(Utc::now()).unwrap(), 2);
+        drop(engine);
+        let engine = StorageEngine::open(segment_config(&dir)).unwrap();
+        assert_eq!(engine.get_flow("cold").unwrap().ingest_versions.len(), 1);
+        assert_eq!(engine.get_flow("flow1").unwrap().hops.len(), 4);
+        
+        let restored = StorageEngine::new();
+        let info = restored.restore(fixtures.join("intdb.snapshot")).unwrap();
+        assert_eq!(info.header.flow_count, 2);
+        assert_eq!(restored.get_flow("flow1").unwrap().hops.len(), 4);
+        assert_eq!(restored.get_flow("cold").unwrap().path.switches, vec!["s1", "s4"]);
+    }
+    
+    #[test]
+    fn test_hops_at_switch_by_hop_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = StorageEngine::open(segment_config(&dir)).unwrap();
+        let now = Utc::now();
+        let ids = |(hops, _): (Vec<SwitchHop>, usize)| hops.into_iter().map(|hop| hop.flow_id).collect::<Vec<_>>();
+        
+        engine.insert_flow(create_test_flow("old", &["s1", "s2"], now - chrono::Duration::hours(3))).unwrap();
+        engine.insert_flow(create_test_flow("long", &["s1"], now - chrono::Duration::hours(3))).unwrap();
+        assert_eq!(engine.freeze_cold_flows(now).unwrap(), 2);
+        
+        // "long" started hours ago but reaches s2 only now
+        engine.insert_flow(create_test_flow("long", &["s2"], now - chrono::Duration::minutes(1))).unwrap();
+        engine.insert_flow(create_test_flow("recent", &["s3", "s2"], now - chrono::Duration::minutes(2))).unwrap();
+        
+        let recent = (now - chrono::Duration::minutes(10), now);
+        let (hops, total_count) = engine.hops_at_switch("s2", recent.0, recent.1, 10).unwrap();
+        assert!(hops.iter().all(|hop| hop.hop.switch_id == "s2"));
+        assert_eq!(total_count, 2);
+        assert_eq!(ids((hops, total_count)), vec!["recent", "long"]);
+        assert!(engine.hops_at_switch("s1", recent.0, recent.1, 10).unwrap().0.is_empty());
+        
+        // Cold flows are read from their segment
+        let earlier = (now - chrono::Duration::hours(4), now - chrono::Duration::hours(2));
+        assert_eq!(ids(engine.hops_at_switch("s2", earlier.0, earlier.1, 10).unwrap()), vec!["old"]);
+        assert_eq!(ids(engine.hops_at_switch("s1", earlier.0, earlier.1, 10).unwrap()), vec!["long", "old"]);
+        
+        // Only the oldest hops are kept, ties going to the lower flow ID, but all are counted
+        assert_eq!(engine.hops_at_switch("s1", earlier.0, earlier.1, 1).unwrap().1, 2);
+        assert_eq!(ids(engine.hops_at_switch("s1", earlier.0, earlier.1, 1).unwrap()), vec!["long"]);
+        assert_eq!(ids(engine.hops_at_switch("s2", recent.0, recent.1, 1).unwrap()), vec!["recent"]);
+        assert!(engine.hops_at_switch("s2", recent.0, recent.1, 0).unwrap().0.is_empty());
+        
+        let stats = engine.switch_hop_stats(recent.0, recent.1).unwrap();
+        assert_eq!(stats.keys().collect::<Vec<_>>(), vec!["s2", "s3"]);
+        assert_eq!(stats["s2"].hops, 2);
+        assert_eq!(stats["s2"].avg_delay_ns(), Some(50.0));
+        
+        engine.remove_flow("recent").unwrap();
+        assert_eq!(ids(engine.hops_at_switch("s2", recent.0, recent.1, 10).unwrap()), vec!["long"]);
+    }
+    
+    #[test]
+    fn test_time_conditions_match_flow_lifetime() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = StorageEngine::open(segment_config(&dir)).unwrap();
+        let now = Utc::now();
+        let ago = |minutes: i64| now - chrono::Duration::minutes(minutes);
+        let ids = |query: QueryBuilder| engine.query(query).unwrap().flow_ids;
+        
+        // A frozen flow active from 5h to 3h ago
+        engine.insert_flow(create_test_flow("cold", &["s1"], ago(300))).unwrap();
+        engine.insert_flow(create_test_flow("cold", &["s2"], ago(180))).unwrap();
+        assert_eq!(engine.freeze_cold_flows(now).unwrap(), 1);
+        
+        // A flow that started 2h ago and only later gets more telemetry
+        engine.insert_flow(create_test_flow("long", &["s1"], ago(120))).unwrap();
+        engine.insert_flow(create_test_flow("short", &["s3"], ago(100))).unwrap();
+        let window = QueryBuilder::in_time_range(ago(30), ago(20));
+        assert!(ids(window.clone()).is_empty());
+        
+        engine.insert_flow(create_test_flow("long", &["s2"], ago(10))).unwrap();
+        assert_eq!(ids(window), vec!["long"]);
+        assert_eq!(ids(QueryBuilder::in_last_minutes(15)), vec!["long"]);
+        assert_eq!(ids(QueryBuilder::new().with_time_condition(TimeCondition::Before(ago(110)))), vec!["long", "cold"]);
+        assert_eq!(ids(QueryBuilder::new().with_time_condition(TimeCondition::Before(ago(100)))), vec!["short", "long", "cold"]);
+        assert_eq!(ids(QueryBuilder::in_time_range(ago(240), ago(200))), vec!["cold"]);
+        assert!(ids(QueryBuilder::in_time_range(ago(170), ago(130))).is_empty());
+    }
+    
+    #[test]
+    fn test_long_lived_flows_skip_time_buckets() {
+        let engine = StorageEngine::with_config(EngineConfig {
+            time_index_max_span: 5,
+            max_flows: Some(3),
+            eviction_policy: EvictionPolicy::EvictOldest,
+            ..EngineConfig::default()
+        });
+        let now = Utc::now();
+        let ago = |minutes: i64| now - chrono::Duration::minutes(minutes);
+        let ids = |query: QueryBuilder| engine.query(query).unwrap().flow_ids;
+        
+        // A switch clock years ahead stretches the flow far past the span limit
+        let mut skewed = create_test_flow("skewed", &["s1", "s2"], ago(30));
+        skewed.hops[1].timestamp = now + chrono::Duration::days(365 * 50);
+        skewed.end_time = skewed.hops[1].timestamp;
+        engine.insert_flow(skewed).unwrap();
+        engine.insert_flow(create_test_flow("skewed", &["s3"], ago(25))).unwrap();
+        engine.insert_flow(create_test_flow("recent", &["s1"], ago(1))).unwrap();
+        
+        // Active for hours, but finished before either of the others
+        let mut stale = create_test_flow("stale", &["s1", "s2"], ago(600));
+        stale.hops[1].timestamp = ago(50);
+        stale.end_time = ago(50);
+        engine.insert_flow(stale).unwrap();
+        
+        let mut time_buckets = 0;
+        for shard in &engine.shards {
+            for partition in shard.partitions().values() {
+                time_buckets += partition.read().unwrap().time_index().stats().bucket_count;
+            }
+        }
+        assert_eq!(time_buckets, 1);
+        assert_eq!(total_flow_refs(&engine), 3);
+        
+        assert_eq!(ids(QueryBuilder::in_time_range(ago(20), ago(10))), vec!["skewed"]);
+        assert_eq!(ids(QueryBuilder::in_last_minutes(5)), vec!["recent", "skewed"]);
+        assert_eq!(ids(QueryBuilder::new().with_time_condition(TimeCondition::Before(ago(10)))), vec!["skewed", "stale"]);
+        
+        // Long-lived flows are still ranked by end time for eviction
+        engine.insert_flow(create_test_flow("new", &["s1"], now)).unwrap();
+        assert!(engine.get_flow("stale").is_none());
+        assert!(engine.get_flow("recent").is_some());
+        
+        engine.remove_flow("skewed").unwrap();
+        assert_eq!(total_flow_refs(&engine), 2);
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+/// What `insert_flow` does when a new flow arrives and `max_flows` is reached
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    /// Reject the new flow with `StorageFull`
+    #[default]
+    Reject,
+    
+    /// Evict the flow with the oldest `end_time`
+    EvictOldest,
+    
+    /// Evict the flow that was least recently queried (or inserted)
+    EvictLeastRecentlyQueried,
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+    
+    /// Parse `reject`, `oldest` or `lru`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "oldest" => Ok(Self::EvictOldest),
+            "lru" => Ok(Self::EvictLeastRecentlyQueried),
+            _ => Err(format!("Unknown eviction policy: {}", s)),
+        }
+    }
+}
+
+/// Recency order of flow accesses for least-recently-queried eviction
+#[derive(Debug, Default)]
+pub struct AccessTracker {
+    /// Monotonic access counter
+    tick: u64,
+    
+    /// Last access tick per flow
+    last_access: HashMap<String, u64>,
+    
+    /// Flows ordered by last access tick
+    order: BTreeMap<u64, String>,
+}
+
+impl AccessTracker {
+    /// Create an empty tracker
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Mark a flow as accessed now
+    pub fn touch(&mut self, flow_id: &str) {
+        self.tick += 1;
+        
+        match self.last_access.get_mut(flow_id) {
+            Some(last) => {
+                let id = self.order.remove(last).unwrap_or_else(|| flow_id.to_string());
+                *last = self.tick;
+                self.order.insert(self.tick, id);
+            }
+            None => {
+                self.last_access.insert(flow_id.to_string(), self.tick);
+                self.order.insert(self.tick, flow_id.to_string());
+            }
+        }
+    }
+    
+    /// Stop tracking a flow
+    pub fn remove(&mut self, flow_id: &str) {
+        if let Some(last) = self.last_access.remove(flow_id) {
+            self.order.remove(&last);
+        }
+    }
+    
+    /// Forget every flow
+    pub fn clear(&mut self) {
+        self.last_access.clear();
+        self.order.clear();
+    }
+    
+    /// Get the least recently accessed flow
+    pub fn least_recent(&self) -> Option<&str> {
+        self.iter().next()
+    }
+    
+    /// Iterate over tracked flows, least recently accessed first
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.order.values().map(String::as_str)
+    }
+    
+    /// Get the number of tracked flows
+    pub fn len(&self) -> usize {
+        self.last_access.len()
+    }
+    
+    /// Check whether no flows are tracked
+    pub fn is_empty(&self) -> bool {
+        self.last_access.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_least_recent_follows_touches() {
+        let mut tracker = AccessTracker::new();
+        tracker.touch("flow1");
+        tracker.touch("flow2");
+        tracker.touch("flow3");
+        assert_eq!(tracker.least_recent(), Some("flow1"));
+        
+        tracker.touch("flow1");
+        assert_eq!(tracker.least_recent(), Some("flow2"));
+        assert_eq!(tracker.len(), 3);
+        
+        tracker.remove("flow2");
+        assert_eq!(tracker.least_recent(), Some("flow3"));
+        
+        tracker.clear();
+        assert!(tracker.is_empty());
+        assert_eq!(tracker.least_recent(), None);
+    }
+    
+    #[test]
+    fn test_eviction_policy_parsing() {
+        assert_eq!("reject".parse::<EvictionPolicy>().unwrap(), EvictionPolicy::Reject);
+        assert_eq!("oldest".parse::<EvictionPolicy>().unwrap(), EvictionPolicy::EvictOldest);
+        assert_eq!("lru".parse::<EvictionPolicy>().unwrap(), EvictionPolicy::EvictLeastRecentlyQueried);
+        assert!("random".parse::<EvictionPolicy>().is_err());
+    }
+}
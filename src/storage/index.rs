@@ -1,18 +1,121 @@
-use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+use std::mem::size_of;
 use chrono::{DateTime, Utc};
+use roaring::{RoaringBitmap, RoaringTreemap};
 use crate::models::{NetworkPath, Flow};
+use crate::storage::{PathId, SwitchId};
 
-/// Path prefix tree for efficient path-based queries
+/// Dense internal handle of a stored flow, assigned by its partition
+pub type FlowHandle = u32;
+
+/// Trie over switch sequences; each node holds the flows whose inserted
+/// sequences pass through it
+///
+/// A node's flows are a superset of its children's, so a node left without
+/// flows is pruned together with its subtree.
+#[derive(Debug, Clone, Default)]
+struct PathTrie {
+    root: PathTrieNode,
+}
+
+#[derive(Debug, Clone, Default)]
+struct PathTrieNode {
+    flows: RoaringBitmap,
+    children: HashMap<SwitchId, PathTrieNode>,
+}
+
+impl PathTrie {
+    /// Record a flow under every prefix of `sequence`
+    fn insert(&mut self, sequence: impl IntoIterator<Item = SwitchId>, handle: FlowHandle) {
+        let mut node = &mut self.root;
+        for switch in sequence {
+            node = node.children.entry(switch).or_default();
+            node.flows.insert(handle);
+        }
+    }
+    
+    /// Drop a flow from every prefix of `sequence`
+    fn remove(&mut self, sequence: &[SwitchId], handle: FlowHandle) {
+        Self::remove_below(&mut self.root, sequence, handle);
+    }
+    
+    fn remove_below(node: &mut PathTrieNode, sequence: &[SwitchId], handle: FlowHandle) {
+        let Some((switch, rest)) = sequence.split_first() else {
+            return;
+        };
+        if let Some(child) = node.children.get_mut(switch) {
+            child.flows.remove(handle);
+            Self::remove_below(child, rest, handle);
+            if child.flows.is_empty() {
+                node.children.remove(switch);
+            }
+        }
+    }
+    
+    /// Get the flows recorded under `sequence`
+    fn find(&self, sequence: impl IntoIterator<Item = SwitchId>) -> RoaringBitmap {
+        let mut node = &self.root;
+        for switch in sequence {
+            match node.children.get(&switch) {
+                Some(child) => node = child,
+                None => return RoaringBitmap::new(),
+            }
+        }
+        node.flows.clone()
+    }
+    
+    /// Number of nodes, i.e. distinct sequences recorded
+    fn len(&self) -> usize {
+        fn count(node: &PathTrieNode) -> usize {
+            node.children.values().map(|child| 1 + count(child)).sum()
+        }
+        count(&self.root)
+    }
+    
+    fn estimated_size_bytes(&self) -> usize {
+        fn size(node: &PathTrieNode) -> usize {
+            node.children
+                .values()
+                .map(|child| {
+                    size_of::<SwitchId>() // key
+                        + child.flows.serialized_size() // compressed posting list
+                        + 32 // HashMap entry overhead
+                        + size(child)
+                })
+                .sum()
+        }
+        size(&self.root)
+    }
+}
+
+/// Path index for efficient path-based queries
+///
+/// Keys are interned switch and path IDs; strings from queries are looked up
+/// without interning, so a switch never seen simply matches nothing. Posting
+/// lists are bitmaps of flow handles.
+///
+/// Prefix, suffix and sub-path lookups each walk one trie keyed by whole
+/// switch IDs, so they cost one step per query switch and match exactly.
 #[derive(Debug, Clone)]
 pub struct PathIndex {
-    /// Maps path hash to set of flow IDs
-    exact_paths: HashMap<String, BTreeSet<String>>,
+    /// Maps path ID to the flows taking it
+    exact_paths: HashMap<PathId, RoaringBitmap>,
+    
+    /// Maps individual switches to flows that pass through them
+    switch_flows: HashMap<SwitchId, RoaringBitmap>,
+    
+    /// Maps directed links (consecutive switch pairs) to flows that crossed them
+    link_flows: HashMap<(SwitchId, SwitchId), RoaringBitmap>,
     
-    /// Maps individual switches to flow IDs that pass through them
-    switch_flows: HashMap<String, BTreeSet<String>>,
+    /// Paths from their first switch, for prefix queries
+    prefixes: PathTrie,
     
-    /// Maps path prefixes to flow IDs
-    prefix_index: HashMap<String, BTreeSet<String>>,
+    /// Paths reversed from their last switch, for suffix queries
+    suffixes: PathTrie,
+    
+    /// Every suffix of each path, so any contiguous sub-path is a trie prefix
+    subpaths: PathTrie,
 }
 
 impl PathIndex {
@@ -21,133 +124,152 @@ impl PathIndex {
         Self {
             exact_paths: HashMap::new(),
             switch_flows: HashMap::new(),
-            prefix_index: HashMap::new(),
+            link_flows: HashMap::new(),
+            prefixes: PathTrie::default(),
+            suffixes: PathTrie::default(),
+            subpaths: PathTrie::default(),
         }
     }
     
     /// Add a flow to the path index
-    pub fn add_flow(&mut self, flow: &Flow) {
-        let flow_id = flow.flow_id.clone();
-        let path_hash = flow.path_hash();
+    pub fn add_flow(&mut self, handle: FlowHandle, flow: &Flow) {
+        let switches = SwitchId::intern_all(&flow.path.switches);
         
         // Add to exact path index
         self.exact_paths
-            .entry(path_hash)
-            .or_insert_with(BTreeSet::new)
-            .insert(flow_id.clone());
+            .entry(PathId::intern(&flow.path))
+            .or_default()
+            .insert(handle);
         
         // Add to switch index
-        for switch in &flow.path.switches {
+        for switch in &switches {
             self.switch_flows
-                .entry(switch.clone())
-                .or_insert_with(BTreeSet::new)
-                .insert(flow_id.clone());
+                .entry(*switch)
+                .or_default()
+                .insert(handle);
+        }
+        
+        // Add to link index
+        for link in switches.windows(2) {
+            self.link_flows
+                .entry((link[0], link[1]))
+                .or_default()
+                .insert(handle);
         }
         
-        // Add to prefix index
-        for i in 1..=flow.path.switches.len() {
-            let prefix = flow.path.switches[..i].join("->");
-            self.prefix_index
-                .entry(prefix)
-                .or_insert_with(BTreeSet::new)
-                .insert(flow_id.clone());
+        // Add to the path tries
+        self.prefixes.insert(switches.iter().copied(), handle);
+        self.suffixes.insert(switches.iter().rev().copied(), handle);
+        for start in 0..switches.len() {
+            self.subpaths.insert(switches[start..].iter().copied(), handle);
         }
     }
     
     /// Remove a flow from the path index
-    pub fn remove_flow(&mut self, flow: &Flow) {
-        let flow_id = &flow.flow_id;
-        let path_hash = flow.path_hash();
+    pub fn remove_flow(&mut self, handle: FlowHandle, flow: &Flow) {
+        // An indexed flow's path and switches are all interned
+        let Some(switches) = SwitchId::lookup_all(&flow.path.switches) else {
+            return;
+        };
         
         // Remove from exact path index
-        if let Some(flows) = self.exact_paths.get_mut(&path_hash) {
-            flows.remove(flow_id);
-            if flows.is_empty() {
-                self.exact_paths.remove(&path_hash);
+        if let Some(path_id) = PathId::lookup(&flow.path) {
+            if let Some(flows) = self.exact_paths.get_mut(&path_id) {
+                flows.remove(handle);
+                if flows.is_empty() {
+                    self.exact_paths.remove(&path_id);
+                }
             }
         }
         
         // Remove from switch index
-        for switch in &flow.path.switches {
+        for switch in &switches {
             if let Some(flows) = self.switch_flows.get_mut(switch) {
-                flows.remove(flow_id);
+                flows.remove(handle);
                 if flows.is_empty() {
                     self.switch_flows.remove(switch);
                 }
             }
         }
         
-        // Remove from prefix index
-        for i in 1..=flow.path.switches.len() {
-            let prefix = flow.path.switches[..i].join("->");
-            if let Some(flows) = self.prefix_index.get_mut(&prefix) {
-                flows.remove(flow_id);
+        // Remove from link index
+        for link in switches.windows(2) {
+            let link = (link[0], link[1]);
+            if let Some(flows) = self.link_flows.get_mut(&link) {
+                flows.remove(handle);
                 if flows.is_empty() {
-                    self.prefix_index.remove(&prefix);
+                    self.link_flows.remove(&link);
                 }
             }
         }
+        
+        // Remove from the path tries
+        self.prefixes.remove(&switches, handle);
+        let reversed: Vec<SwitchId> = switches.iter().rev().copied().collect();
+        self.suffixes.remove(&reversed, handle);
+        for start in 0..switches.len() {
+            self.subpaths.remove(&switches[start..], handle);
+        }
     }
     
     /// Update a flow in the path index (re-add with potentially new path)
-    pub fn update_flow(&mut self, flow: &Flow) {
+    pub fn update_flow(&mut self, handle: FlowHandle, flow: &Flow) {
         // For simplicity, we remove and re-add the flow
         // This handles cases where the path might have changed
-        self.remove_flow(flow);
-        self.add_flow(flow);
+        self.remove_flow(handle, flow);
+        self.add_flow(handle, flow);
     }
     
     /// Find flows with exact path match
-    pub fn find_exact_path(&self, path: &NetworkPath) -> BTreeSet<String> {
-        let path_hash = path.hash();
-        self.exact_paths.get(&path_hash).cloned().unwrap_or_default()
+    pub fn find_exact_path(&self, path: &NetworkPath) -> RoaringBitmap {
+        PathId::lookup(path)
+            .and_then(|path_id| self.exact_paths.get(&path_id).cloned())
+            .unwrap_or_default()
     }
     
     /// Find flows that pass through a specific switch
-    pub fn find_flows_through_switch(&self, switch_id: &str) -> BTreeSet<String> {
-        self.switch_flows.get(switch_id).cloned().unwrap_or_default()
+    pub fn find_flows_through_switch(&self, switch_id: &str) -> RoaringBitmap {
+        SwitchId::lookup(switch_id)
+            .and_then(|switch| self.switch_flows.get(&switch).cloned())
+            .unwrap_or_default()
+    }
+    
+    /// Find flows that went from `from` straight to `to`
+    pub fn find_flows_through_link(&self, from: &str, to: &str) -> RoaringBitmap {
+        SwitchId::lookup(from)
+            .zip(SwitchId::lookup(to))
+            .and_then(|link| self.link_flows.get(&link).cloned())
+            .unwrap_or_default()
     }
     
     /// Find flows that contain the given path as a subpath
-    pub fn find_flows_containing_path(&self, path: &[String]) -> BTreeSet<String> {
-        if path.is_empty() {
-            return BTreeSet::new();
-        }
-        
-        let search_key = path.join("->");
-        let mut result = BTreeSet::new();
-        
-        // Look for exact prefix match
-        if let Some(flows) = self.prefix_index.get(&search_key) {
-            result.extend(flows.clone());
-        }
-        
-        // Also look for flows where this appears as a substring
-        for (prefix, flows) in &self.prefix_index {
-            if prefix.contains(&search_key) {
-                result.extend(flows.clone());
-            }
-        }
-        
-        result
+    pub fn find_flows_containing_path(&self, path: &[String]) -> RoaringBitmap {
+        Self::find_in(&self.subpaths, path, false)
     }
     
     /// Find flows that start with the given path prefix
-    pub fn find_flows_with_prefix(&self, prefix: &[String]) -> BTreeSet<String> {
-        if prefix.is_empty() {
-            return BTreeSet::new();
+    pub fn find_flows_with_prefix(&self, prefix: &[String]) -> RoaringBitmap {
+        Self::find_in(&self.prefixes, prefix, false)
+    }
+    
+    /// Find flows that end with the given path suffix
+    pub fn find_flows_with_suffix(&self, suffix: &[String]) -> RoaringBitmap {
+        Self::find_in(&self.suffixes, suffix, true)
+    }
+    
+    /// Look up a non-empty switch sequence in a trie, optionally walking it backwards
+    fn find_in(trie: &PathTrie, switches: &[String], reversed: bool) -> RoaringBitmap {
+        if switches.is_empty() {
+            return RoaringBitmap::new();
         }
+        let Some(mut sequence) = SwitchId::lookup_all(switches) else {
+            return RoaringBitmap::new();
+        };
         
-        let prefix_key = prefix.join("->");
-        let mut result = BTreeSet::new();
-        
-        for (stored_prefix, flows) in &self.prefix_index {
-            if stored_prefix.starts_with(&prefix_key) {
-                result.extend(flows.clone());
-            }
+        if reversed {
+            sequence.reverse();
         }
-        
-        result
+        trie.find(sequence)
     }
     
     /// Get statistics about the index
@@ -155,36 +277,44 @@ impl PathIndex {
         IndexStats {
             unique_paths: self.exact_paths.len(),
             unique_switches: self.switch_flows.len(),
-            prefix_entries: self.prefix_index.len(),
-            total_flow_refs: self.exact_paths.values().map(|s| s.len()).sum(),
+            unique_links: self.link_flows.len(),
+            prefix_entries: self.prefixes.len(),
+            suffix_entries: self.suffixes.len(),
+            subpath_entries: self.subpaths.len(),
+            total_flow_refs: self.exact_paths.values().map(|s| s.len() as usize).sum(),
         }
     }
     
-    /// Estimate memory usage in bytes
+    /// Estimate memory usage in bytes (the shared interning dictionaries are not included)
     pub fn estimated_size_bytes(&self) -> usize {
         let mut bytes = 0;
         
         // Exact paths HashMap
-        for (path_hash, flow_ids) in &self.exact_paths {
-            bytes += path_hash.len(); // key string
-            bytes += flow_ids.len() * 24; // BTreeSet entries (~24 bytes per flow_id)
+        for flow_ids in self.exact_paths.values() {
+            bytes += size_of::<PathId>(); // key
+            bytes += flow_ids.serialized_size(); // compressed posting list
             bytes += 32; // HashMap entry overhead
         }
         
         // Switch flows HashMap  
-        for (switch_id, flow_ids) in &self.switch_flows {
-            bytes += switch_id.len(); // key string
-            bytes += flow_ids.len() * 24; // BTreeSet entries
+        for flow_ids in self.switch_flows.values() {
+            bytes += size_of::<SwitchId>(); // key
+            bytes += flow_ids.serialized_size(); // compressed posting list
             bytes += 32; // HashMap entry overhead
         }
         
-        // Prefix index HashMap
-        for (prefix, flow_ids) in &self.prefix_index {
-            bytes += prefix.len(); // key string
-            bytes += flow_ids.len() * 24; // BTreeSet entries
+        // Link flows HashMap
+        for flow_ids in self.link_flows.values() {
+            bytes += 2 * size_of::<SwitchId>(); // key
+            bytes += flow_ids.serialized_size(); // compressed posting list
             bytes += 32; // HashMap entry overhead
         }
         
+        // Path tries
+        bytes += self.prefixes.estimated_size_bytes();
+        bytes += self.suffixes.estimated_size_bytes();
+        bytes += self.subpaths.estimated_size_bytes();
+        
         bytes
     }
 }
@@ -195,22 +325,43 @@ impl Default for PathIndex {
     }
 }
 
+/// Most time buckets a flow is registered in by default (a day of minute buckets)
+pub const DEFAULT_MAX_SPAN_BUCKETS: usize = 1440;
+
 /// Time-based index for temporal queries
+///
+/// A flow is registered in every bucket its `[start_time, end_time]` lifetime
+/// touches, so range lookups find long-lived flows that started earlier. Flows
+/// spanning more than `max_span_buckets` buckets (e.g. from a bad switch clock)
+/// are listed as long-lived instead, and every lookup returns them.
 #[derive(Debug, Clone)]
 pub struct TimeIndex {
-    /// Maps time buckets to flow IDs
-    time_buckets: BTreeMap<DateTime<Utc>, BTreeSet<String>>,
+    /// Maps time buckets to the flows active in them
+    time_buckets: BTreeMap<DateTime<Utc>, RoaringBitmap>,
+    
+    /// Flows spanning too many buckets to register in each
+    long_lived: RoaringBitmap,
     
     /// Bucket size in seconds
     bucket_size_secs: i64,
+    
+    /// Most buckets a single flow is registered in
+    max_span_buckets: usize,
 }
 
 impl TimeIndex {
     /// Create a new time index with given bucket size
     pub fn new(bucket_size_secs: i64) -> Self {
+        Self::with_max_span(bucket_size_secs, DEFAULT_MAX_SPAN_BUCKETS)
+    }
+    
+    /// Create a new time index listing flows that span more than `max_span_buckets` buckets as long-lived
+    pub fn with_max_span(bucket_size_secs: i64, max_span_buckets: usize) -> Self {
         Self {
             time_buckets: BTreeMap::new(),
+            long_lived: RoaringBitmap::new(),
             bucket_size_secs,
+            max_span_buckets,
         }
     }
     
@@ -226,80 +377,146 @@ impl TimeIndex {
     
     /// Get the bucket timestamp for a given time
     fn get_bucket(&self, timestamp: DateTime<Utc>) -> DateTime<Utc> {
-        let bucket_timestamp = (timestamp.timestamp() / self.bucket_size_secs) * self.bucket_size_secs;
+        Self::bucket_start(timestamp, self.bucket_size_secs)
+    }
+    
+    /// Get the start of the `bucket_size_secs`-wide window containing `timestamp`
+    pub fn bucket_start(timestamp: DateTime<Utc>, bucket_size_secs: i64) -> DateTime<Utc> {
+        let bucket_timestamp = timestamp.timestamp().div_euclid(bucket_size_secs) * bucket_size_secs;
         DateTime::from_timestamp(bucket_timestamp, 0).unwrap_or(timestamp)
     }
     
+    /// Get the number of `bucket_size_secs`-wide windows `[start, end]` touches
+    pub fn bucket_count(start: DateTime<Utc>, end: DateTime<Utc>, bucket_size_secs: i64) -> usize {
+        let first = start.timestamp().div_euclid(bucket_size_secs);
+        let last = end.timestamp().div_euclid(bucket_size_secs);
+        (last - first).max(0) as usize + 1
+    }
+    
+    /// Get the number of posting lists a flow living over `[start, end]` is added to
+    pub fn posting_count(start: DateTime<Utc>, end: DateTime<Utc>, bucket_size_secs: i64, max_span_buckets: usize) -> usize {
+        match Self::bucket_count(start, end, bucket_size_secs) {
+            count if count > max_span_buckets => 1,
+            count => count,
+        }
+    }
+    
+    /// Get the buckets a flow's lifetime touches, from the one holding its start time,
+    /// or `None` if the flow is long-lived
+    fn lifetime_buckets(&self, flow: &Flow) -> Option<impl Iterator<Item = DateTime<Utc>>> {
+        let count = Self::bucket_count(flow.start_time, flow.end_time, self.bucket_size_secs);
+        if count > self.max_span_buckets {
+            return None;
+        }
+        
+        let first = self.get_bucket(flow.start_time);
+        let bucket_size_secs = self.bucket_size_secs;
+        Some((0..count as i64).map(move |i| first + chrono::Duration::seconds(bucket_size_secs * i)))
+    }
+    
     /// Add a flow to the time index
-    pub fn add_flow(&mut self, flow: &Flow) {
-        let bucket = self.get_bucket(flow.start_time);
-        self.time_buckets
-            .entry(bucket)
-            .or_insert_with(BTreeSet::new)
-            .insert(flow.flow_id.clone());
+    pub fn add_flow(&mut self, handle: FlowHandle, flow: &Flow) {
+        let Some(buckets) = self.lifetime_buckets(flow) else {
+            self.long_lived.insert(handle);
+            return;
+        };
+        
+        for bucket in buckets {
+            self.time_buckets
+                .entry(bucket)
+                .or_default()
+                .insert(handle);
+        }
     }
     
     /// Remove a flow from the time index
-    pub fn remove_flow(&mut self, flow: &Flow) {
-        let bucket = self.get_bucket(flow.start_time);
-        if let Some(flows) = self.time_buckets.get_mut(&bucket) {
-            flows.remove(&flow.flow_id);
-            if flows.is_empty() {
-                self.time_buckets.remove(&bucket);
+    ///
+    /// `flow` must be the flow as added, since its start and end times locate its entries.
+    pub fn remove_flow(&mut self, handle: FlowHandle, flow: &Flow) {
+        let Some(buckets) = self.lifetime_buckets(flow) else {
+            self.long_lived.remove(handle);
+            return;
+        };
+        
+        let buckets: Vec<_> = buckets.collect();
+        for bucket in buckets {
+            if let Some(flows) = self.time_buckets.get_mut(&bucket) {
+                flows.remove(handle);
+                if flows.is_empty() {
+                    self.time_buckets.remove(&bucket);
+                }
             }
         }
     }
     
     /// Update a flow in the time index (re-add with potentially new timestamp)
-    pub fn update_flow(&mut self, flow: &Flow) {
+    pub fn update_flow(&mut self, handle: FlowHandle, flow: &Flow) {
         // For simplicity, we remove and re-add the flow
         // This handles cases where the timestamp might have changed
-        self.remove_flow(flow);
-        self.add_flow(flow);
+        self.remove_flow(handle, flow);
+        self.add_flow(handle, flow);
     }
     
-    /// Find flows within a time range
+    /// Find flows active within a time range
     pub fn find_flows_in_range(
         &self,
         start_time: DateTime<Utc>,
         end_time: DateTime<Utc>,
-    ) -> BTreeSet<String> {
+    ) -> RoaringBitmap {
         let start_bucket = self.get_bucket(start_time);
         let end_bucket = self.get_bucket(end_time);
         
-        let mut result = BTreeSet::new();
+        let mut result = self.long_lived.clone();
         
         for (_bucket_time, flows) in self.time_buckets.range(start_bucket..=end_bucket) {
-            result.extend(flows.clone());
+            result |= flows;
         }
         
         result
     }
     
-    /// Find flows after a specific time
-    pub fn find_flows_after(&self, timestamp: DateTime<Utc>) -> BTreeSet<String> {
+    /// Find flows still active at or after a specific time
+    pub fn find_flows_after(&self, timestamp: DateTime<Utc>) -> RoaringBitmap {
         let start_bucket = self.get_bucket(timestamp);
-        let mut result = BTreeSet::new();
+        let mut result = self.long_lived.clone();
         
         for (_, flows) in self.time_buckets.range(start_bucket..) {
-            result.extend(flows.clone());
+            result |= flows;
         }
         
         result
     }
     
-    /// Find flows before a specific time
-    pub fn find_flows_before(&self, timestamp: DateTime<Utc>) -> BTreeSet<String> {
+    /// Find flows that started in a bucket before the one holding `timestamp`
+    pub fn find_flows_before(&self, timestamp: DateTime<Utc>) -> RoaringBitmap {
         let end_bucket = self.get_bucket(timestamp);
-        let mut result = BTreeSet::new();
+        let mut result = self.long_lived.clone();
         
         for (_, flows) in self.time_buckets.range(..end_bucket) {
-            result.extend(flows.clone());
+            result |= flows;
         }
         
         result
     }
     
+    /// Iterate over buckets and their flows in chronological order
+    ///
+    /// Long-lived flows aren't in any bucket; see `long_lived_flows`.
+    pub fn buckets(&self) -> impl Iterator<Item = (DateTime<Utc>, &RoaringBitmap)> {
+        self.time_buckets.iter().map(|(bucket, flows)| (*bucket, flows))
+    }
+    
+    /// Get the flows spanning too many buckets to be registered in each
+    pub fn long_lived_flows(&self) -> &RoaringBitmap {
+        &self.long_lived
+    }
+    
+    /// Remove every flow from the index
+    pub fn clear(&mut self) {
+        self.time_buckets.clear();
+        self.long_lived.clear();
+    }
+    
     /// Get the earliest time bucket
     pub fn earliest_time(&self) -> Option<DateTime<Utc>> {
         self.time_buckets.keys().next().copied()
@@ -317,7 +534,8 @@ impl TimeIndex {
             bucket_size_secs: self.bucket_size_secs,
             earliest_time: self.earliest_time(),
             latest_time: self.latest_time(),
-            total_flow_refs: self.time_buckets.values().map(|s| s.len()).sum(),
+            long_lived_flows: self.long_lived.len() as usize,
+            total_flow_refs: self.time_buckets.values().map(|s| s.len() as usize).sum::<usize>() + self.long_lived.len() as usize,
         }
     }
     
@@ -326,12 +544,15 @@ impl TimeIndex {
         let mut bytes = 0;
         
         // Time buckets BTreeMap
-        for (_timestamp, flow_ids) in &self.time_buckets {
+        for flow_ids in self.time_buckets.values() {
             bytes += 16; // DateTime<Utc> is ~16 bytes
-            bytes += flow_ids.len() * 24; // BTreeSet entries (~24 bytes per flow_id)
+            bytes += flow_ids.serialized_size(); // compressed posting list
             bytes += 24; // BTreeMap entry overhead
         }
         
+        // Long-lived flows
+        bytes += self.long_lived.serialized_size();
+        
         // Bucket size field
         bytes += 8; // i64
         
@@ -345,12 +566,274 @@ impl Default for TimeIndex {
     }
 }
 
+/// Position of a hop within its flow's hop list
+pub type HopPosition = u32;
+
+/// Per-switch index of hops by their own timestamp
+///
+/// Unlike `TimeIndex`, which places a flow by its lifetime, this places every
+/// hop in the bucket of the time it was seen at its switch. Entries pack the flow
+/// handle and hop position into one `u64`, so each bucket is a compressed set.
+#[derive(Debug, Clone)]
+pub struct HopIndex {
+    /// Hops seen at each switch, by time bucket
+    switch_buckets: HashMap<SwitchId, BTreeMap<DateTime<Utc>, RoaringTreemap>>,
+    
+    /// Bucket size in seconds
+    bucket_size_secs: i64,
+}
+
+impl HopIndex {
+    /// Create a new hop index with given bucket size
+    pub fn new(bucket_size_secs: i64) -> Self {
+        Self {
+            switch_buckets: HashMap::new(),
+            bucket_size_secs,
+        }
+    }
+    
+    fn hop_ref(handle: FlowHandle, position: HopPosition) -> u64 {
+        (handle as u64) << 32 | position as u64
+    }
+    
+    fn split_ref(hop_ref: u64) -> (FlowHandle, HopPosition) {
+        ((hop_ref >> 32) as FlowHandle, hop_ref as HopPosition)
+    }
+    
+    /// Add every hop of a flow to the index
+    pub fn add_flow(&mut self, handle: FlowHandle, flow: &Flow) {
+        for (position, hop) in flow.hops.iter().enumerate() {
+            let switch = SwitchId::intern(&hop.switch_id);
+            let bucket = TimeIndex::bucket_start(hop.timestamp, self.bucket_size_secs);
+            self.switch_buckets
+                .entry(switch)
+                .or_default()
+                .entry(bucket)
+                .or_default()
+                .insert(Self::hop_ref(handle, position as HopPosition));
+        }
+    }
+    
+    /// Remove every hop of a flow from the index
+    ///
+    /// `flow` must be the flow as added, since hop switches, times and positions locate its entries.
+    pub fn remove_flow(&mut self, handle: FlowHandle, flow: &Flow) {
+        for (position, hop) in flow.hops.iter().enumerate() {
+            let Some(switch) = SwitchId::lookup(&hop.switch_id) else {
+                continue;
+            };
+            let Some(buckets) = self.switch_buckets.get_mut(&switch) else {
+                continue;
+            };
+            
+            let bucket = TimeIndex::bucket_start(hop.timestamp, self.bucket_size_secs);
+            if let Some(hops) = buckets.get_mut(&bucket) {
+                hops.remove(Self::hop_ref(handle, position as HopPosition));
+                if hops.is_empty() {
+                    buckets.remove(&bucket);
+                }
+            }
+            if buckets.is_empty() {
+                self.switch_buckets.remove(&switch);
+            }
+        }
+    }
+    
+    /// Find hops at a switch in buckets overlapping `[from, to]`
+    ///
+    /// Results are bucket-granular, so callers check each hop's timestamp.
+    pub fn find_hops(&self, switch_id: &str, from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<(FlowHandle, HopPosition)> {
+        let mut result = Vec::new();
+        if let Some(buckets) = SwitchId::lookup(switch_id).and_then(|switch| self.switch_buckets.get(&switch)) {
+            self.collect_hops(buckets, from, to, &mut |handle, position| result.push((handle, position)));
+        }
+        result
+    }
+    
+    /// Find hops at every switch in buckets overlapping `[from, to]` (bucket-granular, as `find_hops`)
+    pub fn find_all_hops(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<(SwitchId, FlowHandle, HopPosition)> {
+        let mut result = Vec::new();
+        for (switch, buckets) in &self.switch_buckets {
+            self.collect_hops(buckets, from, to, &mut |handle, position| result.push((*switch, handle, position)));
+        }
+        result
+    }
+    
+    fn collect_hops(
+        &self,
+        buckets: &BTreeMap<DateTime<Utc>, RoaringTreemap>,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        visit: &mut impl FnMut(FlowHandle, HopPosition),
+    ) {
+        if from > to {
+            return;
+        }
+        let first = TimeIndex::bucket_start(from, self.bucket_size_secs);
+        for hops in buckets.range(first..=to).map(|(_, hops)| hops) {
+            for hop_ref in hops {
+                let (handle, position) = Self::split_ref(hop_ref);
+                visit(handle, position);
+            }
+        }
+    }
+    
+    /// Get the number of switches with indexed hops
+    pub fn switch_count(&self) -> usize {
+        self.switch_buckets.len()
+    }
+    
+    /// Estimate memory usage in bytes
+    pub fn estimated_size_bytes(&self) -> usize {
+        let mut bytes = 0;
+        
+        for buckets in self.switch_buckets.values() {
+            bytes += size_of::<SwitchId>() + 32; // key and HashMap entry overhead
+            for hops in buckets.values() {
+                bytes += 16; // DateTime<Utc> is ~16 bytes
+                bytes += hops.serialized_size(); // compressed hop set
+                bytes += 24; // BTreeMap entry overhead
+            }
+        }
+        
+        bytes
+    }
+}
+
+/// Flows ordered by one metric, for range predicates
+///
+/// Keys are order-preserving `u64` encodings of the metric, so integer and
+/// floating-point metrics share the same map. Flows without the metric are
+/// not indexed.
+#[derive(Debug, Clone, Default)]
+struct SortedIndex {
+    entries: BTreeMap<u64, RoaringBitmap>,
+}
+
+impl SortedIndex {
+    fn insert(&mut self, key: Option<u64>, handle: FlowHandle) {
+        if let Some(key) = key {
+            self.entries.entry(key).or_default().insert(handle);
+        }
+    }
+    
+    fn remove(&mut self, key: Option<u64>, handle: FlowHandle) {
+        let Some(key) = key else {
+            return;
+        };
+        if let Some(flows) = self.entries.get_mut(&key) {
+            flows.remove(handle);
+            if flows.is_empty() {
+                self.entries.remove(&key);
+            }
+        }
+    }
+    
+    /// Get the flows whose key lies within the bounds (none if the bounds are empty)
+    fn range(&self, lower: Bound<u64>, upper: Bound<u64>) -> RoaringBitmap {
+        let is_empty = match (lower, upper) {
+            (Bound::Included(low), Bound::Included(high)) => low > high,
+            (Bound::Included(low) | Bound::Excluded(low), Bound::Included(high) | Bound::Excluded(high)) => low >= high,
+            _ => false,
+        };
+        if is_empty {
+            return RoaringBitmap::new();
+        }
+        
+        let mut result = RoaringBitmap::new();
+        for flows in self.entries.range((lower, upper)).map(|(_, flows)| flows) {
+            result |= flows;
+        }
+        result
+    }
+    
+    fn estimated_size_bytes(&self) -> usize {
+        self.entries
+            .values()
+            .map(|flow_ids| size_of::<u64>() + flow_ids.serialized_size() + 24) // key, posting list, BTreeMap entry overhead
+            .sum()
+    }
+}
+
+/// Encode a float so that integer order matches `f64::total_cmp`
+fn float_key(value: f64) -> u64 {
+    let bits = value.to_bits();
+    if bits >> 63 == 1 {
+        !bits
+    } else {
+        bits | 1 << 63
+    }
+}
+
+fn float_bound(bound: Bound<f64>) -> Bound<u64> {
+    bound.map(float_key)
+}
+
+/// Secondary indexes over per-flow metrics for range predicates
+///
+/// Each flow is indexed by its total delay and its maximum and average queue
+/// utilization. Lookups return the flows whose value lies within the bounds.
+#[derive(Debug, Clone, Default)]
+pub struct MetricIndex {
+    total_delay: SortedIndex,
+    max_queue_util: SortedIndex,
+    avg_queue_util: SortedIndex,
+}
+
+impl MetricIndex {
+    /// Create a new empty metric index
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Add a flow to the metric index
+    pub fn add_flow(&mut self, handle: FlowHandle, flow: &Flow) {
+        self.total_delay.insert(flow.total_delay(), handle);
+        self.max_queue_util.insert(flow.max_queue_utilization().map(float_key), handle);
+        self.avg_queue_util.insert(flow.avg_queue_utilization().map(float_key), handle);
+    }
+    
+    /// Remove a flow from the metric index
+    ///
+    /// `flow` must be the flow as added, since its metrics locate its entries.
+    pub fn remove_flow(&mut self, handle: FlowHandle, flow: &Flow) {
+        self.total_delay.remove(flow.total_delay(), handle);
+        self.max_queue_util.remove(flow.max_queue_utilization().map(float_key), handle);
+        self.avg_queue_util.remove(flow.avg_queue_utilization().map(float_key), handle);
+    }
+    
+    /// Find flows whose total delay lies within the bounds
+    pub fn find_total_delay(&self, lower: Bound<u64>, upper: Bound<u64>) -> RoaringBitmap {
+        self.total_delay.range(lower, upper)
+    }
+    
+    /// Find flows whose maximum queue utilization lies within the bounds
+    pub fn find_max_queue_util(&self, lower: Bound<f64>, upper: Bound<f64>) -> RoaringBitmap {
+        self.max_queue_util.range(float_bound(lower), float_bound(upper))
+    }
+    
+    /// Find flows whose average queue utilization lies within the bounds
+    pub fn find_avg_queue_util(&self, lower: Bound<f64>, upper: Bound<f64>) -> RoaringBitmap {
+        self.avg_queue_util.range(float_bound(lower), float_bound(upper))
+    }
+    
+    /// Estimate memory usage in bytes
+    pub fn estimated_size_bytes(&self) -> usize {
+        self.total_delay.estimated_size_bytes()
+            + self.max_queue_util.estimated_size_bytes()
+            + self.avg_queue_util.estimated_size_bytes()
+    }
+}
+
 /// Statistics about the path index
 #[derive(Debug, Clone)]
 pub struct IndexStats {
     pub unique_paths: usize,
     pub unique_switches: usize,
+    pub unique_links: usize,
     pub prefix_entries: usize,
+    pub suffix_entries: usize,
+    pub subpath_entries: usize,
     pub total_flow_refs: usize,
 }
 
@@ -361,6 +844,7 @@ pub struct TimeIndexStats {
     pub bucket_size_secs: i64,
     pub earliest_time: Option<DateTime<Utc>>,
     pub latest_time: Option<DateTime<Utc>>,
+    pub long_lived_flows: usize,
     pub total_flow_refs: usize,
 }
 
@@ -369,7 +853,7 @@ mod tests {
     use super::*;
     use crate::models::{Hop, TelemetryMetrics};
     use chrono::Utc;
-
+    
     fn create_test_flow(flow_id: &str, switches: &[&str], start_time: DateTime<Utc>) -> Flow {
         let hops: Vec<Hop> = switches
             .iter()
@@ -386,7 +870,7 @@ mod tests {
         
         Flow::new(flow_id.to_string(), hops).unwrap()
     }
-
+    
     #[test]
     fn test_path_index() {
         let mut index = PathIndex::new();
@@ -396,13 +880,13 @@ mod tests {
         let flow2 = create_test_flow("flow2", &["s1", "s2", "s4"], now);
         let flow3 = create_test_flow("flow3", &["s2", "s3", "s4"], now);
         
-        index.add_flow(&flow1);
-        index.add_flow(&flow2);
-        index.add_flow(&flow3);
+        index.add_flow(1, &flow1);
+        index.add_flow(2, &flow2);
+        index.add_flow(3, &flow3);
         
         // Test exact path matching
         assert_eq!(index.find_exact_path(&flow1.path).len(), 1);
-        assert!(index.find_exact_path(&flow1.path).contains("flow1"));
+        assert!(index.find_exact_path(&flow1.path).contains(1));
         
         // Test switch-based queries
         let flows_through_s2 = index.find_flows_through_switch("s2");
@@ -414,15 +898,15 @@ mod tests {
         // Test prefix queries
         let flows_with_s1_s2 = index.find_flows_with_prefix(&["s1".to_string(), "s2".to_string()]);
         assert_eq!(flows_with_s1_s2.len(), 2);
-        assert!(flows_with_s1_s2.contains("flow1"));
-        assert!(flows_with_s1_s2.contains("flow2"));
+        assert!(flows_with_s1_s2.contains(1));
+        assert!(flows_with_s1_s2.contains(2));
         
         // Test statistics
         let stats = index.stats();
         assert_eq!(stats.unique_paths, 3);
         assert!(stats.unique_switches >= 4);
     }
-
+    
     #[test]
     fn test_time_index() {
         let mut index = TimeIndex::with_minute_buckets();
@@ -432,9 +916,9 @@ mod tests {
         let flow2 = create_test_flow("flow2", &["s2", "s3"], base_time + chrono::Duration::seconds(30));
         let flow3 = create_test_flow("flow3", &["s3", "s4"], base_time + chrono::Duration::minutes(2));
         
-        index.add_flow(&flow1);
-        index.add_flow(&flow2);
-        index.add_flow(&flow3);
+        index.add_flow(1, &flow1);
+        index.add_flow(2, &flow2);
+        index.add_flow(3, &flow3);
         
         // Test range queries
         let flows_in_first_minute = index.find_flows_in_range(
@@ -442,13 +926,13 @@ mod tests {
             base_time + chrono::Duration::minutes(1),
         );
         assert_eq!(flows_in_first_minute.len(), 2);
-        assert!(flows_in_first_minute.contains("flow1"));
-        assert!(flows_in_first_minute.contains("flow2"));
+        assert!(flows_in_first_minute.contains(1));
+        assert!(flows_in_first_minute.contains(2));
         
         // Test after/before queries
         let flows_after = index.find_flows_after(base_time + chrono::Duration::minutes(1));
         assert_eq!(flows_after.len(), 1);
-        assert!(flows_after.contains("flow3"));
+        assert!(flows_after.contains(3));
         
         let flows_before = index.find_flows_before(base_time + chrono::Duration::minutes(2));
         assert_eq!(flows_before.len(), 2);
@@ -460,7 +944,170 @@ mod tests {
         assert!(stats.earliest_time.is_some());
         assert!(stats.latest_time.is_some());
     }
-
+    
+    #[test]
+    fn test_time_index_covers_flow_lifetime() {
+        let mut index = TimeIndex::with_minute_buckets();
+        let base_time = DateTime::from_timestamp(1640995200, 0).unwrap();
+        let minute = |m: i64| base_time + chrono::Duration::minutes(m);
+        
+        // A flow active from minute 0 to minute 10
+        let mut long = create_test_flow("long", &["s1", "s2"], base_time);
+        long.hops[1].timestamp = minute(10);
+        long.end_time = minute(10);
+        index.add_flow(1, &long);
+        index.add_flow(2, &create_test_flow("short", &["s1"], minute(3)));
+        
+        assert_eq!(index.find_flows_in_range(minute(5), minute(6)), RoaringBitmap::from_iter([1]));
+        assert_eq!(index.find_flows_after(minute(9)), RoaringBitmap::from_iter([1]));
+        assert!(index.find_flows_after(minute(11)).is_empty());
+        assert_eq!(index.find_flows_in_range(minute(3), minute(3)).len(), 2);
+        assert_eq!(TimeIndex::bucket_count(long.start_time, long.end_time, 60), 11);
+        
+        index.remove_flow(1, &long);
+        assert!(index.find_flows_in_range(minute(5), minute(6)).is_empty());
+        assert_eq!(index.stats().bucket_count, 1);
+    }
+    
+    #[test]
+    fn test_time_index_lists_long_lived_flows() {
+        let mut index = TimeIndex::with_max_span(60, 5);
+        let base_time = DateTime::from_timestamp(1640995200, 0).unwrap();
+        let minute = |m: i64| base_time + chrono::Duration::minutes(m);
+        
+        // Spanning decades, far more buckets than fit in an i32
+        let mut long = create_test_flow("long", &["s1", "s2"], base_time);
+        long.hops[1].timestamp = base_time + chrono::Duration::days(365 * 5000);
+        long.end_time = long.hops[1].timestamp;
+        index.add_flow(1, &long);
+        index.add_flow(2, &create_test_flow("short", &["s1"], minute(3)));
+        
+        let stats = index.stats();
+        assert_eq!((stats.bucket_count, stats.long_lived_flows, stats.total_flow_refs), (1, 1, 2));
+        assert_eq!(TimeIndex::posting_count(long.start_time, long.end_time, 60, 5), 1);
+        assert_eq!(TimeIndex::posting_count(minute(0), minute(4), 60, 5), 5);
+        
+        // Every lookup includes long-lived flows; callers check exact times
+        assert_eq!(index.find_flows_in_range(minute(10), minute(11)), RoaringBitmap::from_iter([1]));
+        assert_eq!(index.find_flows_after(minute(10)), RoaringBitmap::from_iter([1]));
+        assert_eq!(index.find_flows_before(minute(10)), RoaringBitmap::from_iter([1, 2]));
+        
+        index.remove_flow(1, &long);
+        assert!(index.long_lived_flows().is_empty());
+        assert_eq!(index.find_flows_after(minute(10)), RoaringBitmap::new());
+    }
+    
+    #[test]
+    fn test_path_matching_compares_whole_switch_ids() {
+        let now = Utc::now();
+        let mut index = PathIndex::new();
+        index.add_flow(1, &create_test_flow("flow1", &["s1", "s2", "s3"], now));
+        index.add_flow(2, &create_test_flow("flow2", &["s10", "s2", "s3"], now));
+        
+        let s1 = vec!["s1".to_string()];
+        assert_eq!(index.find_flows_with_prefix(&s1), RoaringBitmap::from_iter([1]));
+        assert_eq!(index.find_flows_containing_path(&["s2".to_string(), "s3".to_string()]).len(), 2);
+        assert!(index.find_flows_containing_path(&["s3".to_string(), "s2".to_string()]).is_empty());
+        assert!(index.find_flows_through_switch("never-seen-switch").is_empty());
+        assert_eq!(index.find_flows_through_link("s1", "s2"), RoaringBitmap::from_iter([1]));
+        assert_eq!(index.find_flows_through_link("s2", "s3").len(), 2);
+        assert!(index.find_flows_through_link("s1", "s3").is_empty());
+    }
+    
+    #[test]
+    fn test_path_tries_answer_prefix_suffix_and_subpath() {
+        let now = Utc::now();
+        let mut index = PathIndex::new();
+        let flow1 = create_test_flow("flow1", &["s1", "s2", "s3"], now);
+        let flow2 = create_test_flow("flow2", &["s11", "s2", "s3", "s1"], now);
+        let flow3 = create_test_flow("flow3", &["s2", "s1", "s2", "s1"], now);
+        index.add_flow(1, &flow1);
+        index.add_flow(2, &flow2);
+        index.add_flow(3, &flow3);
+        
+        let path = |switches: &[&str]| switches.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(index.find_flows_with_prefix(&path(&["s1", "s2"])), RoaringBitmap::from_iter([1]));
+        assert_eq!(index.find_flows_with_suffix(&path(&["s3"])), RoaringBitmap::from_iter([1]));
+        assert_eq!(index.find_flows_with_suffix(&path(&["s2", "s1"])), RoaringBitmap::from_iter([3]));
+        assert_eq!(index.find_flows_with_suffix(&path(&["s3", "s1"])), RoaringBitmap::from_iter([2]));
+        assert_eq!(index.find_flows_containing_path(&path(&["s2", "s3"])), RoaringBitmap::from_iter([1, 2]));
+        assert_eq!(index.find_flows_containing_path(&path(&["s1", "s2"])), RoaringBitmap::from_iter([1, 3]));
+        assert!(index.find_flows_containing_path(&path(&["s1", "s3"])).is_empty());
+        assert!(index.find_flows_with_suffix(&[]).is_empty());
+        
+        // Removing a flow that revisits switches prunes only its own entries
+        index.remove_flow(3, &flow3);
+        assert_eq!(index.find_flows_containing_path(&path(&["s1", "s2"])), RoaringBitmap::from_iter([1]));
+        assert_eq!(index.find_flows_containing_path(&path(&["s3", "s1"])), RoaringBitmap::from_iter([2]));
+        index.remove_flow(1, &flow1);
+        index.remove_flow(2, &flow2);
+        let stats = index.stats();
+        assert_eq!((stats.prefix_entries, stats.suffix_entries, stats.subpath_entries), (0, 0, 0));
+        assert_eq!(index.estimated_size_bytes(), 0);
+    }
+    
+    #[test]
+    fn test_metric_index_ranges() {
+        let now = Utc::now();
+        let mut index = MetricIndex::new();
+        // Delays 100 * i per hop, queue utilization 0.1 * i
+        let flow1 = create_test_flow("flow1", &["s1", "s2"], now);
+        let flow2 = create_test_flow("flow2", &["s1", "s2", "s3"], now);
+        let flow3 = create_test_flow("flow3", &["s1", "s2", "s3", "s4"], now);
+        index.add_flow(1, &flow1);
+        index.add_flow(2, &flow2);
+        index.add_flow(3, &flow3);
+        
+        assert_eq!(index.find_total_delay(Bound::Excluded(100), Bound::Unbounded), RoaringBitmap::from_iter([2, 3]));
+        assert_eq!(index.find_total_delay(Bound::Included(100), Bound::Included(300)), RoaringBitmap::from_iter([1, 2]));
+        assert!(index.find_total_delay(Bound::Included(300), Bound::Included(100)).is_empty());
+        assert!(index.find_total_delay(Bound::Excluded(300), Bound::Excluded(300)).is_empty());
+        assert_eq!(index.find_max_queue_util(Bound::Unbounded, Bound::Excluded(0.25)), RoaringBitmap::from_iter([1, 2]));
+        assert_eq!(index.find_avg_queue_util(Bound::Excluded(0.12), Bound::Unbounded), RoaringBitmap::from_iter([3]));
+        assert_eq!(index.find_max_queue_util(Bound::Excluded(-1.0), Bound::Unbounded).len(), 3);
+        
+        // Appended telemetry changes the flow's metrics
+        let mut appended = flow1.clone();
+        appended.merge(&create_test_flow("flow1", &["s1", "s2", "s3", "s4", "s5"], now));
+        index.remove_flow(1, &flow1);
+        index.add_flow(1, &appended);
+        assert_eq!(index.find_total_delay(Bound::Excluded(600), Bound::Unbounded), RoaringBitmap::from_iter([1]));
+        assert!(index.find_total_delay(Bound::Unbounded, Bound::Excluded(300)).is_empty());
+        
+        for (handle, flow) in [(1, &appended), (2, &flow2), (3, &flow3)] {
+            index.remove_flow(handle, flow);
+        }
+        assert_eq!(index.estimated_size_bytes(), 0);
+    }
+    
+    #[test]
+    fn test_hop_index_finds_hops_by_their_own_time() {
+        let base_time = DateTime::from_timestamp(1640995200, 0).unwrap();
+        let mut index = HopIndex::new(60);
+        
+        // flow1 reaches s3 a few minutes after it starts
+        let mut flow1 = create_test_flow("flow1", &["s1", "s2", "s3"], base_time);
+        flow1.hops[2].timestamp = base_time + chrono::Duration::minutes(5);
+        let flow2 = create_test_flow("flow2", &["s3", "s1"], base_time);
+        index.add_flow(1, &flow1);
+        index.add_flow(2, &flow2);
+        
+        let early = (base_time, base_time + chrono::Duration::seconds(30));
+        let late = (base_time + chrono::Duration::minutes(4), base_time + chrono::Duration::minutes(6));
+        assert_eq!(index.find_hops("s3", early.0, early.1), vec![(2, 0)]);
+        assert_eq!(index.find_hops("s3", late.0, late.1), vec![(1, 2)]);
+        assert!(index.find_hops("s2", late.0, late.1).is_empty());
+        assert!(index.find_hops("never-seen-switch", early.0, late.1).is_empty());
+        assert!(index.find_hops("s3", late.1, early.0).is_empty());
+        assert_eq!(index.find_all_hops(early.0, early.1).len(), 4);
+        
+        index.remove_flow(1, &flow1);
+        assert!(index.find_hops("s3", late.0, late.1).is_empty());
+        assert_eq!(index.switch_count(), 2);
+        index.remove_flow(2, &flow2);
+        assert_eq!(index.estimated_size_bytes(), 0);
+    }
+    
     #[test]
     fn test_index_removal() {
         let mut path_index = PathIndex::new();
@@ -470,17 +1117,19 @@ mod tests {
         let flow = create_test_flow("flow1", &["s1", "s2", "s3"], now);
         
         // Add flow
-        path_index.add_flow(&flow);
-        time_index.add_flow(&flow);
+        path_index.add_flow(1, &flow);
+        time_index.add_flow(1, &flow);
         
         assert_eq!(path_index.find_flows_through_switch("s2").len(), 1);
         assert_eq!(time_index.find_flows_after(now - chrono::Duration::minutes(1)).len(), 1);
         
         // Remove flow
-        path_index.remove_flow(&flow);
-        time_index.remove_flow(&flow);
+        path_index.remove_flow(1, &flow);
+        time_index.remove_flow(1, &flow);
         
         assert_eq!(path_index.find_flows_through_switch("s2").len(), 0);
+        assert!(path_index.find_flows_through_link("s1", "s2").is_empty());
+        assert_eq!(path_index.stats().unique_links, 0);
         assert_eq!(time_index.find_flows_after(now - chrono::Duration::minutes(1)).len(), 0);
     }
 } 
\ No newline at end of file
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::sync::{Arc, OnceLock, RwLock};
+
+use crate::models::NetworkPath;
+
+/// Append-only dictionary mapping strings to dense integer IDs
+///
+/// Entries are never removed, so an ID stays valid for the life of the process.
+#[derive(Debug, Default)]
+pub struct Interner {
+    inner: RwLock<InternerInner>,
+}
+
+#[derive(Debug, Default)]
+struct InternerInner {
+    ids: HashMap<Arc<str>, u32>,
+    names: Vec<Arc<str>>,
+}
+
+impl Interner {
+    /// Create an empty dictionary
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Get the ID of a string, adding it if it is new
+    pub fn intern(&self, name: &str) -> u32 {
+        if let Some(id) = self.lookup(name) {
+            return id;
+        }
+        
+        let mut inner = self.inner.write().unwrap();
+        if let Some(id) = inner.ids.get(name) {
+            return *id;
+        }
+        let id = inner.names.len() as u32;
+        let name: Arc<str> = Arc::from(name);
+        inner.names.push(name.clone());
+        inner.ids.insert(name, id);
+        id
+    }
+    
+    /// Get the ID of a string without adding it
+    pub fn lookup(&self, name: &str) -> Option<u32> {
+        self.inner.read().unwrap().ids.get(name).copied()
+    }
+    
+    /// Get the string behind an ID
+    pub fn resolve(&self, id: u32) -> Arc<str> {
+        self.inner.read().unwrap().names[id as usize].clone()
+    }
+    
+    /// Get the number of distinct strings
+    pub fn len(&self) -> usize {
+        self.inner.read().unwrap().names.len()
+    }
+    
+    /// Check whether the dictionary is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    
+    /// Estimate the heap bytes held by the dictionary
+    pub fn estimated_size_bytes(&self) -> usize {
+        let inner = self.inner.read().unwrap();
+        let strings: usize = inner.names.iter().map(|name| name.len()).sum();
+        strings + inner.names.capacity() * size_of::<Arc<str>>() + inner.ids.capacity() * (size_of::<Arc<str>>() + size_of::<u32>())
+    }
+}
+
+/// Process-wide dictionary of switch IDs
+pub fn switch_interner() -> &'static Interner {
+    static SWITCHES: OnceLock<Interner> = OnceLock::new();
+    SWITCHES.get_or_init(Interner::new)
+}
+
+/// Process-wide dictionary of path hashes
+pub fn path_interner() -> &'static Interner {
+    static PATHS: OnceLock<Interner> = OnceLock::new();
+    PATHS.get_or_init(Interner::new)
+}
+
+/// Compact ID of an interned switch identifier
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SwitchId(u32);
+
+impl SwitchId {
+    /// Get the ID of a switch, interning it if it is new
+    pub fn intern(switch_id: &str) -> Self {
+        Self(switch_interner().intern(switch_id))
+    }
+    
+    /// Get the ID of a switch that has been seen before
+    pub fn lookup(switch_id: &str) -> Option<Self> {
+        switch_interner().lookup(switch_id).map(Self)
+    }
+    
+    /// Intern every switch of a path, in order
+    pub fn intern_all(switches: &[String]) -> Vec<Self> {
+        switches.iter().map(|switch| Self::intern(switch)).collect()
+    }
+    
+    /// Look up every switch of a path, or `None` if any was never seen
+    pub fn lookup_all(switches: &[String]) -> Option<Vec<Self>> {
+        switches.iter().map(|switch| Self::lookup(switch)).collect()
+    }
+    
+    /// Rebuild an ID from its raw value
+    pub fn from_raw(raw: u32) -> Self {
+        Self(raw)
+    }
+    
+    /// Get the raw value
+    pub fn raw(self) -> u32 {
+        self.0
+    }
+    
+    /// Get the switch ID string
+    pub fn resolve(self) -> Arc<str> {
+        switch_interner().resolve(self.0)
+    }
+}
+
+/// Compact ID of an interned path hash
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PathId(u32);
+
+impl PathId {
+    /// Get the ID of a path, interning its hash if it is new
+    pub fn intern(path: &NetworkPath) -> Self {
+        Self(path_interner().intern(&path.hash()))
+    }
+    
+    /// Get the ID of a path that has been seen before
+    pub fn lookup(path: &NetworkPath) -> Option<Self> {
+        path_interner().lookup(&path.hash()).map(Self)
+    }
+    
+    /// Get the path hash string
+    pub fn resolve(self) -> Arc<str> {
+        path_interner().resolve(self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_interner_assigns_dense_stable_ids() {
+        let interner = Interner::new();
+        assert!(interner.is_empty());
+        
+        let a = interner.intern("s1");
+        let b = interner.intern("s2");
+        assert_eq!(interner.intern("s1"), a);
+        assert_ne!(a, b);
+        assert_eq!(interner.len(), 2);
+        assert_eq!(&*interner.resolve(b), "s2");
+        assert_eq!(interner.lookup("s3"), None);
+        assert_eq!(interner.len(), 2);
+    }
+    
+    #[test]
+    fn test_global_switch_and_path_ids() {
+        let switch = SwitchId::intern("intern-test-switch");
+        assert_eq!(SwitchId::lookup("intern-test-switch"), Some(switch));
+        assert_eq!(&*switch.resolve(), "intern-test-switch");
+        assert_eq!(SwitchId::lookup_all(&["intern-test-switch".to_string(), "never-seen".to_string()]), None);
+        
+        let path = NetworkPath::from_switches(&["intern-test-a", "intern-test-b"]);
+        assert_eq!(PathId::lookup(&path), None);
+        let path_id = PathId::intern(&path);
+        assert_eq!(PathId::lookup(&path), Some(path_id));
+        assert_eq!(*path_id.resolve(), path.hash());
+    }
+}
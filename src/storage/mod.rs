@@ -1,7 +1,27 @@
+pub mod backend;
+pub mod changes;
+pub mod compression;
 pub mod engine;
+pub mod eviction;
 pub mod index;
+pub mod intern;
+pub mod partition;
 pub mod query;
+pub mod segment;
+pub mod shard;
+pub mod snapshot;
+pub mod wal;
 
+pub use backend::*;
+pub use changes::*;
+pub use compression::*;
 pub use engine::*;
+pub use eviction::*;
 pub use index::*;
-pub use query::*; 
\ No newline at end of file
+pub use intern::*;
+pub use partition::*;
+pub use query::*;
+pub use segment::*;
+pub use shard::*;
+pub use snapshot::*;
+pub use wal::*; 
\ No newline at end of file
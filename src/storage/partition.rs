@@ -0,0 +1,540 @@
+use std::collections::HashMap;
+use std::ops::Bound;
+use chrono::{DateTime, Utc};
+use roaring::RoaringBitmap;
+
+use crate::models::Flow;
+use crate::storage::{FlowHandle, HopIndex, MetricCondition, MetricIndex, PathCondition, PathIndex, QueryBuilder, TimeCondition, TimeIndex};
+
+/// What a partition keeps of a flow whose record lives in the storage backend
+///
+/// Enough for pruning, eviction and expiry without fetching the record.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlowEntry {
+    pub flow_id: String,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    
+    /// The flow's own retention period, if it has a valid one
+    pub retention: Option<chrono::Duration>,
+}
+
+impl FlowEntry {
+    /// Summarize a flow
+    pub fn new(flow: &Flow) -> Self {
+        Self {
+            flow_id: flow.flow_id.clone(),
+            start_time: flow.start_time,
+            end_time: flow.end_time,
+            retention: flow.retention(),
+        }
+    }
+}
+
+/// Flows whose start time falls in one time window, with their own indexes
+///
+/// The engine keeps one partition per window so that queries skip windows
+/// outside their time range, writers to different windows don't contend, and
+/// an expired window can be dropped in one step.
+#[derive(Debug)]
+pub struct Partition {
+    /// Window covered, `[start, end)`
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    
+    /// Entries of the flows starting in the window, indexed by handle
+    slots: Vec<Option<FlowEntry>>,
+    
+    /// Handle of each flow, by flow ID
+    handles: HashMap<String, FlowHandle>,
+    
+    /// Handles of emptied slots, reused before the slot vector grows
+    free_handles: Vec<FlowHandle>,
+    
+    /// Handles of every flow held
+    live: RoaringBitmap,
+    
+    /// Path-based index of the partition's flows
+    path_index: PathIndex,
+    
+    /// Time-based index of the partition's flows
+    time_index: TimeIndex,
+    
+    /// Delay and queue-utilization index of the partition's flows
+    metric_index: MetricIndex,
+    
+    /// Per-switch index of the partition's hops by hop timestamp
+    hop_index: HopIndex,
+    
+    /// Latest end time of any flow ever added (never lowered, so expiry checks stay conservative)
+    max_end_time: Option<DateTime<Utc>>,
+    
+    /// Longest per-flow retention of any flow ever added
+    longest_retention: Option<chrono::Duration>,
+    
+    /// Whether any flow ever added relies on the engine-wide retention
+    uses_default_retention: bool,
+}
+
+impl Partition {
+    /// Create an empty partition for `[start, start + size_secs)`
+    ///
+    /// Flows spanning more than `max_span_buckets` time buckets are listed as long-lived.
+    pub fn new(start: DateTime<Utc>, size_secs: i64, time_bucket_size: i64, max_span_buckets: usize) -> Self {
+        Self {
+            start,
+            end: start + chrono::Duration::seconds(size_secs),
+            slots: Vec::new(),
+            handles: HashMap::new(),
+            free_handles: Vec::new(),
+            live: RoaringBitmap::new(),
+            path_index: PathIndex::new(),
+            time_index: TimeIndex::with_max_span(time_bucket_size, max_span_buckets),
+            metric_index: MetricIndex::new(),
+            hop_index: HopIndex::new(time_bucket_size),
+            max_end_time: None,
+            longest_retention: None,
+            uses_default_retention: false,
+        }
+    }
+    
+    /// Get the start of the window
+    pub fn start(&self) -> DateTime<Utc> {
+        self.start
+    }
+    
+    /// Get the (exclusive) end of the window
+    pub fn end(&self) -> DateTime<Utc> {
+        self.end
+    }
+    
+    /// Add or replace a flow, returning the entry it replaced
+    ///
+    /// `previous` is the flow being replaced; its index entries are removed
+    /// first so a changed path or start time leaves nothing stale.
+    pub fn insert(&mut self, flow: &Flow, previous: Option<&Flow>) -> Option<FlowEntry> {
+        let handle = match self.handles.get(&flow.flow_id) {
+            Some(handle) => *handle,
+            None => self.allocate_handle(&flow.flow_id),
+        };
+        
+        if let Some(previous) = previous {
+            self.path_index.remove_flow(handle, previous);
+            self.time_index.remove_flow(handle, previous);
+            self.metric_index.remove_flow(handle, previous);
+            self.hop_index.remove_flow(handle, previous);
+        }
+        self.path_index.add_flow(handle, flow);
+        self.time_index.add_flow(handle, flow);
+        self.metric_index.add_flow(handle, flow);
+        self.hop_index.add_flow(handle, flow);
+        
+        if self.max_end_time.is_none_or(|end| flow.end_time > end) {
+            self.max_end_time = Some(flow.end_time);
+        }
+        match flow.retention() {
+            Some(retention) => {
+                self.longest_retention = self.longest_retention.max(Some(retention));
+            }
+            None => self.uses_default_retention = true,
+        }
+        
+        self.slots[handle as usize].replace(FlowEntry::new(flow))
+    }
+    
+    /// Assign a handle to a new flow, reusing an emptied slot if there is one
+    fn allocate_handle(&mut self, flow_id: &str) -> FlowHandle {
+        let handle = self.free_handles.pop().unwrap_or_else(|| {
+            self.slots.push(None);
+            (self.slots.len() - 1) as FlowHandle
+        });
+        self.handles.insert(flow_id.to_string(), handle);
+        self.live.insert(handle);
+        handle
+    }
+    
+    /// Remove a flow, returning its entry
+    ///
+    /// `flow` is the flow as last inserted, whose index entries are removed.
+    pub fn remove(&mut self, flow: &Flow) -> Option<FlowEntry> {
+        let handle = self.handles.remove(&flow.flow_id)?;
+        let entry = self.slots[handle as usize].take()?;
+        self.free_handles.push(handle);
+        self.live.remove(handle);
+        
+        self.path_index.remove_flow(handle, flow);
+        self.time_index.remove_flow(handle, flow);
+        self.metric_index.remove_flow(handle, flow);
+        self.hop_index.remove_flow(handle, flow);
+        Some(entry)
+    }
+    
+    /// Take every flow out of the partition
+    pub fn drain(&mut self) -> impl Iterator<Item = FlowEntry> + '_ {
+        self.path_index = PathIndex::new();
+        let bucket_size_secs = self.time_index.stats().bucket_size_secs;
+        self.time_index.clear();
+        self.metric_index = MetricIndex::new();
+        self.hop_index = HopIndex::new(bucket_size_secs);
+        self.handles.clear();
+        self.free_handles.clear();
+        self.live.clear();
+        self.slots.drain(..).flatten()
+    }
+    
+    /// Get a flow's entry by ID
+    pub fn get(&self, flow_id: &str) -> Option<&FlowEntry> {
+        self.handles.get(flow_id).and_then(|handle| self.get_by_handle(*handle))
+    }
+    
+    /// Get a flow's entry by handle
+    pub fn get_by_handle(&self, handle: FlowHandle) -> Option<&FlowEntry> {
+        self.slots.get(handle as usize).and_then(Option::as_ref)
+    }
+    
+    /// Get the entries behind a set of handles, in handle order
+    pub fn resolve<'a>(&'a self, handles: &'a RoaringBitmap) -> impl Iterator<Item = &'a FlowEntry> {
+        handles.iter().filter_map(|handle| self.get_by_handle(handle))
+    }
+    
+    /// Check whether the partition holds a flow
+    pub fn contains(&self, flow_id: &str) -> bool {
+        self.handles.contains_key(flow_id)
+    }
+    
+    /// Iterate over flow entries
+    pub fn flows(&self) -> impl Iterator<Item = &FlowEntry> {
+        self.slots.iter().flatten()
+    }
+    
+    /// Get the number of flows
+    pub fn len(&self) -> usize {
+        self.handles.len()
+    }
+    
+    /// Check whether the partition holds no flows
+    pub fn is_empty(&self) -> bool {
+        self.handles.is_empty()
+    }
+    
+    /// Get the partition's time index
+    pub fn time_index(&self) -> &TimeIndex {
+        &self.time_index
+    }
+    
+    /// Get the partition's path index
+    pub fn path_index(&self) -> &PathIndex {
+        &self.path_index
+    }
+    
+    /// Get the partition's metric index
+    pub fn metric_index(&self) -> &MetricIndex {
+        &self.metric_index
+    }
+    
+    /// Get the partition's hop index
+    pub fn hop_index(&self) -> &HopIndex {
+        &self.hop_index
+    }
+    
+    /// Check whether every flow in the partition is past its retention
+    pub fn is_expired(&self, now: DateTime<Utc>, default_retention: Option<chrono::Duration>) -> bool {
+        let retention = if self.uses_default_retention {
+            match default_retention {
+                Some(default) => self.longest_retention.max(Some(default)),
+                None => return false,
+            }
+        } else {
+            self.longest_retention
+        };
+        
+        match (self.max_end_time, retention) {
+            (Some(max_end_time), Some(retention)) => max_end_time + retention < now,
+            _ => false,
+        }
+    }
+    
+    /// Check whether the partition's flows may overlap every time condition of a query
+    ///
+    /// Flows start within the window but may stay active up to the latest end time seen.
+    pub fn may_match(&self, query: &QueryBuilder, now: DateTime<Utc>) -> bool {
+        let (_, time_conditions, _) = query.conditions();
+        time_conditions.iter().all(|condition| {
+            let (from, to) = condition.bounds(now);
+            from.is_none_or(|from| self.max_end_time.is_some_and(|end| end >= from))
+                && to.is_none_or(|to| self.start <= to)
+        })
+    }
+    
+    /// Get candidate flow handles from the partition's indexes
+    pub fn candidates(&self, query: &QueryBuilder) -> RoaringBitmap {
+        let (path_conditions, time_conditions, metric_conditions) = query.conditions();
+        
+        let mut candidates: Option<RoaringBitmap> = None;
+        
+        // Apply path-based index optimizations
+        for condition in path_conditions {
+            let path_candidates = self.path_candidates(condition);
+            
+            candidates = match candidates {
+                None => Some(path_candidates),
+                Some(existing) => Some(existing & path_candidates),
+            };
+            
+            // Early exit if no candidates
+            if candidates.as_ref().is_some_and(|c| c.is_empty()) {
+                return RoaringBitmap::new();
+            }
+        }
+        
+        // Apply time-based index optimizations
+        for condition in time_conditions {
+            let time_candidates = self.time_candidates(condition);
+            
+            candidates = match candidates {
+                None => Some(time_candidates),
+                Some(existing) => Some(existing & time_candidates),
+            };
+            
+            // Early exit if no candidates
+            if candidates.as_ref().is_some_and(|c| c.is_empty()) {
+                return RoaringBitmap::new();
+            }
+        }
+        
+        // Apply metric-based index optimizations
+        for condition in metric_conditions {
+            let Some(metric_candidates) = self.metric_candidates(condition) else {
+                continue;
+            };
+            
+            candidates = match candidates {
+                None => Some(metric_candidates),
+                Some(existing) => Some(existing & metric_candidates),
+            };
+            
+            // Early exit if no candidates
+            if candidates.as_ref().is_some_and(|c| c.is_empty()) {
+                return RoaringBitmap::new();
+            }
+        }
+        
+        // If no index-based conditions, return all flows
+        candidates.unwrap_or_else(|| self.live.clone())
+    }
+    
+    /// Get candidate flows from the path index
+    fn path_candidates(&self, condition: &PathCondition) -> RoaringBitmap {
+        match condition {
+            PathCondition::ExactPath(path) => self.path_index.find_exact_path(path),
+            PathCondition::ThroughSwitch(switch_id) => self.path_index.find_flows_through_switch(switch_id),
+            PathCondition::ThroughLink(from, to) => self.path_index.find_flows_through_link(from, to),
+            PathCondition::ContainsPath(subpath) => self.path_index.find_flows_containing_path(subpath),
+            PathCondition::StartsWith(prefix) => self.path_index.find_flows_with_prefix(prefix),
+            PathCondition::EndsWith(suffix) => self.path_index.find_flows_with_suffix(suffix),
+            // For conditions that can't be optimized by index, return all flows
+            _ => self.live.clone(),
+        }
+    }
+    
+    /// Get candidate flows from the metric index, or None if it doesn't cover the condition
+    fn metric_candidates(&self, condition: &MetricCondition) -> Option<RoaringBitmap> {
+        let index = &self.metric_index;
+        let candidates = match condition {
+            MetricCondition::TotalDelayGreaterThan(threshold) => {
+                index.find_total_delay(Bound::Excluded(*threshold), Bound::Unbounded)
+            }
+            MetricCondition::TotalDelayLessThan(threshold) => {
+                index.find_total_delay(Bound::Unbounded, Bound::Excluded(*threshold))
+            }
+            MetricCondition::TotalDelayInRange(min, max) => {
+                index.find_total_delay(Bound::Included(*min), Bound::Included(*max))
+            }
+            MetricCondition::MaxQueueUtilGreaterThan(threshold) => {
+                index.find_max_queue_util(Bound::Excluded(*threshold), Bound::Unbounded)
+            }
+            MetricCondition::MaxQueueUtilLessThan(threshold) => {
+                index.find_max_queue_util(Bound::Unbounded, Bound::Excluded(*threshold))
+            }
+            MetricCondition::AvgQueueUtilGreaterThan(threshold) => {
+                index.find_avg_queue_util(Bound::Excluded(*threshold), Bound::Unbounded)
+            }
+            MetricCondition::DurationGreaterThan(_)
+            | MetricCondition::DurationLessThan(_)
+            | MetricCondition::LinkDelayGreaterThan(..) => return None,
+        };
+        Some(candidates)
+    }
+    
+    /// Get candidate flows from the time index
+    fn time_candidates(&self, condition: &TimeCondition) -> RoaringBitmap {
+        let now = Utc::now();
+        
+        match condition {
+            TimeCondition::After(time) => self.time_index.find_flows_after(*time),
+            // Flows starting earlier in the bucket holding `time` are only listed from that bucket
+            TimeCondition::Before(time) => {
+                self.time_index.find_flows_before(*time) | self.time_index.find_flows_in_range(*time, *time)
+            }
+            TimeCondition::InRange(start, end) => self.time_index.find_flows_in_range(*start, *end),
+            TimeCondition::WithinLast(seconds) => {
+                self.time_index.find_flows_after(now - chrono::Duration::seconds(*seconds))
+            }
+            TimeCondition::WithinLastMinutes(minutes) => {
+                self.time_index.find_flows_after(now - chrono::Duration::minutes(*minutes))
+            }
+            TimeCondition::WithinLastHours(hours) => {
+                self.time_index.find_flows_after(now - chrono::Duration::hours(*hours))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Hop, TelemetryMetrics};
+    use crate::storage::DEFAULT_MAX_SPAN_BUCKETS;
+    
+    fn create_test_flow(flow_id: &str, switches: &[&str], start_time: DateTime<Utc>) -> Flow {
+        let hops: Vec<Hop> = switches
+            .iter()
+            .enumerate()
+            .map(|(i, switch)| {
+                Hop::new(
+                    i as u32,
+                    switch.to_string(),
+                    start_time + chrono::Duration::milliseconds(i as i64 * 10),
+                    TelemetryMetrics::with_basic(0.1 * i as f64, 100 * i as u64),
+                )
+            })
+            .collect();
+        Flow::new(flow_id.to_string(), hops).unwrap()
+    }
+    
+    #[test]
+    fn test_partition_indexes_and_pruning() {
+        let start = TimeIndex::bucket_start(Utc::now(), 3600);
+        let mut partition = Partition::new(start, 3600, 60, DEFAULT_MAX_SPAN_BUCKETS);
+        
+        let flow1 = create_test_flow("flow1", &["s1", "s2"], start + chrono::Duration::minutes(5));
+        let flow2 = create_test_flow("flow2", &["s2", "s3"], start + chrono::Duration::minutes(30));
+        partition.insert(&flow1, None);
+        partition.insert(&flow2, None);
+        
+        assert_eq!(partition.candidates(&QueryBuilder::through_switch("s1")).len(), 1);
+        assert_eq!(partition.candidates(&QueryBuilder::new()).len(), 2);
+        
+        let now = Utc::now();
+        assert!(partition.may_match(&QueryBuilder::in_time_range(start, start + chrono::Duration::minutes(1)), now));
+        assert!(!partition.may_match(&QueryBuilder::in_time_range(start - chrono::Duration::hours(2), start - chrono::Duration::hours(1)), now));
+        assert!(!partition.may_match(&QueryBuilder::new().with_time_condition(TimeCondition::After(partition.end())), now));
+        
+        let removed = partition.remove(&flow1).unwrap();
+        assert_eq!(removed, FlowEntry::new(&flow1));
+        assert!(partition.candidates(&QueryBuilder::through_switch("s1")).is_empty());
+        
+        // A new flow reuses the freed handle without inheriting its postings
+        let flow3 = create_test_flow("flow3", &["s4"], start + chrono::Duration::minutes(40));
+        partition.insert(&flow3, None);
+        assert_eq!(partition.slots.len(), 2);
+        let through_s4 = partition.candidates(&QueryBuilder::through_switch("s4"));
+        assert_eq!(partition.resolve(&through_s4).map(|flow| flow.flow_id.as_str()).collect::<Vec<_>>(), vec!["flow3"]);
+        assert!(partition.candidates(&QueryBuilder::through_switch("s1")).is_empty());
+        assert_eq!(partition.get("flow3").unwrap().end_time, flow3.end_time);
+        assert_eq!(partition.remove(&flow3).unwrap().flow_id, "flow3");
+        assert_eq!(partition.drain().count(), 1);
+        assert!(partition.is_empty());
+    }
+    
+    #[test]
+    fn test_reused_handle_leaves_no_stale_postings() {
+        let start = TimeIndex::bucket_start(Utc::now(), 3600);
+        let mut partition = Partition::new(start, 3600, 60, DEFAULT_MAX_SPAN_BUCKETS);
+        
+        // Total delay 300 through s1..s3 early in the window
+        let old_start = start + chrono::Duration::minutes(5);
+        let old = create_test_flow("old", &["s1", "s2", "s3"], old_start);
+        partition.insert(&old, None);
+        let handle = partition.handles["old"];
+        partition.remove(&old);
+        
+        // Total delay 0 through s4 late in the window, in the freed slot
+        let new = create_test_flow("new", &["s4"], start + chrono::Duration::minutes(40));
+        partition.insert(&new, None);
+        assert_eq!(partition.handles["new"], handle);
+        
+        let old_predicates = [
+            QueryBuilder::through_switch("s1"),
+            QueryBuilder::through_link("s1", "s2"),
+            QueryBuilder::exact_path(old.path.clone()),
+            QueryBuilder::new().with_path_condition(PathCondition::StartsWith(vec!["s1".to_string()])),
+            QueryBuilder::in_time_range(old_start, old_start + chrono::Duration::seconds(1)),
+            QueryBuilder::with_high_delay(200),
+        ];
+        for query in &old_predicates {
+            assert!(!partition.candidates(query).contains(handle), "{:?}", query.conditions());
+        }
+        
+        let window = (old_start, old_start + chrono::Duration::minutes(1));
+        assert!(partition.hop_index().find_hops("s1", window.0, window.1).is_empty());
+        assert!(partition.hop_index().find_all_hops(window.0, window.1).is_empty());
+        
+        // The new flow's own predicates still find it
+        assert!(partition.candidates(&QueryBuilder::through_switch("s4")).contains(handle));
+        let late = start + chrono::Duration::minutes(40);
+        assert!(partition.candidates(&QueryBuilder::in_time_range(late, late)).contains(handle));
+        assert_eq!(partition.hop_index().find_hops("s4", late, late).len(), 1);
+    }
+    
+    #[test]
+    fn test_metric_conditions_narrow_candidates() {
+        let start = TimeIndex::bucket_start(Utc::now(), 3600);
+        let mut partition = Partition::new(start, 3600, 60, DEFAULT_MAX_SPAN_BUCKETS);
+        
+        // Total delays 100 and 300
+        let flow1 = create_test_flow("flow1", &["s1", "s2"], start);
+        let flow2 = create_test_flow("flow2", &["s1", "s2", "s3"], start);
+        partition.insert(&flow1, None);
+        partition.insert(&flow2, None);
+        
+        let slow = QueryBuilder::with_high_delay(200);
+        fn ids(partition: &Partition, query: &QueryBuilder) -> Vec<String> {
+            partition.resolve(&partition.candidates(query)).map(|flow| flow.flow_id.clone()).collect()
+        }
+        assert_eq!(ids(&partition, &slow), vec!["flow2"]);
+        let busy_and_slow = slow.clone().with_metric_condition(MetricCondition::MaxQueueUtilLessThan(0.15));
+        assert!(partition.candidates(&busy_and_slow).is_empty());
+        
+        // Conditions the index doesn't cover leave the candidates alone
+        let long = QueryBuilder::new().with_metric_condition(MetricCondition::DurationGreaterThan(0));
+        assert_eq!(partition.candidates(&long).len(), 2);
+        
+        // Appended telemetry moves the flow in the index; removal drops it
+        let mut appended = flow1.clone();
+        appended.merge(&create_test_flow("flow1", &["s3", "s4", "s5"], start));
+        partition.insert(&appended, Some(&flow1));
+        assert_eq!(partition.candidates(&slow).len(), 2);
+        partition.remove(&flow2);
+        assert_eq!(ids(&partition, &slow), vec!["flow1"]);
+    }
+    
+    #[test]
+    fn test_partition_expiry_uses_longest_retention() {
+        let start = TimeIndex::bucket_start(Utc::now() - chrono::Duration::hours(10), 3600);
+        let mut partition = Partition::new(start, 3600, 60, DEFAULT_MAX_SPAN_BUCKETS);
+        assert!(!partition.is_expired(Utc::now(), Some(chrono::Duration::hours(1))));
+        
+        let mut flow = create_test_flow("flow1", &["s1"], start);
+        flow.retention_policy = Some("1h".to_string());
+        partition.insert(&flow, None);
+        assert!(partition.is_expired(Utc::now(), None));
+        
+        // A flow on the engine default keeps the partition for as long as the default says
+        let flow2 = create_test_flow("flow2", &["s1"], start);
+        partition.insert(&flow2, None);
+        assert!(!partition.is_expired(Utc::now(), None));
+        assert!(!partition.is_expired(Utc::now(), Some(chrono::Duration::hours(24))));
+        assert!(partition.is_expired(Utc::now(), Some(chrono::Duration::hours(2))));
+    }
+}
@@ -1,5 +1,8 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
 use chrono::{DateTime, Utc};
-use crate::models::{Flow, NetworkPath};
+use serde::{Deserialize, Serialize};
+use crate::models::{Flow, FlowStatus, Hop, NetworkPath};
 
 /// Query builder for IntDB
 #[derive(Debug, Clone)]
@@ -18,6 +21,9 @@ pub struct QueryBuilder {
     
     /// Skip some results (for pagination)
     skip: Option<usize>,
+    
+    /// Evaluate against flows as they were stored at this ingest time
+    as_of: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Clone)]
@@ -37,6 +43,9 @@ pub enum PathCondition {
     /// Flow passes through specific switch
     ThroughSwitch(String),
     
+    /// Flow went from the first switch straight to the second
+    ThroughLink(String, String),
+    
     /// Path length equals
     LengthEquals(usize),
     
@@ -44,24 +53,25 @@ pub enum PathCondition {
     LengthInRange(usize, usize),
 }
 
+/// Time window a flow's `[start_time, end_time]` lifetime must overlap
 #[derive(Debug, Clone)]
 pub enum TimeCondition {
-    /// Flows after specific time
+    /// Flows still active at or after specific time
     After(DateTime<Utc>),
     
-    /// Flows before specific time
+    /// Flows started at or before specific time
     Before(DateTime<Utc>),
     
-    /// Flows in time range
+    /// Flows active at some point in time range
     InRange(DateTime<Utc>, DateTime<Utc>),
     
-    /// Flows within last N seconds
+    /// Flows active within last N seconds
     WithinLast(i64),
     
-    /// Flows within last N minutes
+    /// Flows active within last N minutes
     WithinLastMinutes(i64),
     
-    /// Flows within last N hours
+    /// Flows active within last N hours
     WithinLastHours(i64),
 }
 
@@ -90,6 +100,9 @@ pub enum MetricCondition {
     
     /// Duration less than (in milliseconds)
     DurationLessThan(i64),
+    
+    /// Delay of the link between two switches greater than (in nanoseconds, see `Flow::link_delay`)
+    LinkDelayGreaterThan(String, String, u64),
 }
 
 impl QueryBuilder {
@@ -101,6 +114,7 @@ impl QueryBuilder {
             metric_conditions: Vec::new(),
             limit: None,
             skip: None,
+            as_of: None,
         }
     }
     
@@ -134,6 +148,12 @@ impl QueryBuilder {
         self
     }
     
+    /// Evaluate the query as of a past ingest time (see `Flow::as_of`)
+    pub fn as_of(mut self, at: DateTime<Utc>) -> Self {
+        self.as_of = Some(at);
+        self
+    }
+    
     /// Convenience method: find flows with exact path
     pub fn exact_path(path: NetworkPath) -> Self {
         Self::new().with_path_condition(PathCondition::ExactPath(path))
@@ -144,6 +164,11 @@ impl QueryBuilder {
         Self::new().with_path_condition(PathCondition::ThroughSwitch(switch_id.to_string()))
     }
     
+    /// Convenience method: find flows that crossed the link `from -> to`
+    pub fn through_link(from: &str, to: &str) -> Self {
+        Self::new().with_path_condition(PathCondition::ThroughLink(from.to_string(), to.to_string()))
+    }
+    
     /// Convenience method: find flows in time range
     pub fn in_time_range(start: DateTime<Utc>, end: DateTime<Utc>) -> Self {
         Self::new().with_time_condition(TimeCondition::InRange(start, end))
@@ -168,6 +193,11 @@ impl QueryBuilder {
     pub fn pagination(&self) -> (Option<usize>, Option<usize>) {
         (self.limit, self.skip)
     }
+    
+    /// Get the ingest time the query is evaluated as of, if any
+    pub fn as_of_time(&self) -> Option<DateTime<Utc>> {
+        self.as_of
+    }
 }
 
 impl Default for QueryBuilder {
@@ -187,15 +217,26 @@ pub struct QueryResult {
     
     /// Applied limit
     pub limit: Option<usize>,
+    
+    /// Whether more results are available beyond the applied limit
+    pub has_more: bool,
 }
 
 impl QueryResult {
     /// Create a new query result
     pub fn new(flow_ids: Vec<String>, total_count: usize, limit: Option<usize>) -> Self {
+        Self::with_skip(flow_ids, total_count, 0, limit)
+    }
+    
+    /// Create a query result for a page that starts after the first `skip` matches
+    pub fn with_skip(flow_ids: Vec<String>, total_count: usize, skip: usize, limit: Option<usize>) -> Self {
+        let has_more = limit.is_some_and(|limit| total_count > skip.saturating_add(limit));
+        
         Self {
             flow_ids,
             total_count,
             limit,
+            has_more,
         }
     }
     
@@ -210,6 +251,213 @@ impl QueryResult {
     }
 }
 
+/// A matching flow with the start time query results are ordered by
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RankedFlow {
+    pub flow_id: String,
+    pub start_time: DateTime<Utc>,
+}
+
+impl Ord for RankedFlow {
+    /// Most recent first, then by ID so merge order never leaks into pages
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.start_time.cmp(&self.start_time).then_with(|| self.flow_id.cmp(&other.flow_id))
+    }
+}
+
+impl PartialOrd for RankedFlow {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// A hop seen at a switch, with the flow it belongs to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SwitchHop {
+    pub flow_id: String,
+    pub hop: Hop,
+}
+
+impl SwitchHop {
+    /// Oldest first, then by flow ID so merge order never leaks into pages
+    pub fn cmp_by_time(&self, other: &Self) -> Ordering {
+        self.hop.timestamp.cmp(&other.hop.timestamp).then_with(|| self.flow_id.cmp(&other.flow_id))
+    }
+}
+
+/// Telemetry of the hops one switch saw over a time window
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SwitchHopStats {
+    /// Number of hops
+    pub hops: u64,
+    
+    /// Sum and count of the hops' delays, for averaging across nodes
+    pub delay_sum_ns: u64,
+    pub delay_samples: u64,
+    
+    /// Highest queue utilization of any hop
+    pub max_queue_util: Option<f64>,
+}
+
+impl SwitchHopStats {
+    /// Account for one hop
+    pub fn record(&mut self, hop: &Hop) {
+        self.hops += 1;
+        if let Some(delay) = hop.delay() {
+            self.delay_sum_ns += delay;
+            self.delay_samples += 1;
+        }
+        if let Some(util) = hop.queue_utilization() {
+            self.max_queue_util = Some(self.max_queue_util.map_or(util, |max| max.max(util)));
+        }
+    }
+    
+    /// Fold in the stats of the same switch from elsewhere (another node)
+    pub fn merge(&mut self, other: &SwitchHopStats) {
+        self.hops += other.hops;
+        self.delay_sum_ns += other.delay_sum_ns;
+        self.delay_samples += other.delay_samples;
+        if let Some(util) = other.max_queue_util {
+            self.max_queue_util = Some(self.max_queue_util.map_or(util, |max| max.max(util)));
+        }
+    }
+    
+    /// Get the average hop delay, if any hop reported one
+    pub fn avg_delay_ns(&self) -> Option<f64> {
+        (self.delay_samples > 0).then(|| self.delay_sum_ns as f64 / self.delay_samples as f64)
+    }
+}
+
+/// Queue utilization above which a hop counts as congested
+const CONGESTED_QUEUE_UTIL: f64 = 0.8;
+
+/// Telemetry totals over a set of flows, kept as sums and counts so nodes can be combined
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FlowTelemetrySummary {
+    /// Number of flows, and of hops across them
+    pub flows: u64,
+    pub hops: u64,
+    
+    /// Sum, count and highest of the hops' delays
+    pub delay_sum_ns: u64,
+    pub delay_samples: u64,
+    pub max_delay_ns: u64,
+    
+    /// Sum, count and highest of the hops' queue utilizations, and how many were congested
+    pub queue_util_sum: f64,
+    pub queue_util_samples: u64,
+    pub max_queue_util: f64,
+    pub congested_hops: u64,
+    
+    /// Flows by status
+    pub partial_flows: u64,
+    pub complete_flows: u64,
+    pub timeout_flows: u64,
+    
+    /// Switches any hop was seen at
+    pub switches: BTreeSet<String>,
+    
+    /// Number of distinct paths; a path seen on several nodes is counted by each
+    pub distinct_paths: u64,
+    
+    /// Sum and count of the delays between consecutive switches, by source then destination
+    pub link_delays: BTreeMap<String, BTreeMap<String, (u64, u64)>>,
+}
+
+impl FlowTelemetrySummary {
+    /// Account for one flow (its path is counted by the caller, who knows which are distinct)
+    pub fn record(&mut self, flow: &Flow) {
+        self.flows += 1;
+        self.hops += flow.hops.len() as u64;
+        match flow.status {
+            FlowStatus::Partial => self.partial_flows += 1,
+            FlowStatus::Complete => self.complete_flows += 1,
+            FlowStatus::Timeout => self.timeout_flows += 1,
+            FlowStatus::Error(_) => {}
+        }
+        
+        for hop in &flow.hops {
+            if let Some(delay) = hop.metrics.delay_ns {
+                self.delay_sum_ns += delay;
+                self.delay_samples += 1;
+                self.max_delay_ns = self.max_delay_ns.max(delay);
+            }
+            if let Some(util) = hop.metrics.queue_util {
+                self.queue_util_sum += util;
+                self.queue_util_samples += 1;
+                self.max_queue_util = self.max_queue_util.max(util);
+                if util > CONGESTED_QUEUE_UTIL {
+                    self.congested_hops += 1;
+                }
+            }
+            if !self.switches.contains(&hop.switch_id) {
+                self.switches.insert(hop.switch_id.clone());
+            }
+        }
+        
+        for (from, to, delay) in flow.link_delays() {
+            let (sum, count) = self.link_delays.entry(from.to_string()).or_default().entry(to.to_string()).or_default();
+            *sum += delay;
+            *count += 1;
+        }
+    }
+    
+    /// Fold in the summary of other flows (another node's)
+    pub fn merge(&mut self, other: &FlowTelemetrySummary) {
+        self.flows += other.flows;
+        self.hops += other.hops;
+        self.delay_sum_ns += other.delay_sum_ns;
+        self.delay_samples += other.delay_samples;
+        self.max_delay_ns = self.max_delay_ns.max(other.max_delay_ns);
+        self.queue_util_sum += other.queue_util_sum;
+        self.queue_util_samples += other.queue_util_samples;
+        self.max_queue_util = self.max_queue_util.max(other.max_queue_util);
+        self.congested_hops += other.congested_hops;
+        self.partial_flows += other.partial_flows;
+        self.complete_flows += other.complete_flows;
+        self.timeout_flows += other.timeout_flows;
+        self.switches.extend(other.switches.iter().cloned());
+        self.distinct_paths += other.distinct_paths;
+        for (from, links) in &other.link_delays {
+            let ours = self.link_delays.entry(from.clone()).or_default();
+            for (to, (sum, count)) in links {
+                let (our_sum, our_count) = ours.entry(to.clone()).or_default();
+                *our_sum += sum;
+                *our_count += count;
+            }
+        }
+    }
+    
+    /// Get the average hop delay, or 0 if no hop reported one
+    pub fn avg_delay_ns(&self) -> f64 {
+        ratio(self.delay_sum_ns as f64, self.delay_samples)
+    }
+    
+    /// Get the average hop queue utilization, or 0 if no hop reported one
+    pub fn avg_queue_util(&self) -> f64 {
+        ratio(self.queue_util_sum, self.queue_util_samples)
+    }
+    
+    /// Get the share of hops reporting queue utilization that were congested
+    pub fn congestion_ratio(&self) -> f64 {
+        ratio(self.congested_hops as f64, self.queue_util_samples)
+    }
+    
+    /// Get the average number of hops per flow
+    pub fn avg_path_length(&self) -> f64 {
+        ratio(self.hops as f64, self.flows)
+    }
+}
+
+/// Divide, treating an empty set as 0
+fn ratio(sum: f64, count: u64) -> f64 {
+    if count == 0 {
+        0.0
+    } else {
+        sum / count as f64
+    }
+}
+
 /// Helper functions for query condition evaluation
 impl PathCondition {
     /// Check if a flow matches this path condition
@@ -220,6 +468,7 @@ impl PathCondition {
             PathCondition::StartsWith(prefix) => flow.path.starts_with(prefix),
             PathCondition::EndsWith(suffix) => flow.path.ends_with(suffix),
             PathCondition::ThroughSwitch(switch_id) => flow.contains_switch(switch_id),
+            PathCondition::ThroughLink(from, to) => flow.traverses_link(from, to),
             PathCondition::LengthEquals(length) => flow.path_length() == *length,
             PathCondition::LengthInRange(min, max) => {
                 let len = flow.path_length();
@@ -232,22 +481,28 @@ impl PathCondition {
 impl TimeCondition {
     /// Check if a flow matches this time condition
     pub fn matches(&self, flow: &Flow) -> bool {
-        let now = Utc::now();
+        self.matches_lifetime(flow.start_time, flow.end_time)
+    }
+    
+    /// Get the inclusive window this condition selects (None is unbounded)
+    ///
+    /// A flow matches when its `[start_time, end_time]` lifetime overlaps the window.
+    pub fn bounds(&self, now: DateTime<Utc>) -> (Option<DateTime<Utc>>, Option<DateTime<Utc>>) {
         match self {
-            TimeCondition::After(time) => flow.start_time >= *time,
-            TimeCondition::Before(time) => flow.start_time <= *time,
-            TimeCondition::InRange(start, end) => flow.start_time >= *start && flow.start_time <= *end,
-            TimeCondition::WithinLast(seconds) => {
-                flow.start_time >= now - chrono::Duration::seconds(*seconds)
-            }
-            TimeCondition::WithinLastMinutes(minutes) => {
-                flow.start_time >= now - chrono::Duration::minutes(*minutes)
-            }
-            TimeCondition::WithinLastHours(hours) => {
-                flow.start_time >= now - chrono::Duration::hours(*hours)
-            }
+            TimeCondition::After(time) => (Some(*time), None),
+            TimeCondition::Before(time) => (None, Some(*time)),
+            TimeCondition::InRange(start, end) => (Some(*start), Some(*end)),
+            TimeCondition::WithinLast(seconds) => (Some(now - chrono::Duration::seconds(*seconds)), None),
+            TimeCondition::WithinLastMinutes(minutes) => (Some(now - chrono::Duration::minutes(*minutes)), None),
+            TimeCondition::WithinLastHours(hours) => (Some(now - chrono::Duration::hours(*hours)), None),
         }
     }
+    
+    /// Check if a flow active from `start_time` to `end_time` matches this time condition
+    pub fn matches_lifetime(&self, start_time: DateTime<Utc>, end_time: DateTime<Utc>) -> bool {
+        let (from, to) = self.bounds(Utc::now());
+        from.is_none_or(|from| end_time >= from) && to.is_none_or(|to| start_time <= to)
+    }
 }
 
 impl MetricCondition {
@@ -255,22 +510,22 @@ impl MetricCondition {
     pub fn matches(&self, flow: &Flow) -> bool {
         match self {
             MetricCondition::TotalDelayGreaterThan(threshold) => {
-                flow.total_delay().map_or(false, |delay| delay > *threshold)
+                flow.total_delay().is_some_and(|delay| delay > *threshold)
             }
             MetricCondition::TotalDelayLessThan(threshold) => {
-                flow.total_delay().map_or(false, |delay| delay < *threshold)
+                flow.total_delay().is_some_and(|delay| delay < *threshold)
             }
             MetricCondition::TotalDelayInRange(min, max) => {
-                flow.total_delay().map_or(false, |delay| delay >= *min && delay <= *max)
+                flow.total_delay().is_some_and(|delay| delay >= *min && delay <= *max)
             }
             MetricCondition::MaxQueueUtilGreaterThan(threshold) => {
-                flow.max_queue_utilization().map_or(false, |util| util > *threshold)
+                flow.max_queue_utilization().is_some_and(|util| util > *threshold)
             }
             MetricCondition::MaxQueueUtilLessThan(threshold) => {
-                flow.max_queue_utilization().map_or(false, |util| util < *threshold)
+                flow.max_queue_utilization().is_some_and(|util| util < *threshold)
             }
             MetricCondition::AvgQueueUtilGreaterThan(threshold) => {
-                flow.avg_queue_utilization().map_or(false, |util| util > *threshold)
+                flow.avg_queue_utilization().is_some_and(|util| util > *threshold)
             }
             MetricCondition::DurationGreaterThan(threshold) => {
                 flow.duration_ms() > *threshold
@@ -278,6 +533,9 @@ impl MetricCondition {
             MetricCondition::DurationLessThan(threshold) => {
                 flow.duration_ms() < *threshold
             }
+            MetricCondition::LinkDelayGreaterThan(from, to, threshold) => {
+                flow.link_delay(from, to).is_some_and(|delay| delay > *threshold)
+            }
         }
     }
 }
@@ -326,6 +584,10 @@ mod tests {
         let through_condition = PathCondition::ThroughSwitch("s2".to_string());
         assert!(through_condition.matches(&flow));
         
+        // Test through link
+        assert!(PathCondition::ThroughLink("s1".to_string(), "s2".to_string()).matches(&flow));
+        assert!(!PathCondition::ThroughLink("s2".to_string(), "s1".to_string()).matches(&flow));
+        
         // Test length
         let length_condition = PathCondition::LengthEquals(3);
         assert!(length_condition.matches(&flow));
@@ -373,6 +635,12 @@ mod tests {
         // Test queue utilization (max should be 0.2)
         let queue_condition = MetricCondition::MaxQueueUtilGreaterThan(0.1);
         assert!(queue_condition.matches(&flow));
+        
+        // Test link delay (hops are 10ms apart)
+        let link_condition = MetricCondition::LinkDelayGreaterThan("s1".to_string(), "s2".to_string(), 5_000_000);
+        assert!(link_condition.matches(&flow));
+        let link_condition = MetricCondition::LinkDelayGreaterThan("s1".to_string(), "s3".to_string(), 0);
+        assert!(!link_condition.matches(&flow));
     }
 
     #[test]
@@ -423,7 +691,39 @@ mod tests {
         assert!(result.has_more);
         assert!(!result.is_empty());
         
-        let result_no_limit = QueryResult::new(flow_ids, 2, None);
+        let result_no_limit = QueryResult::new(flow_ids.clone(), 2, None);
         assert!(!result_no_limit.has_more);
+        
+        // The last page isn't followed by more results
+        let last_page = QueryResult::with_skip(flow_ids.clone(), 12, 10, Some(5));
+        assert!(!last_page.has_more);
+        
+        let middle_page = QueryResult::with_skip(flow_ids, 12, 5, Some(5));
+        assert!(middle_page.has_more);
     }
-} 
\ No newline at end of file
+    
+    #[test]
+    fn test_telemetry_summary_merges_across_nodes() {
+        let now = Utc::now();
+        let flow1 = create_test_flow("flow1", &["s1", "s2", "s3"], now);
+        let flow2 = create_test_flow("flow2", &["s3", "s4"], now);
+        
+        let mut whole = FlowTelemetrySummary::default();
+        whole.record(&flow1);
+        whole.record(&flow2);
+        
+        let (mut node_a, mut node_b) = (FlowTelemetrySummary::default(), FlowTelemetrySummary::default());
+        node_a.record(&flow1);
+        node_b.record(&flow2);
+        node_a.merge(&node_b);
+        assert_eq!(node_a, whole);
+        
+        // Delays 0, 100, 200 and 0, 100
+        assert_eq!(whole.avg_delay_ns(), 80.0);
+        assert_eq!(whole.max_delay_ns, 200);
+        assert_eq!(whole.avg_path_length(), 2.5);
+        assert_eq!(whole.switches.len(), 4);
+        assert_eq!(whole.link_delays["s1"]["s2"], (10_000_000, 1));
+        assert_eq!(FlowTelemetrySummary::default().congestion_ratio(), 0.0);
+    }
+}
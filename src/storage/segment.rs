@@ -0,0 +1,775 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use chrono::{DateTime, Utc};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+use crate::models::{Flow, FlowStatus, Hop, IngestVersion, Migration, NetworkPath, TelemetryMetrics, Versioned};
+use crate::storage::{MetricCondition, PathCondition, QueryBuilder, StorageError};
+
+/// Segment format version written by this build (older segments are upgraded on read)
+pub const SEGMENT_FORMAT_VERSION: u32 = 2;
+
+/// File extension for segments kept in the segment directory
+pub const SEGMENT_EXTENSION: &str = "segment";
+
+/// Min/max statistics for one column
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ColumnStats<T> {
+    pub min: Option<T>,
+    pub max: Option<T>,
+    pub null_count: usize,
+}
+
+impl<T: PartialOrd + Copy> ColumnStats<T> {
+    /// Compute statistics over a column
+    fn from_values(values: impl IntoIterator<Item = Option<T>>) -> Self {
+        let mut stats = Self { min: None, max: None, null_count: 0 };
+        for value in values {
+            match value {
+                Some(v) => {
+                    if stats.min.is_none_or(|min| v < min) {
+                        stats.min = Some(v);
+                    }
+                    if stats.max.is_none_or(|max| v > max) {
+                        stats.max = Some(v);
+                    }
+                }
+                None => stats.null_count += 1,
+            }
+        }
+        stats
+    }
+}
+
+/// Header stored on the first line of a segment file
+///
+/// The second line holds the flow IDs and the third the column data, so the
+/// segment catalog can be rebuilt without decoding any telemetry.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SegmentHeader {
+    /// Segment format version
+    pub format_version: u32,
+    
+    /// Segment identifier, increasing in creation order
+    pub segment_id: u64,
+    
+    /// WAL sequence number of the record that created the segment (0 without a WAL)
+    pub wal_lsn: u64,
+    
+    /// When the segment was written
+    pub created_at: DateTime<Utc>,
+    
+    /// When every flow in the segment has passed its retention (None if some never expire)
+    pub expires_at: Option<DateTime<Utc>>,
+    
+    /// Number of flows and hops
+    pub flow_count: usize,
+    pub hop_count: usize,
+    
+    /// Flow-level statistics
+    pub start_time: ColumnStats<DateTime<Utc>>,
+    pub end_time: ColumnStats<DateTime<Utc>>,
+    pub path_length: ColumnStats<usize>,
+    
+    /// Hop-level statistics, one per telemetry column
+    pub hop_timestamp: ColumnStats<DateTime<Utc>>,
+    pub queue_util: ColumnStats<f64>,
+    pub delay_ns: ColumnStats<u64>,
+    pub bandwidth_bps: ColumnStats<u64>,
+    pub drop_count: ColumnStats<u64>,
+    pub egress_port: ColumnStats<u32>,
+    pub ingress_port: ColumnStats<u32>,
+    
+    /// Switch dictionary; column data refers to switches by position
+    pub switches: Vec<String>,
+}
+
+/// Column data of a segment
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SegmentColumns {
+    // Flow columns
+    start_time: Vec<DateTime<Utc>>,
+    end_time: Vec<DateTime<Utc>>,
+    status: Vec<FlowStatus>,
+    retention_policy: Vec<Option<String>>,
+    ingest_versions: Vec<Vec<IngestVersion>>,
+    
+    /// Path of flow `i` is `path_switches[path_offsets[i]..path_offsets[i + 1]]`
+    path_offsets: Vec<u32>,
+    path_switches: Vec<u32>,
+    
+    /// Hops of flow `i` are rows `hop_offsets[i]..hop_offsets[i + 1]`
+    hop_offsets: Vec<u32>,
+    
+    // Hop columns
+    hop_index: Vec<u32>,
+    hop_switch: Vec<u32>,
+    hop_timestamp: Vec<DateTime<Utc>>,
+    queue_util: Vec<Option<f64>>,
+    delay_ns: Vec<Option<u64>>,
+    bandwidth_bps: Vec<Option<u64>>,
+    drop_count: Vec<Option<u64>>,
+    egress_port: Vec<Option<u32>>,
+    ingress_port: Vec<Option<u32>>,
+    custom_metrics: Vec<Option<IndexMap<String, serde_json::Value>>>,
+}
+
+/// Column layouts, versioned by the header's `format_version`
+///
+/// 1. The initial layout
+/// 2. Adds the `ingest_versions` flow column
+impl Versioned for SegmentColumns {
+    const KIND: &'static str = "segment columns";
+    const SCHEMA_VERSION: u32 = SEGMENT_FORMAT_VERSION;
+    const MIGRATIONS: &'static [Migration] = &[columns_v1_to_v2];
+}
+
+fn columns_v1_to_v2(columns: &mut serde_json::Map<String, serde_json::Value>) -> Result<(), String> {
+    // Flows frozen before ingest versions were recorded have none
+    let flow_count = columns
+        .get("start_time")
+        .and_then(|times| times.as_array())
+        .map(Vec::len)
+        .ok_or("missing start_time column")?;
+    let no_versions = serde_json::Value::Array(Vec::new());
+    columns.insert("ingest_versions".to_string(), serde_json::Value::Array(vec![no_versions; flow_count]));
+    Ok(())
+}
+
+/// A segment file on disk
+#[derive(Debug, Clone)]
+pub struct SegmentInfo {
+    pub path: PathBuf,
+    pub size_bytes: u64,
+    pub header: SegmentHeader,
+}
+
+impl SegmentInfo {
+    /// Check whether any flow in the segment could match the query, using only the header
+    pub fn may_match(&self, query: &QueryBuilder) -> bool {
+        let (path_conditions, time_conditions, metric_conditions) = query.conditions();
+        let header = &self.header;
+        let now = Utc::now();
+        
+        let path_ok = path_conditions.iter().all(|condition| match condition {
+            PathCondition::ThroughSwitch(switch_id) => header.switches.binary_search(switch_id).is_ok(),
+            PathCondition::ThroughLink(from, to) => [from, to].iter().all(|s| header.switches.binary_search(s).is_ok()),
+            PathCondition::ExactPath(path) => path.switches.iter().all(|s| header.switches.binary_search(s).is_ok()),
+            PathCondition::LengthEquals(length) => in_range(&header.path_length, length, length),
+            PathCondition::LengthInRange(min, max) => in_range(&header.path_length, min, max),
+            _ => true,
+        });
+        
+        // Time conditions select flows whose lifetime overlaps their window
+        let (earliest, latest) = (&header.start_time.min, &header.end_time.max);
+        let time_ok = time_conditions.iter().all(|condition| {
+            let (from, to) = condition.bounds(now);
+            from.is_none_or(|from| latest.is_some_and(|latest| latest >= from))
+                && to.is_none_or(|to| earliest.is_some_and(|earliest| earliest <= to))
+        });
+        
+        let metric_ok = metric_conditions.iter().all(|condition| match condition {
+            MetricCondition::MaxQueueUtilGreaterThan(threshold)
+            | MetricCondition::AvgQueueUtilGreaterThan(threshold) => {
+                header.queue_util.max.is_some_and(|max| max > *threshold)
+            }
+            MetricCondition::MaxQueueUtilLessThan(threshold) => {
+                header.queue_util.min.is_some_and(|min| min < *threshold)
+            }
+            MetricCondition::TotalDelayGreaterThan(_)
+            | MetricCondition::TotalDelayLessThan(_)
+            | MetricCondition::TotalDelayInRange(_, _) => header.delay_ns.max.is_some(),
+            _ => true,
+        });
+        
+        path_ok && time_ok && metric_ok
+    }
+}
+
+/// Check whether a column's [min, max] overlaps [from, to]
+fn in_range<T: PartialOrd>(stats: &ColumnStats<T>, from: &T, to: &T) -> bool {
+    match (&stats.min, &stats.max) {
+        (Some(min), Some(max)) => min <= to && max >= from,
+        _ => false,
+    }
+}
+
+/// Name of the segment file with the given ID
+pub fn segment_file_name(segment_id: u64) -> String {
+    format!("seg-{:020}.{}", segment_id, SEGMENT_EXTENSION)
+}
+
+/// Write flows into a new immutable segment (temporary file, fsync, rename)
+pub fn write_segment(
+    path: impl AsRef<Path>,
+    segment_id: u64,
+    wal_lsn: u64,
+    expires_at: Option<DateTime<Utc>>,
+    flows: &[Flow],
+) -> Result<SegmentInfo, StorageError> {
+    let path = path.as_ref();
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    
+    // Build the switch dictionary (sorted, so the header can be binary searched)
+    let mut dictionary: BTreeMap<&str, u32> = BTreeMap::new();
+    for flow in flows {
+        for switch in flow.path.switches.iter().chain(flow.hops.iter().map(|h| &h.switch_id)) {
+            dictionary.entry(switch.as_str()).or_insert(0);
+        }
+    }
+    for (position, code) in dictionary.values_mut().enumerate() {
+        *code = position as u32;
+    }
+    
+    let mut columns = SegmentColumns::default();
+    columns.path_offsets.push(0);
+    columns.hop_offsets.push(0);
+    
+    for flow in flows {
+        columns.start_time.push(flow.start_time);
+        columns.end_time.push(flow.end_time);
+        columns.status.push(flow.status.clone());
+        columns.retention_policy.push(flow.retention_policy.clone());
+        columns.ingest_versions.push(flow.ingest_versions.clone());
+        
+        columns.path_switches.extend(flow.path.switches.iter().map(|s| dictionary[s.as_str()]));
+        columns.path_offsets.push(columns.path_switches.len() as u32);
+        
+        for hop in &flow.hops {
+            let metrics = &hop.metrics;
+            columns.hop_index.push(hop.hop_index);
+            columns.hop_switch.push(dictionary[hop.switch_id.as_str()]);
+            columns.hop_timestamp.push(hop.timestamp);
+            columns.queue_util.push(metrics.queue_util);
+            columns.delay_ns.push(metrics.delay_ns);
+            columns.bandwidth_bps.push(metrics.bandwidth_bps);
+            columns.drop_count.push(metrics.drop_count);
+            columns.egress_port.push(metrics.egress_port);
+            columns.ingress_port.push(metrics.ingress_port);
+            columns.custom_metrics.push(metrics.custom_metrics.clone());
+        }
+        columns.hop_offsets.push(columns.hop_index.len() as u32);
+    }
+    
+    let header = SegmentHeader {
+        format_version: SEGMENT_FORMAT_VERSION,
+        segment_id,
+        wal_lsn,
+        created_at: Utc::now(),
+        expires_at,
+        flow_count: flows.len(),
+        hop_count: columns.hop_index.len(),
+        start_time: ColumnStats::from_values(columns.start_time.iter().copied().map(Some)),
+        end_time: ColumnStats::from_values(columns.end_time.iter().copied().map(Some)),
+        path_length: ColumnStats::from_values(flows.iter().map(|f| Some(f.path.length()))),
+        hop_timestamp: ColumnStats::from_values(columns.hop_timestamp.iter().copied().map(Some)),
+        queue_util: ColumnStats::from_values(columns.queue_util.iter().copied()),
+        delay_ns: ColumnStats::from_values(columns.delay_ns.iter().copied()),
+        bandwidth_bps: ColumnStats::from_values(columns.bandwidth_bps.iter().copied()),
+        drop_count: ColumnStats::from_values(columns.drop_count.iter().copied()),
+        egress_port: ColumnStats::from_values(columns.egress_port.iter().copied()),
+        ingress_port: ColumnStats::from_values(columns.ingress_port.iter().copied()),
+        switches: dictionary.keys().map(|s| s.to_string()).collect(),
+    };
+    
+    let flow_ids: Vec<&str> = flows.iter().map(|f| f.flow_id.as_str()).collect();
+    
+    let tmp_path = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    serde_json::to_writer(&mut writer, &header).map_err(io::Error::from)?;
+    writer.write_all(b"\n")?;
+    serde_json::to_writer(&mut writer, &flow_ids).map_err(io::Error::from)?;
+    writer.write_all(b"\n")?;
+    serde_json::to_writer(&mut writer, &columns).map_err(io::Error::from)?;
+    writer.write_all(b"\n")?;
+    
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp_path, path)?;
+    
+    Ok(SegmentInfo {
+        path: path.to_path_buf(),
+        size_bytes: fs::metadata(path)?.len(),
+        header,
+    })
+}
+
+/// Read the next line of a segment file
+fn read_line(reader: &mut impl BufRead, what: &str) -> Result<String, StorageError> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(StorageError::CorruptSegment(format!("Missing {}", what)));
+    }
+    Ok(line)
+}
+
+/// Parse one JSON line of a segment file
+fn parse_line<T: for<'de> Deserialize<'de>>(line: &str, what: &str) -> Result<T, StorageError> {
+    serde_json::from_str(line.trim_end())
+        .map_err(|e| StorageError::CorruptSegment(format!("Invalid {}: {}", what, e)))
+}
+
+/// Read the header and flow IDs of a segment
+pub fn read_segment_index(path: impl AsRef<Path>) -> Result<(SegmentInfo, Vec<String>), StorageError> {
+    let path = path.as_ref();
+    let mut reader = BufReader::new(File::open(path)?);
+    
+    let header: SegmentHeader = parse_line(&read_line(&mut reader, "header")?, "header")?;
+    if header.format_version == 0 || header.format_version > SEGMENT_FORMAT_VERSION {
+        return Err(StorageError::CorruptSegment(format!(
+            "Unsupported segment format version {}",
+            header.format_version
+        )));
+    }
+    
+    let flow_ids: Vec<String> = parse_line(&read_line(&mut reader, "flow IDs")?, "flow IDs")?;
+    if flow_ids.len() != header.flow_count {
+        return Err(StorageError::CorruptSegment(format!(
+            "Header declares {} flows but segment lists {}",
+            header.flow_count,
+            flow_ids.len()
+        )));
+    }
+    
+    let info = SegmentInfo {
+        path: path.to_path_buf(),
+        size_bytes: fs::metadata(path)?.len(),
+        header,
+    };
+    Ok((info, flow_ids))
+}
+
+/// Decode every flow stored in a segment
+pub fn read_segment_flows(path: impl AsRef<Path>) -> Result<Vec<Flow>, StorageError> {
+    let path = path.as_ref();
+    let mut reader = BufReader::new(File::open(path)?);
+    
+    let header: SegmentHeader = parse_line(&read_line(&mut reader, "header")?, "header")?;
+    let flow_ids: Vec<String> = parse_line(&read_line(&mut reader, "flow IDs")?, "flow IDs")?;
+    let columns = parse_line(&read_line(&mut reader, "columns")?, "columns")?;
+    let columns = SegmentColumns::upgrade(columns, header.format_version as u64)
+        .map_err(|e| StorageError::CorruptSegment(e.to_string()))?;
+    
+    let corrupt = |what: &str| StorageError::CorruptSegment(format!("Inconsistent {} column", what));
+    let flow_count = flow_ids.len();
+    if columns.start_time.len() != flow_count
+        || columns.end_time.len() != flow_count
+        || columns.status.len() != flow_count
+        || columns.retention_policy.len() != flow_count
+        || columns.ingest_versions.len() != flow_count
+        || columns.path_offsets.len() != flow_count + 1
+        || columns.hop_offsets.len() != flow_count + 1
+    {
+        return Err(corrupt("flow"));
+    }
+    
+    let hop_count = columns.hop_index.len();
+    if [
+        columns.hop_switch.len(),
+        columns.hop_timestamp.len(),
+        columns.queue_util.len(),
+        columns.delay_ns.len(),
+        columns.bandwidth_bps.len(),
+        columns.drop_count.len(),
+        columns.egress_port.len(),
+        columns.ingress_port.len(),
+        columns.custom_metrics.len(),
+    ]
+    .iter()
+    .any(|len| *len != hop_count)
+    {
+        return Err(corrupt("hop"));
+    }
+    
+    let switch = |code: u32| {
+        header.switches.get(code as usize).cloned().ok_or_else(|| corrupt("switch"))
+    };
+    let range = |offsets: &[u32], i: usize, len: usize| {
+        let (start, end) = (offsets[i] as usize, offsets[i + 1] as usize);
+        if start <= end && end <= len { Ok(start..end) } else { Err(corrupt("offset")) }
+    };
+    
+    let mut flows = Vec::with_capacity(flow_count);
+    let mut columns = columns;
+    for (i, flow_id) in flow_ids.into_iter().enumerate() {
+        let path_switches = range(&columns.path_offsets, i, columns.path_switches.len())?
+            .map(|row| switch(columns.path_switches[row]))
+            .collect::<Result<Vec<_>, _>>()?;
+        
+        let mut hops = Vec::new();
+        for row in range(&columns.hop_offsets, i, hop_count)? {
+            hops.push(Hop {
+                hop_index: columns.hop_index[row],
+                switch_id: switch(columns.hop_switch[row])?,
+                timestamp: columns.hop_timestamp[row],
+                metrics: TelemetryMetrics {
+                    queue_util: columns.queue_util[row],
+                    delay_ns: columns.delay_ns[row],
+                    bandwidth_bps: columns.bandwidth_bps[row],
+                    drop_count: columns.drop_count[row],
+                    egress_port: columns.egress_port[row],
+                    ingress_port: columns.ingress_port[row],
+                    custom_metrics: columns.custom_metrics[row].take(),
+                },
+            });
+        }
+        
+        flows.push(Flow {
+            flow_id,
+            path: NetworkPath::new(path_switches),
+            hops,
+            start_time: columns.start_time[i],
+            end_time: columns.end_time[i],
+            status: columns.status[i].clone(),
+            retention_policy: columns.retention_policy[i].take(),
+            ingest_versions: std::mem::take(&mut columns.ingest_versions[i]),
+        });
+    }
+    
+    Ok(flows)
+}
+
+/// List segments in a directory, oldest first
+pub fn list_segments(dir: impl AsRef<Path>) -> Result<Vec<(SegmentInfo, Vec<String>)>, StorageError> {
+    let dir = dir.as_ref();
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) == Some(SEGMENT_EXTENSION) {
+            segments.push(read_segment_index(&path)?);
+        }
+    }
+    
+    segments.sort_by_key(|(info, _)| info.header.segment_id);
+    Ok(segments)
+}
+
+/// Where the current copy of a cold flow lives
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentRef {
+    pub segment_id: u64,
+    pub wal_lsn: u64,
+}
+
+/// In-memory catalog of segments and the cold flows they hold
+///
+/// A flow may appear in several segments if it was appended to after being
+/// frozen; only the newest copy is referenced here.
+#[derive(Debug, Default)]
+pub struct SegmentCatalog {
+    segments: BTreeMap<u64, SegmentInfo>,
+    flows: HashMap<String, SegmentRef>,
+    cache: Mutex<SegmentCache>,
+}
+
+impl SegmentCatalog {
+    /// Create an empty catalog
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Create an empty catalog that keeps up to `capacity` decoded segments for flow lookups
+    pub fn with_cache_capacity(capacity: usize) -> Self {
+        Self {
+            cache: Mutex::new(SegmentCache::new(capacity)),
+            ..Self::default()
+        }
+    }
+    
+    /// Register a segment and point its flows at it
+    pub fn add(&mut self, info: SegmentInfo, flow_ids: Vec<String>) {
+        let segment_ref = SegmentRef {
+            segment_id: info.header.segment_id,
+            wal_lsn: info.header.wal_lsn,
+        };
+        for flow_id in flow_ids {
+            self.flows.insert(flow_id, segment_ref);
+        }
+        self.cache.get_mut().unwrap().remove(segment_ref.segment_id);
+        self.segments.insert(segment_ref.segment_id, info);
+    }
+    
+    /// Unregister a segment and every flow whose current copy it holds
+    pub fn remove_segment(&mut self, segment_id: u64) -> Option<SegmentInfo> {
+        self.flows.retain(|_, r| r.segment_id != segment_id);
+        self.cache.get_mut().unwrap().remove(segment_id);
+        self.segments.remove(&segment_id)
+    }
+    
+    /// Stop referencing a cold flow
+    pub fn forget(&mut self, flow_id: &str) -> Option<SegmentRef> {
+        self.flows.remove(flow_id)
+    }
+    
+    /// Find the segment holding the current copy of a flow
+    pub fn lookup(&self, flow_id: &str) -> Option<SegmentRef> {
+        self.flows.get(flow_id).copied()
+    }
+    
+    /// Get a segment by ID
+    pub fn segment(&self, segment_id: u64) -> Option<&SegmentInfo> {
+        self.segments.get(&segment_id)
+    }
+    
+    /// Decode the current copy of a cold flow
+    ///
+    /// A segment is decoded as a whole, so the result is cached and later
+    /// lookups of flows in the same segment skip the decode.
+    pub fn read_flow(&self, flow_id: &str) -> Result<Option<Flow>, StorageError> {
+        let Some(info) = self.lookup(flow_id).and_then(|r| self.segment(r.segment_id)) else {
+            return Ok(None);
+        };
+        
+        let segment_id = info.header.segment_id;
+        let cached = self.cache.lock().unwrap().get(segment_id);
+        let flows = match cached {
+            Some(flows) => flows,
+            None => {
+                let flows: HashMap<String, Flow> = read_segment_flows(&info.path)?
+                    .into_iter()
+                    .map(|flow| (flow.flow_id.clone(), flow))
+                    .collect();
+                let flows = Arc::new(flows);
+                self.cache.lock().unwrap().insert(segment_id, Arc::clone(&flows));
+                flows
+            }
+        };
+        
+        Ok(flows.get(flow_id).cloned())
+    }
+    
+    /// Iterate over segments, oldest first
+    pub fn segments(&self) -> impl Iterator<Item = &SegmentInfo> {
+        self.segments.values()
+    }
+    
+    /// Iterate over cold flow IDs
+    pub fn flow_ids(&self) -> impl Iterator<Item = &str> {
+        self.flows.keys().map(String::as_str)
+    }
+    
+    /// Get the number of cold flows
+    pub fn flow_count(&self) -> usize {
+        self.flows.len()
+    }
+    
+    /// Get the number of segments
+    pub fn segment_count(&self) -> usize {
+        self.segments.len()
+    }
+    
+    /// Get the flows whose newest copy lives in a segment
+    pub fn live_flow_ids(&self, segment_id: u64) -> Vec<String> {
+        self.flows
+            .iter()
+            .filter(|(_, r)| r.segment_id == segment_id)
+            .map(|(flow_id, _)| flow_id.clone())
+            .collect()
+    }
+    
+    /// Count the flows each segment currently holds the newest copy of
+    pub fn live_flow_counts(&self) -> HashMap<u64, usize> {
+        let mut counts: HashMap<u64, usize> = self.segments.keys().map(|id| (*id, 0)).collect();
+        for segment_ref in self.flows.values() {
+            *counts.entry(segment_ref.segment_id).or_default() += 1;
+        }
+        counts
+    }
+    
+    /// Get the ID the next segment should use
+    pub fn next_segment_id(&self) -> u64 {
+        self.segments.keys().next_back().map_or(1, |id| id + 1)
+    }
+    
+    /// Drop every segment and reference
+    pub fn clear(&mut self) {
+        self.segments.clear();
+        self.flows.clear();
+        self.cache.get_mut().unwrap().clear();
+    }
+}
+
+/// Recently decoded segments, least recently used first
+///
+/// Segments are immutable, so an entry only goes stale once its segment is dropped.
+#[derive(Debug, Default)]
+struct SegmentCache {
+    capacity: usize,
+    entries: VecDeque<(u64, Arc<HashMap<String, Flow>>)>,
+}
+
+impl SegmentCache {
+    fn new(capacity: usize) -> Self {
+        Self { capacity, entries: VecDeque::new() }
+    }
+    
+    /// Get a segment's flows, marking it as recently used
+    fn get(&mut self, segment_id: u64) -> Option<Arc<HashMap<String, Flow>>> {
+        let position = self.entries.iter().position(|(id, _)| *id == segment_id)?;
+        let entry = self.entries.remove(position)?;
+        let flows = Arc::clone(&entry.1);
+        self.entries.push_back(entry);
+        Some(flows)
+    }
+    
+    /// Keep a decoded segment, evicting the least recently used one when full
+    fn insert(&mut self, segment_id: u64, flows: Arc<HashMap<String, Flow>>) {
+        if self.capacity == 0 {
+            return;
+        }
+        
+        self.remove(segment_id);
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((segment_id, flows));
+    }
+    
+    fn remove(&mut self, segment_id: u64) {
+        self.entries.retain(|(id, _)| *id != segment_id);
+    }
+    
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn create_test_flow(flow_id: &str, switches: &[&str], start_time: DateTime<Utc>) -> Flow {
+        let hops: Vec<Hop> = switches
+            .iter()
+            .enumerate()
+            .map(|(i, switch)| {
+                Hop::new(
+                    i as u32,
+                    switch.to_string(),
+                    start_time + chrono::Duration::milliseconds(i as i64 * 10),
+                    TelemetryMetrics::with_basic(0.1 * (i + 1) as f64, 100 * (i + 1) as u64),
+                )
+            })
+            .collect();
+        Flow::new(flow_id.to_string(), hops).unwrap()
+    }
+    
+    #[test]
+    fn test_segment_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(segment_file_name(1));
+        let now = Utc::now();
+        
+        let mut flows = vec![
+            create_test_flow("flow1", &["s1", "s2", "s3"], now),
+            create_test_flow("flow2", &["s2", "s4"], now + chrono::Duration::seconds(5)),
+        ];
+        flows[0].hops[1].metrics.add_custom_metric("ecn".to_string(), serde_json::json!(true));
+        flows[1].retention_policy = Some("7d".to_string());
+        
+        let info = write_segment(&path, 1, 42, None, &flows).unwrap();
+        assert_eq!(info.header.hop_count, 5);
+        assert_eq!(info.header.switches, vec!["s1", "s2", "s3", "s4"]);
+        assert_eq!(info.header.queue_util.max, Some(0.30000000000000004));
+        assert_eq!(info.header.start_time.min, Some(now));
+        
+        let (indexed, flow_ids) = read_segment_index(&path).unwrap();
+        assert_eq!(indexed.header, info.header);
+        assert_eq!(flow_ids, vec!["flow1", "flow2"]);
+        
+        assert_eq!(read_segment_flows(&path).unwrap(), flows);
+        assert_eq!(list_segments(dir.path()).unwrap().len(), 1);
+    }
+    
+    #[test]
+    fn test_header_pruning() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = Utc::now();
+        let flows = vec![create_test_flow("flow1", &["s1", "s2"], now - chrono::Duration::hours(2))];
+        let info = write_segment(dir.path().join(segment_file_name(1)), 1, 0, None, &flows).unwrap();
+        
+        assert!(info.may_match(&QueryBuilder::new()));
+        assert!(info.may_match(&QueryBuilder::through_switch("s2")));
+        assert!(!info.may_match(&QueryBuilder::through_switch("s9")));
+        assert!(!info.may_match(&QueryBuilder::in_last_minutes(30)));
+        assert!(info.may_match(&QueryBuilder::in_time_range(now - chrono::Duration::hours(3), now)));
+        assert!(!info.may_match(&QueryBuilder::new().with_metric_condition(MetricCondition::MaxQueueUtilGreaterThan(0.5))));
+    }
+    
+    #[test]
+    fn test_truncated_segment_is_corrupt() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(segment_file_name(1));
+        write_segment(&path, 1, 0, None, &[create_test_flow("flow1", &["s1"], Utc::now())]).unwrap();
+        
+        let contents = fs::read_to_string(&path).unwrap();
+        let cut = contents.rfind("\"hop_index\"").unwrap();
+        fs::write(&path, &contents[..cut]).unwrap();
+        
+        assert!(read_segment_index(&path).is_ok());
+        assert!(matches!(read_segment_flows(&path), Err(StorageError::CorruptSegment(_))));
+    }
+    
+    #[test]
+    fn test_catalog_tracks_newest_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = Utc::now();
+        let mut catalog = SegmentCatalog::new();
+        assert_eq!(catalog.next_segment_id(), 1);
+        
+        let first = write_segment(dir.path().join(segment_file_name(1)), 1, 10, None, &[
+            create_test_flow("flow1", &["s1"], now),
+            create_test_flow("flow2", &["s1"], now),
+        ]).unwrap();
+        let second = write_segment(dir.path().join(segment_file_name(2)), 2, 20, None, &[
+            create_test_flow("flow1", &["s1"], now),
+        ]).unwrap();
+        catalog.add(first, vec!["flow1".to_string(), "flow2".to_string()]);
+        catalog.add(second, vec!["flow1".to_string()]);
+        
+        assert_eq!(catalog.lookup("flow1").unwrap().segment_id, 2);
+        assert_eq!(catalog.next_segment_id(), 3);
+        
+        assert_eq!(catalog.live_flow_counts()[&1], 1);
+        catalog.remove_segment(1);
+        assert!(catalog.lookup("flow2").is_none());
+        assert_eq!(catalog.lookup("flow1").unwrap().wal_lsn, 20);
+    }
+    
+    #[test]
+    fn test_catalog_caches_decoded_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = Utc::now();
+        let mut catalog = SegmentCatalog::with_cache_capacity(1);
+        
+        let path = dir.path().join(segment_file_name(1));
+        let info = write_segment(&path, 1, 0, None, &[
+            create_test_flow("flow1", &["s1"], now),
+            create_test_flow("flow2", &["s2", "s3"], now),
+        ]).unwrap();
+        catalog.add(info, vec!["flow1".to_string(), "flow2".to_string()]);
+        assert_eq!(catalog.read_flow("flow1").unwrap().unwrap().hops.len(), 1);
+        
+        // Later lookups in the same segment don't read the file again
+        fs::remove_file(&path).unwrap();
+        assert_eq!(catalog.read_flow("flow2").unwrap().unwrap().hops.len(), 2);
+        assert!(catalog.read_flow("flow3").unwrap().is_none());
+        
+        // Reusing a dropped segment's ID doesn't serve its old flows
+        catalog.remove_segment(1);
+        let info = write_segment(&path, 1, 0, None, &[create_test_flow("flow1", &["s4", "s5", "s6"], now)]).unwrap();
+        catalog.add(info, vec!["flow1".to_string()]);
+        assert_eq!(catalog.read_flow("flow1").unwrap().unwrap().hops.len(), 3);
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard};
+use chrono::{DateTime, Utc};
+
+use crate::models::Flow;
+use crate::storage::{FlowEntry, Partition, QueryBuilder};
+
+/// Time partitions of a shard, keyed by window start
+pub type PartitionMap = BTreeMap<DateTime<Utc>, Arc<RwLock<Partition>>>;
+
+/// Pick the shard a flow belongs to
+pub fn shard_index(flow_id: &str, shard_count: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    flow_id.hash(&mut hasher);
+    (hasher.finish() % shard_count.max(1) as u64) as usize
+}
+
+/// The flows whose IDs hash to one shard, partitioned by start time
+///
+/// Each shard has its own partition map and writer lock, so writers to
+/// different shards never wait on each other.
+#[derive(Debug)]
+pub struct Shard {
+    /// Partitions keyed by window start
+    partitions: RwLock<PartitionMap>,
+    
+    /// Serializes read-modify-write updates to the shard's flows
+    writer: Mutex<()>,
+    
+    /// Width of each partition window in seconds
+    partition_secs: i64,
+    
+    /// Time bucket size for partition time indexes in seconds
+    time_bucket_size: i64,
+    
+    /// Most time buckets a flow is registered in before it's listed as long-lived
+    max_span_buckets: usize,
+}
+
+impl Shard {
+    /// Create an empty shard
+    pub fn new(partition_secs: i64, time_bucket_size: i64, max_span_buckets: usize) -> Self {
+        Self {
+            partitions: RwLock::new(BTreeMap::new()),
+            writer: Mutex::new(()),
+            partition_secs,
+            time_bucket_size,
+            max_span_buckets,
+        }
+    }
+    
+    /// Take the writer lock, held across a whole upsert or removal
+    ///
+    /// Readers don't need it: each partition updates a flow and its index entries together.
+    pub fn lock_writes(&self) -> MutexGuard<'_, ()> {
+        self.writer.lock().unwrap()
+    }
+    
+    /// Lock the partition map for reading
+    pub fn partitions(&self) -> RwLockReadGuard<'_, PartitionMap> {
+        self.partitions.read().unwrap()
+    }
+    
+    /// Find the partition holding a flow, newest partitions first
+    pub fn locate(&self, flow_id: &str) -> Option<(DateTime<Utc>, Arc<RwLock<Partition>>)> {
+        let partitions = self.partitions();
+        partitions
+            .iter()
+            .rev()
+            .find(|(_, partition)| partition.read().unwrap().contains(flow_id))
+            .map(|(start, partition)| (*start, partition.clone()))
+    }
+    
+    /// Check whether the shard holds a flow
+    pub fn contains(&self, flow_id: &str) -> bool {
+        self.locate(flow_id).is_some()
+    }
+    
+    /// Visit the partitions that may hold flows matching a query, newest first
+    ///
+    /// The map stays read-locked so no partition appears mid-scan, and flows only ever
+    /// move to earlier partitions (inserted there before being taken from here), so a
+    /// flow that moves during the scan is still visited at least once.
+    pub fn for_each_matching<E>(
+        &self,
+        query: &QueryBuilder,
+        now: DateTime<Utc>,
+        mut visit: impl FnMut(&Partition) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let partitions = self.partitions();
+        for partition in partitions.values().rev() {
+            let partition = partition.read().unwrap();
+            if partition.may_match(query, now) {
+                visit(&partition)?;
+            }
+        }
+        Ok(())
+    }
+    
+    /// Add or replace a flow in the partition starting at `start`, creating the partition if needed
+    ///
+    /// The map lock is held throughout so an empty partition can't be dropped underneath.
+    pub fn insert(&self, start: DateTime<Utc>, flow: &Flow, previous: Option<&Flow>) -> Option<FlowEntry> {
+        {
+            let partitions = self.partitions();
+            if let Some(partition) = partitions.get(&start) {
+                return partition.write().unwrap().insert(flow, previous);
+            }
+        }
+        
+        let mut partitions = self.partitions.write().unwrap();
+        let partition = partitions.entry(start).or_insert_with(|| {
+            Arc::new(RwLock::new(Partition::new(start, self.partition_secs, self.time_bucket_size, self.max_span_buckets)))
+        });
+        let replaced = partition.write().unwrap().insert(flow, previous);
+        replaced
+    }
+    
+    /// Remove a flow from a specific partition, dropping the partition once it is empty
+    ///
+    /// `flow` is the flow as last inserted there, whose index entries are removed.
+    pub fn take(&self, start: DateTime<Utc>, flow: &Flow) -> Option<FlowEntry> {
+        let (removed, now_empty) = {
+            let partitions = self.partitions();
+            let mut partition = partitions.get(&start)?.write().unwrap();
+            let removed = partition.remove(flow);
+            (removed, partition.is_empty())
+        };
+        
+        if now_empty {
+            let mut partitions = self.partitions.write().unwrap();
+            if partitions.get(&start).is_some_and(|p| p.read().unwrap().is_empty()) {
+                partitions.remove(&start);
+            }
+        }
+        
+        removed
+    }
+    
+    /// Detach a whole partition from the shard
+    pub fn remove_partition(&self, start: DateTime<Utc>) -> Option<Arc<RwLock<Partition>>> {
+        self.partitions.write().unwrap().remove(&start)
+    }
+    
+    /// Replace every partition, e.g. when restoring a snapshot
+    pub fn replace_partitions(&self, partitions: BTreeMap<DateTime<Utc>, Partition>) {
+        *self.partitions.write().unwrap() = partitions
+            .into_iter()
+            .map(|(start, partition)| (start, Arc::new(RwLock::new(partition))))
+            .collect();
+    }
+    
+    /// Get the number of flows in the shard
+    pub fn flow_count(&self) -> usize {
+        self.partitions().values().map(|partition| partition.read().unwrap().len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Hop, TelemetryMetrics};
+    use crate::storage::{TimeIndex, DEFAULT_MAX_SPAN_BUCKETS};
+    
+    fn create_test_flow(flow_id: &str, start_time: DateTime<Utc>) -> Flow {
+        let hop = Hop::new(0, "s1".to_string(), start_time, TelemetryMetrics::with_basic(0.1, 100));
+        Flow::new(flow_id.to_string(), vec![hop]).unwrap()
+    }
+    
+    #[test]
+    fn test_shard_index_is_stable_and_in_range() {
+        for id in ["flow1", "flow2", "a-much-longer-flow-identifier"] {
+            let index = shard_index(id, 16);
+            assert!(index < 16);
+            assert_eq!(index, shard_index(id, 16));
+        }
+        assert_eq!(shard_index("flow1", 1), 0);
+        assert_eq!(shard_index("flow1", 0), 0);
+    }
+    
+    #[test]
+    fn test_shard_insert_locate_take() {
+        let shard = Shard::new(3600, 60, DEFAULT_MAX_SPAN_BUCKETS);
+        let start = TimeIndex::bucket_start(Utc::now(), 3600);
+        let flow = create_test_flow("flow1", start);
+        
+        shard.insert(start, &flow, None);
+        assert!(shard.contains("flow1"));
+        assert_eq!(shard.locate("flow1").map(|(s, _)| s), Some(start));
+        assert_eq!(shard.flow_count(), 1);
+        
+        let removed = shard.take(start, &flow).unwrap();
+        assert_eq!(removed, FlowEntry::new(&flow));
+        assert!(!shard.contains("flow1"));
+        assert!(shard.partitions().is_empty());
+    }
+}
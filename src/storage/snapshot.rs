@@ -0,0 +1,312 @@
+use std::borrow::Borrow;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::models::{Flow, SchemaError, Versioned};
+use crate::storage::StorageError;
+
+/// Snapshot format version written by this build
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 1;
+
+/// File extension for snapshots kept in the snapshot directory
+pub const SNAPSHOT_EXTENSION: &str = "snapshot";
+
+/// Header stored on the first line of a snapshot file
+///
+/// The remaining lines hold one flow record each, carrying its own schema version.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotHeader {
+    /// Snapshot format version
+    pub format_version: u32,
+    
+    /// IntDB version that wrote the snapshot
+    pub engine_version: String,
+    
+    /// When the snapshot was taken
+    pub created_at: DateTime<Utc>,
+    
+    /// Last WAL sequence number included in the image (0 without a WAL)
+    pub wal_lsn: u64,
+    
+    /// Number of flow lines following the header
+    pub flow_count: usize,
+    
+    /// Time index bucket size the indexes were built with
+    pub time_bucket_size: i64,
+    
+    /// Path index statistics at snapshot time
+    pub unique_paths: usize,
+    pub unique_switches: usize,
+    
+    /// Time index statistics at snapshot time
+    pub time_buckets: usize,
+}
+
+/// A snapshot file on disk
+#[derive(Debug, Clone)]
+pub struct SnapshotInfo {
+    pub path: PathBuf,
+    pub size_bytes: u64,
+    pub header: SnapshotHeader,
+}
+
+impl SnapshotInfo {
+    /// Get the snapshot file name
+    pub fn name(&self) -> String {
+        self.path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+}
+
+/// Turn a user-supplied snapshot name into a file name, rejecting path components
+pub fn snapshot_file_name(name: &str) -> Option<String> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        && !name.starts_with('.');
+    
+    if !valid {
+        return None;
+    }
+    
+    let suffix = format!(".{}", SNAPSHOT_EXTENSION);
+    if name.ends_with(&suffix) {
+        Some(name.to_string())
+    } else {
+        Some(format!("{}{}", name, suffix))
+    }
+}
+
+/// Write a snapshot atomically (temporary file, fsync, rename)
+pub fn write_snapshot(
+    path: impl AsRef<Path>,
+    header: &SnapshotHeader,
+    flows: impl Iterator<Item = impl Borrow<Flow>>,
+) -> Result<SnapshotInfo, StorageError> {
+    let path = path.as_ref();
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    
+    let tmp_path = path.with_extension("tmp");
+    let file = File::create(&tmp_path)?;
+    let mut writer = BufWriter::new(file);
+    
+    serde_json::to_writer(&mut writer, header).map_err(io::Error::from)?;
+    writer.write_all(b"\n")?;
+    
+    for flow in flows {
+        serde_json::to_writer(&mut writer, &flow.borrow().to_record()?).map_err(io::Error::from)?;
+        writer.write_all(b"\n")?;
+    }
+    
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    drop(file);
+    
+    fs::rename(&tmp_path, path)?;
+    
+    Ok(SnapshotInfo {
+        path: path.to_path_buf(),
+        size_bytes: fs::metadata(path)?.len(),
+        header: header.clone(),
+    })
+}
+
+/// Parse and version-check the header line
+fn parse_header(line: &str) -> Result<SnapshotHeader, StorageError> {
+    let value: serde_json::Value = serde_json::from_str(line)
+        .map_err(|e| StorageError::CorruptSnapshot(format!("Invalid header: {}", e)))?;
+    
+    // Check the version before the rest of the header so that future
+    // layouts are reported as incompatible rather than corrupt
+    let version = value
+        .get("format_version")
+        .and_then(|v| v.as_u64())
+        .ok_or_else(|| StorageError::CorruptSnapshot("Missing format_version".to_string()))?;
+    
+    if version != SNAPSHOT_FORMAT_VERSION as u64 {
+        return Err(StorageError::IncompatibleSnapshot(version as u32));
+    }
+    
+    serde_json::from_value(value)
+        .map_err(|e| StorageError::CorruptSnapshot(format!("Invalid header: {}", e)))
+}
+
+/// Read only the header of a snapshot file
+pub fn read_snapshot_info(path: impl AsRef<Path>) -> Result<SnapshotInfo, StorageError> {
+    let path = path.as_ref();
+    let mut reader = BufReader::new(File::open(path)?);
+    
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let header = parse_header(line.trim_end())?;
+    
+    Ok(SnapshotInfo {
+        path: path.to_path_buf(),
+        size_bytes: fs::metadata(path)?.len(),
+        header,
+    })
+}
+
+/// Read a complete snapshot
+pub fn read_snapshot(path: impl AsRef<Path>) -> Result<(SnapshotInfo, Vec<Flow>), StorageError> {
+    let path = path.as_ref();
+    let mut lines = BufReader::new(File::open(path)?).lines();
+    
+    let header_line = lines
+        .next()
+        .transpose()?
+        .ok_or_else(|| StorageError::CorruptSnapshot("Empty snapshot file".to_string()))?;
+    let header = parse_header(&header_line)?;
+    
+    // The header's count is checked against the file below, so it isn't trusted for sizing
+    let mut flows = Vec::new();
+    for (i, line) in lines.enumerate() {
+        let corrupt = |e: &dyn std::fmt::Display| StorageError::CorruptSnapshot(format!("Invalid flow on line {}: {}", i + 2, e));
+        let record = serde_json::from_str(&line?).map_err(|e| corrupt(&e))?;
+        
+        // Records too new for this build are incompatible rather than corrupt
+        let flow = Flow::from_record(record).map_err(|e| match e {
+            SchemaError::Invalid { .. } => corrupt(&e),
+            e => e.into(),
+        })?;
+        flows.push(flow);
+    }
+    
+    if flows.len() != header.flow_count {
+        return Err(StorageError::CorruptSnapshot(format!(
+            "Header declares {} flows but file contains {}",
+            header.flow_count,
+            flows.len()
+        )));
+    }
+    
+    let info = SnapshotInfo {
+        path: path.to_path_buf(),
+        size_bytes: fs::metadata(path)?.len(),
+        header,
+    };
+    
+    Ok((info, flows))
+}
+
+/// List snapshots in a directory, newest first
+///
+/// Files that are not readable snapshots of a compatible version are skipped.
+pub fn list_snapshots(dir: impl AsRef<Path>) -> Result<Vec<SnapshotInfo>, StorageError> {
+    let dir = dir.as_ref();
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    
+    let mut snapshots = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(SNAPSHOT_EXTENSION) {
+            continue;
+        }
+        
+        match read_snapshot_info(&path) {
+            Ok(info) => snapshots.push(info),
+            Err(e) => log::warn!("Skipping snapshot {}: {}", path.display(), e),
+        }
+    }
+    
+    snapshots.sort_by_key(|s| std::cmp::Reverse(s.header.created_at));
+    Ok(snapshots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Hop, TelemetryMetrics};
+    
+    fn create_test_flow(flow_id: &str) -> Flow {
+        let hops = vec![
+            Hop::new(0, "s1".to_string(), Utc::now(), TelemetryMetrics::with_basic(0.1, 100)),
+            Hop::new(1, "s2".to_string(), Utc::now(), TelemetryMetrics::with_basic(0.2, 200)),
+        ];
+        Flow::new(flow_id.to_string(), hops).unwrap()
+    }
+    
+    fn create_test_header(flow_count: usize) -> SnapshotHeader {
+        SnapshotHeader {
+            format_version: SNAPSHOT_FORMAT_VERSION,
+            engine_version: env!("CARGO_PKG_VERSION").to_string(),
+            created_at: Utc::now(),
+            wal_lsn: 0,
+            flow_count,
+            time_bucket_size: 60,
+            unique_paths: 1,
+            unique_switches: 2,
+            time_buckets: 1,
+        }
+    }
+    
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("backup.snapshot");
+        let flows = vec![create_test_flow("flow1"), create_test_flow("flow2")];
+        
+        let written = write_snapshot(&path, &create_test_header(2), flows.iter()).unwrap();
+        assert!(written.size_bytes > 0);
+        assert!(!dir.path().join("backup.tmp").exists());
+        
+        let (info, restored) = read_snapshot(&path).unwrap();
+        assert_eq!(info.header, written.header);
+        assert_eq!(restored, flows);
+        
+        let listed = list_snapshots(dir.path()).unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].name(), "backup.snapshot");
+    }
+    
+    #[test]
+    fn test_incompatible_version_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("future.snapshot");
+        let header = SnapshotHeader {
+            format_version: SNAPSHOT_FORMAT_VERSION + 1,
+            ..create_test_header(0)
+        };
+        
+        write_snapshot(&path, &header, std::iter::empty::<&Flow>()).unwrap();
+        
+        assert!(matches!(
+            read_snapshot(&path),
+            Err(StorageError::IncompatibleSnapshot(v)) if v == SNAPSHOT_FORMAT_VERSION + 1
+        ));
+        assert!(list_snapshots(dir.path()).unwrap().is_empty());
+    }
+    
+    #[test]
+    fn test_truncated_snapshot_is_corrupt() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("short.snapshot");
+        
+        write_snapshot(&path, &create_test_header(3), [create_test_flow("flow1")].iter()).unwrap();
+        
+        assert!(matches!(read_snapshot(&path), Err(StorageError::CorruptSnapshot(_))));
+        
+        // A corrupt count is reported rather than allocated for
+        write_snapshot(&path, &create_test_header(usize::MAX), [create_test_flow("flow1")].iter()).unwrap();
+        
+        assert!(matches!(read_snapshot(&path), Err(StorageError::CorruptSnapshot(_))));
+    }
+    
+    #[test]
+    fn test_snapshot_file_name() {
+        assert_eq!(snapshot_file_name("nightly"), Some("nightly.snapshot".to_string()));
+        assert_eq!(snapshot_file_name("a.snapshot"), Some("a.snapshot".to_string()));
+        assert_eq!(snapshot_file_name("../etc/passwd"), None);
+        assert_eq!(snapshot_file_name(""), None);
+    }
+}
@@ -0,0 +1,601 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use log::warn;
+
+use crate::models::Flow;
+
+/// Smallest log worth compacting
+const COMPACT_MIN_BYTES: u64 = 64 << 20;
+
+/// Records a follower may fall behind the log before it is dropped and has to catch up from the file
+pub const FOLLOWER_BUFFER: usize = 4096;
+
+/// When appended WAL records are forced to stable storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WalSyncPolicy {
+    /// fsync after every appended record
+    #[default]
+    EveryWrite,
+    
+    /// fsync once every N appended records
+    Batched(usize),
+    
+    /// fsync when the interval has elapsed since the last sync
+    Periodic(Duration),
+}
+
+impl FromStr for WalSyncPolicy {
+    type Err = String;
+    
+    /// Parse `always`, `batch:<records>` or `periodic:<millis>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, arg) = match s.split_once(':') {
+            Some((kind, arg)) => (kind, Some(arg)),
+            None => (s, None),
+        };
+        
+        match (kind, arg) {
+            ("always", None) => Ok(Self::EveryWrite),
+            ("batch", Some(n)) => n
+                .parse::<usize>()
+                .ok()
+                .filter(|n| *n > 0)
+                .map(Self::Batched)
+                .ok_or_else(|| format!("Invalid WAL batch size: {}", n)),
+            ("periodic", Some(ms)) => ms
+                .parse::<u64>()
+                .ok()
+                .filter(|ms| *ms > 0)
+                .map(|ms| Self::Periodic(Duration::from_millis(ms)))
+                .ok_or_else(|| format!("Invalid WAL sync interval: {}", ms)),
+            _ => Err(format!("Unknown WAL sync policy: {}", s)),
+        }
+    }
+}
+
+/// A single mutation recorded in the write-ahead log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum WalRecord {
+    /// A flow passed to `insert_flow` (either a new flow or an append)
+    Insert {
+        #[serde(with = "crate::models::schema::record")]
+        flow: Flow,
+    },
+    
+    /// A flow removed with `remove_flow`
+    Delete { flow_id: String },
+    
+    /// Flows moved from memory into a cold segment
+    Freeze { segment_id: u64, flow_ids: Vec<String> },
+    
+    /// A cold segment dropped once its flows expired or were superseded
+    DropSegment { segment_id: u64 },
+    
+    /// In-memory flows starting in `[start, end)`, dropped together as an expired partition
+    DropPartition { start: DateTime<Utc>, end: DateTime<Utc> },
+}
+
+/// A WAL record together with its log sequence number
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WalEntry {
+    pub lsn: u64,
+    pub record: WalRecord,
+}
+
+/// Append-only, newline-delimited JSON write-ahead log
+#[derive(Debug)]
+pub struct WriteAheadLog {
+    /// Log file location
+    path: PathBuf,
+    
+    /// Open handle positioned at the end of the log
+    file: File,
+    
+    /// Bytes of complete records in the file
+    len: u64,
+    
+    /// Bytes the log held after it was last rewritten (0 until then)
+    rewritten_len: u64,
+    
+    /// Set once a failed write couldn't be undone or an fsync failed, after which appends are refused
+    failed: bool,
+    
+    /// fsync policy
+    sync_policy: WalSyncPolicy,
+    
+    /// Sequence number of the first record in the file
+    first_lsn: u64,
+    
+    /// Sequence number assigned to the next appended record
+    next_lsn: u64,
+    
+    /// Records written since the last fsync
+    unsynced: usize,
+    
+    /// Time of the last fsync
+    last_sync: Instant,
+    
+    /// Followers receiving each record as it is appended
+    followers: Vec<SyncSender<WalEntry>>,
+}
+
+/// What a follower resuming at some LSN needs to catch up and keep up
+#[derive(Debug)]
+pub struct WalFollow {
+    /// The follower must discard its state first, because the log no longer
+    /// holds the records it is missing (or it is ahead of the log)
+    pub reset: bool,
+    
+    /// Records already in the log, from the requested LSN (or the start of the log on reset)
+    pub backlog: WalBacklog,
+    
+    /// Records appended from now on; disconnected when the log is rewritten or the
+    /// follower falls more than `FOLLOWER_BUFFER` records behind
+    pub live: Receiver<WalEntry>,
+}
+
+/// The records in the log when a follower started, read without holding up writers
+///
+/// It has its own handle on the file, so a rewrite renaming a new log into place
+/// doesn't change what it reads.
+#[derive(Debug)]
+pub struct WalBacklog {
+    file: File,
+    len: u64,
+    from_lsn: u64,
+}
+
+impl WalBacklog {
+    /// Visit each record in log order
+    pub fn scan<E: From<io::Error>>(self, mut visit: impl FnMut(WalEntry) -> Result<(), E>) -> Result<(), E> {
+        for line in BufReader::new(self.file.take(self.len)).lines() {
+            let entry: WalEntry = serde_json::from_str(&line?).map_err(io::Error::from)?;
+            if entry.lsn >= self.from_lsn {
+                visit(entry)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl WriteAheadLog {
+    /// Open (or create) the log at `path` and return it with all committed entries
+    ///
+    /// A torn final line left by a crash mid-write is truncated away. Corruption
+    /// anywhere else is reported as `InvalidData`.
+    pub fn open(path: impl AsRef<Path>, sync_policy: WalSyncPolicy) -> io::Result<(Self, Vec<WalEntry>)> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        
+        let (entries, valid_len) = Self::read_entries(&file)?;
+        if valid_len < file.metadata()?.len() {
+            warn!("Truncating torn record at end of WAL {}", path.display());
+            file.set_len(valid_len)?;
+            file.sync_data()?;
+        }
+        
+        let next_lsn = entries.last().map(|e| e.lsn + 1).unwrap_or(1);
+        let first_lsn = entries.first().map_or(next_lsn, |e| e.lsn);
+        
+        let wal = Self {
+            path,
+            file,
+            len: valid_len,
+            rewritten_len: 0,
+            failed: false,
+            sync_policy,
+            first_lsn,
+            next_lsn,
+            unsynced: 0,
+            last_sync: Instant::now(),
+            followers: Vec::new(),
+        };
+        
+        Ok((wal, entries))
+    }
+    
+    /// Read the committed entries of the log at `path` without opening it for writing
+    ///
+    /// A torn final line is skipped rather than truncated, and a missing log has no entries.
+    pub fn read(path: impl AsRef<Path>) -> io::Result<Vec<WalEntry>> {
+        match File::open(path) {
+            Ok(file) => Self::read_entries(&file).map(|(entries, _)| entries),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+    
+    /// Read all complete entries, returning them with the byte length they cover
+    fn read_entries(file: &File) -> io::Result<(Vec<WalEntry>, u64)> {
+        let mut reader = BufReader::new(file);
+        let mut entries = Vec::new();
+        let mut valid_len = 0u64;
+        let mut line = String::new();
+        
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 {
+                break;
+            }
+            
+            // Only the final record may be missing its newline
+            if !line.ends_with('\n') {
+                break;
+            }
+            
+            match serde_json::from_str::<WalEntry>(line.trim_end()) {
+                Ok(entry) => {
+                    entries.push(entry);
+                    valid_len += read as u64;
+                }
+                Err(e) => {
+                    // A bad line followed by more data is real corruption
+                    let mut rest = String::new();
+                    if reader.read_line(&mut rest)? == 0 {
+                        break;
+                    }
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Corrupt WAL record after byte {}: {}", valid_len, e),
+                    ));
+                }
+            }
+        }
+        
+        Ok((entries, valid_len))
+    }
+    
+    /// Append a record, syncing according to the policy, and return its LSN
+    ///
+    /// A record that fails to write is cut off again, so later records never follow
+    /// a torn line. If that fails too, or an fsync fails, the log refuses further
+    /// appends until it is reopened.
+    pub fn append(&mut self, record: WalRecord) -> io::Result<u64> {
+        if self.failed {
+            return Err(io::Error::other(format!("WAL {} is unusable after a failed write", self.path.display())));
+        }
+        
+        let entry = WalEntry {
+            lsn: self.next_lsn,
+            record,
+        };
+        
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        if let Err(e) = self.file.write_all(&line) {
+            // A fresh handle, in case the failure was the open one's
+            let truncated = OpenOptions::new().write(true).open(&self.path).and_then(|file| file.set_len(self.len));
+            if let Err(truncate_error) = truncated {
+                warn!("Failed to cut a torn record off WAL {}: {}", self.path.display(), truncate_error);
+                self.failed = true;
+            }
+            return Err(e);
+        }
+        
+        self.len += line.len() as u64;
+        self.next_lsn += 1;
+        self.unsynced += 1;
+        
+        let due = match self.sync_policy {
+            WalSyncPolicy::EveryWrite => true,
+            WalSyncPolicy::Batched(n) => self.unsynced >= n,
+            WalSyncPolicy::Periodic(interval) => self.last_sync.elapsed() >= interval,
+        };
+        if due {
+            self.sync()?;
+        }
+        
+        // Followers that have gone away or fallen too far behind are dropped here;
+        // they reconnect and catch up from the file
+        if !self.followers.is_empty() {
+            self.followers.retain(|follower| follower.try_send(entry.clone()).is_ok());
+        }
+        
+        Ok(entry.lsn)
+    }
+    
+    /// Replace the whole log with the given records (e.g. after a snapshot restore)
+    ///
+    /// The new log is written next to the old one and renamed over it, so a crash
+    /// leaves either the old or the new log intact. LSNs keep increasing.
+    pub fn rewrite(&mut self, records: impl IntoIterator<Item = WalRecord>) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("wal.tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        
+        let mut next_lsn = self.next_lsn;
+        let mut len = 0;
+        let mut buffer = Vec::new();
+        for record in records {
+            let entry = WalEntry { lsn: next_lsn, record };
+            serde_json::to_writer(&mut buffer, &entry)?;
+            buffer.push(b'\n');
+            next_lsn += 1;
+            
+            if buffer.len() >= 1 << 20 {
+                tmp.write_all(&buffer)?;
+                len += buffer.len() as u64;
+                buffer.clear();
+            }
+        }
+        tmp.write_all(&buffer)?;
+        len += buffer.len() as u64;
+        tmp.sync_all()?;
+        drop(tmp);
+        
+        std::fs::rename(&tmp_path, &self.path)?;
+        
+        self.file = OpenOptions::new().read(true).append(true).open(&self.path)?;
+        self.len = len;
+        self.rewritten_len = len;
+        self.failed = false;
+        self.first_lsn = self.next_lsn;
+        self.next_lsn = next_lsn;
+        self.unsynced = 0;
+        self.last_sync = Instant::now();
+        
+        // The rewritten log is a new image, so followers reconnect and reset
+        self.followers.clear();
+        
+        Ok(())
+    }
+    
+    /// Force all appended records to stable storage
+    ///
+    /// After a failed fsync the kernel may have dropped the unsynced pages, so the
+    /// log is marked unusable rather than retried.
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced > 0 {
+            if let Err(e) = self.file.sync_data() {
+                self.failed = true;
+                return Err(e);
+            }
+            self.unsynced = 0;
+        }
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+    
+    /// Get the log file location
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    
+    /// Get the sync policy
+    pub fn sync_policy(&self) -> WalSyncPolicy {
+        self.sync_policy
+    }
+    
+    /// Check whether the log has grown enough since it was last rewritten to be worth compacting
+    pub fn compaction_due(&self) -> bool {
+        self.len >= COMPACT_MIN_BYTES.max(2 * self.rewritten_len)
+    }
+    
+    /// Get the LSN of the last appended record (0 if the log is empty)
+    pub fn last_lsn(&self) -> u64 {
+        self.next_lsn - 1
+    }
+    
+    /// Start streaming records to a follower that has applied everything before `from_lsn`
+    ///
+    /// A follower at the very start of the log also resets, since the log may be a
+    /// rewritten image rather than the history it has already applied. The backlog
+    /// ends where the live records begin, and is read after the caller lets go of the log.
+    pub fn follow(&mut self, from_lsn: u64) -> io::Result<WalFollow> {
+        let reset = from_lsn <= self.first_lsn || from_lsn > self.next_lsn;
+        let backlog = WalBacklog {
+            file: File::open(&self.path)?,
+            len: self.len,
+            from_lsn: if reset { 0 } else { from_lsn },
+        };
+        
+        let (sender, live) = mpsc::sync_channel(FOLLOWER_BUFFER);
+        self.followers.push(sender);
+        
+        Ok(WalFollow { reset, backlog, live })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Hop, TelemetryMetrics};
+    use chrono::Utc;
+    
+    fn create_test_flow(flow_id: &str) -> Flow {
+        let hops = vec![
+            Hop::new(0, "s1".to_string(), Utc::now(), TelemetryMetrics::with_basic(0.1, 100)),
+            Hop::new(1, "s2".to_string(), Utc::now(), TelemetryMetrics::with_basic(0.2, 200)),
+        ];
+        Flow::new(flow_id.to_string(), hops).unwrap()
+    }
+    
+    #[test]
+    fn test_append_and_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("intdb.wal");
+        
+        {
+            let (mut wal, entries) = WriteAheadLog::open(&path, WalSyncPolicy::EveryWrite).unwrap();
+            assert!(entries.is_empty());
+            assert_eq!(wal.append(WalRecord::Insert { flow: create_test_flow("flow1") }).unwrap(), 1);
+            assert_eq!(wal.append(WalRecord::Insert { flow: create_test_flow("flow2") }).unwrap(), 2);
+        }
+        
+        let (wal, entries) = WriteAheadLog::open(&path, WalSyncPolicy::EveryWrite).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(wal.last_lsn(), 2);
+        assert!(matches!(&entries[1].record, WalRecord::Insert { flow } if flow.flow_id == "flow2"));
+    }
+    
+    #[test]
+    fn test_torn_tail_is_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("intdb.wal");
+        
+        {
+            let (mut wal, _) = WriteAheadLog::open(&path, WalSyncPolicy::Batched(10)).unwrap();
+            wal.append(WalRecord::Insert { flow: create_test_flow("flow1") }).unwrap();
+            wal.sync().unwrap();
+        }
+        
+        // Simulate a crash in the middle of writing the second record
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"lsn\":2,\"record\":{\"op\":\"ins").unwrap();
+        drop(file);
+        
+        // Reading leaves the torn tail in place
+        let torn_len = std::fs::metadata(&path).unwrap().len();
+        assert_eq!(WriteAheadLog::read(&path).unwrap().len(), 1);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), torn_len);
+        
+        let (mut wal, entries) = WriteAheadLog::open(&path, WalSyncPolicy::EveryWrite).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(wal.append(WalRecord::Insert { flow: create_test_flow("flow2") }).unwrap(), 2);
+        
+        let (_, entries) = WriteAheadLog::open(&path, WalSyncPolicy::EveryWrite).unwrap();
+        assert_eq!(entries.len(), 2);
+    }
+    
+    #[test]
+    fn test_failed_append_leaves_no_torn_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("intdb.wal");
+        let (mut wal, _) = WriteAheadLog::open(&path, WalSyncPolicy::EveryWrite).unwrap();
+        wal.append(WalRecord::Insert { flow: create_test_flow("flow1") }).unwrap();
+        let good_len = std::fs::metadata(&path).unwrap().len();
+        
+        // Part of the line reaches the file before the write fails
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"lsn\":2,\"record\":{\"op\":\"ins").unwrap();
+        let writable = std::mem::replace(&mut wal.file, File::open(&path).unwrap());
+        assert!(wal.append(WalRecord::Insert { flow: create_test_flow("flow2") }).is_err());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), good_len);
+        
+        // The next record follows the last complete one
+        wal.file = writable;
+        assert_eq!(wal.append(WalRecord::Insert { flow: create_test_flow("flow3") }).unwrap(), 2);
+        assert_eq!(WriteAheadLog::read(&path).unwrap().len(), 2);
+        
+        // A record that can't be cut off again leaves the log refusing appends
+        wal.file = File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(wal.append(WalRecord::Insert { flow: create_test_flow("flow4") }).is_err());
+        wal.file = OpenOptions::new().append(true).create(true).open(&path).unwrap();
+        assert!(wal.append(WalRecord::Insert { flow: create_test_flow("flow4") }).is_err());
+    }
+    
+    #[test]
+    fn test_rewrite_replaces_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("intdb.wal");
+        
+        {
+            let (mut wal, _) = WriteAheadLog::open(&path, WalSyncPolicy::EveryWrite).unwrap();
+            wal.append(WalRecord::Insert { flow: create_test_flow("flow1") }).unwrap();
+            wal.append(WalRecord::Insert { flow: create_test_flow("flow2") }).unwrap();
+            
+            wal.rewrite(vec![WalRecord::Insert { flow: create_test_flow("flow3") }]).unwrap();
+            assert_eq!(wal.append(WalRecord::Insert { flow: create_test_flow("flow4") }).unwrap(), 4);
+        }
+        
+        let (_, entries) = WriteAheadLog::open(&path, WalSyncPolicy::EveryWrite).unwrap();
+        let ids: Vec<_> = entries
+            .iter()
+            .map(|e| match &e.record {
+                WalRecord::Insert { flow } => flow.flow_id.as_str(),
+                WalRecord::Delete { flow_id } => flow_id.as_str(),
+                WalRecord::Freeze { .. } | WalRecord::DropSegment { .. } | WalRecord::DropPartition { .. } => {
+                    unreachable!()
+                }
+            })
+            .collect();
+        assert_eq!(ids, vec!["flow3", "flow4"]);
+    }
+    
+    fn backlog_lsns(backlog: WalBacklog) -> Vec<u64> {
+        let mut lsns = Vec::new();
+        backlog
+            .scan(|entry| {
+                lsns.push(entry.lsn);
+                Ok::<_, io::Error>(())
+            })
+            .unwrap();
+        lsns
+    }
+    
+    #[test]
+    fn test_follow_resumes_or_resets() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("intdb.wal");
+        let (mut wal, _) = WriteAheadLog::open(&path, WalSyncPolicy::EveryWrite).unwrap();
+        for id in ["flow1", "flow2", "flow3"] {
+            wal.append(WalRecord::Insert { flow: create_test_flow(id) }).unwrap();
+        }
+        
+        // Records appended before the backlog is read arrive live instead
+        let follow = wal.follow(3).unwrap();
+        assert!(!follow.reset);
+        wal.append(WalRecord::Delete { flow_id: "flow1".to_string() }).unwrap();
+        assert_eq!(backlog_lsns(follow.backlog), vec![3]);
+        assert_eq!(follow.live.try_recv().unwrap().lsn, 4);
+        
+        // Nothing applied yet, or more than the log holds, means starting over
+        assert!(wal.follow(1).unwrap().reset);
+        assert!(wal.follow(9).unwrap().reset);
+        assert_eq!(backlog_lsns(wal.follow(9).unwrap().backlog), vec![1, 2, 3, 4]);
+        
+        // A rewrite disconnects live followers, but a backlog still reads the old log
+        let pending = wal.follow(2).unwrap();
+        wal.rewrite([WalRecord::Delete { flow_id: "flow2".to_string() }]).unwrap();
+        assert!(follow.live.recv().is_err());
+        assert_eq!(backlog_lsns(pending.backlog), vec![2, 3, 4]);
+        assert!(wal.follow(5).unwrap().reset);
+        assert!(!wal.follow(6).unwrap().reset);
+    }
+    
+    #[test]
+    fn test_lagging_follower_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("intdb.wal");
+        let (mut wal, _) = WriteAheadLog::open(&path, WalSyncPolicy::Batched(FOLLOWER_BUFFER * 2)).unwrap();
+        
+        let follow = wal.follow(1).unwrap();
+        for _ in 0..=FOLLOWER_BUFFER {
+            wal.append(WalRecord::Delete { flow_id: "flow1".to_string() }).unwrap();
+        }
+        
+        // The buffered records still arrive, then the follower must reconnect
+        assert_eq!(follow.live.try_iter().count(), FOLLOWER_BUFFER);
+        assert!(follow.live.recv().is_err());
+        assert!(wal.followers.is_empty());
+    }
+    
+    #[test]
+    fn test_sync_policy_parsing() {
+        assert_eq!("always".parse::<WalSyncPolicy>().unwrap(), WalSyncPolicy::EveryWrite);
+        assert_eq!("batch:64".parse::<WalSyncPolicy>().unwrap(), WalSyncPolicy::Batched(64));
+        assert_eq!(
+            "periodic:250".parse::<WalSyncPolicy>().unwrap(),
+            WalSyncPolicy::Periodic(Duration::from_millis(250))
+        );
+        assert!("batch:0".parse::<WalSyncPolicy>().is_err());
+        assert!("periodic:0".parse::<WalSyncPolicy>().is_err());
+        assert!("sometimes".parse::<WalSyncPolicy>().is_err());
+    }
+}